@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Settings read from ~/.config/pung/config.toml
+/// Every field is optional; command line arguments take precedence over the file.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub receive_port_range: Option<String>,
+    pub send_port_range: Option<String>,
+    pub bind: Option<String>,
+    pub network: Option<String>,
+    pub room: Option<String>,
+    pub room_password: Option<String>,
+    pub secret: Option<String>,
+    pub psk: Option<String>,
+    pub dnssd_domain: Option<String>,
+    pub discovery_backend: Option<String>,
+    pub rendezvous: Option<String>,
+    pub dscp: Option<String>,
+    pub syslog: Option<bool>,
+    pub sleepy: Option<bool>,
+    pub privacy: Option<bool>,
+    pub panic_wipe: Option<String>,    // keys, state or all
+    pub notifications: Option<String>, // on, off or mentions-only
+    // Event (message, mention, join, leave) -> off, bell or a shell command
+    pub alerts: Option<BTreeMap<String, String>>,
+    pub theme: Option<String>,
+    pub tz: Option<String>,          // local, utc or an offset like +8
+    pub time_format: Option<String>, // full, short, 12h, relative, hidden or strftime
+    pub status_bar: Option<bool>,
+    // Theme name -> part (system, event, timestamp, own, direct) -> color name or SGR code
+    pub themes: Option<BTreeMap<String, BTreeMap<String, String>>>,
+    pub max_peers: Option<usize>,
+    pub heartbeat_interval: Option<u64>,        // seconds
+    pub peer_timeout: Option<u64>,              // seconds
+    pub removed_peer_grace_period: Option<u64>, // seconds
+    pub scan_on_start: Option<bool>,
+    pub disabled_features: Option<Vec<String>>,
+    pub allow_plaintext: Option<bool>,
+}
+
+/// Directory holding pung's config and state files
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".config").join("pung"))
+}
+
+/// Load the config file, falling back to defaults if it's missing or invalid
+pub fn load() -> Config {
+    let Some(path) = config_dir().map(|dir| dir.join(CONFIG_FILE)) else {
+        return Config::default();
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => match toml::from_str(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                say!(
+                    "Warning: Could not parse {}, using defaults: {e}",
+                    path.display()
+                );
+                Config::default()
+            }
+        },
+        Err(_) => Config::default(),
+    }
+}
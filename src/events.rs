@@ -0,0 +1,44 @@
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+// Events that aren't picked up quickly enough are dropped for slow subscribers
+const EVENT_BUFFER: usize = 64;
+
+/// Things that happen in the app that other parts may want to react to
+#[derive(Debug, Clone)]
+pub enum Event {
+    // We sent a chat message to our peers
+    ChatSent,
+    // The user ran a command; holds the command word as typed, e.g. "/p"
+    CommandRun(String),
+    // A new peer showed up; placeholders are peers others told us about, which haven't
+    // told us their name or node ID themselves yet
+    PeerDiscovered {
+        username: String,
+        node_id: Option<String>,
+        placeholder: bool,
+    },
+    // A peer answered one of our discoveries; holds its username
+    DiscoveryReply(String),
+    // A peer left or timed out
+    PeerLeft,
+    // Chat from a peer was shown; whether it mentions us as @name
+    ChatReceived {
+        mentions_us: bool,
+    },
+}
+
+static BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+
+fn bus() -> &'static broadcast::Sender<Event> {
+    BUS.get_or_init(|| broadcast::channel(EVENT_BUFFER).0)
+}
+
+/// Publish an event; it's fine if nobody is listening
+pub fn publish(event: Event) {
+    let _ = bus().send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    bus().subscribe()
+}
@@ -0,0 +1,108 @@
+use crate::net::tcp;
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex};
+
+/// Optional features; each can be left out at compile time (cargo features),
+/// switched off at startup or with /features, and the active ones are advertised to peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Stream,
+    SideChannel,
+    Encryption,
+}
+
+impl Feature {
+    pub const ALL: &[Feature] = &[Feature::Stream, Feature::SideChannel, Feature::Encryption];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Stream => "stream",
+            Feature::SideChannel => "side-channel",
+            Feature::Encryption => "encryption",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Feature> {
+        Feature::ALL.iter().copied().find(|f| f.name() == name)
+    }
+
+    // Whether the feature was built into this binary
+    pub fn is_compiled(self) -> bool {
+        match self {
+            Feature::Stream => cfg!(feature = "stream"),
+            Feature::SideChannel => cfg!(feature = "side-channel"),
+            Feature::Encryption => cfg!(feature = "encryption"),
+        }
+    }
+}
+
+// Features switched off, at startup or since
+static DISABLED: LazyLock<Mutex<HashSet<&'static str>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Switch off features by name, returning the names that aren't known features
+pub fn disable(names: &[String]) -> Vec<String> {
+    let mut disabled = HashSet::new();
+    let mut unknown = Vec::new();
+    for name in names {
+        match Feature::by_name(name.trim()) {
+            Some(feature) => {
+                disabled.insert(feature.name());
+            }
+            None => unknown.push(name.clone()),
+        }
+    }
+    if let Ok(mut current) = DISABLED.lock() {
+        *current = disabled;
+    }
+    unknown
+}
+
+/// Switch a feature on or off while running; peers learn of it from our next heartbeat
+pub fn set_active(feature: Feature, active: bool) -> Result<(), String> {
+    if !feature.is_compiled() {
+        return Err(format!(
+            "{} isn't compiled in; build with --features {}",
+            feature.name(),
+            feature.name()
+        ));
+    }
+    // The encryption layer wraps the transport everything else holds on to
+    if feature == Feature::Encryption {
+        return Err(
+            "encryption can only be switched at startup, with or without --disable-features encryption"
+                .to_string(),
+        );
+    }
+    // Its listener is only started when it's active at startup
+    if feature == Feature::SideChannel && active && !tcp::is_listening() {
+        return Err(
+            "the side channel wasn't started; restart without --disable-features side-channel"
+                .to_string(),
+        );
+    }
+    if let Ok(mut disabled) = DISABLED.lock() {
+        if active {
+            disabled.remove(feature.name());
+        } else {
+            disabled.insert(feature.name());
+        }
+    }
+    Ok(())
+}
+
+pub fn is_active(feature: Feature) -> bool {
+    feature.is_compiled()
+        && !DISABLED
+            .lock()
+            .is_ok_and(|disabled| disabled.contains(feature.name()))
+}
+
+/// Names of the active features, as advertised in the capabilities field
+pub fn capabilities() -> Vec<String> {
+    Feature::ALL
+        .iter()
+        .filter(|f| is_active(**f))
+        .map(|f| f.name().to_string())
+        .collect()
+}
@@ -0,0 +1,86 @@
+use crate::ui::{output, privacy};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Only our own records are shown; dependencies (rustyline logs every keystroke) stay quiet
+const TARGET: &str = env!("CARGO_CRATE_NAME");
+
+static LOGGER: Logger = Logger;
+// Whether records go into the chat view as %%% lines, instead of to stderr
+static IN_CHAT: AtomicBool = AtomicBool::new(false);
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with(TARGET)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("{} {}: {}", record.level(), record.target(), record.args());
+        // Records print addresses as they are, which privacy mode keeps out of the chat view
+        if IN_CHAT.load(Ordering::SeqCst) && !privacy::is_enabled() {
+            output::line(format!("%%% {line}"));
+        } else {
+            output::log_line(line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger, at the level RUST_LOG names (e.g. debug or pung=debug) and off
+/// otherwise; /debug changes it later
+pub fn init() {
+    let level = match std::env::var("RUST_LOG") {
+        Ok(filter) => env_level(&filter).unwrap_or_else(|| {
+            eprintln!("Ignoring RUST_LOG={filter}: expected a level, like debug or {TARGET}=debug");
+            LevelFilter::Off
+        }),
+        Err(_) => LevelFilter::Off,
+    };
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+// Our level in a RUST_LOG filter, as env_logger reads it: comma-separated directives that
+// are a level, or target=level where our crate or its modules count; a later one wins
+fn env_level(filter: &str) -> Option<LevelFilter> {
+    let mut level = None;
+    let mut valid = false;
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let (target, name) = match directive.split_once('=') {
+            Some((target, name)) => (Some(target.trim()), name),
+            None => (None, directive),
+        };
+        let parsed = parse_level(name)?;
+        valid = true;
+        if target.is_none_or(|target| TARGET.starts_with(target) || target.starts_with(TARGET)) {
+            level = Some(parsed);
+        }
+    }
+    valid.then(|| level.unwrap_or(LevelFilter::Off))
+}
+
+/// A level name: trace, debug, info, warn, error or off
+pub fn parse_level(name: &str) -> Option<LevelFilter> {
+    name.trim().parse().ok()
+}
+
+/// Log at `level` from now on, into the chat view or to stderr
+pub fn set(level: LevelFilter, in_chat: bool) {
+    log::set_max_level(level);
+    IN_CHAT.store(in_chat, Ordering::SeqCst);
+}
+
+pub fn level() -> LevelFilter {
+    log::max_level()
+}
+
+pub fn in_chat() -> bool {
+    IN_CHAT.load(Ordering::SeqCst)
+}
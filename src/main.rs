@@ -1,4 +1,19 @@
+/// Like println!, but the line goes through ui::output, which colors it by the theme
+macro_rules! say {
+    () => {
+        $crate::ui::output::line(String::new())
+    };
+    ($($arg:tt)*) => {
+        $crate::ui::output::line(format!($($arg)*))
+    };
+}
+
+mod config;
+mod events;
+mod features;
+mod logger;
 mod message;
+mod mirror;
 mod net;
 mod peer;
 mod ui;
@@ -6,27 +21,68 @@ mod utils;
 
 use clap::{Arg, Command};
 use dashmap::DashMap;
+use events::Event;
+use features::Feature;
 use message::Message;
-use net::{listener, sender};
+use net::listener::ListenerContext;
+use net::noise::NoiseLayer;
+use net::simulate::{ImpairedTransport, Impairment};
+use net::stats::{NetStats, SharedNetStats};
+use net::transport::{SharedTransport, UdpTransport};
+use net::{auth, codec, e2e, interfaces, listener, network_id, psk, share, tcp};
 use peer::PeerList;
-use peer::{discovery, heartbeats};
+use peer::lifecycle::{self, PeerEvent};
+use peer::peer_list::DEFAULT_MAX_PEERS;
+use peer::{
+    anti_entropy, cache, discovery, dnssd, groups, heartbeats, known_keys, node_id, pex,
+    rendezvous, sas, scan, ssdp, static_peers,
+};
 use rand::RngCore;
-use rustyline::DefaultEditor;
+use rustyline::config::Configurer;
 use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{ColorMode, Editor};
 use std::io::Write;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, mpsc};
 use tokio::task;
+use ui::completion::LineHelper;
+use ui::reactions::{self, Audience, Recent};
+use ui::theme::{self, Role};
+use ui::{alert, chat_log, notify, output, session, status_bar, wipe};
+use utils::PortRange;
 
 const DEFAULT_RECV_INIT_PORT: u16 = 9487;
+const DEFAULT_RECEIVE_PORT_RANGE: PortRange = PortRange::new(10000, 20000);
+const DEFAULT_SEND_PORT_RANGE: PortRange = PortRange::new(20001, 30000);
 const MAX_USERNAME_LEN: usize = 12;
 // Get version from Cargo.toml
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-#[tokio::main]
-async fn main() -> rustyline::Result<()> {
+fn main() -> rustyline::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let wipe_scope = runtime.block_on(run());
+    // Also when run failed, so the error isn't lost on the alternate screen
+    #[cfg(feature = "tui")]
+    ui::tui::stop();
+    let wipe_scope = wipe_scope?;
+    // Background tasks write files too, e.g. the key of a peer that just showed up, so
+    // /panic only wipes once they've stopped
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    if let Some(scope) = wipe_scope {
+        for failure in wipe::wipe(scope) {
+            say!("Error: Could not delete {failure}");
+        }
+    }
+    Ok(())
+}
+
+// Everything up to exiting; returns what /panic wants wiped, if it was used
+async fn run() -> rustyline::Result<Option<wipe::Scope>> {
+    logger::init();
     let app_state: Arc<DashMap<&str, String>> = Arc::new(DashMap::new());
     // Parse command line arguments using clap
     let matches = Command::new("pung")
@@ -54,18 +110,177 @@ async fn main() -> rustyline::Result<()> {
                 .value_name("WIDTH")
                 .help("Sets the terminal width for message display (default: 80)"),
         )
+        .arg(
+            Arg::new("receive_port_range")
+                .long("receive-port-range")
+                .value_name("MIN-MAX")
+                .help("Sets the range random receive ports are picked from (default: 10000-20000)"),
+        )
+        .arg(
+            Arg::new("send_port_range")
+                .long("send-port-range")
+                .value_name("MIN-MAX")
+                .help("Sets the range random send ports are picked from (default: 20001-30000)"),
+        )
+        .arg(
+            Arg::new("network")
+                .long("network")
+                .value_name("ID")
+                .help("Sets the network ID; messages from other networks are dropped (default: public)"),
+        )
+        .arg(
+            Arg::new("secret")
+                .long("secret")
+                .value_name("PHRASE")
+                .help("Only peers with instances that know this shared secret"),
+        )
+        .arg(
+            Arg::new("psk")
+                .long("psk")
+                .value_name("PASSPHRASE")
+                .help("Encrypts all traffic with a key derived from this passphrase; anything else is dropped"),
+        )
+        .arg(
+            Arg::new("room")
+                .long("room")
+                .value_name("ROOM")
+                .help("Only peers with instances in the same room, so several chats can share a LAN"),
+        )
+        .arg(
+            Arg::new("room-password")
+                .long("room-password")
+                .value_name("PASSWORD")
+                .help("Encrypts the room's traffic with a key derived from this password"),
+        )
+        .arg(
+            Arg::new("bind")
+                .long("bind")
+                .value_name("IP")
+                .help("Binds the sockets to this local address instead of all interfaces"),
+        )
+        .arg(
+            Arg::new("dnssd_domain")
+                .long("dnssd-domain")
+                .value_name("DOMAIN")
+                .help("Finds peers through wide-area DNS-SD records in this domain"),
+        )
+        .arg(
+            Arg::new("rendezvous")
+                .long("rendezvous")
+                .value_name("HOST:PORT")
+                .help("Registers with a rendezvous server to find peers over VPNs and routed networks"),
+        )
+        .arg(
+            Arg::new("scan_on_start")
+                .long("scan-on-start")
+                .action(clap::ArgAction::SetTrue)
+                .help("Probes the receive port range on the local /24, for networks that block broadcasts"),
+        )
+        .arg(
+            Arg::new("max_peers")
+                .long("max-peers")
+                .value_name("COUNT")
+                .value_parser(clap::value_parser!(usize))
+                .help("Caps the peer list, dropping the least recently heard peer (default: 256)"),
+        )
+        .arg(
+            Arg::new("heartbeat_interval")
+                .long("heartbeat-interval")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64).range(1..=3600))
+                .help("Sets how often heartbeats are sent (default: 6)"),
+        )
+        .arg(
+            Arg::new("peer_timeout")
+                .long("peer-timeout")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Sets how long a silent peer is kept, at least 2.5 heartbeat intervals (default: 15)"),
+        )
+        .arg(
+            Arg::new("grace_period")
+                .long("grace-period")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Sets how long removed peers aren't re-added from others' lists (default: 30)"),
+        )
+        .arg(
+            Arg::new("dscp")
+                .long("dscp")
+                .value_name("CLASS")
+                .help("Marks outgoing packets with a DSCP class, e.g. AF21, EF, CS1 or 0-63"),
+        )
+        .arg(
+            Arg::new("syslog")
+                .long("syslog")
+                .action(clap::ArgAction::SetTrue)
+                .help("Mirrors chat and peer events to syslog/journald"),
+        )
+        .arg(
+            Arg::new("sleepy")
+                .long("sleepy")
+                .action(clap::ArgAction::SetTrue)
+                .help("Asks peers for a longer timeout, for machines that suspend often"),
+        )
+        .arg(
+            Arg::new("tz")
+                .long("tz")
+                .value_name("TZ")
+                .help("Timezone for message times: local (default), utc or an offset like +8 or -05:30"),
+        )
+        .arg(
+            Arg::new("privacy")
+                .long("privacy")
+                .action(clap::ArgAction::SetTrue)
+                .help("Never shows peer addresses, only a short hash of each peer's node ID"),
+        )
+        .arg(
+            Arg::new("tui")
+                .long("tui")
+                .action(clap::ArgAction::SetTrue)
+                .help("Full-screen layout with a chat pane, a peer sidebar and an input box"),
+        )
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .value_name("SPEC")
+                .help("Simulates a bad network for outgoing messages, e.g. loss=10%,delay=50ms,jitter=20ms"),
+        )
+        .arg(
+            Arg::new("disable_features")
+                .long("disable-features")
+                .value_name("FEATURES")
+                .help("Switches off optional features, comma separated (stream, side-channel, encryption)"),
+        )
+        .arg(
+            Arg::new("codec")
+                .short('c')
+                .long("codec")
+                .value_name("CODEC")
+                .help("Sets the wire codec for outgoing messages: bincode, json or cbor (default: bincode)"),
+        )
+        .subcommand(
+            Command::new("rendezvous")
+                .about("Runs a rendezvous server that only tells registering peers who else is online")
+                .arg(
+                    Arg::new("port")
+                        .short('p')
+                        .long("port")
+                        .value_name("PORT")
+                        .value_parser(clap::value_parser!(u16))
+                        .required(true)
+                        .help("Sets the UDP port to listen for registrations on"),
+                ),
+        )
         .get_matches();
 
     app_state.insert("static:version", VERSION.to_string());
+    let config = config::load();
     // Extract values from command line arguments
     let username = match matches.get_one::<String>("username") {
         Some(username) => {
             // Limit username to MAX_USERNAME_LEN characters
-            if username.len() > MAX_USERNAME_LEN {
-                username[0..MAX_USERNAME_LEN].to_string()
-            } else {
-                username.clone()
-            }
+            username.chars().take(MAX_USERNAME_LEN).collect()
         }
         None => {
             let mut bytes = [0u8; 2];
@@ -75,51 +290,357 @@ async fn main() -> rustyline::Result<()> {
     };
     app_state.insert("static:username", username.clone());
 
+    // Resolve the port ranges (command line, then config file, then defaults)
+    let mut receive_port_range = port_range_setting(
+        matches.get_one::<String>("receive_port_range"),
+        config.receive_port_range.as_deref(),
+        DEFAULT_RECEIVE_PORT_RANGE,
+    );
+    let mut send_port_range = port_range_setting(
+        matches.get_one::<String>("send_port_range"),
+        config.send_port_range.as_deref(),
+        DEFAULT_SEND_PORT_RANGE,
+    );
+    if receive_port_range.overlaps(&send_port_range) {
+        say!(
+            "Warning: Receive port range {receive_port_range} overlaps send port range {send_port_range}, using defaults"
+        );
+        receive_port_range = DEFAULT_RECEIVE_PORT_RANGE;
+        send_port_range = DEFAULT_SEND_PORT_RANGE;
+    }
+    app_state.insert("static:receive_port_range", receive_port_range.to_string());
+    app_state.insert("static:send_port_range", send_port_range.to_string());
+
     // Generate a random port for sending
-    let send_port = utils::get_random_port(20000, 30000);
+    let send_port = send_port_range.random_port();
     app_state.insert("static:send_port", send_port.to_string());
 
     // Generate a random port for receiving if not specified
     let receive_port = match matches.get_one::<String>("receive_port") {
         Some(port_str) => port_str
             .parse::<u16>()
-            .unwrap_or_else(|_| utils::get_random_port(10000, 20000)),
-        None => utils::get_random_port(10000, 20000),
+            .unwrap_or_else(|_| receive_port_range.random_port()),
+        None => receive_port_range.random_port(),
     };
+    if send_port_range.contains(receive_port) {
+        say!(
+            "Warning: Receive port {receive_port} lies inside the send port range {send_port_range}"
+        );
+    }
     app_state.insert("static:receive_port", receive_port.to_string());
 
     // Get terminal width from command-line arguments or use default
-    let terminal_width = match matches.get_one::<String>("terminal_width") {
-        Some(width_str) => width_str.parse::<usize>().unwrap_or(80),
-        None => 80,
+    if let Some(width_str) = matches.get_one::<String>("terminal_width") {
+        let width = width_str.parse::<usize>().map_err(|e| e.to_string());
+        if let Err(e) = width.and_then(utils::set_chat_width) {
+            say!(
+                "Warning: Invalid width '{width_str}': {e}; using {}",
+                utils::chat_width()
+            );
+        }
+    }
+    app_state.insert("pref:terminal_width", utils::chat_width().to_string());
+
+    // Mirror chat and peer lifecycle events to syslog if requested
+    if matches.get_flag("syslog") || config.syslog.unwrap_or(false) {
+        match mirror::init() {
+            Ok(()) => {
+                app_state.insert("static:syslog", "enabled".to_string());
+            }
+            Err(e) => say!("Warning: Could not connect to syslog: {e}"),
+        }
+    }
+
+    // Let peers know we nap often, so they don't flag us as gone every time
+    if matches.get_flag("sleepy") || config.sleepy.unwrap_or(false) {
+        heartbeats::advertise_sleepy();
+        app_state.insert("static:sleepy", "advertised".to_string());
+    }
+
+    // Keep addresses off the screen, for screen sharing and streaming
+    if matches.get_flag("privacy") || config.privacy.unwrap_or(false) {
+        ui::privacy::set(true);
+    }
+
+    // Desktop notifications for private messages and mentions, when built with them
+    if let Some(name) = &config.notifications {
+        match notify::Mode::parse(name) {
+            Some(mode) => notify::set_mode(mode),
+            None => say!(
+                "Warning: Unknown notifications mode '{name}' (available: on, off, mentions-only), using on"
+            ),
+        }
+    }
+
+    // Sounds for chat and for peers coming and going; /set alert.<event> changes them
+    for (name, value) in config.alerts.clone().unwrap_or_default() {
+        match alert::Event::by_name(&name) {
+            Some(event) => alert::set(event, alert::Sound::parse(&value)),
+            None => {
+                say!("Warning: Unknown alert '{name}' (available: message, mention, join, leave)")
+            }
+        }
+    }
+    for event in alert::Event::ALL {
+        app_state.insert(event.pref_key(), alert::get(*event).describe());
+    }
+    app_state.insert("pref:bell", "on".to_string());
+    alert::start();
+    session::start();
+
+    // Show times in the system's timezone unless told otherwise
+    let time_zone = matches
+        .get_one::<String>("tz")
+        .cloned()
+        .or(config.tz.clone())
+        .unwrap_or_else(|| "local".to_string());
+    match utils::parse_time_zone(&time_zone) {
+        Ok(setting) => {
+            utils::set_time_zone(setting);
+            app_state.insert("pref:tz", time_zone);
+        }
+        Err(e) => {
+            say!("Warning: {e}; using local");
+            app_state.insert("pref:tz", "local".to_string());
+        }
+    }
+
+    let time_format = config
+        .time_format
+        .clone()
+        .unwrap_or_else(|| "full".to_string());
+    match utils::parse_time_format(&time_format) {
+        Ok(setting) => {
+            utils::set_time_format(setting);
+            app_state.insert("pref:time_format", time_format);
+        }
+        Err(e) => {
+            say!("Warning: {e}; using full");
+            app_state.insert("pref:time_format", "full".to_string());
+        }
+    }
+
+    // Colors for system messages, peer events, timestamps and our own chat
+    for warning in theme::add_themes(&config.themes.clone().unwrap_or_default()) {
+        say!("Warning: Skipping {warning}");
+    }
+    let theme_name = config
+        .theme
+        .clone()
+        .unwrap_or_else(|| theme::DEFAULT_THEME.to_string());
+    if !theme::select(&theme_name) {
+        say!(
+            "Warning: Unknown theme '{theme_name}' (available: {}), using {}",
+            theme::names().join(", "),
+            theme::DEFAULT_THEME
+        );
+        theme::select(theme::DEFAULT_THEME);
+    }
+    app_state.insert("pref:theme", theme::current_name().unwrap_or_default());
+
+    // What /panic deletes when it isn't told
+    let panic_scope = match config.panic_wipe.as_deref() {
+        None => wipe::Scope::State,
+        Some(name) => wipe::Scope::parse(name).unwrap_or_else(|| {
+            say!("Warning: Unknown panic_wipe '{name}' (available: keys, state, all), using state");
+            wipe::Scope::State
+        }),
     };
-    app_state.insert("pref:terminal_width", terminal_width.to_string());
+
+    // Switch off optional features, which also stops advertising them to peers
+    let disabled_features: Vec<String> = match matches.get_one::<String>("disable_features") {
+        Some(names) => names.split(',').map(|name| name.to_string()).collect(),
+        None => config.disabled_features.clone().unwrap_or_default(),
+    };
+    let unknown_features = features::disable(&disabled_features);
+    if !unknown_features.is_empty() {
+        say!(
+            "Warning: Unknown features ignored: {}",
+            unknown_features.join(", ")
+        );
+    }
+    app_state.insert("static:features", features::capabilities().join(", "));
+
+    // Select the wire codec; incoming messages are decoded with whatever codec the sender used
+    if let Some(codec_name) = matches.get_one::<String>("codec") {
+        match codec::by_name(codec_name) {
+            Some(selected) => codec::select(selected),
+            None => say!(
+                "Warning: Unknown codec '{codec_name}' (available: {}), using bincode",
+                codec::names().join(", ")
+            ),
+        }
+    }
+    app_state.insert("static:codec", codec::selected().name().to_string());
+
+    // Authenticate peering messages, so strangers on the LAN can't get into the peer list
+    if let Some(secret) = matches
+        .get_one::<String>("secret")
+        .cloned()
+        .or(config.secret.clone())
+    {
+        auth::set_secret(&secret);
+        app_state.insert("static:secret", "set".to_string());
+    }
+
+    // Encrypt everything with a key shared out of band, so a network can stay private
+    // on a LAN we don't trust, e.g. guest Wi-Fi
+    if let Some(passphrase) = matches
+        .get_one::<String>("psk")
+        .cloned()
+        .or(config.psk.clone())
+    {
+        // Carrying on unencrypted would be worse than not starting
+        if let Err(e) = psk::set_passphrase(&passphrase) {
+            say!("Error: Could not derive a key from the pre-shared passphrase: {e}");
+            return Ok(None);
+        }
+        app_state.insert("static:psk", "set".to_string());
+    }
+
+    // Rendezvous servers don't chat, they only bootstrap other peers
+    if let Some(rendezvous_matches) = matches.subcommand_matches("rendezvous") {
+        let port = rendezvous_matches
+            .get_one::<u16>("port")
+            .copied()
+            .unwrap_or_default();
+        rendezvous::serve(port).await?;
+        return Ok(None);
+    }
+
+    // Keep e.g. test and production instances from seeing each other at all
+    if let Some(network) = matches
+        .get_one::<String>("network")
+        .cloned()
+        .or(config.network.clone())
+    {
+        network_id::set(network);
+    }
+    app_state.insert("static:network", network_id::current().to_string());
+
+    // Keep separate chats on the same LAN apart
+    if let Some(room) = matches
+        .get_one::<String>("room")
+        .cloned()
+        .or(config.room.clone())
+    {
+        app_state.insert("static:room", room.clone());
+        if let Some(password) = matches
+            .get_one::<String>("room-password")
+            .cloned()
+            .or(config.room_password.clone())
+        {
+            // Like --psk, a room that's meant to be private mustn't start in the open
+            if let Err(e) = psk::set_room_password(&room, Some(&password)) {
+                say!("Error: Could not derive a key from the room password: {e}");
+                return Ok(None);
+            }
+            app_state.insert("static:room", format!("{room} (password-protected)"));
+        }
+        discovery::join_room(Some(room));
+    }
 
     // Create shared peer list for tracking peers
-    let peer_list = Arc::new(Mutex::new(PeerList::new()));
+    let mut peers = PeerList::new();
+    let max_peers = matches
+        .get_one::<usize>("max_peers")
+        .copied()
+        .or(config.max_peers)
+        .unwrap_or(DEFAULT_MAX_PEERS);
+    peers.set_max_peers(max_peers);
+    app_state.insert("static:max_peers", max_peers.to_string());
+    let peer_list = Arc::new(Mutex::new(peers));
+
+    // Heartbeat timing; large networks want it slower, demos faster
+    let defaults = heartbeats::Timing::default();
+    let timing = heartbeats::Timing {
+        interval: matches
+            .get_one::<u64>("heartbeat_interval")
+            .copied()
+            .or(config.heartbeat_interval)
+            .unwrap_or(defaults.interval),
+        timeout: matches
+            .get_one::<u64>("peer_timeout")
+            .copied()
+            .or(config.peer_timeout)
+            .unwrap_or(defaults.timeout),
+        grace_period: matches
+            .get_one::<u64>("grace_period")
+            .copied()
+            .or(config.removed_peer_grace_period)
+            .unwrap_or(defaults.grace_period),
+    };
+    if let Err(e) = heartbeats::configure(timing) {
+        say!("Warning: Invalid heartbeat timing, {e}; using the defaults");
+    }
+    let timing = heartbeats::timing();
+    app_state.insert("static:heartbeat", timing.to_string());
+    app_state.insert("pref:heartbeat_interval", timing.interval.to_string());
+
+    // Bind a specific local address on multi-homed hosts, so replies leave from a routable interface
+    let bind_setting = matches
+        .get_one::<String>("bind")
+        .cloned()
+        .or(config.bind.clone());
+    let bind_ip: Option<IpAddr> = bind_setting.and_then(|ip| match ip.parse() {
+        Ok(ip) => Some(ip),
+        Err(_) => {
+            say!("Warning: Invalid bind address '{ip}', binding all interfaces");
+            None
+        }
+    });
+    let unspecified: IpAddr = "0.0.0.0".parse().unwrap();
+    let bind_addr = bind_ip.unwrap_or(unspecified);
+    if let Some(ip) = bind_ip {
+        interfaces::pin(ip);
+        app_state.insert("static:bind", ip.to_string());
+    }
 
     // Get local LAN IP address
-    let local_ip = utils::get_local_ip().unwrap_or_else(|| {
-        println!("Warning: Could not determine local IP address, using 0.0.0.0");
-        "0.0.0.0".parse().unwrap()
+    let local_ip = bind_ip.or_else(utils::get_local_ip).unwrap_or_else(|| {
+        say!("Warning: Could not determine local IP address, using 0.0.0.0");
+        unspecified
     });
     app_state.insert("static:local_ip", local_ip.to_string());
 
     // Bind sockets
-    let socket_send = Arc::new(UdpSocket::bind(format!("0.0.0.0:{send_port}")).await?);
+    let socket_send = Arc::new(UdpSocket::bind(SocketAddr::new(bind_addr, send_port)).await?);
     socket_send.set_broadcast(true)?;
 
+    // Mark outgoing traffic so managed switches can prioritize (or deprioritize) it
+    let dscp_setting = matches
+        .get_one::<String>("dscp")
+        .cloned()
+        .or(config.dscp.clone());
+    if let Some(dscp_value) = dscp_setting {
+        match utils::parse_dscp(&dscp_value) {
+            Ok(dscp) => match socket2::SockRef::from(&*socket_send).set_tos(u32::from(dscp) << 2) {
+                Ok(()) => {
+                    app_state.insert(
+                        "static:dscp",
+                        format!("{} ({dscp})", dscp_value.to_ascii_uppercase()),
+                    );
+                }
+                Err(e) => say!("Warning: Could not set DSCP on the send socket: {e}"),
+            },
+            Err(e) => say!("Warning: {e}, not marking outgoing packets"),
+        }
+    }
+
     // Only bind the receive socket
     let socket_recv = Some(Arc::new(
-        UdpSocket::bind(format!("0.0.0.0:{receive_port}")).await?,
+        UdpSocket::bind(SocketAddr::new(bind_addr, receive_port)).await?,
     ));
 
     // Create a proper socket address with the local IP for peer discovery
     let local_addr = SocketAddr::new(local_ip, receive_port);
+    node_id::init(receive_port);
 
     // Always send a discovery broadcast, regardless of whether the init port is available
     // This ensures we can find all peers, even after restarting
     // Try to bind to the init port, but don't worry if it's already in use
+    // This one stays on all interfaces even with --bind: sockets bound to a unicast
+    // address don't receive broadcasts
     let socket_recv_only_for_init =
         match UdpSocket::bind(format!("0.0.0.0:{DEFAULT_RECV_INIT_PORT}")).await {
             Ok(sock) => {
@@ -133,25 +654,70 @@ async fn main() -> rustyline::Result<()> {
             Err(e) => return Err(e.into()),
         };
 
-    // Prepare shared socket for sending
-    let socket_send_clone = socket_send.clone();
+    // Prepare shared transport for sending
+    let net_stats: SharedNetStats = Arc::new(std::sync::Mutex::new(NetStats::new()));
+    let mut transport: SharedTransport =
+        Arc::new(UdpTransport::new(socket_send.clone(), net_stats.clone()));
+
+    // Impair outgoing traffic on purpose, to exercise timeouts and dedup on a single machine
+    if let Some(spec) = matches.get_one::<String>("simulate") {
+        match Impairment::parse(spec) {
+            Ok(impairment) => {
+                app_state.insert("static:simulate", impairment.to_string());
+                transport = Arc::new(ImpairedTransport::new(transport, impairment));
+            }
+            Err(e) => say!("Warning: {e}, not simulating network conditions"),
+        }
+    }
+
+    // Encrypt everything after discovery
+    if features::is_active(Feature::Encryption) {
+        let allow_plaintext = config.allow_plaintext.unwrap_or(false);
+        match NoiseLayer::install(transport.clone(), local_addr, allow_plaintext) {
+            Ok(layer) => {
+                app_state.insert(
+                    "static:encryption",
+                    if allow_plaintext {
+                        "noise (plaintext allowed)".to_string()
+                    } else {
+                        "noise".to_string()
+                    },
+                );
+                transport = layer;
+            }
+            Err(e) => say!("Warning: Could not set up encryption, sending unencrypted: {e}"),
+        }
+    }
+    log::debug!("[Transport] Sending from {}", transport.local_addr()?);
 
     // Set up two-way communication (both sending and receiving)
     if let Some(recv_socket) = socket_recv {
-        // Start the listener
-        let peer_list_clone = peer_list.clone();
-        let username_clone = username.clone();
+        // Accept oversized payloads over TCP on the same port number as the receive socket
+        let (side_channel_tx, side_channel_rx) = mpsc::channel(32);
+        if features::is_active(Feature::SideChannel) {
+            match tcp::start_side_channel(recv_socket.local_addr()?, side_channel_tx).await {
+                Ok(()) => {
+                    if let Some(tcp_port) = tcp::advertised_port() {
+                        app_state.insert("static:tcp_port", tcp_port.to_string());
+                    }
+                }
+                Err(e) => say!("@@@ Continuing without TCP side channel: {e}"),
+            }
+        }
 
-        let terminal_width_clone = terminal_width;
+        // Start the listener
+        let listener_ctx = ListenerContext {
+            transport: transport.clone(),
+            peer_list: Some(peer_list.clone()),
+            username: Some(username.clone()),
+            local_addr: Some(local_addr),
+            net_stats: net_stats.clone(),
+        };
+        let listener_ctx_clone = listener_ctx.clone();
+        let listen_socket = recv_socket.clone();
         tokio::spawn(async move {
-            if let Err(e) = listener::listen(
-                recv_socket.clone(),
-                Some(peer_list_clone),
-                Some(username_clone),
-                Some(local_addr),
-                Some(terminal_width_clone),
-            )
-            .await
+            if let Err(e) =
+                listener::listen(listen_socket, listener_ctx_clone, side_channel_rx).await
             {
                 eprintln!("Listen error: {e:?}");
             }
@@ -159,108 +725,664 @@ async fn main() -> rustyline::Result<()> {
 
         // Only spawn the init listener if we successfully bound to the init port
         if let Some(init_socket) = socket_recv_only_for_init {
-            let peer_list_clone = peer_list.clone();
-            let username_clone = username.clone();
             tokio::spawn(async move {
-                if let Err(e) = listener::listen_for_init(
-                    init_socket,
-                    Some(peer_list_clone),
-                    Some(username_clone),
-                    Some(local_addr),
-                )
-                .await
-                {
+                if let Err(e) = listener::listen_for_init(init_socket, listener_ctx).await {
                     eprintln!("Listen for init error: {e:?}");
                 }
             });
         } else {
             // No special mode - we just don't listen on the init port
             // This is fine as we've already sent a discovery message
-            println!("@@@ Continuing without init port listener (already in use)");
+            say!("@@@ Continuing without init port listener (already in use)");
         }
 
         // Show static state and tips on startup
         ui::app_state::show_static_state(&app_state);
         ui::app_state::show_tips();
 
-        // Start peer discovery - always send a broadcast to find all peers
+        // Contact the peers of the last run directly, they may not hear our broadcast
+        let cached = cache::start(
+            transport.clone(),
+            username.clone(),
+            local_addr,
+            peer_list.clone(),
+        )
+        .await;
+        if cached > 0 {
+            say!("@@@ Contacting {cached} peer(s) from the last run...");
+        }
+
+        // Pick the discovery backends; SSDP gets through routers that filter our broadcasts
+        let backend = config
+            .discovery_backend
+            .clone()
+            .unwrap_or_else(|| "broadcast".to_string());
+        let (use_broadcast, use_ssdp) = match backend.as_str() {
+            "broadcast" => (true, false),
+            "ssdp" => (false, true),
+            "both" => (true, true),
+            other => {
+                say!(
+                    "Warning: Unknown discovery backend '{other}' (available: broadcast, ssdp, both), using broadcast"
+                );
+                (true, false)
+            }
+        };
+        app_state.insert(
+            "static:discovery",
+            match (use_broadcast, use_ssdp) {
+                (true, true) => "broadcast, ssdp",
+                (false, true) => "ssdp",
+                _ => "broadcast",
+            }
+            .to_string(),
+        );
+
+        // Start peer discovery - send a broadcast to find all peers
         // This ensures we can find all peers, even after restarting
-        let username_clone = username.clone();
-        println!("@@@ Sending discovery broadcast to find peers...");
-        discovery::start_discovery(socket_send_clone.clone(), username_clone, local_addr).await?;
+        if use_broadcast {
+            let username_clone = username.clone();
+            say!("@@@ Sending discovery broadcast to find peers...");
+            discovery::start_discovery(
+                transport.clone(),
+                username_clone,
+                local_addr,
+                peer_list.clone(),
+            )
+            .await?;
+        }
+        if use_ssdp {
+            say!("@@@ Searching for peers over SSDP...");
+            ssdp::start(
+                transport.clone(),
+                username.clone(),
+                local_addr,
+                peer_list.clone(),
+            );
+        }
+
+        // Find peers on other subnets through DNS-SD records
+        let dnssd_domain = matches
+            .get_one::<String>("dnssd_domain")
+            .cloned()
+            .or(config.dnssd_domain.clone());
+        if let Some(domain) = dnssd_domain {
+            app_state.insert("static:dnssd_domain", domain.clone());
+            dnssd::start(
+                domain,
+                transport.clone(),
+                username.clone(),
+                local_addr,
+                peer_list.clone(),
+            );
+        }
+
+        // Learn about peers on other networks from a rendezvous server
+        let rendezvous_server = matches
+            .get_one::<String>("rendezvous")
+            .cloned()
+            .or(config.rendezvous.clone());
+        if let Some(server) = rendezvous_server {
+            app_state.insert("static:rendezvous", server.clone());
+            // From the receive socket, so the server's answers come back through any NAT
+            let register_transport: SharedTransport =
+                Arc::new(UdpTransport::new(recv_socket.clone(), net_stats.clone()));
+            rendezvous::register(server, register_transport, username.clone(), local_addr);
+        }
+
+        // Probe for peers directly when neither broadcast nor multicast gets through
+        if (matches.get_flag("scan_on_start") || config.scan_on_start.unwrap_or(false))
+            && let Some(probes) = scan::start(
+                transport.clone(),
+                username.clone(),
+                local_addr,
+                receive_port_range,
+            )
+        {
+            say!(
+                "@@@ Scanning ports {receive_port_range} on the local /24 ({probes} probes, about {} min)",
+                scan::duration_of(probes).as_secs().div_ceil(60)
+            );
+        }
+
+        // Reach known hosts directly, in case broadcasts don't get to them
+        let static_peers = static_peers::load();
+        if !static_peers.is_empty() {
+            app_state.insert("static:static_peers", static_peers.join(", "));
+            static_peers::start(
+                static_peers,
+                transport.clone(),
+                username.clone(),
+                local_addr,
+                peer_list.clone(),
+            );
+        }
 
         // Start heartbeat mechanism
         let peer_list_clone = peer_list.clone();
         let username_clone = username.clone();
         heartbeats::start_heartbeat(
-            socket_send_clone.clone(),
+            transport.clone(),
             username_clone,
             local_addr,
             peer_list_clone,
         )
         .await?;
+
+        // Share peer list changes as they happen
+        pex::start(
+            transport.clone(),
+            username.clone(),
+            local_addr,
+            peer_list.clone(),
+        );
+
+        // Periodically reconcile peer lists with a random peer
+        anti_entropy::start(
+            transport.clone(),
+            username.clone(),
+            local_addr,
+            peer_list.clone(),
+        );
     }
 
-    let rl = Arc::new(Mutex::new(DefaultEditor::new()?));
+    // The TUI has a status bar of its own, and output goes to its chat pane
+    if matches.get_flag("tui") {
+        #[cfg(feature = "tui")]
+        {
+            // Chat fits the pane, unless --width says otherwise
+            let fit_width = matches.get_one::<String>("terminal_width").is_none();
+            let helper = LineHelper::new(peer_list.clone());
+            let printer = ui::tui::start(peer_list.clone(), username.clone(), helper, fit_width)?;
+            output::use_printer(printer);
+        }
+        #[cfg(not(feature = "tui"))]
+        say!("Warning: This build has no TUI; build it with --features tui");
+    }
+    if config.status_bar.unwrap_or(false) && !tui_active() {
+        status_bar::start(peer_list.clone(), username.clone());
+    }
+    let mut editor = Editor::new()?;
+    editor.set_helper(Some(LineHelper::new(peer_list.clone())));
+    // Without a terminal there's no prompt to keep intact, and output goes to stdout as is
+    if !tui_active()
+        && let Ok(printer) = editor.create_external_printer()
+    {
+        output::use_printer(printer);
+    }
+    let rl = Arc::new(Mutex::new(editor));
+    let mut wipe_scope = None;
 
     loop {
-        let rl_clone = rl.clone();
-        let line_result = task::spawn_blocking(move || {
-            let mut rl = rl_clone.blocking_lock();
-            rl.readline("")
-        })
-        .await
-        .map_err(|e| {
-            rustyline::error::ReadlineError::Io(std::io::Error::other(format!("JoinError: {e}")))
-        })?; // handle JoinError (maybe caused by panic etc)
+        let line_result = read_line(&rl, String::new(), false).await?;
 
         match line_result {
             Ok(line) => {
-                print!("\x1B[1A\x1B[2K");
-                std::io::stdout().flush()?;
-                if line.starts_with("/") {
+                notify::record_activity();
+                // The TUI's input box empties itself; the line editor leaves the line behind
+                if !tui_active() {
+                    print!("\x1B[1A\x1B[2K");
+                    std::io::stdout().flush()?;
+                }
+                // Chat that came in while the line was typed has been seen now
+                output::mark_read();
+                // Group messages go to whichever members are online when they're sent
+                if let Some(rest) = line.strip_prefix("/g ") {
+                    let (group, text) = rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+                    let Some(members) = groups::members(group) else {
+                        say!("@@@ There's no group {group}; create it with /group add");
+                        continue;
+                    };
+                    if text.trim().is_empty() {
+                        say!("@@@ Usage: /g <group> <message>");
+                        continue;
+                    }
+                    let msg = Message {
+                        group: Some(group.to_string()),
+                        ..Message::new_chat(
+                            username.clone(),
+                            text.trim().to_string(),
+                            Some(local_addr),
+                        )
+                    };
+                    // Chat only ever leaves end-to-end encrypted
+                    let (recipients, unsealed): (Vec<_>, Vec<_>) = peer_list
+                        .lock()
+                        .await
+                        .get_peers()
+                        .into_iter()
+                        .filter(|peer| members.contains(&peer.username))
+                        .partition(e2e::can_seal_for);
+                    for peer in &recipients {
+                        log::debug!("[Chat] Sending group message to: {}", peer.addr);
+                        tcp::send_to_peer(&transport, peer, &msg).await?;
+                    }
+                    echo_own(&msg);
+                    report_unsealed(&unsealed);
+                    say!(
+                        "@@@ Sent to {} of {} member(s) of {group}",
+                        recipients.len(),
+                        members.len()
+                    );
+                    events::publish(Event::ChatSent);
+                } else if let Some(rest) = line.strip_prefix("/msg ") {
+                    // Private messages only ever leave end-to-end encrypted
+                    let (target, text) = rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+                    if text.trim().is_empty() {
+                        say!("@@@ Usage: /msg <username> <message>");
+                        continue;
+                    }
+                    let peers = peer_list.lock().await.find_matching(target);
+                    let peer = match peers.as_slice() {
+                        [] => {
+                            say!("@@@ No peer named {target}");
+                            continue;
+                        }
+                        [peer] => peer,
+                        _ => {
+                            say!(
+                                "@@@ {target} matches several peers; use its name#n or address (see /peers)"
+                            );
+                            continue;
+                        }
+                    };
+                    if !e2e::can_seal_for(peer) {
+                        say!(
+                            "@@@ Can't send {target} a private message: it has no identity key to encrypt to (older pung?)"
+                        );
+                        continue;
+                    }
+                    let msg = Message {
+                        recipient: Some(target.to_string()),
+                        ..Message::new_chat(
+                            username.clone(),
+                            text.trim().to_string(),
+                            Some(local_addr),
+                        )
+                    };
+                    log::debug!("[Chat] Sending private message to: {}", peer.addr);
+                    tcp::send_to_peer(&transport, peer, &msg).await?;
+                    echo_own(&msg);
+                    events::publish(Event::ChatSent);
+                } else if line == "/react" || line.starts_with("/react ") {
+                    let args: Vec<&str> = line.split_whitespace().skip(1).collect();
+                    let (back, reaction) = match args.as_slice() {
+                        [reaction] => (Some(1), *reaction),
+                        [back, reaction] => (back.parse().ok().filter(|&back| back > 0), *reaction),
+                        _ => (None, ""),
+                    };
+                    let (Some(back), Some(reaction)) = (back, reactions::parse(reaction)) else {
+                        say!(
+                            "@@@ Usage: /react [n] <1-5|emoji> (n counts back from the latest message, default: 1)"
+                        );
+                        continue;
+                    };
+                    let Some(target) = reactions::from_peers(back) else {
+                        say!("@@@ No message to react to");
+                        continue;
+                    };
+                    let msg = Message {
+                        reaction_to: Some(target.message_id.clone()),
+                        group: match &target.audience {
+                            Audience::Group(group) => Some(group.clone()),
+                            _ => None,
+                        },
+                        recipient: (target.audience == Audience::Private)
+                            .then(|| target.sender.clone()),
+                        ..Message::new_chat(username.clone(), reaction, Some(local_addr))
+                    };
+                    // Reactions to a /msg or /g only go back to its sender
+                    let (recipients, unsealed): (Vec<_>, Vec<_>) = peer_list
+                        .lock()
+                        .await
+                        .get_peers()
+                        .into_iter()
+                        .filter(|peer| {
+                            target.audience == Audience::Room
+                                || Some(peer.addr) == target.sender_addr
+                        })
+                        .partition(e2e::can_seal_for);
+                    if target.audience != Audience::Room && recipients.is_empty() {
+                        say!(
+                            "@@@ Can't react: {} isn't reachable right now",
+                            target.sender
+                        );
+                        continue;
+                    }
+                    for peer in &recipients {
+                        log::debug!("[Chat] Sending reaction to: {}", peer.addr);
+                        tcp::send_to_peer(&transport, peer, &msg).await?;
+                    }
+                    echo_own(&msg);
+                    report_unsealed(&unsealed);
+                    events::publish(Event::ChatSent);
+                } else if line == "/join" || line.starts_with("/join ") {
+                    // Switching rooms starts over with the peers of the new room
+                    let room = line.strip_prefix("/join").unwrap_or("").trim().to_string();
+                    let password = if room.is_empty() {
+                        None
+                    } else {
+                        let password =
+                            ask_secret(&rl, format!("Password for {room} (empty for none): "))
+                                .await?;
+                        Some(password).filter(|password| !password.is_empty())
+                    };
+                    if let Err(e) = psk::set_room_password(&room, password.as_deref()) {
+                        say!("@@@ Could not derive a key from the room password: {e}");
+                        continue;
+                    }
+                    let forgotten = peer_list.lock().await.forget("all");
+                    for peer in &forgotten {
+                        lifecycle::record(
+                            PeerEvent::Forgotten,
+                            &peer.username,
+                            &peer.addr.to_string(),
+                        );
+                    }
+                    if room.is_empty() {
+                        app_state.remove("static:room");
+                        discovery::join_room(None);
+                        say!("@@@ Left the room");
+                    } else {
+                        let protection = if password.is_some() {
+                            " (password-protected)"
+                        } else {
+                            ""
+                        };
+                        app_state.insert("static:room", format!("{room}{protection}"));
+                        discovery::join_room(Some(room.clone()));
+                        say!("@@@ Joined room {room}{protection}");
+                    }
+                    discovery::send_discovery_message(transport.clone(), &username, local_addr)
+                        .await?;
+                } else if line == "/panic" || line.starts_with("/panic ") {
+                    let scope = match line.split_whitespace().nth(1) {
+                        None => panic_scope,
+                        Some(name) => match wipe::Scope::parse(name) {
+                            Some(scope) => scope,
+                            None => {
+                                say!("@@@ Usage: /panic [keys|state|all]");
+                                continue;
+                            }
+                        },
+                    };
+                    // Leave nothing behind on a shared machine: not what's on screen, not
+                    // what was typed, not our keys
+                    rl.lock().await.clear_history()?;
+                    #[cfg(feature = "tui")]
+                    {
+                        ui::tui::clear_history();
+                        ui::tui::stop();
+                    }
+                    status_bar::stop();
+                    wipe::clear_terminal();
+                    wipe_scope = Some(scope);
+                    break;
+                } else if line == "/sas" || line.starts_with("/sas ") {
+                    // Like /verify, with emoji to read out instead of a fingerprint to compare
+                    let target = line.strip_prefix("/sas").unwrap_or("").trim();
+                    if target.is_empty() {
+                        say!("@@@ Usage: /sas <username|address>");
+                        continue;
+                    }
+                    let peers = peer_list.lock().await.find_matching(target);
+                    let peer = match peers.as_slice() {
+                        [] => {
+                            say!("@@@ No peer named {target}");
+                            continue;
+                        }
+                        [peer] => peer,
+                        _ => {
+                            say!(
+                                "@@@ {target} matches several peers; use its name#n or address (see /peers)"
+                            );
+                            continue;
+                        }
+                    };
+                    let Some(key) = peer.key_to_verify() else {
+                        say!("@@@ {target} doesn't sign its messages, there's no key to verify");
+                        continue;
+                    };
+                    say!("@@@ Waiting for {target} to run /sas <your name>...");
+                    let sas = match sas::exchange(&transport, &username, local_addr, peer.addr, key)
+                        .await
+                    {
+                        Ok(sas) => sas,
+                        Err(e) => {
+                            say!("@@@ Not verified: {e}");
+                            continue;
+                        }
+                    };
+                    let symbols: Vec<&str> = sas.iter().map(|(symbol, _)| *symbol).collect();
+                    let names: Vec<&str> = sas.iter().map(|(_, name)| *name).collect();
+                    let lines = vec![
+                        format!("Read these out with {target}:"),
+                        "".to_string(),
+                        format!("    {}", symbols.join("  ")),
+                        format!("    {}", names.join(", ")),
+                        "".to_string(),
+                        "All of them match on both screens only if nobody is in between."
+                            .to_string(),
+                    ];
+                    utils::display_message_block("Verify (/sas)", lines);
+                    let answer = ask(&rl, format!("Do they match {target}'s? [y/N]: ")).await?;
+                    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                        say!(
+                            "@@@ Not verified. If they didn't match, someone may be between you and {target}"
+                        );
+                        continue;
+                    }
+                    let peer_id = known_keys::peer_id(peer.node_id.as_deref(), &peer.username);
+                    match known_keys::verify(peer_id, &peer.username, key) {
+                        Ok(()) => {
+                            peer_list.lock().await.set_verified(&peer.addr, key);
+                            say!("@@@ Marked {target} as verified");
+                        }
+                        Err(e) => say!("@@@ Could not save the known keys: {e}"),
+                    }
+                } else if line.starts_with("/") {
                     let peer_list_clone = peer_list.clone();
-                    let socket_clone = socket_send_clone.clone();
+                    let transport_clone = transport.clone();
                     let username_clone = username.clone();
                     if let Some(response) = ui::commands::handle_command(
                         &line,
                         peer_list_clone,
-                        Some(socket_clone),
+                        Some(transport_clone),
                         Some(username_clone),
                         Some(local_addr),
                         app_state.clone(),
+                        net_stats.clone(),
                     )
                     .await
                     {
                         if response == "exit" {
-                            println!("@@@ bye!");
+                            say!("@@@ bye!");
                             break;
                         }
-                        println!("{response}");
+                        say!("{response}");
                     }
+                    let command = line.split_whitespace().next().unwrap_or("");
+                    events::publish(Event::CommandRun(command.to_string()));
                 } else if line.is_empty() {
                     continue;
+                } else if share::send_line(line.clone()) {
+                    // Typed lines go to the share session while one is running
+                    say!("  │ {line}");
                 } else {
                     let msg = Message::new_chat(username.clone(), line, Some(local_addr));
-                    let peers = peer_list.lock().await.get_peers();
+                    let (peers, unsealed): (Vec<_>, Vec<_>) = peer_list
+                        .lock()
+                        .await
+                        .get_peers()
+                        .into_iter()
+                        .partition(e2e::can_seal_for);
                     for peer in &peers {
-                        let target_addr = peer.addr.to_string();
-                        log::debug!("[Chat] Sending chat message to: {target_addr}");
-                        sender::send_message(socket_send_clone.clone(), &msg, &target_addr).await?;
+                        log::debug!("[Chat] Sending chat message to: {}", peer.addr);
+                        tcp::send_to_peer(&transport, peer, &msg).await?;
                     }
+                    echo_own(&msg);
+                    report_unsealed(&unsealed);
+                    events::publish(Event::ChatSent);
                 }
             }
             Err(ReadlineError::Interrupted) => {
-                println!("@@@ Type [/quit] to exit.");
+                output::mark_read();
+                say!("@@@ Type [/quit] to exit.");
             }
             Err(ReadlineError::Eof) => {
-                println!("@@@ Type [/quit] to exit.");
+                output::mark_read();
+                say!("@@@ Type [/quit] to exit.");
             }
             Err(err) => {
-                println!("Readline error: {err:?}");
+                say!("Readline error: {err:?}");
                 break;
             }
         }
     }
-    Ok(())
+
+    status_bar::stop();
+    // However we got here, let peers drop us right away instead of waiting for a timeout
+    heartbeats::send_goodbyes(&transport, &username, local_addr, &peer_list).await;
+    // After /panic, the cache would only bring back what was just deleted
+    if wipe_scope.is_none()
+        && let Err(e) = cache::save(&peer_list).await
+    {
+        log::error!("Error saving the peer cache: {e}");
+    }
+    Ok(wipe_scope)
+}
+
+type LineEditor = Editor<LineHelper, DefaultHistory>;
+
+// Whether --tui has the terminal
+#[cfg(feature = "tui")]
+fn tui_active() -> bool {
+    ui::tui::is_active()
+}
+
+#[cfg(not(feature = "tui"))]
+fn tui_active() -> bool {
+    false
+}
+
+// The next line typed, from the TUI's input box or the line editor. The outer error is
+// the blocking task failing (maybe caused by panic etc), the inner one the editor's.
+async fn read_line(
+    rl: &Arc<Mutex<LineEditor>>,
+    prompt: String,
+    secret: bool,
+) -> rustyline::Result<rustyline::Result<String>> {
+    #[cfg(feature = "tui")]
+    if ui::tui::is_active() {
+        return Ok(ui::tui::read_line(&prompt, secret).await);
+    }
+    let rl = rl.clone();
+    task::spawn_blocking(move || {
+        let mut rl = rl.blocking_lock();
+        if !secret {
+            return rl.readline(&prompt);
+        }
+        // Masking is done by the highlighter, which only runs with colors on
+        let color_mode = rl.config_mut().color_mode();
+        rl.set_color_mode(ColorMode::Forced);
+        if let Some(helper) = rl.helper() {
+            helper.set_masking(true);
+        }
+        let answer = rl.readline(&prompt);
+        if let Some(helper) = rl.helper() {
+            helper.set_masking(false);
+        }
+        rl.set_color_mode(color_mode);
+        answer
+    })
+    .await
+    .map_err(|e| ReadlineError::Io(std::io::Error::other(format!("JoinError: {e}"))))
+}
+
+// Ask the user something on its own prompt line
+async fn ask(rl: &Arc<Mutex<LineEditor>>, prompt: String) -> rustyline::Result<String> {
+    let answer = read_line(rl, prompt, false).await?;
+    output::mark_read();
+    answer
+}
+
+// Ask for a secret, like a password, on its own prompt line without showing what's typed
+async fn ask_secret(rl: &Arc<Mutex<LineEditor>>, prompt: String) -> rustyline::Result<String> {
+    let answer = read_line(rl, prompt, true).await?;
+    output::mark_read();
+    answer
+}
+
+// Show a chat message we sent like the ones we receive, dimmed or in the private message
+// color, since typing it left nothing on screen
+fn echo_own(msg: &Message) {
+    let group = msg
+        .group
+        .as_ref()
+        .map(|group| format!("<{group}> "))
+        .unwrap_or_default();
+    let from = match (&msg.recipient, &msg.group) {
+        (Some(recipient), _) => format!("{} → {recipient}", msg.sender),
+        (None, Some(group)) => format!("<{group}> {}", msg.sender),
+        (None, None) => msg.sender.clone(),
+    };
+    let (content, separator) = match &msg.reaction_to {
+        Some(message_id) => (reactions::describe(&msg.content, message_id), " "),
+        None => {
+            // So it can be told what peers' reactions are about
+            reactions::record(Recent {
+                message_id: msg.message_id.clone(),
+                sender: msg.sender.clone(),
+                sender_addr: None,
+                content: msg.content.clone(),
+                audience: match &msg.group {
+                    _ if msg.recipient.is_some() => Audience::Private,
+                    Some(group) => Audience::Group(group.clone()),
+                    None => Audience::Room,
+                },
+                own: true,
+            });
+            (msg.content.clone(), ": ")
+        }
+    };
+    chat_log::record(msg.timestamp, &from, msg.sender_addr.as_deref(), &content);
+    let (base_msg, role) = match &msg.recipient {
+        Some(recipient) => (
+            format!("🔒 [{} → {recipient}]{separator}{content}", msg.sender),
+            Role::Direct,
+        ),
+        None => (
+            format!("{group}[{}]{separator}{content}", msg.sender),
+            Role::Own,
+        ),
+    };
+    let line = utils::format_chat_line(&base_msg, msg.timestamp, utils::chat_width());
+    output::own_chat(theme::paint(role, &line));
+}
+
+// Name the peers a chat message wasn't sent to, for lack of a key to encrypt it for them
+fn report_unsealed(peers: &[peer::peer_list::PeerInfo]) {
+    if peers.is_empty() {
+        return;
+    }
+    let names: Vec<&str> = peers.iter().map(|peer| peer.username.as_str()).collect();
+    say!(
+        "@@@ Not sent to {}: no end-to-end key for them yet (older pung, or not signing)",
+        names.join(", ")
+    );
+}
+
+// Pick a port range from the command line or config file, warning about invalid values
+fn port_range_setting(
+    cli_value: Option<&String>,
+    config_value: Option<&str>,
+    default: PortRange,
+) -> PortRange {
+    match cli_value.map(|value| value.as_str()).or(config_value) {
+        Some(value) => PortRange::parse(value).unwrap_or_else(|e| {
+            say!("Warning: {e}, using default port range {default}");
+            default
+        }),
+        None => default,
+    }
 }
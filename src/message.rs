@@ -1,13 +1,85 @@
+use crate::VERSION;
+use crate::features;
+use crate::net::{e2e, frame, identity, network_id, replay, tcp};
+use crate::peer::{discovery, heartbeats, nick, node_id};
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
+// Content of discovery replies; older peers just see another discovery
+const DISCOVERY_REPLY: &str = "DISCOVERY_REPLY";
+
 #[derive(Debug, Serialize, Deserialize, Clone, Encode, Decode)]
 pub enum MessageType {
     Chat,
     Discovery,
     Heartbeat,
     PeerList,
+    StreamChunk,
+    KeepAlive,
+    Goodbye,
+    PeerDigest,
+    PeerExchange,
+    Rename,
+    Sas,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Encode, Decode)]
+pub enum StreamState {
+    Data,
+    End,
+    Abort,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Encode, Decode)]
+pub struct StreamChunk {
+    pub stream_id: String,
+    pub seq: u32,
+    pub state: StreamState,
+    pub title: Option<String>, // Shown in the receiver's header, e.g. "shared session"
+}
+
+// Incremental peer exchange: the sender's peer list changes from one generation to the next;
+// acks carry the generation the receiver is at, in `to_generation`
+#[derive(Debug, Serialize, Deserialize, Clone, Encode, Decode)]
+pub struct PeerExchange {
+    pub from_generation: u64, // 0 means a full list
+    pub to_generation: u64,
+    pub added: Vec<(String, String)>, // (username, addr); no username for placeholders
+    pub left: Vec<String>,            // addrs of peers that said goodbye
+    pub is_ack: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default, Encode, Decode)]
+pub enum Availability {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+impl Availability {
+    pub const ALL: [Availability; 3] =
+        [Availability::Online, Availability::Away, Availability::Busy];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Availability::Online => "online",
+            Availability::Away => "away",
+            Availability::Busy => "busy",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Availability> {
+        Availability::ALL.into_iter().find(|a| a.name() == name)
+    }
+}
+
+// Whether the sender is at the keyboard, shared with every heartbeat
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Encode, Decode)]
+pub struct Presence {
+    pub availability: Availability,
+    pub text: Option<String>, // e.g. "back at 3"
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Encode, Decode)]
@@ -18,47 +90,127 @@ pub struct Message {
     pub timestamp: i64,
     pub msg_type: MessageType,
     pub sender_addr: Option<String>, // String representation of SocketAddr for serialization
-    pub known_peers: Option<Vec<(String, String)>>, // (username, addr as string)
+    pub known_peers: Option<Vec<(String, String)>>, // (username or "", addr as string)
+    pub protocol_range: Option<(u8, u8)>, // (min, max) supported protocol versions
+    pub stream: Option<StreamChunk>,
+    pub tcp_port: Option<u16>, // TCP side-channel port for payloads too big for UDP
+    pub sleepy: Option<bool>,  // Sender suspends often and wants a longer timeout
+    pub heartbeat_seq: Option<u32>, // Increments with every heartbeat round, for loss estimation
+    pub heartbeat_every: Option<(u32, u32)>, // (rounds, seconds) between our heartbeats to the recipient, unless the default
+    pub heartbeat_echo: Option<(u32, u32)>, // (recipient's last heartbeat seq we got, ms since), for RTT
+    pub capabilities: Option<Vec<String>>,  // Names of the sender's active features
+    pub peer_exchange: Option<PeerExchange>,
+    pub room: Option<String>, // Room the sender joined with --room; other rooms' messages are dropped
+    pub network: Option<String>, // Network ID the sender is on; None for peers that predate them
+    pub mac: Option<String>,  // HMAC-SHA256 with the --secret, on peering messages
+    pub presence: Option<Presence>,
+    pub version: Option<String>, // pung release of the sender, on discovery and heartbeats
+    pub node_id: Option<String>, // Stable ID of the sender's installation; None for older peers
+    pub group: Option<String>,   // Group a chat was sent to with /g
+    pub recipient: Option<String>, // Who a private /msg is for, as the sender knows them
+    pub public_key: Option<String>, // Sender's Ed25519 key, on discovery and heartbeats
+    pub e2e_key: Option<String>, // Sender's current ephemeral X25519 key, on discovery and heartbeats
+    pub signature: Option<String>, // Ed25519 signature over everything but itself and the MAC
+    pub enc: Option<bool>,       // Content is end-to-end encrypted for the recipient
+    pub counter: Option<u64>, // Grows with every message the sender makes, so replays fall behind
+    pub reaction_to: Option<String>, // ID of the message a chat reacts to; the content is the reaction
 }
 
 impl Message {
-    pub fn new_chat(sender: String, content: String, sender_addr: Option<SocketAddr>) -> Self {
+    // Common fields shared by every message type
+    fn new(
+        sender: String,
+        content: String,
+        msg_type: MessageType,
+        sender_addr: Option<SocketAddr>,
+    ) -> Self {
         Message {
-            sender,
+            // Renamed with /nick since the caller got its username
+            sender: nick::current().unwrap_or(sender),
             content,
             message_id: nanoid::nanoid!(),
             timestamp: chrono::Utc::now().timestamp(),
-            msg_type: MessageType::Chat,
+            msg_type,
             sender_addr: sender_addr.map(|addr| addr.to_string()),
             known_peers: None,
+            protocol_range: None,
+            stream: None,
+            tcp_port: None,
+            sleepy: None,
+            heartbeat_seq: None,
+            heartbeat_every: None,
+            heartbeat_echo: None,
+            capabilities: None,
+            peer_exchange: None,
+            room: discovery::room(),
+            network: Some(network_id::current().to_string()),
+            mac: None,
+            presence: None,
+            version: None,
+            node_id: Some(node_id::current().to_string()),
+            group: None,
+            recipient: None,
+            public_key: None,
+            e2e_key: None,
+            signature: None,
+            enc: None,
+            counter: Some(replay::next_counter()),
+            reaction_to: None,
         }
     }
 
+    pub fn new_chat(sender: String, content: String, sender_addr: Option<SocketAddr>) -> Self {
+        Message::new(sender, content, MessageType::Chat, sender_addr)
+    }
+
     pub fn new_discovery(sender: String, sender_addr: SocketAddr) -> Self {
         Message {
-            sender,
-            content: "DISCOVERY".to_string(),
-            message_id: nanoid::nanoid!(),
-            timestamp: chrono::Utc::now().timestamp(),
-            msg_type: MessageType::Discovery,
-            sender_addr: Some(sender_addr.to_string()),
-            known_peers: None,
+            protocol_range: Some(frame::supported_range()),
+            tcp_port: tcp::advertised_port(),
+            sleepy: heartbeats::advertises_sleepy().then_some(true),
+            capabilities: Some(features::capabilities()),
+            version: Some(VERSION.to_string()),
+            public_key: Some(identity::public_key()),
+            e2e_key: Some(e2e::ephemeral_key()),
+            ..Message::new(
+                sender,
+                "DISCOVERY".to_string(),
+                MessageType::Discovery,
+                Some(sender_addr),
+            )
         }
     }
 
-    pub fn new_heartbeat(
-        sender: String,
-        sender_addr: SocketAddr,
-        known_peers: Vec<(String, String)>,
-    ) -> Self {
+    // Answer to a discovery; peers don't answer these again, so discoveries can't ping-pong
+    pub fn new_discovery_reply(sender: String, sender_addr: SocketAddr) -> Self {
         Message {
-            sender,
-            content: "HEARTBEAT".to_string(),
-            message_id: nanoid::nanoid!(),
-            timestamp: chrono::Utc::now().timestamp(),
-            msg_type: MessageType::Heartbeat,
-            sender_addr: Some(sender_addr.to_string()),
-            known_peers: Some(known_peers),
+            content: DISCOVERY_REPLY.to_string(),
+            ..Message::new_discovery(sender, sender_addr)
+        }
+    }
+
+    pub fn is_discovery_reply(&self) -> bool {
+        matches!(self.msg_type, MessageType::Discovery) && self.content == DISCOVERY_REPLY
+    }
+
+    // Known peers are shared through peer exchange messages instead
+    pub fn new_heartbeat(sender: String, sender_addr: SocketAddr, seq: u32) -> Self {
+        Message {
+            heartbeat_seq: Some(seq),
+            presence: Some(heartbeats::presence()),
+            protocol_range: Some(frame::supported_range()),
+            tcp_port: tcp::advertised_port(),
+            sleepy: heartbeats::advertises_sleepy().then_some(true),
+            capabilities: Some(features::capabilities()),
+            version: Some(VERSION.to_string()),
+            public_key: Some(identity::public_key()),
+            e2e_key: Some(e2e::ephemeral_key()),
+            ..Message::new(
+                sender,
+                "HEARTBEAT".to_string(),
+                MessageType::Heartbeat,
+                Some(sender_addr),
+            )
         }
     }
 
@@ -66,14 +218,83 @@ impl Message {
         // Format peer list as a comma-separated string
         let peer_list = peers.join(",");
 
+        Message::new(sender, peer_list, MessageType::PeerList, Some(sender_addr))
+    }
+
+    pub fn new_stream_chunk(
+        sender: String,
+        sender_addr: SocketAddr,
+        stream_id: String,
+        seq: u32,
+        state: StreamState,
+        title: Option<String>,
+        content: String,
+    ) -> Self {
         Message {
+            stream: Some(StreamChunk {
+                stream_id,
+                seq,
+                state,
+                title,
+            }),
+            ..Message::new(sender, content, MessageType::StreamChunk, Some(sender_addr))
+        }
+    }
+
+    pub fn new_keepalive(sender: String, sender_addr: SocketAddr) -> Self {
+        Message::new(
             sender,
-            content: peer_list,
-            message_id: nanoid::nanoid!(),
-            timestamp: chrono::Utc::now().timestamp(),
-            msg_type: MessageType::PeerList,
-            sender_addr: Some(sender_addr.to_string()),
-            known_peers: None,
+            String::new(),
+            MessageType::KeepAlive,
+            Some(sender_addr),
+        )
+    }
+
+    // Anti-entropy exchange; content says which step it is, known_peers carries the full list
+    pub fn new_peer_digest(
+        sender: String,
+        sender_addr: SocketAddr,
+        content: String,
+        known_peers: Option<Vec<(String, String)>>,
+    ) -> Self {
+        Message {
+            known_peers,
+            ..Message::new(sender, content, MessageType::PeerDigest, Some(sender_addr))
+        }
+    }
+
+    pub fn new_peer_exchange(
+        sender: String,
+        sender_addr: SocketAddr,
+        peer_exchange: PeerExchange,
+    ) -> Self {
+        Message {
+            peer_exchange: Some(peer_exchange),
+            ..Message::new(
+                sender,
+                "PEX".to_string(),
+                MessageType::PeerExchange,
+                Some(sender_addr),
+            )
         }
     }
+
+    // Content is the name we went by until now
+    pub fn new_rename(sender: String, previous: String, sender_addr: SocketAddr) -> Self {
+        Message::new(sender, previous, MessageType::Rename, Some(sender_addr))
+    }
+
+    // Content is a step of a /sas exchange: "commit <hex>" or "reveal <hex>"
+    pub fn new_sas(sender: String, step: String, sender_addr: SocketAddr) -> Self {
+        Message::new(sender, step, MessageType::Sas, Some(sender_addr))
+    }
+
+    pub fn new_goodbye(sender: String, sender_addr: SocketAddr) -> Self {
+        Message::new(
+            sender,
+            "GOODBYE".to_string(),
+            MessageType::Goodbye,
+            Some(sender_addr),
+        )
+    }
 }
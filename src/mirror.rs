@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use syslog::{Facility, Formatter5424, Logger, LoggerBackend};
+
+// SD-ID for our structured data; the "@32473" suffix is the RFC 5424 example enterprise number
+const SD_ID: &str = "pung@32473";
+const MSGID_CHAT: u32 = 1;
+const MSGID_PEER: u32 = 2;
+
+// Set once at startup when mirroring is enabled; every function below is a no-op otherwise
+static SYSLOG: OnceLock<Mutex<Logger<LoggerBackend, Formatter5424>>> = OnceLock::new();
+
+/// Connect to the local syslog socket (journald listens there on systemd hosts)
+pub fn init() -> Result<(), String> {
+    let formatter = Formatter5424 {
+        facility: Facility::LOG_USER,
+        process: "pung".to_string(),
+        pid: std::process::id(),
+        ..Formatter5424::default()
+    };
+    let logger = syslog::unix(formatter).map_err(|e| e.to_string())?;
+    let _ = SYSLOG.set(Mutex::new(logger));
+    Ok(())
+}
+
+/// Mirror a received chat message
+pub fn chat(sender: &str, sender_addr: Option<&str>, content: &str) {
+    let mut fields = vec![("event", "chat"), ("sender", sender)];
+    if let Some(addr) = sender_addr {
+        fields.push(("addr", addr));
+    }
+    send(MSGID_CHAT, &fields, &format!("[{sender}]: {content}"));
+}
+
+/// Mirror a peer lifecycle event such as "discovered" or "timed_out"
+pub fn peer_event(event: &str, username: &str, addr: &str) {
+    let fields = [("event", event), ("peer", username), ("addr", addr)];
+    send(
+        MSGID_PEER,
+        &fields,
+        &format!("peer {username} ({addr}) {event}"),
+    );
+}
+
+fn send(msgid: u32, fields: &[(&str, &str)], message: &str) {
+    let Some(logger) = SYSLOG.get() else {
+        return;
+    };
+
+    let params: HashMap<String, String> = fields
+        .iter()
+        .map(|(key, value)| (key.to_string(), escape_param(value)))
+        .collect();
+    let data = HashMap::from([(SD_ID.to_string(), params)]);
+
+    if let Ok(mut logger) = logger.lock()
+        && let Err(e) = logger.info((msgid, data, message))
+    {
+        log::error!("Error mirroring to syslog: {e}");
+    }
+}
+
+// Structured data values must escape '"', '\' and ']' (RFC 5424, section 6.3.3)
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,101 @@
+use crate::message::Message;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+// SHA-256 block size, for HMAC (RFC 2104)
+const BLOCK_SIZE: usize = 64;
+
+// Set by --secret; only peers that know it can get into the peer list or talk to us
+static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Authenticate peering messages with a shared secret
+pub fn set_secret(phrase: &str) {
+    let _ = SECRET.set(phrase.as_bytes().to_vec());
+}
+
+/// The message with its MAC, if a secret is set; applied when encoding, after everything
+/// else (like the sender address) is final. None if it can't be encoded for the MAC.
+pub fn sign(msg: &Message) -> Option<Cow<'_, Message>> {
+    match SECRET.get() {
+        Some(secret) => {
+            let mut signed = msg.clone();
+            signed.mac = Some(hex::encode(mac(secret, msg)?));
+            Some(Cow::Owned(signed))
+        }
+        _ => Some(Cow::Borrowed(msg)),
+    }
+}
+
+/// Whether the message may be processed: with a secret set, every message needs a valid
+/// MAC, since even a goodbye or a chat can change what we think of a peer
+pub fn verify(msg: &Message) -> bool {
+    let Some(secret) = SECRET.get() else {
+        return true;
+    };
+    let Some(received) = msg.mac.as_ref().and_then(|mac| hex::decode(mac).ok()) else {
+        return false;
+    };
+    let Some(expected) = mac(secret, msg) else {
+        return false;
+    };
+    // Compare in constant time, so the MAC can't be guessed byte by byte
+    received.len() == expected.len()
+        && received
+            .iter()
+            .zip(expected.iter())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// MAC over everything but the MAC itself, including the timestamp and ID the replay guard checks
+fn mac(secret: &[u8], msg: &Message) -> Option<[u8; 32]> {
+    let unsigned = Message {
+        mac: None,
+        ..msg.clone()
+    };
+    let bytes = bincode::encode_to_vec(&unsigned, bincode::config::standard()).ok()?;
+    Some(hmac_sha256(secret, &bytes))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block_key = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block_key[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block_key.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block_key.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_sha256_matches_rfc4231_vectors() {
+        // Test case 2 of RFC 4231
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            hex::encode(mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        // Test case 6: a key longer than the block size is hashed first
+        let mac = hmac_sha256(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+        );
+        assert_eq!(
+            hex::encode(mac),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use crate::config;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+
+const BANLIST_FILE: &str = "banlist.toml";
+
+/// IPs and subnets banned with /ban, kept in ~/.config/pung/banlist.toml
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct BanlistFile {
+    banned: BTreeSet<String>,
+}
+
+/// An IP or a subnet in CIDR notation; a single IP is a subnet with a full-length prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Parses "192.168.1.7", "10.0.0.0/8" or the IPv6 equivalents; host bits are cleared
+    pub fn parse(text: &str) -> Option<Self> {
+        let (ip, prefix) = match text.split_once('/') {
+            Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (text.parse::<IpAddr>().ok()?, None),
+        };
+        let ip = ip.to_canonical();
+        let max_prefix = max_prefix(ip);
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return None;
+        }
+        Some(Self {
+            network: mask(ip, prefix),
+            prefix,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.network.is_ipv4() && mask(ip, self.prefix) == self.network
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix == max_prefix(self.network) {
+            write!(f, "{}", self.network)
+        } else {
+            write!(f, "{}/{}", self.network, self.prefix)
+        }
+    }
+}
+
+fn max_prefix(ip: IpAddr) -> u8 {
+    if ip.is_ipv4() { 32 } else { 128 }
+}
+
+// Keep the first `prefix` bits of the address
+fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let bits = u32::from(ip) & u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4(bits.into())
+        }
+        IpAddr::V6(ip) => {
+            let bits = u128::from(ip) & u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6(bits.into())
+        }
+    }
+}
+
+static BANNED: LazyLock<Mutex<BTreeSet<Subnet>>> = LazyLock::new(|| Mutex::new(load()));
+
+fn banlist_file() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join(BANLIST_FILE))
+}
+
+fn load() -> BTreeSet<Subnet> {
+    let Some(path) = banlist_file() else {
+        return BTreeSet::new();
+    };
+    let Ok(contents) = std::fs::read_to_string(&path) else {
+        return BTreeSet::new();
+    };
+    match toml::from_str::<BanlistFile>(&contents) {
+        Ok(file) => file
+            .banned
+            .iter()
+            .filter_map(|entry| {
+                let subnet = Subnet::parse(entry);
+                if subnet.is_none() {
+                    say!(
+                        "Warning: Ignoring {entry} in {}: not an IP or subnet",
+                        path.display()
+                    );
+                }
+                subnet
+            })
+            .collect(),
+        Err(e) => {
+            say!("Warning: Could not parse {}: {e}", path.display());
+            BTreeSet::new()
+        }
+    }
+}
+
+fn save(banned: &BTreeSet<Subnet>) -> std::io::Result<()> {
+    let path = banlist_file()
+        .ok_or_else(|| std::io::Error::other("could not determine the home directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = BanlistFile {
+        banned: banned.iter().map(Subnet::to_string).collect(),
+    };
+    let contents = toml::to_string(&file).map_err(std::io::Error::other)?;
+    std::fs::write(&path, contents)
+}
+
+/// Ban an IP or subnet; returns false if it was already banned
+pub fn ban(subnet: Subnet) -> std::io::Result<bool> {
+    let mut banned = BANNED
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    if !banned.insert(subnet) {
+        return Ok(false);
+    }
+    save(&banned)?;
+    Ok(true)
+}
+
+/// Lift a ban; returns false if exactly that IP or subnet wasn't banned
+pub fn unban(subnet: Subnet) -> std::io::Result<bool> {
+    let mut banned = BANNED
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    if !banned.remove(&subnet) {
+        return Ok(false);
+    }
+    save(&banned)?;
+    Ok(true)
+}
+
+pub fn list() -> Vec<String> {
+    BANNED
+        .lock()
+        .map(|banned| banned.iter().map(Subnet::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Whether traffic from this IP is dropped, checked before anything else looks at a packet
+pub fn is_banned(ip: IpAddr) -> bool {
+    BANNED
+        .lock()
+        .is_ok_and(|banned| banned.iter().any(|subnet| subnet.contains(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn subnets_contain_their_addresses_only() {
+        let subnet = Subnet::parse("10.0.0.0/8").unwrap();
+        assert!(subnet.contains(ip("10.255.1.2")));
+        assert!(!subnet.contains(ip("11.0.0.1")));
+        assert!(!subnet.contains(ip("::1")));
+
+        let host = Subnet::parse("192.168.1.7").unwrap();
+        assert!(host.contains(ip("192.168.1.7")));
+        assert!(!host.contains(ip("192.168.1.8")));
+
+        let v6 = Subnet::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8:1::5")));
+        assert!(!v6.contains(ip("2001:db9::5")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+
+        let everything = Subnet::parse("0.0.0.0/0").unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_dont_dodge_a_ban() {
+        let subnet = Subnet::parse("192.0.2.0/24").unwrap();
+        assert!(subnet.contains(ip("::ffff:192.0.2.44")));
+        assert_eq!(
+            Subnet::parse("::ffff:192.0.2.44"),
+            Subnet::parse("192.0.2.44")
+        );
+    }
+
+    #[test]
+    fn host_bits_are_cleared() {
+        let subnet = Subnet::parse("10.1.2.3/8").unwrap();
+        assert_eq!(subnet.to_string(), "10.0.0.0/8");
+        assert_eq!(Subnet::parse("10.0.0.0/8"), Some(subnet));
+        assert_eq!(
+            Subnet::parse("192.168.1.7/32").unwrap().to_string(),
+            "192.168.1.7"
+        );
+    }
+
+    #[test]
+    fn invalid_subnets_are_rejected() {
+        assert_eq!(Subnet::parse("10.0.0.0/33"), None);
+        assert_eq!(Subnet::parse("2001:db8::/129"), None);
+        assert_eq!(Subnet::parse("10.0.0.0/-1"), None);
+        assert_eq!(Subnet::parse("10.0.0/8"), None);
+        assert_eq!(Subnet::parse("bob"), None);
+        assert_eq!(Subnet::parse(""), None);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::message::Message;
+use bincode;
+use std::sync::OnceLock;
+
+/// Serializes messages to and from bytes on the wire.
+/// The codec id is carried in the frame header, so receivers can decode any codec
+/// regardless of which one they use for sending.
+pub trait Codec: Send + Sync {
+    fn id(&self) -> u8;
+    fn name(&self) -> &'static str;
+    fn encode(&self, msg: &Message) -> Result<Vec<u8>, String>;
+    fn decode(&self, bytes: &[u8]) -> Option<Message>;
+}
+
+/// Compact binary encoding (the default)
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn id(&self) -> u8 {
+        b'b'
+    }
+
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn encode(&self, msg: &Message) -> Result<Vec<u8>, String> {
+        bincode::encode_to_vec(msg, bincode::config::standard()).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<Message> {
+        bincode::decode_from_slice::<Message, _>(bytes, bincode::config::standard())
+            .ok()
+            .map(|(msg, _)| msg)
+    }
+}
+
+/// Human-readable encoding, handy with tcpdump/netcat and for third-party clients
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn id(&self) -> u8 {
+        b'j'
+    }
+
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, msg: &Message) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(msg).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<Message> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+/// Self-describing binary encoding with wide library support
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn id(&self) -> u8 {
+        b'c'
+    }
+
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, msg: &Message) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(msg, &mut bytes).map_err(|e| e.to_string())?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<Message> {
+        ciborium::from_reader(bytes).ok()
+    }
+}
+
+static CODECS: [&dyn Codec; 3] = [&BincodeCodec, &JsonCodec, &CborCodec];
+static SELECTED: OnceLock<&'static dyn Codec> = OnceLock::new();
+
+/// Look up a codec by its name (as given on the command line)
+pub fn by_name(name: &str) -> Option<&'static dyn Codec> {
+    CODECS
+        .iter()
+        .copied()
+        .find(|codec| codec.name().eq_ignore_ascii_case(name))
+}
+
+/// Look up a codec by the id found in a frame header
+pub fn by_id(id: u8) -> Option<&'static dyn Codec> {
+    CODECS.iter().copied().find(|codec| codec.id() == id)
+}
+
+/// Names of all available codecs, for help and error messages
+pub fn names() -> Vec<&'static str> {
+    CODECS.iter().map(|codec| codec.name()).collect()
+}
+
+/// Choose the codec used for outgoing messages; only the first call takes effect
+pub fn select(codec: &'static dyn Codec) {
+    let _ = SELECTED.set(codec);
+}
+
+/// The codec used for outgoing messages (bincode unless selected otherwise)
+pub fn selected() -> &'static dyn Codec {
+    *SELECTED.get_or_init(|| &BincodeCodec)
+}
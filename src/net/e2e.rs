@@ -0,0 +1,304 @@
+use crate::message::Message;
+use crate::net::identity;
+use crate::peer::heartbeats;
+use crate::peer::peer_list::PeerInfo;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// Keeps these keys apart from anything else that might be derived from the same secrets
+const KEY_CONTEXT: &[u8] = b"pung e2e v2";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+// Our ephemeral key is replaced this often, and the one before it kept for as long again
+// for messages sealed before peers heard of the new one; after that, what was sealed for
+// it can't be opened anymore, not even with both identity keys
+const EPHEMERAL_LIFETIME: u64 = 600; // seconds
+// Peers hear of a new key from our heartbeats, so with slow heartbeats a key lives for
+// at least this many of them
+const EPHEMERAL_HEARTBEATS: u64 = 3;
+
+struct Ephemeral {
+    secret: StaticSecret,
+    public: [u8; KEY_LEN],
+    created_at: Instant,
+}
+
+impl Ephemeral {
+    fn generate() -> Self {
+        let secret = StaticSecret::from(random_bytes());
+        Ephemeral {
+            public: PublicKey::from(&secret).to_bytes(),
+            secret,
+            created_at: Instant::now(),
+        }
+    }
+}
+
+// Our current ephemeral key and the one before it
+static EPHEMERAL: LazyLock<Mutex<(Ephemeral, Option<Ephemeral>)>> =
+    LazyLock::new(|| Mutex::new((Ephemeral::generate(), None)));
+
+fn random_bytes() -> [u8; KEY_LEN] {
+    let mut bytes = [0u8; KEY_LEN];
+    rand::rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Our current ephemeral X25519 key, which discovery and heartbeats advertise so peers
+/// can seal content for us; replaced with a new one every few minutes
+pub fn ephemeral_key() -> String {
+    let Ok(mut keys) = EPHEMERAL.lock() else {
+        return String::new();
+    };
+    if keys.0.created_at.elapsed() >= ephemeral_lifetime() {
+        let previous = std::mem::replace(&mut keys.0, Ephemeral::generate());
+        keys.1 = Some(previous);
+    }
+    hex::encode(keys.0.public)
+}
+
+fn ephemeral_lifetime() -> Duration {
+    let heartbeats = EPHEMERAL_HEARTBEATS.saturating_mul(heartbeats::heartbeat_every());
+    Duration::from_secs(EPHEMERAL_LIFETIME.max(heartbeats))
+}
+
+// The secret of one of our ephemeral keys, unless it's been dropped
+fn ephemeral_secret(public: &[u8]) -> Option<StaticSecret> {
+    let keys = EPHEMERAL.lock().ok()?;
+    let (current, previous) = &*keys;
+    if current.public == public {
+        return Some(current.secret.clone());
+    }
+    previous
+        .as_ref()
+        .filter(|previous| {
+            previous.public == public && previous.created_at.elapsed() < 2 * ephemeral_lifetime()
+        })
+        .map(|previous| previous.secret.clone())
+}
+
+// The key for one message: an agreement between the sender's one-off key and the
+// recipient's ephemeral key, which nobody keeps for long, mixed with one between both
+// identity keys, so only the sender could have made it
+fn message_key(
+    identity_shared: [u8; KEY_LEN],
+    ephemeral_shared: &x25519_dalek::SharedSecret,
+    sender_ephemeral: &[u8],
+    recipient_ephemeral: &[u8],
+) -> Option<[u8; KEY_LEN]> {
+    // A low-order key from the other side would make the agreement predictable
+    if !ephemeral_shared.was_contributory() {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_CONTEXT);
+    hasher.update(identity_shared);
+    hasher.update(ephemeral_shared.as_bytes());
+    hasher.update(sender_ephemeral);
+    hasher.update(recipient_ephemeral);
+    Some(hasher.finalize().into())
+}
+
+/// Whether we can seal content for the peer: we know both its identity key and its
+/// current ephemeral key
+pub fn can_seal_for(peer: &PeerInfo) -> bool {
+    peer.public_key.is_some() && peer.e2e_key.is_some()
+}
+
+/// The message with its content encrypted for `peer`; None if we can't encrypt for it,
+/// in which case it must not be sent at all
+pub fn seal_for(peer: &PeerInfo, msg: &Message) -> Option<Message> {
+    let identity_shared = identity::agree(peer.public_key.as_deref()?)?;
+    let recipient_ephemeral: [u8; KEY_LEN] = hex::decode(peer.e2e_key.as_deref()?)
+        .ok()?
+        .try_into()
+        .ok()?;
+    let one_off = StaticSecret::from(random_bytes());
+    let sender_ephemeral = PublicKey::from(&one_off).to_bytes();
+    let key = message_key(
+        identity_shared,
+        &one_off.diffie_hellman(&PublicKey::from(recipient_ephemeral)),
+        &sender_ephemeral,
+        &recipient_ephemeral,
+    )?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    // The message ID is authenticated too, so the content can't be moved to another message
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: msg.content.as_bytes(),
+                aad: msg.message_id.as_bytes(),
+            },
+        )
+        .ok()?;
+    Some(Message {
+        content: hex::encode(
+            [
+                sender_ephemeral.as_slice(),
+                &recipient_ephemeral,
+                &nonce,
+                &ciphertext,
+            ]
+            .concat(),
+        ),
+        enc: Some(true),
+        ..msg.clone()
+    })
+}
+
+/// The message with its content decrypted, given the identity key of its sender; None
+/// if it can't be, e.g. because it was encrypted for someone else or for an ephemeral
+/// key we've dropped since
+pub fn open(msg: &Message, sender_key: Option<&str>) -> Option<Message> {
+    let identity_shared = identity::agree(sender_key?)?;
+    let sealed = hex::decode(&msg.content).ok()?;
+    if sealed.len() < 2 * KEY_LEN + NONCE_LEN {
+        return None;
+    }
+    let (sender_ephemeral, rest) = sealed.split_at(KEY_LEN);
+    let (recipient_ephemeral, rest) = rest.split_at(KEY_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let sender_public: [u8; KEY_LEN] = sender_ephemeral.try_into().ok()?;
+    let key = message_key(
+        identity_shared,
+        &ephemeral_secret(recipient_ephemeral)?.diffie_hellman(&PublicKey::from(sender_public)),
+        sender_ephemeral,
+        recipient_ephemeral,
+    )?;
+    let content = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: msg.message_id.as_bytes(),
+            },
+        )
+        .ok()?;
+    Some(Message {
+        content: String::from_utf8(content).ok()?,
+        enc: None,
+        ..msg.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::peer_list::PeerList;
+    use ed25519_dalek::SigningKey;
+
+    // Only we hold the secrets to our ephemeral keys, so the messages are sealed for us
+    fn ourselves() -> PeerInfo {
+        let addr = "127.0.0.1:10001".parse().unwrap();
+        let mut peer_list = PeerList::new();
+        peer_list.add_or_update_peer(addr, "me".to_string(), None);
+        let mut peer = peer_list.get_peers().remove(0);
+        peer.public_key = Some(identity::public_key());
+        peer.e2e_key = Some(ephemeral_key());
+        peer
+    }
+
+    fn chat(content: &str) -> Message {
+        Message::new_chat("me".to_string(), content.to_string(), None)
+    }
+
+    fn someone_else() -> String {
+        hex::encode(SigningKey::from_bytes(&[7; 32]).verifying_key().as_bytes())
+    }
+
+    #[test]
+    fn sealed_content_round_trips() {
+        let msg = chat("meet at noon");
+        let sealed = seal_for(&ourselves(), &msg).unwrap();
+        assert_ne!(sealed.content, msg.content);
+        assert!(!sealed.content.contains("noon"));
+        assert_eq!(sealed.enc, Some(true));
+
+        let opened = open(&sealed, Some(&identity::public_key())).unwrap();
+        assert_eq!(opened.content, "meet at noon");
+        assert_eq!(opened.enc, None);
+    }
+
+    #[test]
+    fn content_only_opens_with_the_senders_key() {
+        let sealed = seal_for(&ourselves(), &chat("from me")).unwrap();
+        assert!(open(&sealed, Some(&someone_else())).is_none());
+        assert!(open(&sealed, None).is_none());
+    }
+
+    #[test]
+    fn tampered_or_moved_content_is_rejected() {
+        let sealed = seal_for(&ourselves(), &chat("as sent")).unwrap();
+        let key = identity::public_key();
+
+        let mut bytes = hex::decode(&sealed.content).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        let tampered = Message {
+            content: hex::encode(bytes),
+            ..sealed.clone()
+        };
+        assert!(open(&tampered, Some(&key)).is_none());
+
+        // The content is bound to the message ID
+        let moved = Message {
+            message_id: "another-message".to_string(),
+            ..sealed.clone()
+        };
+        assert!(open(&moved, Some(&key)).is_none());
+
+        let truncated = Message {
+            content: sealed.content[..2 * (2 * KEY_LEN + NONCE_LEN) - 2].to_string(),
+            ..sealed
+        };
+        assert!(open(&truncated, Some(&key)).is_none());
+    }
+
+    #[test]
+    fn content_for_another_ephemeral_key_stays_closed() {
+        let mut peer = ourselves();
+        peer.e2e_key = Some(hex::encode(
+            PublicKey::from(&StaticSecret::from([9; 32])).to_bytes(),
+        ));
+        let sealed = seal_for(&peer, &chat("not for us")).unwrap();
+        assert!(open(&sealed, Some(&identity::public_key())).is_none());
+    }
+
+    #[test]
+    fn nothing_is_sealed_without_usable_keys() {
+        let msg = chat("hi");
+
+        let mut peer = ourselves();
+        peer.e2e_key = None;
+        assert!(!can_seal_for(&peer));
+        assert!(seal_for(&peer, &msg).is_none());
+
+        // A low-order key would make the agreement predictable
+        let mut peer = ourselves();
+        peer.e2e_key = Some(hex::encode([0u8; KEY_LEN]));
+        assert!(seal_for(&peer, &msg).is_none());
+
+        let mut peer = ourselves();
+        peer.e2e_key = Some("abcd".to_string());
+        assert!(seal_for(&peer, &msg).is_none());
+    }
+
+    #[test]
+    fn ephemeral_keys_outlive_slow_heartbeats() {
+        heartbeats::set_interval(3600).unwrap();
+        // Peers still hear of the next key before this one is dropped
+        assert!(ephemeral_lifetime() >= Duration::from_secs(3 * 3600));
+        heartbeats::set_interval(heartbeats::DEFAULT_HEARTBEAT_INTERVAL).unwrap();
+        assert_eq!(
+            ephemeral_lifetime(),
+            Duration::from_secs(EPHEMERAL_LIFETIME)
+        );
+    }
+}
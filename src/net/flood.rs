@@ -0,0 +1,168 @@
+use crate::message::MessageType;
+use crate::ui::privacy;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// Each source's messages are counted over windows of this length
+const WINDOW: u64 = 10; // seconds
+// A kind of message a source sent too many of is ignored from it for this long; the
+// source's other messages still get through, so a flood spoofing a peer's address
+// can't cut it off altogether
+const IGNORE_PERIOD: u64 = 60; // seconds
+// Quiet sources are pruned once there are this many, and the longest quiet ones make
+// room beyond that, so spoofed sources can't grow the table
+const MAX_SOURCES: usize = 1024;
+
+static SOURCES: LazyLock<Mutex<HashMap<SocketAddr, Source>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+struct Source {
+    window_start: Instant,
+    counts: HashMap<&'static str, u32>,
+    // The kinds of message being ignored, until when, and how many were dropped since
+    ignored: HashMap<&'static str, (Instant, u64)>,
+}
+
+// The kind of message for counting, and how many of them a source may send per window;
+// generous enough for a peer relaying a busy network, far below what a flood sends
+fn kind(msg_type: &MessageType) -> (&'static str, u32) {
+    match msg_type {
+        MessageType::Discovery => ("discovery", 20),
+        MessageType::Heartbeat | MessageType::KeepAlive => ("heartbeat", 30),
+        MessageType::PeerList | MessageType::PeerDigest | MessageType::PeerExchange => {
+            ("peer list", 20)
+        }
+        MessageType::Chat => ("chat", 50),
+        // Streamed command output comes in bursts; senders pace it below this
+        MessageType::StreamChunk => ("stream", 500),
+        MessageType::Goodbye | MessageType::Rename => ("goodbye/rename", 10),
+        MessageType::Sas => ("sas", 10),
+    }
+}
+
+/// Count a message from a source; false if the source is over its limit for that kind
+/// of message, or that kind is still being ignored from it for an earlier flood
+pub fn allow(source: SocketAddr, msg_type: &MessageType) -> bool {
+    let Ok(mut sources) = SOURCES.lock() else {
+        return true;
+    };
+    count(&mut sources, source, msg_type)
+}
+
+fn count(
+    sources: &mut HashMap<SocketAddr, Source>,
+    source: SocketAddr,
+    msg_type: &MessageType,
+) -> bool {
+    let now = Instant::now();
+    if !sources.contains_key(&source) && sources.len() >= MAX_SOURCES {
+        sources.retain(|_, source| {
+            now.duration_since(source.window_start) < Duration::from_secs(WINDOW)
+                || source.ignored.values().any(|(until, _)| *until > now)
+        });
+        if sources.len() >= MAX_SOURCES
+            && let Some(oldest) = sources
+                .iter()
+                .min_by_key(|(_, source)| source.window_start)
+                .map(|(addr, _)| *addr)
+        {
+            sources.remove(&oldest);
+        }
+    }
+    let source_state = sources.entry(source).or_insert_with(|| Source {
+        window_start: now,
+        counts: HashMap::new(),
+        ignored: HashMap::new(),
+    });
+
+    let (kind, limit) = kind(msg_type);
+    if let Some((until, dropped)) = source_state.ignored.get_mut(kind) {
+        if *until > now {
+            *dropped += 1;
+            return false;
+        }
+        source_state.ignored.remove(kind);
+    }
+    if now.duration_since(source_state.window_start) >= Duration::from_secs(WINDOW) {
+        source_state.window_start = now;
+        source_state.counts.clear();
+    }
+
+    let count = source_state.counts.entry(kind).or_default();
+    *count += 1;
+    if *count <= limit {
+        return true;
+    }
+    source_state
+        .ignored
+        .insert(kind, (now + Duration::from_secs(IGNORE_PERIOD), 1));
+    say!(
+        "### Ignoring {kind} messages from {} for {IGNORE_PERIOD}s: over {limit} in {WINDOW}s",
+        privacy::addr(source, None)
+    );
+    false
+}
+
+/// The sources being ignored right now, for /flood
+pub fn offenders() -> Vec<String> {
+    let now = Instant::now();
+    let Ok(sources) = SOURCES.lock() else {
+        return Vec::new();
+    };
+    let mut offenders: Vec<(Duration, String)> = sources
+        .iter()
+        .flat_map(|(addr, source)| {
+            source.ignored.iter().filter_map(move |(kind, (until, dropped))| {
+                let remaining = until.checked_duration_since(now)?;
+                Some((
+                    remaining,
+                    format!(
+                        "{addr:21} : {kind} flood, ignored for {}s more, {dropped} message(s) dropped",
+                        remaining.as_secs()
+                    ),
+                ))
+            })
+        })
+        .collect();
+    offenders.sort_by_key(|(remaining, _)| std::cmp::Reverse(*remaining));
+    offenders.into_iter().map(|(_, line)| line).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(n: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, (n / 256) as u8, (n % 256) as u8], 10001))
+    }
+
+    #[test]
+    fn sources_are_limited_per_kind_of_message() {
+        let mut sources = HashMap::new();
+        let (_, limit) = kind(&MessageType::Chat);
+        for _ in 0..limit {
+            assert!(count(&mut sources, source(1), &MessageType::Chat));
+        }
+        assert!(!count(&mut sources, source(1), &MessageType::Chat));
+        // Still ignored, not counted again
+        assert!(!count(&mut sources, source(1), &MessageType::Chat));
+        assert_eq!(sources[&source(1)].ignored["chat"].1, 2);
+
+        // A chat flood from a spoofed address doesn't cut the peer's heartbeats off
+        assert!(count(&mut sources, source(1), &MessageType::Heartbeat));
+        // Nor anyone else's chat
+        assert!(count(&mut sources, source(2), &MessageType::Chat));
+    }
+
+    #[test]
+    fn spoofed_sources_cant_grow_the_table() {
+        let mut sources = HashMap::new();
+        for n in 0..MAX_SOURCES + 100 {
+            count(&mut sources, source(n), &MessageType::Discovery);
+        }
+        assert_eq!(sources.len(), MAX_SOURCES);
+        assert!(sources.contains_key(&source(MAX_SOURCES + 99)));
+    }
+}
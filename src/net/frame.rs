@@ -0,0 +1,118 @@
+use crate::message::Message;
+use crate::net::codec::{self, Codec};
+use crate::net::{auth, identity};
+
+// Every datagram starts with a small header: b"PG", the protocol version and the codec id.
+// This lets peers tell releases apart before trying to decode the payload.
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+const MAGIC: &[u8; 2] = b"PG";
+const HEADER_LEN: usize = 4;
+// Largest possible UDP payload; text codecs produce much bigger datagrams than bincode
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// Releases from before protocol versioning sent a bare bincode message with these fields:
+// sender, content, message_id, timestamp, message type (0-3), sender_addr, known_peers
+type LegacyMessage = (
+    String,
+    String,
+    String,
+    i64,
+    u32,
+    Option<String>,
+    Option<Vec<(String, String)>>,
+);
+const LEGACY_MESSAGE_TYPES: u32 = 4;
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// No header, but a message from a release before protocol versioning (treated as v0)
+    Legacy,
+    /// A header with a version outside our supported range
+    UnsupportedVersion(u8),
+    /// A codec id we don't know about
+    UnknownCodec(u8),
+    /// A valid header but the payload couldn't be decoded
+    Malformed,
+}
+
+/// The range of protocol versions we can speak, advertised in discovery and heartbeats
+pub fn supported_range() -> (u8, u8) {
+    (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+}
+
+/// Pick the highest protocol version both sides support, if any
+pub fn negotiate(remote_range: (u8, u8)) -> Option<u8> {
+    let (remote_min, remote_max) = remote_range;
+    let common_max = remote_max.min(PROTOCOL_VERSION);
+    if common_max >= remote_min.max(MIN_PROTOCOL_VERSION) {
+        Some(common_max)
+    } else {
+        None
+    }
+}
+
+/// Encode a message into a framed datagram using the selected codec
+pub fn encode(msg: &Message) -> std::io::Result<Vec<u8>> {
+    let codec = codec::selected();
+    let mut frame = Vec::with_capacity(256);
+    frame.extend_from_slice(MAGIC);
+    frame.push(PROTOCOL_VERSION);
+    frame.push(codec.id());
+    let signed =
+        identity::sign(msg).ok_or_else(|| std::io::Error::other("could not sign message"))?;
+    let signed =
+        auth::sign(&signed).ok_or_else(|| std::io::Error::other("could not sign message"))?;
+    let payload = codec
+        .encode(&signed)
+        .map_err(|e| std::io::Error::other(format!("could not encode message: {e}")))?;
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Decode a framed datagram, rejecting versions we don't understand
+pub fn decode(buf: &[u8]) -> Result<Message, FrameError> {
+    // Accept bare JSON objects, so a message can be typed straight into netcat
+    if buf.first() == Some(&b'{') {
+        return codec::JsonCodec.decode(buf).ok_or(FrameError::Malformed);
+    }
+
+    if buf.len() < HEADER_LEN || &buf[..2] != MAGIC {
+        // Anything else without a header is just noise, not an old release
+        return Err(if is_legacy(buf) {
+            FrameError::Legacy
+        } else {
+            FrameError::Malformed
+        });
+    }
+
+    let version = buf[2];
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(FrameError::UnsupportedVersion(version));
+    }
+
+    let codec = codec::by_id(buf[3]).ok_or(FrameError::UnknownCodec(buf[3]))?;
+    codec
+        .decode(&buf[HEADER_LEN..])
+        .ok_or(FrameError::Malformed)
+}
+
+fn is_legacy(buf: &[u8]) -> bool {
+    matches!(
+        bincode::decode_from_slice::<LegacyMessage, _>(buf, bincode::config::standard()),
+        Ok(((_, _, _, _, kind, _, _), read)) if kind < LEGACY_MESSAGE_TYPES && read == buf.len()
+    )
+}
+
+/// Describe a version mismatch in a way that tells the user who needs to upgrade
+pub fn describe_mismatch(addr: &str, remote_version: u8) -> String {
+    if remote_version > PROTOCOL_VERSION {
+        format!(
+            "@@@ Peer {addr} speaks protocol v{remote_version} (we speak v{PROTOCOL_VERSION}), please upgrade pung"
+        )
+    } else {
+        format!(
+            "@@@ Peer {addr} speaks protocol v{remote_version} (we speak v{PROTOCOL_VERSION}), they need to upgrade pung"
+        )
+    }
+}
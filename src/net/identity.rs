@@ -0,0 +1,422 @@
+use crate::config;
+use crate::message::Message;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const KEY_FILE: &str = "identity.key";
+// Short authentication strings are this many emoji, 6 bits each. Both sides commit to a
+// random nonce before either reveals theirs, so someone in between can't grind keys until
+// the strings match; they get one guess per exchange, with odds of 1 in 2^42.
+const SAS_LENGTH: usize = 7;
+// Emoji that are easy to tell apart, with a name to read out loud
+const SAS_EMOJI: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐴", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("🌈", "rainbow"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("💜", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("🌂", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("📝", "note"),
+    ("📎", "paperclip"),
+    ("🧲", "magnet"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("📞", "telephone"),
+    ("🏁", "flag"),
+    ("🚆", "train"),
+    ("🚲", "bicycle"),
+    ("🚗", "car"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+// Ed25519 key of this installation, kept in ~/.config/pung/identity.key; every message
+// we send is signed with it and discovery and heartbeats carry its public half
+static KEY: LazyLock<SigningKey> = LazyLock::new(load_or_create);
+
+/// What a message's signature says about its sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signed {
+    Valid,
+    // Unsigned, from a peer whose key we don't have (e.g. an older peer)
+    Unsigned,
+    // Doesn't match the key, forged or tampered with, or unsigned although we know
+    // the sender's key
+    Invalid,
+}
+
+fn key_file() -> Option<PathBuf> {
+    // Tests get a key that only lasts for the run, never the user's
+    if cfg!(test) {
+        return None;
+    }
+    config::config_dir().map(|dir| dir.join(KEY_FILE))
+}
+
+fn load_or_create() -> SigningKey {
+    let path = key_file();
+    if let Some(seed) = path
+        .as_ref()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|contents| hex::decode(contents.trim()).ok())
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+    {
+        return SigningKey::from_bytes(&seed);
+    }
+
+    let mut seed = [0u8; 32];
+    rand::rng().fill_bytes(&mut seed);
+    // Without a config directory the key only lasts for this run
+    if let Some(path) = path
+        && let Err(e) = save(&path, &seed)
+    {
+        say!(
+            "Warning: Could not save the identity key to {}: {e}",
+            path.display()
+        );
+    }
+    SigningKey::from_bytes(&seed)
+}
+
+fn save(path: &PathBuf, seed: &[u8; 32]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Whoever can read the key can speak for us, so it's never readable by others, not
+    // even between creating and writing it
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, not to a broken key file being replaced
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(hex::encode(seed).as_bytes())
+}
+
+pub fn public_key() -> String {
+    hex::encode(KEY.verifying_key().as_bytes())
+}
+
+/// Our identity key as an X25519 private key, which the encryption layer's handshakes
+/// use, so a session is with whoever holds the identity key and nobody else
+pub fn x25519_private_key() -> [u8; 32] {
+    KEY.to_scalar_bytes()
+}
+
+/// A peer's identity key as the X25519 key its handshakes use
+pub fn x25519_public_key(peer_key: &str) -> Option<[u8; 32]> {
+    hex::decode(peer_key)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .map(|key| key.to_montgomery().to_bytes())
+}
+
+/// X25519 agreement between our identity key and a peer's, both converted from Ed25519;
+/// the peer computes the same secret from its key and ours
+pub fn agree(peer_key: &str) -> Option<[u8; 32]> {
+    let peer_key = hex::decode(peer_key)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())?;
+    let secret = StaticSecret::from(KEY.to_scalar_bytes());
+    let peer_key = PublicKey::from(peer_key.to_montgomery().to_bytes());
+    Some(secret.diffie_hellman(&peer_key).to_bytes())
+}
+
+/// A short fingerprint of our key and a peer's, the same on both sides, for comparing
+/// out of band; it only matches if neither key was swapped on the way
+pub fn fingerprint(peer_key: &str) -> String {
+    let own_key = public_key();
+    let mut keys = [own_key.as_str(), peer_key];
+    keys.sort();
+    let digest = Sha256::digest(keys.concat());
+    digest[..16]
+        .chunks(2)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// What /sas sends before its nonce: a hash tying the nonce to our key, so neither side
+/// can pick its nonce after seeing the other's
+pub fn sas_commitment(key: &str, nonce: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"pung sas commit")
+        .chain_update(key)
+        .chain_update(nonce)
+        .finalize()
+        .into()
+}
+
+/// A short authentication string for us and a peer: emoji derived from both keys and
+/// the nonces both sides committed to, for reading out to each other across the room;
+/// like the fingerprint, it's the same on both sides unless a key was swapped on the way
+pub fn sas(
+    peer_key: &str,
+    own_nonce: &[u8; 32],
+    peer_nonce: &[u8; 32],
+) -> Vec<(&'static str, &'static str)> {
+    sas_between((&public_key(), own_nonce), (peer_key, peer_nonce))
+}
+
+fn sas_between(
+    own: (&str, &[u8; 32]),
+    peer: (&str, &[u8; 32]),
+) -> Vec<(&'static str, &'static str)> {
+    let mut sides = [own, peer];
+    sides.sort();
+    // Its own digest, so it tells nothing about the fingerprint
+    let mut hasher = Sha256::new().chain_update(b"pung sas v2");
+    for (key, nonce) in sides {
+        hasher.update(key);
+        hasher.update(nonce);
+    }
+    let digest = hasher.finalize();
+    (0..SAS_LENGTH)
+        .map(|i| {
+            // The 6 bits starting at bit 6i, spanning at most two bytes
+            let bit = 6 * i;
+            let pair = u16::from_be_bytes([digest[bit / 8], digest[bit / 8 + 1]]);
+            SAS_EMOJI[(pair >> (10 - bit % 8)) as usize & 63]
+        })
+        .collect()
+}
+
+/// The message with our signature; applied when encoding, before the MAC. None if it
+/// can't be encoded for signing.
+pub fn sign(msg: &Message) -> Option<Message> {
+    sign_with(&KEY, msg)
+}
+
+fn sign_with(key: &SigningKey, msg: &Message) -> Option<Message> {
+    let mut signed = msg.clone();
+    signed.signature = Some(hex::encode(key.sign(&signed_bytes(msg)?).to_bytes()));
+    Some(signed)
+}
+
+/// Check a message's signature against the key it carries, or else the key we know for
+/// its sender; a message carrying a different key than the one we know is invalid, and
+/// so is an unsigned one once we know a key, or stripping the signature would get by
+pub fn check(msg: &Message, known_key: Option<&str>) -> Signed {
+    if let (Some(carried), Some(known)) = (msg.public_key.as_deref(), known_key)
+        && carried != known
+    {
+        return Signed::Invalid;
+    }
+    let Some(key) = msg.public_key.as_deref().or(known_key) else {
+        return Signed::Unsigned;
+    };
+    let Some(signature) = msg.signature.as_deref() else {
+        return if known_key.is_some() {
+            Signed::Invalid
+        } else {
+            Signed::Unsigned
+        };
+    };
+
+    let key = hex::decode(key)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok());
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| <[u8; 64]>::try_from(bytes).ok())
+        .map(|bytes| Signature::from_bytes(&bytes));
+    match (key, signature, signed_bytes(msg)) {
+        (Some(key), Some(signature), Some(bytes)) if key.verify(&bytes, &signature).is_ok() => {
+            Signed::Valid
+        }
+        _ => Signed::Invalid,
+    }
+}
+
+// Everything but the signature and the MAC (which is added after signing)
+fn signed_bytes(msg: &Message) -> Option<Vec<u8>> {
+    let unsigned = Message {
+        signature: None,
+        mac: None,
+        ..msg.clone()
+    };
+    bincode::encode_to_vec(&unsigned, bincode::config::standard()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn public(key: &SigningKey) -> String {
+        hex::encode(key.verifying_key().as_bytes())
+    }
+
+    // A chat message from alice, carrying her key like discovery and heartbeats do
+    fn signed_by(key: &SigningKey) -> Message {
+        let msg = Message {
+            public_key: Some(public(key)),
+            ..Message::new_chat("alice".to_string(), "hi".to_string(), None)
+        };
+        sign_with(key, &msg).unwrap()
+    }
+
+    #[test]
+    fn signed_messages_check_out() {
+        let alice = key(1);
+        let msg = signed_by(&alice);
+        assert_eq!(check(&msg, None), Signed::Valid);
+        assert_eq!(check(&msg, Some(&public(&alice))), Signed::Valid);
+
+        // Without a key of its own, it's checked against the one we know
+        let bare = sign_with(
+            &alice,
+            &Message::new_chat("alice".to_string(), "hi".to_string(), None),
+        )
+        .unwrap();
+        assert_eq!(check(&bare, Some(&public(&alice))), Signed::Valid);
+    }
+
+    #[test]
+    fn tampered_messages_are_invalid() {
+        let msg = Message {
+            content: "send me your keys".to_string(),
+            ..signed_by(&key(1))
+        };
+        assert_eq!(check(&msg, None), Signed::Invalid);
+
+        let msg = Message {
+            signature: Some("00".repeat(64)),
+            ..signed_by(&key(1))
+        };
+        assert_eq!(check(&msg, None), Signed::Invalid);
+    }
+
+    #[test]
+    fn forged_senders_are_invalid() {
+        let alice = key(1);
+        let mallory = key(2);
+
+        // Mallory's own key, where we know alice's
+        let msg = signed_by(&mallory);
+        assert_eq!(check(&msg, Some(&public(&alice))), Signed::Invalid);
+
+        // Alice's key, but signed by mallory
+        let msg = Message {
+            public_key: Some(public(&alice)),
+            ..signed_by(&mallory)
+        };
+        assert_eq!(check(&msg, None), Signed::Invalid);
+
+        // Stripping the signature doesn't get by once we know the key
+        let msg = Message {
+            signature: None,
+            public_key: None,
+            ..signed_by(&mallory)
+        };
+        assert_eq!(check(&msg, Some(&public(&alice))), Signed::Invalid);
+        assert_eq!(check(&msg, None), Signed::Unsigned);
+    }
+
+    #[test]
+    fn both_sides_of_an_x25519_agreement_match() {
+        let (alice, bob) = (key(1), key(2));
+        let agree = |own: &SigningKey, peer: &SigningKey| {
+            let peer = x25519_public_key(&public(peer)).unwrap();
+            StaticSecret::from(own.to_scalar_bytes())
+                .diffie_hellman(&PublicKey::from(peer))
+                .to_bytes()
+        };
+        assert_eq!(agree(&alice, &bob), agree(&bob, &alice));
+        assert_ne!(agree(&alice, &bob), agree(&alice, &key(3)));
+
+        assert_eq!(x25519_public_key("not hex"), None);
+        assert_eq!(x25519_public_key(&"ab".repeat(31)), None);
+    }
+
+    #[test]
+    fn sas_is_the_same_on_both_sides() {
+        let (alice, bob) = (public(&key(1)), public(&key(2)));
+        let (alice_nonce, bob_nonce) = ([1; 32], [2; 32]);
+        let sas = sas_between((&alice, &alice_nonce), (&bob, &bob_nonce));
+        assert_eq!(sas.len(), SAS_LENGTH);
+        assert_eq!(sas, sas_between((&bob, &bob_nonce), (&alice, &alice_nonce)));
+        // A key swapped on the way shows
+        let mallory = public(&key(3));
+        assert_ne!(
+            sas,
+            sas_between((&alice, &alice_nonce), (&mallory, &bob_nonce))
+        );
+        // And every exchange comes out differently
+        assert_ne!(sas, sas_between((&alice, &[3; 32]), (&bob, &bob_nonce)));
+    }
+
+    #[test]
+    fn sas_commitments_bind_key_and_nonce() {
+        let alice = public(&key(1));
+        let commitment = sas_commitment(&alice, &[1; 32]);
+        assert_eq!(commitment, sas_commitment(&alice, &[1; 32]));
+        assert_ne!(commitment, sas_commitment(&alice, &[2; 32]));
+        assert_ne!(commitment, sas_commitment(&public(&key(2)), &[1; 32]));
+    }
+}
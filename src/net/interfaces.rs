@@ -0,0 +1,127 @@
+use crate::message::Message;
+use get_if_addrs::{IfAddr, get_if_addrs};
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Interfaces come and go (VPNs, Wi-Fi), but not often enough to list them for every packet
+const INTERFACE_CACHE_TTL: u64 = 30; // seconds
+
+/// A local IPv4 network we can broadcast on
+#[derive(Debug, Clone, Copy)]
+pub struct Network {
+    pub ip: Ipv4Addr,
+    netmask: Ipv4Addr,
+    pub broadcast: Ipv4Addr,
+}
+
+impl Network {
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask);
+        u32::from(self.ip) & mask == u32::from(ip) & mask
+    }
+}
+
+// Set by --bind; we only send from and advertise that address then
+static PINNED: OnceLock<IpAddr> = OnceLock::new();
+// When the networks were last listed, and what they were
+type NetworkCache = Option<(Instant, Vec<Network>)>;
+
+static CACHE: LazyLock<Mutex<NetworkCache>> = LazyLock::new(|| Mutex::new(None));
+
+/// Restrict discovery and advertised addresses to a single local address
+pub fn pin(ip: IpAddr) {
+    let _ = PINNED.set(ip);
+}
+
+/// Every non-loopback IPv4 network we're on (only the pinned one with --bind)
+pub fn networks() -> Vec<Network> {
+    let networks = match CACHE.lock() {
+        Ok(mut cache) => match cache.as_ref() {
+            Some((listed_at, networks))
+                if listed_at.elapsed() < Duration::from_secs(INTERFACE_CACHE_TTL) =>
+            {
+                networks.clone()
+            }
+            _ => {
+                let networks = list_networks();
+                *cache = Some((Instant::now(), networks.clone()));
+                networks
+            }
+        },
+        Err(_) => list_networks(),
+    };
+    match PINNED.get() {
+        Some(pinned) => networks
+            .into_iter()
+            .filter(|network| IpAddr::V4(network.ip) == *pinned)
+            .collect(),
+        None => networks,
+    }
+}
+
+fn list_networks() -> Vec<Network> {
+    let Ok(interfaces) = get_if_addrs() else {
+        return Vec::new();
+    };
+    interfaces
+        .into_iter()
+        .filter(|interface| !interface.is_loopback())
+        .filter_map(|interface| match interface.addr {
+            IfAddr::V4(v4) => Some(Network {
+                ip: v4.ip,
+                netmask: v4.netmask,
+                // Some interfaces (e.g. point-to-point VPNs) don't report one
+                broadcast: v4
+                    .broadcast
+                    .unwrap_or_else(|| Ipv4Addr::from(u32::from(v4.ip) | !u32::from(v4.netmask))),
+            }),
+            IfAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Our address on the network `peer` is on, if it's directly reachable
+pub fn local_ip_for(peer: IpAddr) -> Option<IpAddr> {
+    let IpAddr::V4(peer) = peer else {
+        return None;
+    };
+    networks()
+        .into_iter()
+        .find(|network| network.contains(peer))
+        .map(|network| IpAddr::V4(network.ip))
+}
+
+/// Whether the address is one of ours, e.g. our own broadcast coming back on another interface
+pub fn is_own_addr(addr: SocketAddr, local_addr: SocketAddr) -> bool {
+    addr == local_addr
+        || (addr.port() == local_addr.port()
+            && networks()
+                .iter()
+                .any(|network| IpAddr::V4(network.ip) == addr.ip()))
+}
+
+/// The message with its sender address on the network of `dest`, so peers on a second
+/// NIC, VLAN or VPN get an address they can actually reach us at
+pub fn addressed_for<'a>(msg: &'a Message, dest: &str) -> Cow<'a, Message> {
+    let Some(sender) = msg
+        .sender_addr
+        .as_ref()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+    else {
+        return Cow::Borrowed(msg);
+    };
+    match dest
+        .parse::<SocketAddr>()
+        .ok()
+        .and_then(|dest| local_ip_for(dest.ip()))
+    {
+        Some(ip) if ip != sender.ip() => {
+            let mut msg = msg.clone();
+            msg.sender_addr = Some(SocketAddr::new(ip, sender.port()).to_string());
+            Cow::Owned(msg)
+        }
+        _ => Cow::Borrowed(msg),
+    }
+}
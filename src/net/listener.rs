@@ -1,103 +1,395 @@
+use crate::events::{self, Event};
+use crate::features::{self, Feature};
 use crate::message::{Message, MessageType};
+use crate::mirror;
+use crate::net::frame::{self, FrameError};
+use crate::net::identity::{self, Signed};
+use crate::net::noise::{self, Opened, SessionPeer};
+use crate::net::replay::ReplayGuard;
+use crate::net::stats::SharedNetStats;
+use crate::net::stream::StreamTracker;
+use crate::net::transport::SharedTransport;
+use crate::net::{auth, ban, e2e, flood, network_id, psk, validate};
 use crate::peer::SharedPeerList;
-use crate::peer::discovery;
-use crate::peer::heartbeats;
-use crate::utils;
-use bincode;
+use crate::peer::discovery::{self, DiscoveryLimiter};
+use crate::peer::{anti_entropy, blocklist, heartbeats, known_keys, nick, node_id, pex, sas};
+use crate::ui::reactions::{self, Audience, Recent};
+use crate::ui::theme::{self, Role};
+use crate::ui::{chat_log, mute, notify, output, privacy};
 use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use unicode_width::UnicodeWidthStr;
+use tokio::sync::{Mutex, mpsc};
+
+// Senders remembered as already told about, for the version and plaintext notices;
+// beyond this, the lists start over, at worst repeating a notice
+const MAX_NOTICES: usize = 256;
+
+/// Shared state the listeners need to process incoming messages
+#[derive(Clone)]
+pub struct ListenerContext {
+    pub transport: SharedTransport,
+    pub peer_list: Option<SharedPeerList>,
+    pub username: Option<String>,
+    pub local_addr: Option<SocketAddr>,
+    pub net_stats: SharedNetStats,
+}
 
 pub async fn listen(
     socket: Arc<UdpSocket>,
-    peer_list: Option<SharedPeerList>,
-    username: Option<String>,
-    local_addr: Option<SocketAddr>,
-    terminal_width: Option<usize>,
+    ctx: ListenerContext,
+    mut side_channel: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
 ) -> std::io::Result<()> {
-    let mut buf = [0u8; 1024];
+    let ListenerContext {
+        transport,
+        peer_list,
+        username,
+        local_addr,
+        net_stats,
+    } = ctx;
+    let mut buf = vec![0u8; frame::MAX_DATAGRAM_SIZE];
 
     // Track seen message IDs to avoid showing duplicates
     // We use a HashSet wrapped in Arc<Mutex<>> for thread safety
     let seen_message_ids = Arc::new(Mutex::new(HashSet::new()));
+    let mut version_notices = VersionNotices::default();
+    let mut replay_guard = ReplayGuard::default();
+    // Source IPs we've told the user we ignore unencrypted messages from
+    let mut plaintext_notices = HashSet::new();
+    let mut discovery_limiter = DiscoveryLimiter::default();
+    let mut stream_tracker = StreamTracker::default();
+    let mut stream_check = tokio::time::interval(std::time::Duration::from_secs(1));
     let socket_clone = socket.clone();
 
     loop {
-        let (len, addr) = socket_clone.clone().recv_from(&mut buf).await?;
-        if let Ok((msg, _)) =
-            bincode::decode_from_slice::<Message, _>(&buf[..len], bincode::config::standard())
-        {
+        // Frames arrive either as datagrams or over the TCP side channel
+        let (frame_bytes, addr) = tokio::select! {
+            received = socket_clone.recv_from(&mut buf) => {
+                let (len, addr) = received?;
+                (buf[..len].to_vec(), addr)
+            }
+            Some(side_frame) = side_channel.recv() => side_frame,
+            _ = stream_check.tick() => {
+                stream_tracker.skip_stalled();
+                continue;
+            }
+        };
+        if ban::is_banned(addr.ip()) {
+            continue;
+        }
+        // With --psk or a room password, anything not sealed with those keys is dropped right away
+        let Some(frame_bytes) = psk::open(&frame_bytes).map(|opened| opened.into_owned()) else {
+            log::debug!("Dropping packet from {addr}: not sealed with our pre-shared keys");
+            continue;
+        };
+        let Some((frame_bytes, session_peer)) = open_packet(frame_bytes, addr).await else {
+            continue;
+        };
+        let encrypted = session_peer.is_some();
+        let decoded = frame::decode(&frame_bytes);
+        record_traffic(&net_stats, addr, frame_bytes.len(), &decoded);
+        if let Ok(mut msg) = decoded {
+            // Counted before anything costly, like checking signatures
+            if !flood::allow(addr, &msg.msg_type) {
+                continue;
+            }
+            if let Err(e) = validate::check(&msg) {
+                log::debug!("Dropping {:?} from {addr}: {e}", msg.msg_type);
+                continue;
+            }
+            // Other networks share the LAN, but never our peer list; neither do strangers
+            // without the secret
+            if !network_id::is_ours(&msg) || !auth::verify(&msg) {
+                continue;
+            }
+            // Instances in other rooms share the network, but no chat, streams or peers
+            if !discovery::is_same_room(&msg) {
+                log::debug!(
+                    "Ignoring {:?} from {} in room {:?}",
+                    msg.msg_type,
+                    msg.sender,
+                    msg.room
+                );
+                continue;
+            }
+            // Forged messages could rewrite the peer list or put words in a peer's mouth;
+            // stripping the signature off doesn't get them past either
+            let signed = check_signature(&peer_list, &mut msg).await;
+            if signed == Signed::Invalid {
+                log::debug!("Dropping {:?} from {addr}: bad signature", msg.msg_type);
+                continue;
+            }
+            // A session vouches for one peer; it can't carry messages in the name of another
+            if let Some(session_peer) = &session_peer
+                && !is_session_peer(&peer_list, &msg, session_peer).await
+            {
+                log::debug!(
+                    "Dropping {:?} from {addr}: not from the peer its session is with",
+                    msg.msg_type
+                );
+                continue;
+            }
+            if let Err(e) = replay_guard.check(&msg, addr) {
+                log::debug!("Dropping {:?} from {addr}: {e}", msg.msg_type);
+                continue;
+            }
+            // Checked after the signature, which covers the encrypted content
+            let e2e_encrypted = msg.enc == Some(true);
+            let msg = if e2e_encrypted {
+                let Some(opened) = open_e2e(&peer_list, &msg).await else {
+                    say!(
+                        "### Could not decrypt a message from {} ({})",
+                        msg.sender,
+                        privacy::addr(addr, msg.node_id.as_deref())
+                    );
+                    continue;
+                };
+                opened
+            } else {
+                msg
+            };
+
+            // With encryption on, only discovery may arrive in plaintext unless explicitly allowed
+            let unencrypted = !encrypted && noise::layer().is_some();
+            let is_discovery = matches!(msg.msg_type, MessageType::Discovery);
+            if unencrypted
+                && !is_discovery
+                && !noise::layer().is_some_and(|layer| layer.allows_plaintext())
+            {
+                if plaintext_notices.len() >= MAX_NOTICES {
+                    plaintext_notices.clear();
+                }
+                if plaintext_notices.insert(addr.ip()) {
+                    say!(
+                        "### Ignoring unencrypted messages from {} ({}); set allow_plaintext = true in config.toml to accept them",
+                        msg.sender,
+                        privacy::addr(addr, msg.node_id.as_deref())
+                    );
+                }
+                continue;
+            }
+            if noise::layer().is_some() && !is_discovery {
+                mark_plaintext(&peer_list, &msg, unencrypted).await;
+            }
+            // Spoofed messages must not keep a peer that's gone in the list
+            let authentic = is_authentic(&peer_list, &msg, signed, addr).await;
+            if authentic {
+                mark_alive(&peer_list, &msg).await;
+            }
+
             // Check if we've already seen this message
             let mut seen_ids = seen_message_ids.lock().await;
 
             // Process the message based on its type
             match msg.msg_type {
                 MessageType::Chat => {
+                    if blocklist::is_blocked(&msg.sender, Some(addr.ip())) {
+                        log::debug!("Dropping chat from blocked {} ({addr})", msg.sender);
+                        continue;
+                    }
+                    if !seen_ids.contains(&msg.message_id)
+                        && let (Some(peer_list), Some(sender_addr)) = (
+                            &peer_list,
+                            msg.sender_addr
+                                .as_ref()
+                                .and_then(|addr| addr.parse::<SocketAddr>().ok()),
+                        )
+                    {
+                        peer_list
+                            .lock()
+                            .await
+                            .record_chat(&sender_addr, &msg.sender);
+                    }
+                    // Muted peers stay connected, their chat just isn't shown
+                    if mute::is_muted(&msg.sender) {
+                        seen_ids.insert(msg.message_id.clone());
+                        continue;
+                    }
                     // If this is a new message (not seen before), display it
                     if seen_ids.insert(msg.message_id.clone()) {
-                        let formatted_time = utils::display_time_from_timestamp(msg.timestamp);
-                        let sender_name = &msg.sender;
+                        let verified_sender = verify_sender(&peer_list, &msg, signed).await;
+                        // A reaction is shown with the start of the message it's about
+                        let content = match &msg.reaction_to {
+                            Some(message_id) => reactions::describe(&msg.content, message_id),
+                            None => {
+                                reactions::record(Recent {
+                                    message_id: msg.message_id.clone(),
+                                    sender: verified_sender.clone(),
+                                    sender_addr: msg
+                                        .sender_addr
+                                        .as_ref()
+                                        .and_then(|addr| addr.parse().ok()),
+                                    content: msg.content.clone(),
+                                    audience: match (&msg.recipient, &msg.group) {
+                                        (Some(_), _) => Audience::Private,
+                                        (None, Some(group)) => Audience::Group(group.clone()),
+                                        (None, None) => Audience::Room,
+                                    },
+                                    own: false,
+                                });
+                                msg.content.clone()
+                            }
+                        };
+                        mirror::chat(&verified_sender, msg.sender_addr.as_deref(), &content);
+                        let from = match (&msg.recipient, &msg.group) {
+                            (Some(_), _) => format!("{verified_sender} → you"),
+                            (None, Some(group)) => format!("<{group}> {verified_sender}"),
+                            (None, None) => verified_sender.clone(),
+                        };
+                        chat_log::record(msg.timestamp, &from, Some(&addr.to_string()), &content);
 
-                        // Verify the sender's username against our peer list if available
-                        let verified_sender = if let (Some(peer_list), Some(sender_addr)) =
-                            (&peer_list, &msg.sender_addr)
-                        {
-                            if let Ok(socket_addr) = sender_addr.parse::<SocketAddr>() {
-                                let peer_list_lock = peer_list.lock().await;
-                                // Use find_username_by_addr to verify the sender's username
-                                match peer_list_lock.find_username_by_addr(&socket_addr) {
-                                    Some(verified_name) => {
-                                        if &verified_name != sender_name {
-                                            // Username mismatch - use the verified one but note the discrepancy
-                                            format!("{verified_name} (claimed: {sender_name})")
-                                        } else {
-                                            // Username matches what we expect
-                                            verified_name
-                                        }
-                                    }
-                                    None => {
-                                        // We don't know this peer yet, use the claimed name but mark as unverified
-                                        format!("{sender_name} (unverified)")
-                                    }
-                                }
+                        // Warnings about how the message arrived go before the sender
+                        let marker = format!(
+                            "{}{}",
+                            if unencrypted && !e2e_encrypted {
+                                "[unencrypted] "
+                            } else {
+                                ""
+                            },
+                            if signed == Signed::Unsigned {
+                                "[unsigned] "
                             } else {
-                                sender_name.clone()
+                                ""
                             }
+                        );
+                        let group = msg
+                            .group
+                            .as_ref()
+                            .map(|group| format!("<{group}> "))
+                            .unwrap_or_default();
+                        let name = theme::paint_sender(
+                            &format!("[{verified_sender}]"),
+                            msg.node_id.as_deref().unwrap_or(&msg.sender),
+                        );
+                        // Private messages name both ends, in their own color, so they
+                        // stand out from room chat
+                        let direct =
+                            theme::paint(Role::Direct, &format!("[{verified_sender} → you]"));
+                        let separator = if msg.reaction_to.is_some() { " " } else { ": " };
+                        // Only private messages that were end-to-end encrypted get the lock
+                        let prefix = if msg.recipient.is_some() && e2e_encrypted {
+                            format!("🔒 {marker}{direct}{separator}")
+                        } else if msg.recipient.is_some() {
+                            format!("{marker}(private) {direct}{separator}")
                         } else {
-                            sender_name.clone()
+                            format!("{marker}{group}{name}{separator}")
                         };
-
-                        // Use provided terminal width or default to 80 characters
-                        let term_width = terminal_width.unwrap_or(80);
-
-                        // Calculate the base message length (sender + content)
-                        let base_msg = format!("[{}]: {}", verified_sender, msg.content);
-                        let time_display = format!(" ({formatted_time})");
-
-                        // Calculate padding needed to right-align the timestamp
-                        // Use UnicodeWidthStr to get the correct display width for multi-byte characters
-                        let base_msg_width = UnicodeWidthStr::width(base_msg.as_str());
-                        let time_display_width = UnicodeWidthStr::width(time_display.as_str());
-                        let padding = term_width
-                            .saturating_sub(base_msg_width)
-                            .saturating_sub(time_display_width);
-
-                        // Format with proper padding
-                        println!("{}{}{}", base_msg, " ".repeat(padding), time_display);
+                        output::chat(output::ChatLine {
+                            prefix,
+                            content,
+                            timestamp: msg.timestamp,
+                        });
+                        // Reactions don't call for attention like messages do
+                        if let Some(username) = &username
+                            && msg.reaction_to.is_none()
+                        {
+                            let own_name = nick::current().unwrap_or_else(|| username.clone());
+                            let mentions_us = notify::mentions(&msg.content, &own_name);
+                            notify::chat(
+                                &verified_sender,
+                                &msg.content,
+                                msg.recipient.is_some(),
+                                mentions_us,
+                            );
+                            events::publish(Event::ChatReceived { mentions_us });
+                        }
+                    }
+                }
+                MessageType::StreamChunk => {
+                    if features::is_active(Feature::Stream)
+                        && seen_ids.insert(msg.message_id.clone())
+                    {
+                        let verified_sender = verify_sender(&peer_list, &msg, signed).await;
+                        stream_tracker.handle_chunk(msg, &verified_sender);
                     }
                 }
-                MessageType::Discovery => {} // Do nothing
+                MessageType::Discovery => {
+                    version_notices.check_range(&msg);
+                    if msg.is_discovery_reply() {
+                        events::publish(Event::DiscoveryReply(msg.sender.clone()));
+                    }
+                    // Direct discoveries (e.g. /connect to our port) and replies to ours
+                    if !discovery_limiter.should_handle(addr) {
+                        log::debug!("[Discovery] Ignoring repeat from {}", msg.sender);
+                    } else if let (Some(peer_list), Some(username), Some(local_addr)) =
+                        (&peer_list, &username, local_addr)
+                        && let Err(e) = discovery::handle_discovery_message(
+                            &msg,
+                            peer_list,
+                            transport.clone(),
+                            username,
+                            local_addr,
+                            authentic,
+                        )
+                        .await
+                    {
+                        log::error!("Error handling discovery message: {e}");
+                    }
+                }
+                MessageType::PeerExchange => {
+                    if let (Some(peer_list), Some(username), Some(local_addr)) =
+                        (&peer_list, &username, local_addr)
+                        && let Err(e) = pex::handle_peer_exchange_message(
+                            &msg,
+                            peer_list,
+                            transport.clone(),
+                            username,
+                            local_addr,
+                            authentic,
+                        )
+                        .await
+                    {
+                        log::error!("Error handling peer exchange: {e}");
+                    }
+                }
+                MessageType::PeerDigest => {
+                    if let (Some(peer_list), Some(username), Some(local_addr)) =
+                        (&peer_list, &username, local_addr)
+                        && let Err(e) = anti_entropy::handle_digest_message(
+                            &msg,
+                            peer_list,
+                            transport.clone(),
+                            username,
+                            local_addr,
+                        )
+                        .await
+                    {
+                        log::error!("Error handling peer digest: {e}");
+                    }
+                }
+                MessageType::Goodbye => {
+                    if let Some(peer_list) = &peer_list {
+                        heartbeats::handle_goodbye_message(&msg, peer_list, authentic).await;
+                    }
+                }
+                MessageType::Rename => {
+                    if let Some(peer_list) = &peer_list {
+                        heartbeats::handle_rename_message(&msg, peer_list, authentic).await;
+                    }
+                }
+                MessageType::Sas => {
+                    // Only the peer itself may take part in checking its key
+                    if authentic && signed == Signed::Valid {
+                        sas::handle_sas_message(&msg);
+                    }
+                }
+                MessageType::KeepAlive => {
+                    log::debug!("[KeepAlive] received from: {} ({addr})", msg.sender);
+                }
                 MessageType::Heartbeat => {
+                    version_notices.check_range(&msg);
                     log::debug!("[Heartbeat] message received from: {}", msg.sender);
                     if let Some(addr) = &msg.sender_addr {
                         log::debug!("[Heartbeat] Sender address: {addr}");
                     }
                     // Handle heartbeat message if peer tracking is enabled
+                    #[allow(clippy::collapsible_if)]
                     if let Some(peer_list) = &peer_list {
-                        if let Err(e) = heartbeats::handle_heartbeat_message(&msg, peer_list).await
+                        if let Err(e) = heartbeats::handle_heartbeat_message(
+                            &msg, addr, peer_list, local_addr, authentic,
+                        )
+                        .await
                         {
                             log::error!("Error handling heartbeat message: {e}");
                         }
@@ -112,13 +404,14 @@ pub async fn listen(
                     log::debug!("[PeerList] Peer list content: {}", msg.content);
 
                     // Handle peer list message if peer tracking is enabled
+                    #[allow(clippy::collapsible_if)]
                     if let (Some(peer_list), Some(username), Some(local_addr)) =
                         (&peer_list, &username, local_addr)
                     {
                         if let Err(e) = discovery::handle_peer_list_message(
                             &msg,
                             peer_list,
-                            socket_clone.clone(),
+                            transport.clone(),
                             username,
                             local_addr,
                         )
@@ -136,55 +429,417 @@ pub async fn listen(
                 // In a real app, you might want a more sophisticated approach
                 *seen_ids = seen_ids.iter().take(500).cloned().collect();
             }
-        } else {
-            log::error!("Received invalid message from {addr}");
+        } else if let Err(e) = decoded {
+            version_notices.report(addr, e);
         }
     }
 }
 
 pub async fn listen_for_init(
     socket_recv_only_for_init: Arc<UdpSocket>,
-    peer_list: Option<SharedPeerList>,
-    username: Option<String>,
-    local_addr: Option<SocketAddr>,
+    ctx: ListenerContext,
 ) -> std::io::Result<()> {
-    let mut buf = [0u8; 1024];
+    let ListenerContext {
+        transport,
+        peer_list,
+        username,
+        local_addr,
+        net_stats,
+        ..
+    } = ctx;
+    let mut buf = vec![0u8; frame::MAX_DATAGRAM_SIZE];
+    let mut version_notices = VersionNotices::default();
+    let mut replay_guard = ReplayGuard::default();
+    let mut discovery_limiter = DiscoveryLimiter::default();
     // Start peer discovery
     loop {
         let (len, addr) = socket_recv_only_for_init
             .clone()
             .recv_from(&mut buf)
             .await?;
-        if let Ok((msg, _)) =
-            bincode::decode_from_slice::<Message, _>(&buf[..len], bincode::config::standard())
-        {
-            // Process the message based on its type
-            if let MessageType::Discovery = msg.msg_type {
-                // DEBUG: Display discovery message
-                log::debug!("[Discovery] message received from: {}", msg.sender);
-                if let Some(addr) = &msg.sender_addr {
-                    log::debug!("[Discovery] Sender address: {addr}");
-                }
-
-                // Handle discovery message if peer tracking is enabled
-                if let (Some(peer_list), Some(username), Some(local_addr)) =
-                    (&peer_list, &username, local_addr)
-                {
-                    if let Err(e) = discovery::handle_discovery_message(
-                        &msg,
-                        peer_list,
-                        socket_recv_only_for_init.clone(),
-                        username,
-                        local_addr,
-                    )
-                    .await
+        if ban::is_banned(addr.ip()) {
+            continue;
+        }
+        let Some(packet) = psk::open(&buf[..len]) else {
+            log::debug!(
+                "Dropping packet on the init port from {addr}: not sealed with our pre-shared keys"
+            );
+            continue;
+        };
+        // Only plaintext discovery is expected on the init port
+        if noise::is_noise_packet(&packet) {
+            log::debug!("Ignoring encrypted packet on the init port from {addr}");
+            continue;
+        }
+        let decoded = frame::decode(&packet);
+        record_traffic(&net_stats, addr, len, &decoded);
+        match decoded {
+            Ok(mut msg) => {
+                if !flood::allow(addr, &msg.msg_type) {
+                    continue;
+                }
+                if let Err(e) = validate::check(&msg) {
+                    log::debug!(
+                        "Dropping {:?} on the init port from {addr}: {e}",
+                        msg.msg_type
+                    );
+                    continue;
+                }
+                if !network_id::is_ours(&msg) || !auth::verify(&msg) {
+                    continue;
+                }
+                if !discovery::is_same_room(&msg) {
+                    log::debug!(
+                        "Ignoring {:?} on the init port from {} in room {:?}",
+                        msg.msg_type,
+                        msg.sender,
+                        msg.room
+                    );
+                    continue;
+                }
+                let signed = check_signature(&peer_list, &mut msg).await;
+                if signed == Signed::Invalid {
+                    log::debug!("Dropping {:?} from {addr}: bad signature", msg.msg_type);
+                    continue;
+                }
+                if let Err(e) = replay_guard.check(&msg, addr) {
+                    log::debug!("Dropping {:?} from {addr}: {e}", msg.msg_type);
+                    continue;
+                }
+
+                // Process the message based on its type
+                if let MessageType::Discovery = msg.msg_type {
+                    version_notices.check_range(&msg);
+                    if !discovery_limiter.should_handle(addr) {
+                        log::debug!("[Discovery] Ignoring repeat from {}", msg.sender);
+                        continue;
+                    }
+                    // DEBUG: Display discovery message
+                    log::debug!("[Discovery] message received from: {}", msg.sender);
+                    if let Some(addr) = &msg.sender_addr {
+                        log::debug!("[Discovery] Sender address: {addr}");
+                    }
+
+                    // Handle discovery message if peer tracking is enabled
+                    let authentic = is_authentic(&peer_list, &msg, signed, addr).await;
+                    #[allow(clippy::collapsible_if)]
+                    if let (Some(peer_list), Some(username), Some(local_addr)) =
+                        (&peer_list, &username, local_addr)
                     {
-                        log::error!("Error handling discovery message: {e}");
+                        if let Err(e) = discovery::handle_discovery_message(
+                            &msg,
+                            peer_list,
+                            transport.clone(),
+                            username,
+                            local_addr,
+                            authentic,
+                        )
+                        .await
+                        {
+                            log::error!("Error handling discovery message: {e}");
+                        }
                     }
                 }
             }
+            Err(e) => version_notices.report(addr, e),
+        }
+    }
+}
+
+// Strip the encryption layer, returning the frame and, if it was encrypted, the peer
+// whose session it came over; None if there's nothing (more) to process, e.g. for
+// handshake packets
+async fn open_packet(packet: Vec<u8>, addr: SocketAddr) -> Option<(Vec<u8>, Option<SessionPeer>)> {
+    if !noise::is_noise_packet(&packet) {
+        return Some((packet, None));
+    }
+    let Some(layer) = noise::layer() else {
+        log::debug!("Ignoring encrypted packet from {addr}: encryption is disabled");
+        return None;
+    };
+    match layer.open(&packet, addr).await {
+        Opened::Frame(frame, peer) => Some((frame, Some(peer))),
+        Opened::Handshake => None,
+        Opened::Rejected(reason) => {
+            log::debug!("[Noise] Rejected packet from {addr}: {reason}");
+            None
+        }
+    }
+}
+
+// Flag peers we accept unencrypted traffic from, so /peers can show it
+async fn mark_plaintext(peer_list: &Option<SharedPeerList>, msg: &Message, unencrypted: bool) {
+    if let Some(peer_list) = peer_list
+        && let Some(addr) = msg
+            .sender_addr
+            .as_ref()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+    {
+        peer_list.lock().await.set_plaintext(&addr, unencrypted);
+    }
+}
+
+// Check a message's signature against the key of the peer it claims to be: the one
+// pinned for its node ID, or that of the peers we have at its address or node ID. A node
+// ID only counts if it's derived from the signing key; unsigned messages lose theirs,
+// anyone could have claimed it.
+async fn check_signature(peer_list: &Option<SharedPeerList>, msg: &mut Message) -> Signed {
+    let sender_addr = msg
+        .sender_addr
+        .as_ref()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok());
+    let mut known_keys = match peer_list {
+        Some(peer_list) => peer_list
+            .lock()
+            .await
+            .keys_of(sender_addr.as_ref(), msg.node_id.as_deref()),
+        None => Vec::new(),
+    };
+    if let Some(pinned) = msg.node_id.as_deref().and_then(known_keys::pinned)
+        && !known_keys.contains(&pinned)
+    {
+        known_keys.push(pinned);
+    }
+    // Whoever it claims to be, it can't be two peers with different keys at once
+    if known_keys.len() > 1 {
+        return Signed::Invalid;
+    }
+    let known_key = known_keys.pop();
+    let signed = identity::check(msg, known_key.as_deref());
+    match signed {
+        Signed::Valid => {
+            let key = msg.public_key.clone().or(known_key).unwrap_or_default();
+            let bound = match (&msg.node_id, sender_addr) {
+                (Some(node_id), Some(addr)) => *node_id == node_id::derive(&key, addr.port()),
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+            if bound {
+                Signed::Valid
+            } else {
+                Signed::Invalid
+            }
+        }
+        Signed::Unsigned => {
+            msg.node_id = None;
+            // Nor is the key it carries, without a signature to show for it
+            msg.public_key = None;
+            msg.e2e_key = None;
+            Signed::Unsigned
+        }
+        Signed::Invalid => Signed::Invalid,
+    }
+}
+
+// Whether a message that came over an encrypted session claims to be from the peer at
+// the other end: the key we know it by is the one the peer proved to have in the
+// handshake or, for peers we have no key for, the address it names is the session's
+async fn is_session_peer(
+    peer_list: &Option<SharedPeerList>,
+    msg: &Message,
+    session_peer: &SessionPeer,
+) -> bool {
+    let Some(sender_addr) = msg
+        .sender_addr
+        .as_ref()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+    else {
+        return false;
+    };
+    let known_key = match (&msg.public_key, peer_list) {
+        (Some(key), _) => Some(key.clone()),
+        (None, Some(peer_list)) => peer_list.lock().await.public_key_of(&sender_addr),
+        (None, None) => None,
+    };
+    match known_key {
+        Some(key) => identity::x25519_public_key(&key)
+            .is_some_and(|key| key.as_slice() == session_peer.key.as_slice()),
+        None => session_peer.addr.parse::<SocketAddr>().ok() == Some(sender_addr),
+    }
+}
+
+// Decrypt end-to-end encrypted content, sealed by its sender with its identity key
+async fn open_e2e(peer_list: &Option<SharedPeerList>, msg: &Message) -> Option<Message> {
+    let key = match (
+        peer_list,
+        msg.sender_addr
+            .as_ref()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok()),
+    ) {
+        (Some(peer_list), Some(addr)) => peer_list.lock().await.public_key_of(&addr),
+        _ => None,
+    };
+    e2e::open(msg, key.as_deref())
+}
+
+// Whether a message really comes from the peer it names: sent from the host it claims
+// to be on (peers send from another port than they receive on, so only the IP counts),
+// or signed with the key of the peers at the address it claims
+async fn is_authentic(
+    peer_list: &Option<SharedPeerList>,
+    msg: &Message,
+    signed: Signed,
+    source: SocketAddr,
+) -> bool {
+    let Some(sender_addr) = msg
+        .sender_addr
+        .as_ref()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+    else {
+        return false;
+    };
+    if sender_addr.ip() == source.ip() {
+        return true;
+    }
+    let (Signed::Valid, Some(peer_list)) = (signed, peer_list) else {
+        return false;
+    };
+    let peer_list = peer_list.lock().await;
+    let key = msg
+        .public_key
+        .clone()
+        .or_else(|| {
+            peer_list
+                .keys_of(Some(&sender_addr), msg.node_id.as_deref())
+                .pop()
+        })
+        .or_else(|| msg.node_id.as_deref().and_then(known_keys::pinned));
+    key.is_some_and(|key| peer_list.speaks_for(&sender_addr, &key))
+}
+
+// Anything a known peer sends shows it's alive, not just its heartbeats, so a peer
+// that's clearly chatting doesn't time out when a few heartbeats get lost
+async fn mark_alive(peer_list: &Option<SharedPeerList>, msg: &Message) {
+    if let Some(peer_list) = peer_list
+        && let Some(addr) = msg
+            .sender_addr
+            .as_ref()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+    {
+        peer_list.lock().await.mark_alive(&addr);
+    }
+}
+
+// Count a received frame against the advertised sender address, if it came from that
+// host (the port differs, it's sent from another socket), or else the source address
+fn record_traffic(
+    net_stats: &SharedNetStats,
+    source_addr: SocketAddr,
+    len: usize,
+    decoded: &Result<Message, FrameError>,
+) {
+    if let Ok(mut stats) = net_stats.lock() {
+        match decoded {
+            Ok(msg) => {
+                let addr = msg
+                    .sender_addr
+                    .as_ref()
+                    .and_then(|addr| addr.parse::<SocketAddr>().ok())
+                    .filter(|addr| addr.ip() == source_addr.ip())
+                    .unwrap_or(source_addr);
+                stats.record_received(&addr.to_string(), len);
+            }
+            Err(_) => stats.record_decode_failure(&source_addr.to_string()),
+        }
+    }
+}
+
+// Resolve the name to display for a message's sender: the name of the peer at its
+// address, whose key the signature was checked against, not the name the message claims
+async fn verify_sender(
+    peer_list: &Option<SharedPeerList>,
+    msg: &Message,
+    signed: Signed,
+) -> String {
+    let sender_name = &msg.sender;
+
+    // Verify the sender's username against our peer list if available
+    if let (Some(peer_list), Some(sender_addr)) = (&peer_list, &msg.sender_addr) {
+        if let Ok(socket_addr) = sender_addr.parse::<SocketAddr>() {
+            let peer_list_lock = peer_list.lock().await;
+            // Use find_username_by_addr to verify the sender's username
+            match peer_list_lock.find_username_by_addr(&socket_addr) {
+                Some(verified_name) => {
+                    // Peers sharing a name are shown as name#1, name#2...
+                    let display_name = peer_list_lock
+                        .display_names()
+                        .remove(&socket_addr)
+                        .unwrap_or_else(|| verified_name.clone());
+                    let owner = known_keys::name_owner(sender_name);
+                    if &verified_name != sender_name
+                        && signed == Signed::Valid
+                        && owner.is_some()
+                        && owner != peer_list_lock.public_key_of(&socket_addr)
+                    {
+                        // Signed by one key, claiming a name another key owns
+                        format!(
+                            "{display_name} (IMPERSONATING {sender_name}: that name belongs to another key)"
+                        )
+                    } else if signed == Signed::Unsigned && owner.is_some() {
+                        // Unsigned, under a name that only its key may use
+                        format!("{sender_name} (impostor?)")
+                    } else if &verified_name != sender_name {
+                        // Username mismatch - use the verified one but note the discrepancy
+                        format!("{display_name} (claimed: {sender_name})")
+                    } else {
+                        // Username matches what we expect
+                        display_name
+                    }
+                }
+                None => {
+                    // We don't know this peer yet, use the claimed name but mark as unverified
+                    format!("{sender_name} (unverified)")
+                }
+            }
         } else {
-            log::error!("Received invalid message from {addr}");
+            sender_name.clone()
+        }
+    } else {
+        sender_name.clone()
+    }
+}
+
+// Remembers which peers we've already warned about, so a version mismatch
+// produces a single notice instead of one line per datagram
+#[derive(Default)]
+struct VersionNotices {
+    notified: HashSet<String>,
+}
+
+impl VersionNotices {
+    // Report a datagram that failed to decode
+    fn report(&mut self, addr: SocketAddr, error: FrameError) {
+        match error {
+            FrameError::Legacy => self.notify_once(addr.to_string(), 0),
+            FrameError::UnsupportedVersion(version) => self.notify_once(addr.to_string(), version),
+            FrameError::UnknownCodec(id) => {
+                log::error!("Received message with unknown codec {id:#04x} from {addr}")
+            }
+            FrameError::Malformed => log::error!("Received invalid message from {addr}"),
+        }
+    }
+
+    // Warn when a peer advertises a protocol range that doesn't overlap ours
+    fn check_range(&mut self, msg: &Message) {
+        if let (Some(range), Some(addr)) = (msg.protocol_range, &msg.sender_addr)
+            && frame::negotiate(range).is_none()
+        {
+            let release = msg
+                .version
+                .as_ref()
+                .map(|version| format!(", pung {version}"))
+                .unwrap_or_default();
+            let addr = privacy::addr(addr, msg.node_id.as_deref());
+            self.notify_once(format!("{} ({addr}{release})", msg.sender), range.1);
+        }
+    }
+
+    fn notify_once(&mut self, peer: String, version: u8) {
+        if self.notified.len() >= MAX_NOTICES {
+            self.notified.clear();
+        }
+        if self.notified.insert(peer.clone()) {
+            say!("{}", frame::describe_mismatch(&peer, version));
         }
     }
 }
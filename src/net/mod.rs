@@ -1,2 +1,21 @@
+pub mod auth;
+pub mod ban;
+pub mod codec;
+pub mod e2e;
+pub mod flood;
+pub mod frame;
+pub mod identity;
+pub mod interfaces;
 pub mod listener;
-pub mod sender;
+pub mod network_id;
+pub mod noise;
+pub mod psk;
+pub mod replay;
+pub mod resolver;
+pub mod share;
+pub mod simulate;
+pub mod stats;
+pub mod stream;
+pub mod tcp;
+pub mod transport;
+pub mod validate;
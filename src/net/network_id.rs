@@ -0,0 +1,22 @@
+use crate::message::Message;
+use std::sync::OnceLock;
+
+// Network of instances that didn't pick one, and of peers that predate network IDs
+pub const DEFAULT_NETWORK: &str = "public";
+
+// Set by --network; every message we send carries it
+static NETWORK: OnceLock<String> = OnceLock::new();
+
+/// Join a network other than the default one
+pub fn set(id: String) {
+    let _ = NETWORK.set(id);
+}
+
+pub fn current() -> &'static str {
+    NETWORK.get().map_or(DEFAULT_NETWORK, String::as_str)
+}
+
+/// Whether the message was sent on our network; anything else is dropped on arrival
+pub fn is_ours(msg: &Message) -> bool {
+    msg.network.as_deref().unwrap_or(DEFAULT_NETWORK) == current()
+}
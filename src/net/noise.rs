@@ -0,0 +1,953 @@
+use crate::message::{Message, MessageType};
+use crate::net::transport::{BoxFuture, SharedTransport, Transport};
+use crate::net::{frame, identity, interfaces};
+use crate::ui::privacy;
+use snow::{HandshakeState, StatelessTransportState};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// XX: both sides learn each other's static key during the handshake, nothing is needed up front
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+// Encrypted packets start with this instead of the plaintext frame header ("PG")
+const MAGIC: &[u8; 2] = b"PN";
+const HANDSHAKE_INIT: u8 = 1;
+const HANDSHAKE_RESPONSE: u8 = 2;
+const HANDSHAKE_FINAL: u8 = 3;
+const DATA: u8 = 4;
+
+const HANDSHAKE_TIMEOUT: u64 = 3; // seconds
+// How long to wait before trying to handshake again with a peer that didn't answer
+const HANDSHAKE_RETRY: u64 = 60; // seconds
+// Sessions we started are replaced by a fresh handshake this often
+const REKEY_INTERVAL: u64 = 3600; // seconds
+// A replaced session still decrypts what the peer sent before switching, for this long
+const RETIRED_SESSION_GRACE: u64 = 30; // seconds
+const MAX_NOISE_MESSAGE: usize = 65535;
+const HEADER_LEN: usize = 11;
+// Large enough for any XX handshake message without a payload
+const HANDSHAKE_BUFFER: usize = 256;
+// Sessions kept at once, and handshakes others started that may wait for completion;
+// beyond them, the oldest session makes room and new handshakes are turned away
+const MAX_SESSIONS: usize = 1024;
+const MAX_OPEN_HANDSHAKES: usize = 64;
+// Frames queued for one peer while its handshake runs
+const MAX_PENDING_FRAMES: usize = 64;
+const TAG_LEN: usize = 16;
+// Payloads larger than a single Noise message are split into blocks with consecutive nonces
+const MAX_BLOCK: usize = MAX_NOISE_MESSAGE - TAG_LEN;
+
+// A packet to send once the session lock is released: (peer address, bytes)
+type Outgoing = (String, Vec<u8>);
+
+static LAYER: OnceLock<Arc<NoiseLayer>> = OnceLock::new();
+
+/// The encryption layer, if encryption is enabled
+pub fn layer() -> Option<&'static Arc<NoiseLayer>> {
+    LAYER.get()
+}
+
+/// Whether a received packet belongs to the encryption layer
+pub fn is_noise_packet(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Result of feeding a received packet to the encryption layer
+pub enum Opened {
+    // Decrypted frame, ready for frame::decode, and the peer whose session it came over
+    Frame(Vec<u8>, SessionPeer),
+    // Handshake traffic, nothing more to do
+    Handshake,
+    Rejected(String),
+}
+
+/// The peer at the other end of a session; frames that came over it may only speak for
+/// that peer
+pub struct SessionPeer {
+    /// Its receiving address
+    pub addr: String,
+    /// The static key it proved to have in the handshake, its identity key as X25519
+    pub key: Vec<u8>,
+}
+
+enum SessionState {
+    Handshake(Box<HandshakeState>),
+    Established(StatelessTransportState),
+}
+
+struct Session {
+    // The peer's receiving address: the one it advertised, on the host its handshake came
+    // from
+    peer: String,
+    // The static key the peer proved to have, once the handshake got that far
+    remote_key: Vec<u8>,
+    initiator: bool,
+    state: SessionState,
+    started: Instant,
+    send_nonce: u64,
+    recv_window: NonceWindow,
+    // Replaced by a newer session; only kept to decrypt packets already on the way
+    retired: Option<Instant>,
+}
+
+// Accepts each nonce once, tolerating some reordering, like the IPsec anti-replay window
+#[derive(Default)]
+struct NonceWindow {
+    highest: Option<u64>,
+    // Bit i is set if nonce (highest - i) was seen
+    seen: u128,
+}
+
+impl NonceWindow {
+    fn accept(&mut self, nonce: u64) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(nonce);
+            self.seen = 1;
+            return true;
+        };
+        if nonce > highest {
+            let shift = nonce - highest;
+            self.seen = if shift >= 128 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = Some(nonce);
+            return true;
+        }
+        let age = highest - nonce;
+        if age >= 128 || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+#[derive(Default)]
+struct Sessions {
+    sessions: HashMap<u64, Session>,
+    // Session used to send to each peer address
+    by_peer: HashMap<String, u64>,
+    // Frames waiting for a handshake to complete
+    pending: HashMap<String, Vec<Vec<u8>>>,
+    // Peers that didn't complete a handshake, and when we gave up
+    failed: HashMap<String, Instant>,
+    // Peers we've already told the user about
+    notified: HashSet<String>,
+}
+
+impl Sessions {
+    // Make room for one more session by dropping the oldest, if there are too many
+    fn make_room(&mut self) {
+        if self.sessions.len() < MAX_SESSIONS {
+            return;
+        }
+        let Some((&sid, _)) = self
+            .sessions
+            .iter()
+            .min_by_key(|(_, session)| session.started)
+        else {
+            return;
+        };
+        if let Some(session) = self.sessions.remove(&sid)
+            && self.by_peer.get(&session.peer) == Some(&sid)
+        {
+            self.by_peer.remove(&session.peer);
+            self.pending.remove(&session.peer);
+        }
+    }
+
+    // Stop sending on the peer's established session, so the next frame starts a new
+    // handshake; false if there's none
+    fn retire(&mut self, peer: &str) -> bool {
+        let Some(&sid) = self.by_peer.get(peer) else {
+            return false;
+        };
+        let Some(session) = self.sessions.get_mut(&sid) else {
+            return false;
+        };
+        if !matches!(session.state, SessionState::Established(_)) {
+            return false;
+        }
+        session.retired = Some(Instant::now());
+        self.by_peer.remove(peer);
+        true
+    }
+}
+
+enum SendAction {
+    Send(Vec<u8>),
+    Initiate(Vec<u8>),
+    Queued,
+    Plaintext,
+    Dropped,
+}
+
+/// Wraps unicast traffic in Noise XX sessions, set up lazily on first contact;
+/// broadcasts (discovery) stay in plaintext so peers can still find each other
+pub struct NoiseLayer {
+    inner: SharedTransport,
+    local_addr: SocketAddr,
+    private_key: Vec<u8>,
+    allow_plaintext: bool,
+    sessions: Mutex<Sessions>,
+}
+
+impl NoiseLayer {
+    /// Set up the layer with our identity key as its static key, and make it available
+    /// through `layer()`
+    pub fn install(
+        inner: SharedTransport,
+        local_addr: SocketAddr,
+        allow_plaintext: bool,
+    ) -> Result<Arc<NoiseLayer>, String> {
+        NOISE_PARAMS
+            .parse::<snow::params::NoiseParams>()
+            .map_err(|e| format!("{e:?}"))?;
+        let layer = Arc::new(NoiseLayer {
+            inner,
+            local_addr,
+            private_key: identity::x25519_private_key().to_vec(),
+            allow_plaintext,
+            sessions: Mutex::new(Sessions::default()),
+        });
+        LAYER
+            .set(layer.clone())
+            .map_err(|_| "encryption layer already installed".to_string())?;
+
+        // Give up on handshakes that don't complete, and keep session keys fresh
+        let layer_clone = layer.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                layer_clone.expire_handshakes().await;
+                layer_clone.rotate_sessions();
+            }
+        });
+
+        Ok(layer)
+    }
+
+    pub fn allows_plaintext(&self) -> bool {
+        self.allow_plaintext
+    }
+
+    /// Encrypt a frame for a peer we already have a session with, e.g. for the TCP side channel
+    pub fn seal_for(&self, addr: &str, frame: &[u8]) -> Option<Vec<u8>> {
+        let mut guard = self.sessions.lock().ok()?;
+        let sessions = &mut *guard;
+        let sid = sessions.by_peer.get(addr).copied()?;
+        let session = sessions.sessions.get_mut(&sid)?;
+        match session.state {
+            SessionState::Established(_) => seal(sid, session, frame).ok(),
+            SessionState::Handshake(_) => None,
+        }
+    }
+
+    /// Replace the session with a peer, or with every peer, by a fresh handshake on the
+    /// next frame sent to it; returns how many sessions were replaced
+    pub fn rekey(&self, peer: Option<&str>) -> usize {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return 0;
+        };
+        let peers: Vec<String> = match peer {
+            Some(peer) => vec![peer.to_string()],
+            None => sessions.by_peer.keys().cloned().collect(),
+        };
+        peers.iter().filter(|peer| sessions.retire(peer)).count()
+    }
+
+    // Retire sessions we started that are due for new keys, and drop retired sessions
+    // once nothing sent before the switch can still arrive
+    fn rotate_sessions(&self) {
+        let Ok(mut guard) = self.sessions.lock() else {
+            return;
+        };
+        let sessions = &mut *guard;
+        // Only the initiator rotates, so both sides don't handshake at once
+        let due: Vec<String> = sessions
+            .by_peer
+            .iter()
+            .filter(|(_, sid)| {
+                sessions.sessions.get(sid).is_some_and(|session| {
+                    session.initiator
+                        && session.started.elapsed() > Duration::from_secs(REKEY_INTERVAL)
+                })
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in due {
+            if sessions.retire(&peer) {
+                log::debug!("[Noise] Rotating the session keys with {peer}");
+            }
+        }
+        sessions.sessions.retain(|_, session| {
+            session.retired.is_none_or(|retired| {
+                retired.elapsed() < Duration::from_secs(RETIRED_SESSION_GRACE)
+            })
+        });
+    }
+
+    fn builder(&self) -> Result<snow::Builder<'_>, snow::Error> {
+        let params = NOISE_PARAMS.parse()?;
+        Ok(snow::Builder::new(params).local_private_key(&self.private_key))
+    }
+
+    fn prepare_send(&self, bytes: &[u8], addr: &str) -> Result<SendAction, String> {
+        let mut guard = self.sessions.lock().map_err(|e| e.to_string())?;
+        let sessions = &mut *guard;
+
+        if let Some(sid) = sessions.by_peer.get(addr).copied()
+            && let Some(session) = sessions.sessions.get_mut(&sid)
+        {
+            return match &session.state {
+                SessionState::Established(_) => seal(sid, session, bytes).map(SendAction::Send),
+                SessionState::Handshake(_) => {
+                    let pending = sessions.pending.entry(addr.to_string()).or_default();
+                    if pending.len() >= MAX_PENDING_FRAMES {
+                        return Ok(SendAction::Dropped);
+                    }
+                    pending.push(bytes.to_vec());
+                    Ok(SendAction::Queued)
+                }
+            };
+        }
+
+        // Don't keep knocking on a peer that didn't answer recently
+        if let Some(failed_at) = sessions.failed.get(addr)
+            && failed_at.elapsed() < Duration::from_secs(HANDSHAKE_RETRY)
+        {
+            return Ok(if self.allow_plaintext {
+                SendAction::Plaintext
+            } else {
+                SendAction::Dropped
+            });
+        }
+
+        // Start a handshake, queueing the frame until it completes
+        let mut handshake = self
+            .builder()
+            .and_then(|builder| builder.build_initiator())
+            .map_err(|e| e.to_string())?;
+        let mut buf = [0u8; HANDSHAKE_BUFFER];
+        let len = handshake
+            .write_message(&[], &mut buf)
+            .map_err(|e| e.to_string())?;
+
+        let sid: u64 = rand::random();
+        // Tell the peer where to answer, on its network if we're multi-homed
+        let local_ip = addr
+            .parse::<SocketAddr>()
+            .ok()
+            .and_then(|dest| interfaces::local_ip_for(dest.ip()))
+            .unwrap_or(self.local_addr.ip());
+        let local_addr = SocketAddr::new(local_ip, self.local_addr.port()).to_string();
+        let mut packet = header(HANDSHAKE_INIT, sid, 1 + local_addr.len() + len);
+        packet.push(local_addr.len() as u8);
+        packet.extend_from_slice(local_addr.as_bytes());
+        packet.extend_from_slice(&buf[..len]);
+
+        sessions.make_room();
+        sessions.sessions.insert(
+            sid,
+            Session {
+                peer: addr.to_string(),
+                remote_key: Vec::new(),
+                initiator: true,
+                state: SessionState::Handshake(Box::new(handshake)),
+                started: Instant::now(),
+                send_nonce: 0,
+                recv_window: NonceWindow::default(),
+                retired: None,
+            },
+        );
+        sessions.by_peer.insert(addr.to_string(), sid);
+        sessions
+            .pending
+            .insert(addr.to_string(), vec![bytes.to_vec()]);
+        Ok(SendAction::Initiate(packet))
+    }
+
+    /// Handle an encryption-layer packet received from `source`
+    pub async fn open(&self, packet: &[u8], source: SocketAddr) -> Opened {
+        let result = match self.process(packet, source) {
+            Ok(result) => result,
+            Err(e) => return Opened::Rejected(e),
+        };
+        let (opened, replies) = result;
+        for (peer, reply) in replies {
+            if let Err(e) = self.inner.send_bytes_to(&reply, &peer).await {
+                log::error!("[Noise] Error sending to {peer}: {e}");
+            }
+        }
+        opened
+    }
+
+    // Returns what was received, plus packets to send in response as (address, packet)
+    fn process(
+        &self,
+        packet: &[u8],
+        source: SocketAddr,
+    ) -> Result<(Opened, Vec<Outgoing>), String> {
+        let (kind, sid, body) = parse_header(packet).ok_or("truncated packet")?;
+        let mut guard = self.sessions.lock().map_err(|e| e.to_string())?;
+        let sessions = &mut *guard;
+        // Decrypted data is never longer than what came in, handshake messages are short
+        let mut buf = vec![0u8; packet.len().max(HANDSHAKE_BUFFER)];
+
+        match kind {
+            HANDSHAKE_INIT => {
+                let (&addr_len, rest) = body.split_first().ok_or("truncated handshake")?;
+                let addr_len = addr_len as usize;
+                if rest.len() < addr_len {
+                    return Err("truncated handshake".to_string());
+                }
+                let advertised = std::str::from_utf8(&rest[..addr_len])
+                    .ok()
+                    .and_then(|addr| addr.parse::<SocketAddr>().ok())
+                    .ok_or("invalid sender address in handshake")?;
+                // Peers send from another port than they receive on, so the port is the one
+                // it advertises; the host is the one the packet came from, so a handshake
+                // can't have us answer to anyone else
+                let peer = SocketAddr::new(source.ip(), advertised.port()).to_string();
+                let open_handshakes = sessions
+                    .sessions
+                    .values()
+                    .filter(|session| {
+                        !session.initiator && matches!(session.state, SessionState::Handshake(_))
+                    })
+                    .count();
+                if open_handshakes >= MAX_OPEN_HANDSHAKES {
+                    return Err("too many handshakes at once".to_string());
+                }
+                // Session IDs travel in the clear, so anyone could otherwise take over a
+                // live session by starting a handshake with its ID
+                if sessions.sessions.contains_key(&sid) {
+                    return Err("handshake for a session in use".to_string());
+                }
+
+                let mut handshake = self
+                    .builder()
+                    .and_then(|builder| builder.build_responder())
+                    .map_err(|e| e.to_string())?;
+                handshake
+                    .read_message(&rest[addr_len..], &mut buf)
+                    .map_err(|e| e.to_string())?;
+                let len = handshake
+                    .write_message(&[], &mut buf)
+                    .map_err(|e| e.to_string())?;
+
+                sessions.make_room();
+                sessions.sessions.insert(
+                    sid,
+                    Session {
+                        peer: peer.clone(),
+                        remote_key: Vec::new(),
+                        initiator: false,
+                        state: SessionState::Handshake(Box::new(handshake)),
+                        started: Instant::now(),
+                        send_nonce: 0,
+                        recv_window: NonceWindow::default(),
+                        retired: None,
+                    },
+                );
+                let mut reply = header(HANDSHAKE_RESPONSE, sid, len);
+                reply.extend_from_slice(&buf[..len]);
+                Ok((Opened::Handshake, vec![(peer, reply)]))
+            }
+            HANDSHAKE_RESPONSE => {
+                let session = sessions
+                    .sessions
+                    .get_mut(&sid)
+                    .filter(|session| session.initiator)
+                    .ok_or("response for unknown handshake")?;
+                let SessionState::Handshake(handshake) = &mut session.state else {
+                    return Err("response for completed handshake".to_string());
+                };
+                // The handshake is left as it was if this fails, so a forged response
+                // can't end it
+                handshake
+                    .read_message(body, &mut buf)
+                    .map_err(|e| e.to_string())?;
+                let mut session = sessions
+                    .sessions
+                    .remove(&sid)
+                    .ok_or("response for unknown handshake")?;
+                let SessionState::Handshake(mut handshake) = session.state else {
+                    return Err("response for completed handshake".to_string());
+                };
+                let len = handshake
+                    .write_message(&[], &mut buf)
+                    .map_err(|e| e.to_string())?;
+                let mut reply = header(HANDSHAKE_FINAL, sid, len);
+                reply.extend_from_slice(&buf[..len]);
+                session.remote_key = handshake.get_remote_static().unwrap_or_default().to_vec();
+                session.state = SessionState::Established(
+                    handshake
+                        .into_stateless_transport_mode()
+                        .map_err(|e| e.to_string())?,
+                );
+
+                let peer = session.peer.clone();
+                log::debug!("[Noise] Encrypted session established with {peer}");
+                sessions.failed.remove(&peer);
+
+                // Now that the session is up, send what was waiting for it
+                let mut replies = vec![(peer.clone(), reply)];
+                for frame in sessions.pending.remove(&peer).unwrap_or_default() {
+                    replies.push((peer.clone(), seal(sid, &mut session, &frame)?));
+                }
+                sessions.sessions.insert(sid, session);
+                Ok((Opened::Handshake, replies))
+            }
+            HANDSHAKE_FINAL => {
+                let session = sessions
+                    .sessions
+                    .get_mut(&sid)
+                    .filter(|session| !session.initiator)
+                    .ok_or("final message for unknown handshake")?;
+                let SessionState::Handshake(handshake) = &mut session.state else {
+                    return Err("final message for completed handshake".to_string());
+                };
+                handshake
+                    .read_message(body, &mut buf)
+                    .map_err(|e| e.to_string())?;
+                let mut session = sessions
+                    .sessions
+                    .remove(&sid)
+                    .ok_or("final message for unknown handshake")?;
+                let SessionState::Handshake(handshake) = session.state else {
+                    return Err("final message for completed handshake".to_string());
+                };
+                session.remote_key = handshake.get_remote_static().unwrap_or_default().to_vec();
+                session.state = SessionState::Established(
+                    handshake
+                        .into_stateless_transport_mode()
+                        .map_err(|e| e.to_string())?,
+                );
+
+                let peer = session.peer.clone();
+                log::debug!("[Noise] Encrypted session established with {peer}");
+                sessions.sessions.insert(sid, session);
+
+                // The newest session wins, e.g. when the peer restarted with new keys or
+                // rekeyed; the one it replaces still takes what was sent before the switch
+                if let Some(old_sid) = sessions.by_peer.insert(peer.clone(), sid)
+                    && old_sid != sid
+                    && let Some(old) = sessions.sessions.get_mut(&old_sid)
+                {
+                    old.retired = Some(Instant::now());
+                }
+                sessions.failed.remove(&peer);
+                Ok((Opened::Handshake, vec![]))
+            }
+            DATA => {
+                let session = sessions
+                    .sessions
+                    .get_mut(&sid)
+                    .ok_or("data for unknown session")?;
+                let SessionState::Established(transport) = &session.state else {
+                    return Err("data before handshake completed".to_string());
+                };
+                let first_nonce = u64::from_be_bytes(
+                    body.get(..8)
+                        .and_then(|b| b.try_into().ok())
+                        .ok_or("truncated data")?,
+                );
+
+                let mut frame = Vec::new();
+                let mut rest = &body[8..];
+                let mut nonce = first_nonce;
+                while !rest.is_empty() {
+                    let len = u32::from_be_bytes(
+                        rest.get(..4)
+                            .and_then(|b| b.try_into().ok())
+                            .ok_or("truncated block")?,
+                    ) as usize;
+                    let block = rest.get(4..4 + len).ok_or("truncated block")?;
+                    let plain_len = transport
+                        .read_message(nonce, block, &mut buf)
+                        .map_err(|e| e.to_string())?;
+                    // Only authenticated blocks may move the window
+                    if !session.recv_window.accept(nonce) {
+                        return Err("replayed packet".to_string());
+                    }
+                    frame.extend_from_slice(&buf[..plain_len]);
+                    rest = &rest[4 + len..];
+                    nonce += 1;
+                }
+                let peer = SessionPeer {
+                    addr: session.peer.clone(),
+                    key: session.remote_key.clone(),
+                };
+                Ok((Opened::Frame(frame, peer), vec![]))
+            }
+            _ => Err(format!("unknown packet type {kind}")),
+        }
+    }
+
+    // Drop handshakes that didn't complete in time; for peers we were trying to reach,
+    // fall back to plaintext if that's allowed, otherwise drop what was queued
+    async fn expire_handshakes(&self) {
+        let mut plaintext = Vec::new();
+        let mut notices = Vec::new();
+        if let Ok(mut guard) = self.sessions.lock() {
+            let sessions = &mut *guard;
+            let expired: Vec<u64> = sessions
+                .sessions
+                .iter()
+                .filter(|(_, session)| {
+                    matches!(session.state, SessionState::Handshake(_))
+                        && session.started.elapsed() > Duration::from_secs(HANDSHAKE_TIMEOUT)
+                })
+                .map(|(sid, _)| *sid)
+                .collect();
+
+            for sid in expired {
+                let Some(session) = sessions.sessions.remove(&sid) else {
+                    continue;
+                };
+                if !session.initiator || sessions.by_peer.get(&session.peer) != Some(&sid) {
+                    continue;
+                }
+                let peer = session.peer;
+                sessions.by_peer.remove(&peer);
+                sessions.failed.insert(peer.clone(), Instant::now());
+                let pending = sessions.pending.remove(&peer).unwrap_or_default();
+                let first_notice = sessions.notified.insert(peer.clone());
+
+                if self.allow_plaintext {
+                    if first_notice {
+                        notices.push(format!(
+                            "### {} doesn't support encryption, sending to it unencrypted",
+                            privacy::addr(&peer, None)
+                        ));
+                    }
+                    plaintext.extend(pending.into_iter().map(|frame| (peer.clone(), frame)));
+                } else if first_notice {
+                    notices.push(format!(
+                        "### Could not set up an encrypted session with {}, not sending to it (set allow_plaintext = true in config.toml to allow unencrypted peers)",
+                        privacy::addr(&peer, None)
+                    ));
+                }
+            }
+        }
+
+        for notice in notices {
+            say!("{notice}");
+        }
+        for (peer, frame) in plaintext {
+            if let Err(e) = self.inner.send_bytes_to(&frame, &peer).await {
+                log::error!("[Noise] Error sending to {peer}: {e}");
+            }
+        }
+    }
+}
+
+impl Transport for NoiseLayer {
+    fn send_to<'a>(
+        &'a self,
+        msg: &'a Message,
+        addr: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            // Discovery may go to a peer's init port, which doesn't take part in sessions
+            if matches!(msg.msg_type, MessageType::Discovery) {
+                return self.inner.send_to(msg, addr).await;
+            }
+            let encoded = frame::encode(&interfaces::addressed_for(msg, addr))?;
+            self.send_bytes_to(&encoded, addr).await
+        })
+    }
+
+    fn send_bytes_to<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        addr: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            match self
+                .prepare_send(bytes, addr)
+                .map_err(std::io::Error::other)?
+            {
+                SendAction::Send(packet) | SendAction::Initiate(packet) => {
+                    self.inner.send_bytes_to(&packet, addr).await
+                }
+                SendAction::Plaintext => self.inner.send_bytes_to(bytes, addr).await,
+                SendAction::Queued | SendAction::Dropped => Ok(()),
+            }
+        })
+    }
+
+    fn probe<'a>(&'a self, msg: &'a Message, addr: &'a str) -> BoxFuture<'a, std::io::Result<()>> {
+        // Probes are discoveries, which aren't encrypted either
+        self.inner.probe(msg, addr)
+    }
+
+    fn broadcast<'a>(&'a self, msg: &'a Message, port: u16) -> BoxFuture<'a, std::io::Result<()>> {
+        // Broadcasts can't be encrypted for peers we don't know yet
+        self.inner.broadcast(msg, port)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+// The header of a packet, with room for `body_len` more bytes
+fn header(kind: u8, sid: u64, body_len: usize) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LEN + body_len);
+    packet.extend_from_slice(MAGIC);
+    packet.push(kind);
+    packet.extend_from_slice(&sid.to_be_bytes());
+    packet
+}
+
+fn parse_header(packet: &[u8]) -> Option<(u8, u64, &[u8])> {
+    let rest = packet.strip_prefix(MAGIC)?;
+    let (&kind, rest) = rest.split_first()?;
+    let sid = u64::from_be_bytes(rest.get(..8)?.try_into().ok()?);
+    Some((kind, sid, &rest[8..]))
+}
+
+// Encrypt a frame as one data packet, split into as many blocks as needed
+fn seal(sid: u64, session: &mut Session, frame: &[u8]) -> Result<Vec<u8>, String> {
+    let SessionState::Established(transport) = &session.state else {
+        return Err("session not established".to_string());
+    };
+    // An empty frame still needs one (empty) block
+    let blocks: Vec<&[u8]> = if frame.is_empty() {
+        vec![frame]
+    } else {
+        frame.chunks(MAX_BLOCK).collect()
+    };
+    let mut packet = header(DATA, sid, 8 + frame.len() + blocks.len() * (4 + TAG_LEN));
+    packet.extend_from_slice(&session.send_nonce.to_be_bytes());
+
+    let mut buf = vec![0u8; frame.len().min(MAX_BLOCK) + TAG_LEN];
+    for block in blocks {
+        let len = transport
+            .write_message(session.send_nonce, block, &mut buf)
+            .map_err(|e| e.to_string())?;
+        session.send_nonce += 1;
+        packet.extend_from_slice(&(len as u32).to_be_bytes());
+        packet.extend_from_slice(&buf[..len]);
+    }
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    const ALICE: &str = "127.0.0.1:10001";
+    const BOB: &str = "127.0.0.1:10002";
+
+    // Replies are taken from `process`, so nothing ever needs to be sent
+    struct Nowhere;
+
+    impl Transport for Nowhere {
+        fn send_bytes_to<'a>(
+            &'a self,
+            _bytes: &'a [u8],
+            _addr: &'a str,
+        ) -> BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn broadcast<'a>(
+            &'a self,
+            _msg: &'a Message,
+            _port: u16,
+        ) -> BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Ok("127.0.0.1:0".parse().unwrap())
+        }
+    }
+
+    fn layer(key: [u8; 32], local_addr: &str) -> NoiseLayer {
+        NoiseLayer {
+            inner: Arc::new(Nowhere),
+            local_addr: local_addr.parse().unwrap(),
+            private_key: key.to_vec(),
+            allow_plaintext: false,
+            sessions: Mutex::new(Sessions::default()),
+        }
+    }
+
+    // Peers send from another port than the one they receive on
+    fn sender_of(addr: &str) -> SocketAddr {
+        let addr: SocketAddr = addr.parse().unwrap();
+        SocketAddr::new(addr.ip(), addr.port() + 10000)
+    }
+
+    fn public_key(key: [u8; 32]) -> Vec<u8> {
+        PublicKey::from(&StaticSecret::from(key))
+            .to_bytes()
+            .to_vec()
+    }
+
+    fn replies(result: Result<(Opened, Vec<Outgoing>), String>) -> Vec<Outgoing> {
+        match result {
+            Ok((Opened::Handshake, replies)) => replies,
+            Ok(_) => panic!("expected handshake traffic"),
+            Err(e) => panic!("handshake failed: {e}"),
+        }
+    }
+
+    fn opened(result: Result<(Opened, Vec<Outgoing>), String>) -> (Vec<u8>, SessionPeer) {
+        match result {
+            Ok((Opened::Frame(frame, peer), _)) => (frame, peer),
+            Ok(_) => panic!("expected a frame"),
+            Err(e) => panic!("could not open the frame: {e}"),
+        }
+    }
+
+    // Alice sends Bob a frame, which takes a handshake first; returns the data packet
+    // that carries the frame once Bob has a session with her
+    fn connect(alice: &NoiseLayer, bob: &NoiseLayer, frame: &[u8]) -> Vec<u8> {
+        let SendAction::Initiate(init) = alice.prepare_send(frame, BOB).unwrap() else {
+            panic!("expected a handshake to start");
+        };
+        let response = replies(bob.process(&init, sender_of(ALICE)));
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].0, ALICE);
+        let mut from_alice = replies(alice.process(&response[0].1, sender_of(BOB)));
+        // The final handshake message, then the frame that waited for it
+        assert_eq!(from_alice.len(), 2);
+        let (_, data) = from_alice.pop().unwrap();
+        let (_, last) = from_alice.pop().unwrap();
+        assert!(replies(bob.process(&last, sender_of(ALICE))).is_empty());
+        data
+    }
+
+    #[test]
+    fn frames_round_trip_and_are_bound_to_the_sender() {
+        let alice = layer([1; 32], ALICE);
+        let bob = layer([2; 32], BOB);
+        let data = connect(&alice, &bob, b"hello bob");
+
+        let (frame, peer) = opened(bob.process(&data, sender_of(ALICE)));
+        assert_eq!(frame, b"hello bob");
+        assert_eq!(peer.addr, ALICE);
+        assert_eq!(peer.key, public_key([1; 32]));
+
+        // Later frames go straight over the session
+        let SendAction::Send(data) = alice.prepare_send(b"again", BOB).unwrap() else {
+            panic!("expected the session to be used");
+        };
+        assert_eq!(opened(bob.process(&data, sender_of(ALICE))).0, b"again");
+    }
+
+    #[test]
+    fn frames_larger_than_a_noise_message_are_split() {
+        let alice = layer([1; 32], ALICE);
+        let bob = layer([2; 32], BOB);
+        let big = vec![0x5a; 3 * MAX_NOISE_MESSAGE];
+        let data = connect(&alice, &bob, &big);
+        assert_eq!(opened(bob.process(&data, sender_of(ALICE))).0, big);
+    }
+
+    #[test]
+    fn replayed_and_tampered_packets_are_rejected() {
+        let alice = layer([1; 32], ALICE);
+        let bob = layer([2; 32], BOB);
+        let data = connect(&alice, &bob, b"once");
+
+        let mut tampered = data.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(bob.process(&tampered, sender_of(ALICE)).is_err());
+
+        opened(bob.process(&data, sender_of(ALICE)));
+        assert!(bob.process(&data, sender_of(ALICE)).is_err());
+    }
+
+    #[test]
+    fn sessions_only_open_with_their_own_keys() {
+        let alice = layer([1; 32], ALICE);
+        let bob = layer([2; 32], BOB);
+        let data = connect(&alice, &bob, b"for bob only");
+
+        // Mallory, with keys of her own, knows nothing of the session
+        let mallory = layer([3; 32], BOB);
+        assert!(mallory.process(&data, sender_of(ALICE)).is_err());
+    }
+
+    #[test]
+    fn handshakes_are_answered_on_the_host_they_came_from() {
+        let alice = layer([1; 32], ALICE);
+        let bob = layer([2; 32], BOB);
+        let SendAction::Initiate(init) = alice.prepare_send(b"hi", BOB).unwrap() else {
+            panic!("expected a handshake to start");
+        };
+
+        // Whatever address the handshake claims, the reply goes to the host that sent it
+        let elsewhere: SocketAddr = "192.0.2.7:4444".parse().unwrap();
+        let response = replies(bob.process(&init, elsewhere));
+        assert_eq!(response[0].0, "192.0.2.7:10001");
+    }
+
+    #[test]
+    fn handshakes_cant_take_over_or_end_sessions() {
+        let alice = layer([1; 32], ALICE);
+        let bob = layer([2; 32], BOB);
+        let mallory = layer([3; 32], "127.0.0.1:10003");
+        let data = connect(&alice, &bob, b"still ours");
+        let (_, sid, _) = parse_header(&data).unwrap();
+
+        // Mallory starts a handshake under the ID of the session she saw go by
+        let SendAction::Initiate(mut init) = mallory.prepare_send(b"hi", BOB).unwrap() else {
+            panic!("expected a handshake to start");
+        };
+        init[3..HEADER_LEN].copy_from_slice(&sid.to_be_bytes());
+        assert!(bob.process(&init, sender_of(ALICE)).is_err());
+        assert_eq!(
+            opened(bob.process(&data, sender_of(ALICE))).0,
+            b"still ours"
+        );
+
+        // Nor can a made-up response end a handshake that's under way
+        let SendAction::Initiate(init) = alice.prepare_send(b"hi", "127.0.0.1:10003").unwrap()
+        else {
+            panic!("expected a handshake to start");
+        };
+        let (_, sid, _) = parse_header(&init).unwrap();
+        let mut forged = header(HANDSHAKE_RESPONSE, sid, 96);
+        forged.extend_from_slice(&[7; 96]);
+        assert!(alice.process(&forged, sender_of(BOB)).is_err());
+        let response = replies(mallory.process(&init, sender_of(ALICE)));
+        assert_eq!(
+            replies(alice.process(&response[0].1, sender_of(BOB))).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn truncated_and_unknown_packets_are_rejected() {
+        let bob = layer([2; 32], BOB);
+        assert!(bob.process(b"PN", sender_of(ALICE)).is_err());
+        assert!(bob.process(&header(DATA, 7, 0), sender_of(ALICE)).is_err());
+        assert!(bob.process(&header(9, 7, 0), sender_of(ALICE)).is_err());
+        let mut init = header(HANDSHAKE_INIT, 7, 0);
+        init.push(200);
+        assert!(bob.process(&init, sender_of(ALICE)).is_err());
+    }
+
+    #[test]
+    fn nonce_window_accepts_each_nonce_once() {
+        let mut window = NonceWindow::default();
+        assert!(window.accept(5));
+        assert!(window.accept(3));
+        assert!(!window.accept(5));
+        assert!(!window.accept(3));
+        assert!(window.accept(200));
+        // Too far behind to tell whether it was seen
+        assert!(!window.accept(4));
+    }
+}
@@ -0,0 +1,162 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use std::borrow::Cow;
+use std::sync::{Mutex, OnceLock};
+
+// Sealed packets start with this instead of a frame ("PG") or Noise ("PN") header
+const MAGIC: &[u8; 2] = b"PK";
+// Packets sealed with a room password; inside the network key's seal when both are set
+const ROOM_MAGIC: &[u8; 2] = b"PR";
+// Everyone with the same passphrase has to derive the same key, so the salt is fixed
+const SALT: &[u8] = b"pung pre-shared key";
+// Room keys are salted with the room name too, so one password doesn't open every room
+const ROOM_SALT: &str = "pung room key";
+const NONCE_LEN: usize = 12;
+
+// Set by --psk; every packet we send is sealed with it and anything else is dropped
+static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+// Set by --room-password or /join; works like the network key, for the room we're in
+static ROOM_KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+
+/// Encrypt and authenticate all traffic with a key derived from the passphrase
+pub fn set_passphrase(passphrase: &str) -> Result<(), String> {
+    let key = derive(passphrase, SALT)?;
+    KEY.set(key)
+        .map_err(|_| "pre-shared key already set".to_string())
+}
+
+/// Encrypt all traffic with a key derived from a room's password, or stop with None;
+/// instances in the room without the password only see opaque packets
+pub fn set_room_password(room: &str, password: Option<&str>) -> Result<(), String> {
+    let key = match password {
+        Some(password) => Some(derive(password, format!("{ROOM_SALT} {room}").as_bytes())?),
+        None => None,
+    };
+    *ROOM_KEY.lock().map_err(|e| e.to_string())? = key;
+    Ok(())
+}
+
+fn derive(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+fn room_key() -> Option<[u8; 32]> {
+    ROOM_KEY.lock().ok().and_then(|key| *key)
+}
+
+/// Seal an outgoing packet with the room key, then the network key; unchanged without them
+pub fn seal(packet: &[u8]) -> std::io::Result<Cow<'_, [u8]>> {
+    let mut packet = Cow::Borrowed(packet);
+    if let Some(key) = room_key() {
+        packet = Cow::Owned(seal_with(&key, ROOM_MAGIC, &packet)?);
+    }
+    if let Some(key) = KEY.get() {
+        packet = Cow::Owned(seal_with(key, MAGIC, &packet)?);
+    }
+    Ok(packet)
+}
+
+/// A received packet without the network and room keys' encryption; None if it doesn't
+/// decrypt with our keys, so it has to be dropped. Unchanged without keys.
+pub fn open(packet: &[u8]) -> Option<Cow<'_, [u8]>> {
+    let mut packet = Cow::Borrowed(packet);
+    if let Some(key) = KEY.get() {
+        packet = Cow::Owned(open_with(key, MAGIC, &packet)?);
+    }
+    if let Some(key) = room_key() {
+        packet = Cow::Owned(open_with(&key, ROOM_MAGIC, &packet)?);
+    }
+    Some(packet)
+}
+
+fn seal_with(key: &[u8; 32], magic: &[u8; 2], packet: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: packet,
+                aad: magic,
+            },
+        )
+        .map_err(|e| std::io::Error::other(format!("could not encrypt packet: {e}")))?;
+    Ok([magic.as_slice(), &nonce, &ciphertext].concat())
+}
+
+fn open_with(key: &[u8; 32], magic: &[u8; 2], packet: &[u8]) -> Option<Vec<u8>> {
+    let sealed = packet.strip_prefix(magic)?;
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: magic,
+            },
+        )
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [1; 32];
+    const OTHER_KEY: [u8; 32] = [2; 32];
+
+    #[test]
+    fn sealed_packets_round_trip() {
+        let sealed = seal_with(&KEY, MAGIC, b"PG hello").unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert!(!sealed.windows(5).any(|w| w == b"hello"));
+        assert_eq!(open_with(&KEY, MAGIC, &sealed).unwrap(), b"PG hello");
+
+        // Room packets sit inside the network's
+        let both = seal_with(
+            &KEY,
+            MAGIC,
+            &seal_with(&OTHER_KEY, ROOM_MAGIC, b"hi").unwrap(),
+        )
+        .unwrap();
+        let room = open_with(&KEY, MAGIC, &both).unwrap();
+        assert_eq!(open_with(&OTHER_KEY, ROOM_MAGIC, &room).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn packets_only_open_with_their_key() {
+        let sealed = seal_with(&KEY, MAGIC, b"secret").unwrap();
+        assert!(open_with(&OTHER_KEY, MAGIC, &sealed).is_none());
+        // Network and room seals can't stand in for each other
+        assert!(open_with(&KEY, ROOM_MAGIC, &sealed).is_none());
+        // Neither can an unsealed packet
+        assert!(open_with(&KEY, MAGIC, b"PG plain").is_none());
+    }
+
+    #[test]
+    fn tampered_and_truncated_packets_are_rejected() {
+        let sealed = seal_with(&KEY, MAGIC, b"secret").unwrap();
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open_with(&KEY, MAGIC, &tampered).is_none());
+        assert!(open_with(&KEY, MAGIC, &sealed[..MAGIC.len() + NONCE_LEN - 1]).is_none());
+        assert!(open_with(&KEY, MAGIC, &sealed[..sealed.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn room_keys_differ_from_each_other_and_the_network_key() {
+        let room = |name: &str| derive("correct horse", format!("{ROOM_SALT} {name}").as_bytes());
+        let lobby = room("lobby").unwrap();
+        assert_ne!(lobby, room("attic").unwrap());
+        assert_ne!(lobby, derive("correct horse", SALT).unwrap());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::message::Message;
+use crate::ui::privacy;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Messages whose timestamp is further than this from our clock are rejected
+const MAX_CLOCK_SKEW: i64 = 300; // seconds
+// How long processed message IDs are remembered; must cover the whole acceptance window
+// (both directions), so a replay is either a known ID or outside the window
+const DEDUP_HORIZON: i64 = 2 * MAX_CLOCK_SKEW; // seconds
+// Message IDs remembered at most; a flood of fresh IDs pushes out the oldest instead of
+// growing the guard without bound
+const MAX_PROCESSED: usize = 16 * 1024;
+// Senders remembered as having a skewed clock, before the list starts over
+const MAX_SKEW_WARNED: usize = 256;
+// How far a counter may lag behind the sender's highest and still be taken; messages
+// overtake each other on the way, and the ID check still catches repeats among them
+const COUNTER_WINDOW: u64 = 1024;
+// Senders whose counters are remembered; beyond this, the one whose latest message is
+// oldest is forgotten
+const MAX_COUNTERS: usize = 4096;
+
+// Counter of the next message we make. It starts at the time in microseconds, so it
+// keeps growing across restarts without having to be stored anywhere.
+static NEXT_COUNTER: LazyLock<AtomicU64> =
+    LazyLock::new(|| AtomicU64::new(chrono::Utc::now().timestamp_micros().max(0) as u64));
+
+/// The counter for a new message of ours; every call returns a higher one
+pub fn next_counter() -> u64 {
+    NEXT_COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    // Timestamp too far in the past or future, by this many seconds
+    OutsideWindow(i64),
+    // Same (sender, message_id) was already processed
+    Duplicate,
+    // Counter too far behind the highest seen from the sender, by this much
+    BehindCounter(u64),
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReplayError::OutsideWindow(skew) => write!(f, "timestamp off by {skew}s"),
+            ReplayError::Duplicate => write!(f, "already processed"),
+            ReplayError::BehindCounter(behind) => {
+                write!(f, "counter {behind} behind the sender's latest")
+            }
+        }
+    }
+}
+
+/// Rejects replayed or stale messages, so a captured packet can't re-print an old chat
+/// line or refresh a dead peer. The timestamp is only trustworthy once messages are
+/// authenticated; the guard just enforces the window. It's checked after the
+/// signature, so the node ID senders are told apart by is theirs. Each signed sender's
+/// highest counter is kept too, so an old message stays rejected once its ID is forgotten.
+#[derive(Default)]
+pub struct ReplayGuard {
+    // (sender's node ID, or the IP it came from, message_id) -> message timestamp
+    processed: HashMap<(String, String), i64>,
+    // Senders we've already warned about a skewed clock
+    skew_warned: HashSet<String>,
+    // Signed sender's node ID -> highest counter seen from it
+    counters: HashMap<String, u64>,
+}
+
+impl ReplayGuard {
+    pub fn check(&mut self, msg: &Message, source: SocketAddr) -> Result<(), ReplayError> {
+        let now = chrono::Utc::now().timestamp();
+        let skew = msg.timestamp - now;
+        let sender = msg
+            .node_id
+            .clone()
+            .unwrap_or_else(|| source.ip().to_string());
+
+        if skew.abs() > MAX_CLOCK_SKEW {
+            if self.skew_warned.len() >= MAX_SKEW_WARNED {
+                self.skew_warned.clear();
+            }
+            if self.skew_warned.insert(sender) {
+                say!(
+                    "@@@ Ignoring messages from {} ({}): its clock is off by {skew}s",
+                    msg.sender,
+                    privacy::addr(source, msg.node_id.as_deref())
+                );
+            }
+            return Err(ReplayError::OutsideWindow(skew));
+        }
+
+        // Forget IDs that have aged out of the window; a replay of those fails the check above
+        self.processed
+            .retain(|_, timestamp| now - *timestamp <= DEDUP_HORIZON);
+        if self.processed.len() >= MAX_PROCESSED
+            && let Some(oldest) = self
+                .processed
+                .iter()
+                .min_by_key(|(_, timestamp)| **timestamp)
+                .map(|(key, _)| key.clone())
+        {
+            self.processed.remove(&oldest);
+        }
+
+        if self
+            .processed
+            .contains_key(&(sender.clone(), msg.message_id.clone()))
+        {
+            return Err(ReplayError::Duplicate);
+        }
+        self.check_counter(msg)?;
+        self.processed
+            .insert((sender, msg.message_id.clone()), msg.timestamp);
+        Ok(())
+    }
+
+    // Unlike message IDs, counters are remembered past the timestamp window. Only signed
+    // messages keep their node ID, so nobody can push a peer's counter ahead of it.
+    fn check_counter(&mut self, msg: &Message) -> Result<(), ReplayError> {
+        let (Some(node_id), Some(counter)) = (&msg.node_id, msg.counter) else {
+            return Ok(());
+        };
+        if let Some(&highest) = self.counters.get(node_id) {
+            let behind = highest.saturating_sub(counter);
+            if behind >= COUNTER_WINDOW {
+                return Err(ReplayError::BehindCounter(behind));
+            }
+            if counter > highest {
+                self.counters.insert(node_id.clone(), counter);
+            }
+            return Ok(());
+        }
+        if self.counters.len() >= MAX_COUNTERS
+            && let Some(oldest) = self
+                .counters
+                .iter()
+                .min_by_key(|(_, highest)| **highest)
+                .map(|(node_id, _)| node_id.clone())
+        {
+            self.counters.remove(&oldest);
+        }
+        self.counters.insert(node_id.clone(), counter);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(last: u8) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, last], 10001))
+    }
+
+    fn from_node(node_id: &str) -> Message {
+        Message {
+            node_id: Some(node_id.to_string()),
+            ..Message::new_chat("alice".to_string(), "hi".to_string(), None)
+        }
+    }
+
+    #[test]
+    fn replays_are_rejected() {
+        let mut guard = ReplayGuard::default();
+        let msg = from_node("alice-node");
+        assert!(guard.check(&msg, source(1)).is_ok());
+        assert!(matches!(
+            guard.check(&msg, source(1)),
+            Err(ReplayError::Duplicate)
+        ));
+        // Replaying it from another address doesn't make it new
+        assert!(matches!(
+            guard.check(&msg, source(2)),
+            Err(ReplayError::Duplicate)
+        ));
+    }
+
+    #[test]
+    fn message_ids_are_only_unique_per_sender() {
+        let mut guard = ReplayGuard::default();
+        let msg = from_node("alice-node");
+        assert!(guard.check(&msg, source(1)).is_ok());
+        let other = Message {
+            node_id: Some("bob-node".to_string()),
+            ..msg.clone()
+        };
+        assert!(guard.check(&other, source(1)).is_ok());
+
+        // Without node IDs, senders are told apart by IP
+        let unnamed = Message {
+            node_id: None,
+            ..msg
+        };
+        assert!(guard.check(&unnamed, source(1)).is_ok());
+        assert!(guard.check(&unnamed, source(2)).is_ok());
+        assert!(guard.check(&unnamed, source(1)).is_err());
+    }
+
+    #[test]
+    fn stale_and_future_messages_are_rejected() {
+        let mut guard = ReplayGuard::default();
+        let now = chrono::Utc::now().timestamp();
+        for timestamp in [now - MAX_CLOCK_SKEW - 5, now + MAX_CLOCK_SKEW + 5] {
+            let msg = Message {
+                timestamp,
+                ..from_node("alice-node")
+            };
+            assert!(matches!(
+                guard.check(&msg, source(1)),
+                Err(ReplayError::OutsideWindow(_))
+            ));
+        }
+        let recent = Message {
+            timestamp: now - MAX_CLOCK_SKEW + 5,
+            ..from_node("alice-node")
+        };
+        assert!(guard.check(&recent, source(1)).is_ok());
+    }
+
+    #[test]
+    fn a_flood_of_fresh_ids_is_capped() {
+        let mut guard = ReplayGuard::default();
+        for _ in 0..MAX_PROCESSED + 10 {
+            assert!(guard.check(&from_node("flood"), source(1)).is_ok());
+        }
+        assert!(guard.processed.len() <= MAX_PROCESSED);
+    }
+
+    #[test]
+    fn counters_far_behind_the_latest_are_rejected() {
+        let mut guard = ReplayGuard::default();
+        let counted = |counter| Message {
+            counter: Some(counter),
+            ..from_node("alice-node")
+        };
+        assert!(guard.check(&counted(5000), source(1)).is_ok());
+        // Overtaken on the way, but still close enough
+        assert!(guard.check(&counted(4990), source(1)).is_ok());
+        assert!(guard.check(&counted(6000), source(1)).is_ok());
+        // Long gone from the ID list, but not from the counters
+        assert!(matches!(
+            guard.check(&counted(6000 - COUNTER_WINDOW), source(1)),
+            Err(ReplayError::BehindCounter(_))
+        ));
+        // Other senders count on their own
+        let bob = Message {
+            counter: Some(1),
+            ..from_node("bob-node")
+        };
+        assert!(guard.check(&bob, source(2)).is_ok());
+    }
+
+    #[test]
+    fn our_counter_only_grows() {
+        let first = next_counter();
+        assert!(next_counter() > first);
+        assert!(Message::new_chat("me".to_string(), "hi".to_string(), None).counter > Some(first));
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net;
+
+const CACHE_TTL: u64 = 300; // seconds
+
+// "host:port" -> resolved address and when it was looked up
+static CACHE: LazyLock<Mutex<HashMap<String, (SocketAddr, Instant)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Resolve a "host:port" target, which may also be a plain "ip:port"
+/// Lookups are cached for a few minutes; IPv4 results are preferred since we bind IPv4 sockets.
+pub async fn resolve(target: &str) -> std::io::Result<SocketAddr> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    if let Ok(cache) = CACHE.lock()
+        && let Some((addr, resolved_at)) = cache.get(target)
+        && resolved_at.elapsed() < Duration::from_secs(CACHE_TTL)
+    {
+        return Ok(*addr);
+    }
+
+    let addrs: Vec<SocketAddr> = net::lookup_host(target).await?.collect();
+    let addr = addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no addresses found for {target}"),
+            )
+        })?;
+    log::debug!("[Resolver] {target} -> {addr}");
+
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.insert(target.to_string(), (addr, Instant::now()));
+    }
+    Ok(addr)
+}
+
+/// Whether the target names a host rather than a literal IP address
+pub fn is_hostname(target: &str) -> bool {
+    target.parse::<SocketAddr>().is_err()
+}
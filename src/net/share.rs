@@ -0,0 +1,171 @@
+use crate::net::stream::StreamSender;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+
+// Sessions end on their own after this long, so a forgotten share doesn't leak forever
+const SHARE_TIME_LIMIT: u64 = 30 * 60; // seconds
+// Receivers drop streams that go quiet for a minute, so idle sessions send an empty chunk
+const SHARE_IDLE_PING: u64 = 30; // seconds
+const TAIL_POLL_INTERVAL: u64 = 500; // milliseconds
+
+/// Where a share session's lines come from
+pub enum ShareSource {
+    // Lines the user types while the session is active
+    Input,
+    // Lines appended to a file, like `tail -f`
+    Tail(PathBuf),
+}
+
+struct ShareSession {
+    id: u64,
+    // Only set for input sessions
+    lines: Option<mpsc::Sender<String>>,
+    stop: oneshot::Sender<()>,
+}
+
+static SESSION: LazyLock<Mutex<Option<ShareSession>>> = LazyLock::new(|| Mutex::new(None));
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Start a share session, replacing any running one
+pub fn start(source: ShareSource, stream: StreamSender) {
+    let (lines_tx, lines_rx) = mpsc::channel(64);
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+
+    let input_lines = match source {
+        ShareSource::Input => Some(lines_tx),
+        ShareSource::Tail(path) => {
+            tokio::spawn(tail_file(path, lines_tx));
+            None
+        }
+    };
+
+    if let Ok(mut session) = SESSION.lock()
+        && let Some(previous) = session.replace(ShareSession {
+            id,
+            lines: input_lines,
+            stop: stop_tx,
+        })
+    {
+        let _ = previous.stop.send(());
+    }
+
+    tokio::spawn(run_session(id, stream, lines_rx, stop_rx));
+}
+
+/// Stop the running session; returns false if there was none
+pub fn stop() -> bool {
+    match SESSION.lock().ok().and_then(|mut session| session.take()) {
+        Some(session) => {
+            let _ = session.stop.send(());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Hand a typed line to the running input session; returns false if there's none
+pub fn send_line(line: String) -> bool {
+    let Some(lines) = SESSION
+        .lock()
+        .ok()
+        .and_then(|session| session.as_ref().and_then(|s| s.lines.clone()))
+    else {
+        return false;
+    };
+    lines.try_send(line).is_ok()
+}
+
+async fn run_session(
+    id: u64,
+    mut stream: StreamSender,
+    mut lines: mpsc::Receiver<String>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let deadline = time::sleep(Duration::from_secs(SHARE_TIME_LIMIT));
+    tokio::pin!(deadline);
+    let mut idle_ping = time::interval(Duration::from_secs(SHARE_IDLE_PING));
+    idle_ping.tick().await;
+
+    let result = loop {
+        tokio::select! {
+            line = lines.recv() => match line {
+                Some(line) => {
+                    if let Err(e) = stream.send(line).await {
+                        break stream.abort(format!("failed to send: {e}")).await;
+                    }
+                    idle_ping.reset();
+                }
+                // The tailed file went away
+                None => break stream.abort("source closed".to_string()).await,
+            },
+            _ = idle_ping.tick() => {
+                if let Err(e) = stream.send(String::new()).await {
+                    log::error!("Error keeping share session alive: {e}");
+                }
+            }
+            _ = &mut stop => break stream.finish().await,
+            _ = &mut deadline => {
+                say!("@@@ Share session ended: time limit reached");
+                break stream.abort("time limit reached".to_string()).await;
+            }
+        }
+    };
+    if let Err(e) = result {
+        log::error!("Error ending share session: {e}");
+    }
+
+    // Clear the slot, unless a newer session already replaced this one
+    if let Ok(mut session) = SESSION.lock()
+        && session.as_ref().is_some_and(|s| s.id == id)
+    {
+        session.take();
+    }
+}
+
+// Follow a file from its current end, forwarding every complete new line
+async fn tail_file(path: PathBuf, lines: mpsc::Sender<String>) {
+    let mut file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            say!("@@@ Cannot tail {}: {e}", path.display());
+            return;
+        }
+    };
+    if let Err(e) = file.seek(std::io::SeekFrom::End(0)).await {
+        say!("@@@ Cannot tail {}: {e}", path.display());
+        return;
+    }
+
+    let mut reader = BufReader::new(file);
+    let mut partial = String::new();
+    loop {
+        match reader.read_line(&mut partial).await {
+            // Nothing new yet
+            Ok(0) => time::sleep(Duration::from_millis(TAIL_POLL_INTERVAL)).await,
+            Ok(_) if partial.ends_with('\n') => {
+                let line = partial.trim_end_matches(['\n', '\r']).to_string();
+                partial.clear();
+                if lines.send(line).await.is_err() {
+                    // The session ended
+                    return;
+                }
+            }
+            // Wait for the rest of the line
+            Ok(_) => time::sleep(Duration::from_millis(TAIL_POLL_INTERVAL)).await,
+            Err(e) => {
+                say!("@@@ Stopped tailing {}: {e}", path.display());
+                return;
+            }
+        }
+        if lines.is_closed() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::message::Message;
+use crate::net::transport::{BoxFuture, SharedTransport, Transport};
+use rand::Rng;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time;
+
+/// Synthetic network impairment applied to outgoing messages, for testing on a single machine
+#[derive(Debug, Clone, Default)]
+pub struct Impairment {
+    pub loss: f64, // probability (0.0 - 1.0) that a message is dropped
+    pub delay: Duration,
+    pub jitter: Duration, // random extra delay on top of `delay`, so messages may reorder
+}
+
+impl Impairment {
+    /// Parse a spec such as "loss=10%,delay=50ms,jitter=20ms"; every key is optional
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut impairment = Impairment::default();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part.split_once('=').ok_or_else(|| {
+                format!("Invalid simulation setting '{part}', expected key=value")
+            })?;
+            match key.trim() {
+                "loss" => {
+                    let percent: f64 = value
+                        .trim()
+                        .trim_end_matches('%')
+                        .parse()
+                        .map_err(|_| format!("Invalid loss '{value}', expected e.g. 10%"))?;
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(format!("Loss must be between 0% and 100%, got {value}"));
+                    }
+                    impairment.loss = percent / 100.0;
+                }
+                "delay" => impairment.delay = parse_millis(value)?,
+                "jitter" => impairment.jitter = parse_millis(value)?,
+                _ => {
+                    return Err(format!(
+                        "Unknown simulation setting '{key}' (available: loss, delay, jitter)"
+                    ));
+                }
+            }
+        }
+        Ok(impairment)
+    }
+}
+
+impl std::fmt::Display for Impairment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "loss={}%,delay={}ms,jitter={}ms",
+            self.loss * 100.0,
+            self.delay.as_millis(),
+            self.jitter.as_millis()
+        )
+    }
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    value
+        .trim()
+        .trim_end_matches("ms")
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("Invalid duration '{value}', expected e.g. 50ms"))
+}
+
+/// Wraps another transport and drops or delays messages according to an `Impairment`
+pub struct ImpairedTransport {
+    inner: SharedTransport,
+    impairment: Impairment,
+}
+
+impl ImpairedTransport {
+    pub fn new(inner: SharedTransport, impairment: Impairment) -> Self {
+        ImpairedTransport { inner, impairment }
+    }
+
+    // Decide the fate of a message: None if it's lost, otherwise how long to hold it
+    fn schedule(&self) -> Option<Duration> {
+        let mut rng = rand::rng();
+        if rng.random_bool(self.impairment.loss) {
+            return None;
+        }
+        let jitter_ms = self.impairment.jitter.as_millis() as u64;
+        let jitter = Duration::from_millis(rng.random_range(0..=jitter_ms));
+        Some(self.impairment.delay + jitter)
+    }
+}
+
+impl Transport for ImpairedTransport {
+    fn send_bytes_to<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        addr: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            let Some(hold) = self.schedule() else {
+                log::debug!("[Simulate] Dropped {} bytes to {addr}", bytes.len());
+                return Ok(());
+            };
+            // Deliver in the background so a delayed message doesn't hold up the sender,
+            // and so messages with different delays can overtake each other
+            let inner = self.inner.clone();
+            let bytes = bytes.to_vec();
+            let addr = addr.to_string();
+            tokio::spawn(async move {
+                time::sleep(hold).await;
+                if let Err(e) = inner.send_bytes_to(&bytes, &addr).await {
+                    log::error!("[Simulate] Error sending to {addr}: {e}");
+                }
+            });
+            Ok(())
+        })
+    }
+
+    // Scans aren't what the impairment is for
+    fn probe<'a>(&'a self, msg: &'a Message, addr: &'a str) -> BoxFuture<'a, std::io::Result<()>> {
+        self.inner.probe(msg, addr)
+    }
+
+    fn broadcast<'a>(&'a self, msg: &'a Message, port: u16) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            let Some(hold) = self.schedule() else {
+                log::debug!("[Simulate] Dropped {:?} broadcast", msg.msg_type);
+                return Ok(());
+            };
+            let inner = self.inner.clone();
+            let msg = msg.clone();
+            tokio::spawn(async move {
+                time::sleep(hold).await;
+                if let Err(e) = inner.broadcast(&msg, port).await {
+                    log::error!("[Simulate] Error broadcasting: {e}");
+                }
+            });
+            Ok(())
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Traffic counters for a single peer address
+#[derive(Debug, Default, Clone)]
+pub struct TrafficStats {
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    pub bytes_received: u64,
+    pub packets_received: u64,
+    pub decode_failures: u64,
+    pub last_activity: Option<Instant>,
+}
+
+// Addresses tracked at most; beyond that the quietest one makes room, so packets from
+// made-up addresses can't grow the table without bound
+const MAX_ENTRIES: usize = 1024;
+
+/// Per-peer traffic statistics, updated by the transport and the listeners
+#[derive(Debug, Default)]
+pub struct NetStats {
+    // Keyed by the peer's address as a string ("ip:port", or "broadcast:port")
+    peers: HashMap<String, TrafficStats>,
+}
+
+impl NetStats {
+    pub fn new() -> Self {
+        NetStats {
+            peers: HashMap::new(),
+        }
+    }
+
+    // The counters for an address, making room for it if it's new
+    fn entry(&mut self, addr: &str) -> &mut TrafficStats {
+        if !self.peers.contains_key(addr)
+            && self.peers.len() >= MAX_ENTRIES
+            && let Some(quietest) = self
+                .peers
+                .iter()
+                .min_by_key(|(_, stats)| stats.last_activity)
+                .map(|(addr, _)| addr.clone())
+        {
+            self.peers.remove(&quietest);
+        }
+        self.peers.entry(addr.to_string()).or_default()
+    }
+
+    pub fn record_sent(&mut self, addr: &str, bytes: usize) {
+        let stats = self.entry(addr);
+        stats.bytes_sent += bytes as u64;
+        stats.packets_sent += 1;
+        stats.last_activity = Some(Instant::now());
+    }
+
+    pub fn record_received(&mut self, addr: &str, bytes: usize) {
+        let stats = self.entry(addr);
+        stats.bytes_received += bytes as u64;
+        stats.packets_received += 1;
+        stats.last_activity = Some(Instant::now());
+    }
+
+    pub fn record_decode_failure(&mut self, addr: &str) {
+        let stats = self.entry(addr);
+        stats.decode_failures += 1;
+        stats.last_activity = Some(Instant::now());
+    }
+
+    /// Forget everything recorded so far, e.g. for /clear counters
+    pub fn reset(&mut self) {
+        self.peers.clear();
+    }
+
+    /// All entries, sorted by address so the table is stable between invocations
+    pub fn entries(&self) -> Vec<(String, TrafficStats)> {
+        let mut entries: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(addr, stats)| (addr.clone(), stats.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+}
+
+// Create a thread-safe shared NetStats
+// A std Mutex is enough: it's never held across an await point
+pub type SharedNetStats = Arc<Mutex<NetStats>>;
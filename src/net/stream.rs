@@ -0,0 +1,377 @@
+use crate::features::Feature;
+use crate::message::{Message, StreamState};
+use crate::net::transport::SharedTransport;
+use crate::net::{e2e, tcp};
+use crate::peer::SharedPeerList;
+use crate::utils;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+// Streams that haven't received a chunk for this long are considered dead
+const STREAM_IDLE_TIMEOUT: u64 = 60; // seconds
+// A missing chunk is given up on after this long, or once half as many chunks as can
+// wait are waiting behind it
+const MISSING_CHUNK_TIMEOUT: u64 = 5; // seconds
+const MAX_PENDING_CHUNKS: u32 = 512;
+// Streams received at once; chunks of further ones are dropped
+const MAX_STREAMS: usize = 32;
+// Chunks go out at most this often, 40 a second; receivers ignore streams from a
+// source sending more than 500 chunks in 10s
+const CHUNK_INTERVAL: Duration = Duration::from_millis(25);
+
+/// Sends a sequence of chunks to the current peers under a single stream id
+pub struct StreamSender {
+    transport: SharedTransport,
+    peer_list: SharedPeerList,
+    username: String,
+    local_addr: SocketAddr,
+    stream_id: String,
+    seq: u32,
+    // When the next chunk may go out
+    next_chunk: Instant,
+    title: Option<String>,
+    // Usernames to send to; everyone if None
+    recipients: Option<Vec<String>>,
+}
+
+impl StreamSender {
+    pub fn new(
+        transport: SharedTransport,
+        peer_list: SharedPeerList,
+        username: String,
+        local_addr: SocketAddr,
+    ) -> Self {
+        StreamSender {
+            transport,
+            peer_list,
+            username,
+            local_addr,
+            stream_id: nanoid::nanoid!(),
+            seq: 0,
+            next_chunk: Instant::now(),
+            title: None,
+            recipients: None,
+        }
+    }
+
+    /// Set the title receivers show in the stream's header
+    pub fn with_title(mut self, title: String) -> Self {
+        self.title = Some(title);
+        self
+    }
+
+    /// Only send to peers with these usernames
+    pub fn with_recipients(mut self, recipients: Vec<String>) -> Self {
+        self.recipients = Some(recipients);
+        self
+    }
+
+    /// Send a chunk of output
+    pub async fn send(&mut self, content: String) -> std::io::Result<()> {
+        self.send_chunk(StreamState::Data, content).await
+    }
+
+    /// Mark the stream as complete
+    pub async fn finish(mut self) -> std::io::Result<()> {
+        self.send_chunk(StreamState::End, String::new()).await
+    }
+
+    /// Mark the stream as aborted, with a reason shown to the receivers
+    pub async fn abort(mut self, reason: String) -> std::io::Result<()> {
+        self.send_chunk(StreamState::Abort, reason).await
+    }
+
+    async fn send_chunk(&mut self, state: StreamState, content: String) -> std::io::Result<()> {
+        tokio::time::sleep_until(self.next_chunk.into()).await;
+        self.next_chunk = Instant::now() + CHUNK_INTERVAL;
+        let msg = Message::new_stream_chunk(
+            self.username.clone(),
+            self.local_addr,
+            self.stream_id.clone(),
+            self.seq,
+            state,
+            self.title.clone(),
+            content,
+        );
+        self.seq += 1;
+
+        let peers = self.peer_list.lock().await.get_peers();
+        // Peers that switched streaming off would only drop the chunks, and output only
+        // leaves end-to-end encrypted
+        let recipients = peers.iter().filter(|peer| {
+            peer.supports(Feature::Stream)
+                && e2e::can_seal_for(peer)
+                && self
+                    .recipients
+                    .as_ref()
+                    .is_none_or(|names| names.contains(&peer.username))
+        });
+        for peer in recipients {
+            tcp::send_to_peer(&self.transport, peer, &msg).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a shell command and streams its output line by line to all peers
+pub async fn stream_command_output(
+    command: String,
+    mut stream: StreamSender,
+) -> std::io::Result<()> {
+    let mut child = match Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return stream.abort(format!("failed to run command: {e}")).await,
+    };
+
+    stream.send(format!("$ {command}")).await?;
+    if let Some(stdout) = child.stdout.take() {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            stream.send(line).await?;
+        }
+    }
+
+    match child.wait().await {
+        Ok(status) if status.success() => stream.finish().await,
+        Ok(status) => stream.abort(format!("command exited with {status}")).await,
+        Err(e) => {
+            stream
+                .abort(format!("failed to wait for command: {e}"))
+                .await
+        }
+    }
+}
+
+// Receiving side of a single stream
+struct IncomingStream {
+    sender_name: String,
+    next_seq: u32,
+    pending: BTreeMap<u32, Message>,
+    lines: usize,
+    last_activity: Instant,
+    // Since when a chunk is missing while later ones wait in `pending`
+    stalled_since: Option<Instant>,
+}
+
+impl IncomingStream {
+    // Print chunks as long as they arrive in sequence; true once the stream is over
+    fn print_ready(&mut self) -> bool {
+        while let Some(next) = self.pending.remove(&self.next_seq) {
+            self.next_seq += 1;
+            let Some(state) = next.stream.map(|chunk| chunk.state) else {
+                continue;
+            };
+            match state {
+                StreamState::Data => {
+                    for line in next.content.lines() {
+                        say!("  │ {line}");
+                        self.lines += 1;
+                    }
+                }
+                StreamState::End => {
+                    say!(
+                        "  └ end of stream from {} ({} lines)",
+                        self.sender_name,
+                        self.lines
+                    );
+                    return true;
+                }
+                StreamState::Abort => {
+                    say!(
+                        "  └ stream from {} aborted: {}",
+                        self.sender_name,
+                        next.content
+                    );
+                    return true;
+                }
+            }
+        }
+        self.stalled_since = if self.pending.is_empty() {
+            None
+        } else {
+            self.stalled_since.or(Some(Instant::now()))
+        };
+        false
+    }
+
+    // Give up on the missing chunks before the first one waiting
+    fn skip_gap(&mut self) -> bool {
+        let Some(&first) = self.pending.keys().next() else {
+            return false;
+        };
+        say!("  │ … {} chunk(s) missing", first - self.next_seq);
+        self.next_seq = first;
+        self.stalled_since = None;
+        self.print_ready()
+    }
+}
+
+/// Reassembles incoming stream chunks and renders them progressively
+#[derive(Default)]
+pub struct StreamTracker {
+    // (sender, stream id) -> stream; ids are only unique per sender
+    streams: HashMap<(String, String), IncomingStream>,
+}
+
+impl StreamTracker {
+    /// Handle a chunk, printing everything that's now in order
+    pub fn handle_chunk(&mut self, msg: Message, sender_name: &str) {
+        let Some(chunk) = msg.stream.clone() else {
+            return;
+        };
+
+        self.streams.retain(|_, stream| {
+            stream.last_activity.elapsed() < Duration::from_secs(STREAM_IDLE_TIMEOUT)
+        });
+
+        let sender = msg
+            .node_id
+            .clone()
+            .or_else(|| msg.sender_addr.clone())
+            .unwrap_or_default();
+        let key = (sender, chunk.stream_id.clone());
+        if !self.streams.contains_key(&key) && self.streams.len() >= MAX_STREAMS {
+            log::debug!("Dropping a chunk from {sender_name}: too many streams at once");
+            return;
+        }
+        let stream = self.streams.entry(key.clone()).or_insert_with(|| {
+            let formatted_time = utils::display_time_from_timestamp(msg.timestamp);
+            let title = chunk.title.as_deref().unwrap_or("streaming output");
+            say!("[{sender_name}] ▶ {title} ({formatted_time})");
+            IncomingStream {
+                sender_name: sender_name.to_string(),
+                next_seq: 0,
+                pending: BTreeMap::new(),
+                lines: 0,
+                last_activity: Instant::now(),
+                stalled_since: None,
+            }
+        });
+        stream.last_activity = Instant::now();
+        // Chunks too far ahead would only pile up behind a missing one
+        if chunk.seq >= stream.next_seq && chunk.seq - stream.next_seq < MAX_PENDING_CHUNKS {
+            stream.pending.insert(chunk.seq, msg);
+        }
+
+        let mut finished = stream.print_ready();
+        if !finished && stream.pending.len() as u32 >= MAX_PENDING_CHUNKS / 2 {
+            finished = stream.skip_gap();
+        }
+        if finished {
+            self.streams.remove(&key);
+        }
+    }
+
+    /// Skip chunks that have been missing for a while, printing what waited behind them;
+    /// called regularly, since the stream may have nothing more to send
+    pub fn skip_stalled(&mut self) {
+        let timeout = Duration::from_secs(MISSING_CHUNK_TIMEOUT);
+        self.streams.retain(|_, stream| {
+            let stalled = stream
+                .stalled_since
+                .is_some_and(|since| since.elapsed() >= timeout);
+            !(stalled && stream.skip_gap())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::StreamChunk;
+
+    fn chunk(node_id: &str, stream_id: &str, seq: u32, state: StreamState) -> Message {
+        Message {
+            node_id: Some(node_id.to_string()),
+            stream: Some(StreamChunk {
+                stream_id: stream_id.to_string(),
+                seq,
+                state,
+                title: None,
+            }),
+            ..Message::new_chat("alice".to_string(), format!("line {seq}"), None)
+        }
+    }
+
+    fn stream<'a>(
+        tracker: &'a StreamTracker,
+        node_id: &str,
+        stream_id: &str,
+    ) -> &'a IncomingStream {
+        &tracker.streams[&(node_id.to_string(), stream_id.to_string())]
+    }
+
+    #[test]
+    fn chunks_are_put_back_in_order() {
+        let mut tracker = StreamTracker::default();
+        tracker.handle_chunk(chunk("a", "s", 1, StreamState::Data), "alice");
+        assert_eq!(stream(&tracker, "a", "s").lines, 0);
+        tracker.handle_chunk(chunk("a", "s", 0, StreamState::Data), "alice");
+        assert_eq!(stream(&tracker, "a", "s").lines, 2);
+        assert_eq!(stream(&tracker, "a", "s").next_seq, 2);
+
+        // Repeats are only printed once
+        tracker.handle_chunk(chunk("a", "s", 1, StreamState::Data), "alice");
+        assert_eq!(stream(&tracker, "a", "s").lines, 2);
+
+        tracker.handle_chunk(chunk("a", "s", 2, StreamState::End), "alice");
+        assert!(tracker.streams.is_empty());
+    }
+
+    #[test]
+    fn senders_cant_write_into_each_others_streams() {
+        let mut tracker = StreamTracker::default();
+        tracker.handle_chunk(chunk("a", "s", 0, StreamState::Data), "alice");
+        // Same stream ID, another sender: a stream of its own
+        tracker.handle_chunk(chunk("m", "s", 1, StreamState::Abort), "mallory");
+        tracker.handle_chunk(chunk("m", "s", 0, StreamState::Data), "mallory");
+        assert_eq!(tracker.streams.len(), 1);
+        assert_eq!(stream(&tracker, "a", "s").lines, 1);
+    }
+
+    #[test]
+    fn chunks_far_ahead_are_dropped() {
+        let mut tracker = StreamTracker::default();
+        tracker.handle_chunk(
+            chunk("a", "s", MAX_PENDING_CHUNKS, StreamState::Data),
+            "alice",
+        );
+        tracker.handle_chunk(chunk("a", "s", u32::MAX, StreamState::Data), "alice");
+        assert!(stream(&tracker, "a", "s").pending.is_empty());
+    }
+
+    #[test]
+    fn a_missing_chunk_is_skipped_once_enough_wait_behind_it() {
+        let mut tracker = StreamTracker::default();
+        for seq in 1..MAX_PENDING_CHUNKS / 2 {
+            tracker.handle_chunk(chunk("a", "s", seq, StreamState::Data), "alice");
+        }
+        assert_eq!(stream(&tracker, "a", "s").lines, 0);
+        tracker.handle_chunk(
+            chunk("a", "s", MAX_PENDING_CHUNKS / 2, StreamState::Data),
+            "alice",
+        );
+        let stream = stream(&tracker, "a", "s");
+        assert_eq!(stream.lines, MAX_PENDING_CHUNKS as usize / 2);
+        assert!(stream.pending.is_empty());
+    }
+
+    #[test]
+    fn streams_at_once_are_capped() {
+        let mut tracker = StreamTracker::default();
+        for n in 0..MAX_STREAMS + 5 {
+            tracker.handle_chunk(chunk("a", &n.to_string(), 0, StreamState::Data), "alice");
+        }
+        assert_eq!(tracker.streams.len(), MAX_STREAMS);
+    }
+}
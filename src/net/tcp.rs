@@ -0,0 +1,150 @@
+use crate::features::{self, Feature};
+use crate::message::Message;
+use crate::net::transport::SharedTransport;
+use crate::net::{e2e, frame, noise, psk};
+use crate::peer::peer_list::PeerInfo;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Semaphore, mpsc};
+use tokio::time;
+
+// Frames larger than this (a few Ethernet MTUs) go over TCP when the peer supports it
+const MAX_UDP_FRAME_SIZE: usize = 4000;
+// Upper bound for a single side-channel frame, to stop a peer from exhausting our memory;
+// messages are capped at 64 KiB of content, so this leaves room for encryption and the rest
+const MAX_TCP_FRAME_SIZE: u32 = 256 * 1024;
+// Connections being read at once; more are closed right away
+const MAX_CONNECTIONS: usize = 32;
+const CONNECT_TIMEOUT: u64 = 3; // seconds
+const READ_TIMEOUT: u64 = 10; // seconds
+
+static ADVERTISED_PORT: OnceLock<u16> = OnceLock::new();
+
+/// The TCP port we accept side-channel streams on, if the listener is running and the
+/// side channel hasn't been switched off since
+pub fn advertised_port() -> Option<u16> {
+    ADVERTISED_PORT
+        .get()
+        .copied()
+        .filter(|_| features::is_active(Feature::SideChannel))
+}
+
+/// Whether the side-channel listener was started
+pub fn is_listening() -> bool {
+    ADVERTISED_PORT.get().is_some()
+}
+
+/// Starts accepting side-channel connections; every received frame is handed to the
+/// regular listener through `frames`, so it's processed exactly like a datagram
+pub async fn start_side_channel(
+    bind_addr: SocketAddr,
+    frames: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(bind_addr).await?;
+    let _ = ADVERTISED_PORT.set(listener.local_addr()?.port());
+
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    // Switched off with /features
+                    if !features::is_active(Feature::SideChannel) {
+                        continue;
+                    }
+                    let Ok(permit) = connections.clone().try_acquire_owned() else {
+                        log::debug!("[TCP] Too many connections, closing the one from {addr}");
+                        continue;
+                    };
+                    let frames_clone = frames.clone();
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        match time::timeout(Duration::from_secs(READ_TIMEOUT), read_frame(stream))
+                            .await
+                        {
+                            Ok(Ok(frame)) => {
+                                let _ = frames_clone.send((frame, addr)).await;
+                            }
+                            Ok(Err(e)) => log::error!("[TCP] Error reading frame from {addr}: {e}"),
+                            Err(_) => log::error!("[TCP] Timed out reading frame from {addr}"),
+                        }
+                    });
+                }
+                Err(e) => log::error!("[TCP] Error accepting connection: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Each connection carries a single frame, prefixed by its length; the buffer grows with
+// what actually arrives, so a length alone doesn't get memory reserved
+async fn read_frame(mut stream: TcpStream) -> std::io::Result<Vec<u8>> {
+    let len = stream.read_u32().await?;
+    if len > MAX_TCP_FRAME_SIZE {
+        return Err(std::io::Error::other(format!(
+            "frame too large: {len} bytes"
+        )));
+    }
+    let mut frame = Vec::new();
+    stream.take(u64::from(len)).read_to_end(&mut frame).await?;
+    if frame.len() != len as usize {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(frame)
+}
+
+async fn write_frame(addr: SocketAddr, frame: &[u8]) -> std::io::Result<()> {
+    let mut stream = time::timeout(
+        Duration::from_secs(CONNECT_TIMEOUT),
+        TcpStream::connect(addr),
+    )
+    .await
+    .map_err(|_| std::io::Error::other("connection timed out"))??;
+    let frame = psk::seal(frame)?;
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(&frame).await?;
+    stream.shutdown().await
+}
+
+/// Send a message to a peer, end-to-end encrypted, using a short-lived TCP connection
+/// when it's too big for a single datagram and falling back to UDP if that fails. Nothing
+/// is sent if we have no key to encrypt it for the peer (see e2e::can_seal_for).
+pub async fn send_to_peer(
+    transport: &SharedTransport,
+    peer: &PeerInfo,
+    msg: &Message,
+) -> std::io::Result<()> {
+    let Some(msg) = &e2e::seal_for(peer, msg) else {
+        log::debug!("Not sending to {}: no end-to-end key", peer.addr);
+        return Ok(());
+    };
+    let target_addr = peer.addr.to_string();
+    if let Some(tcp_port) = peer.tcp_port {
+        let encoded = frame::encode(msg)?;
+        // With encryption on, only use TCP once a session exists; UDP sets one up
+        let encoded = match noise::layer() {
+            Some(layer) if encoded.len() > MAX_UDP_FRAME_SIZE => {
+                layer.seal_for(&target_addr, &encoded)
+            }
+            _ => Some(encoded),
+        };
+        if let Some(encoded) = encoded
+            && encoded.len() > MAX_UDP_FRAME_SIZE
+        {
+            let tcp_addr = SocketAddr::new(peer.addr.ip(), tcp_port);
+            match write_frame(tcp_addr, &encoded).await {
+                Ok(()) => {
+                    log::debug!("[TCP] Sent {} bytes to {tcp_addr}", encoded.len());
+                    return Ok(());
+                }
+                Err(e) => log::debug!("[TCP] Falling back to UDP for {target_addr}: {e}"),
+            }
+        }
+    }
+    transport.send_to(msg, &target_addr).await
+}
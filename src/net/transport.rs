@@ -0,0 +1,112 @@
+use crate::message::Message;
+use crate::net::stats::SharedNetStats;
+use crate::net::{frame, interfaces, psk, resolver};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+const BROADCAST_ADDR: &str = "255.255.255.255";
+// What scan probes are counted as in the traffic stats
+const PROBES_KEY: &str = "scan probes";
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Abstraction over how messages leave this peer, so other transports
+/// (or test doubles) can be swapped in without touching the callers
+pub trait Transport: Send + Sync {
+    /// Send an already encoded frame to a single peer address ("ip:port" or "host:port")
+    fn send_bytes_to<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        addr: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<()>>;
+
+    /// Send a message to a single peer address
+    fn send_to<'a>(
+        &'a self,
+        msg: &'a Message,
+        addr: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            let encoded = frame::encode(&interfaces::addressed_for(msg, addr))?;
+            self.send_bytes_to(&encoded, addr).await
+        })
+    }
+
+    /// Send a scan probe to an address that most likely isn't a peer; probes are counted
+    /// together in the traffic stats, instead of one entry per address
+    fn probe<'a>(&'a self, msg: &'a Message, addr: &'a str) -> BoxFuture<'a, std::io::Result<()>> {
+        self.send_to(msg, addr)
+    }
+
+    /// Send a message to every host on the local network on the given port
+    fn broadcast<'a>(&'a self, msg: &'a Message, port: u16) -> BoxFuture<'a, std::io::Result<()>>;
+
+    /// The local address messages are sent from
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+// Create a thread-safe shared Transport
+pub type SharedTransport = Arc<dyn Transport>;
+
+/// Plain UDP transport; the socket must have broadcast enabled for `broadcast` to work
+pub struct UdpTransport {
+    socket: Arc<UdpSocket>,
+    net_stats: SharedNetStats,
+}
+
+impl UdpTransport {
+    pub fn new(socket: Arc<UdpSocket>, net_stats: SharedNetStats) -> Self {
+        UdpTransport { socket, net_stats }
+    }
+
+    fn record_sent(&self, addr: &str, bytes: usize) {
+        if let Ok(mut stats) = self.net_stats.lock() {
+            stats.record_sent(addr, bytes);
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_bytes_to<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        addr: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            let target = resolver::resolve(addr).await?;
+            let bytes = psk::seal(bytes)?;
+            self.socket.send_to(&bytes, target).await?;
+            self.record_sent(addr, bytes.len());
+            Ok(())
+        })
+    }
+
+    fn probe<'a>(&'a self, msg: &'a Message, addr: &'a str) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            let target = resolver::resolve(addr).await?;
+            let encoded =
+                psk::seal(&frame::encode(&interfaces::addressed_for(msg, addr))?)?.into_owned();
+            self.socket.send_to(&encoded, target).await?;
+            self.record_sent(PROBES_KEY, encoded.len());
+            Ok(())
+        })
+    }
+
+    fn broadcast<'a>(&'a self, msg: &'a Message, port: u16) -> BoxFuture<'a, std::io::Result<()>> {
+        Box::pin(async move {
+            let encoded = psk::seal(&frame::encode(msg)?)?.into_owned();
+            self.socket
+                .send_to(&encoded, format!("{BROADCAST_ADDR}:{port}"))
+                .await?;
+            self.record_sent(&format!("broadcast:{port}"), encoded.len());
+            Ok(())
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
@@ -0,0 +1,255 @@
+use crate::MAX_USERNAME_LEN;
+use crate::message::Message;
+use crate::net::interfaces;
+use crate::peer::peer_list::DEFAULT_MAX_PEERS;
+use std::net::{IpAddr, SocketAddr};
+
+// Chat and stream content; bigger payloads come over the TCP side channel, but nobody
+// types (or streams) this much in one message
+const MAX_CONTENT_LEN: usize = 64 * 1024; // bytes
+// IDs, room, network, group, version, status text and the like
+const MAX_FIELD_LEN: usize = 64; // bytes
+// Peers and features listed in a single message
+const MAX_LIST_LEN: usize = DEFAULT_MAX_PEERS;
+
+/// Check every field a message carries before any of it reaches our state or the
+/// terminal; a datagram is whatever anyone on the LAN chose to send. Timestamps are
+/// left to the replay guard, which rejects anything outside its window.
+pub fn check(msg: &Message) -> Result<(), String> {
+    username(&msg.sender)?;
+    // End-to-end encrypted content is hex, twice the size plus the nonce and tag
+    let max_content_len = if msg.enc == Some(true) {
+        2 * (MAX_CONTENT_LEN + 28)
+    } else {
+        MAX_CONTENT_LEN
+    };
+    if msg.content.len() > max_content_len {
+        return Err(format!("content is {} bytes", msg.content.len()));
+    }
+    // Escape sequences could rewrite the screen, carriage returns overwrite a line
+    if msg
+        .content
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+    {
+        return Err("control characters in content".to_string());
+    }
+    field("message id", &msg.message_id)?;
+    if let Some(addr) = &msg.sender_addr {
+        peer_addr(addr)?;
+    }
+
+    for (name, value) in [
+        ("room", &msg.room),
+        ("network", &msg.network),
+        ("group", &msg.group),
+        ("version", &msg.version),
+        ("node id", &msg.node_id),
+        ("recipient", &msg.recipient),
+        ("end-to-end key", &msg.e2e_key),
+        ("reaction to", &msg.reaction_to),
+    ] {
+        if let Some(value) = value {
+            field(name, value)?;
+        }
+    }
+    if let Some(text) = msg
+        .presence
+        .as_ref()
+        .and_then(|presence| presence.text.as_ref())
+    {
+        field("status", text)?;
+    }
+    if let Some(stream) = &msg.stream {
+        field("stream id", &stream.stream_id)?;
+        if let Some(title) = &stream.title {
+            field("stream title", title)?;
+        }
+    }
+    if let Some(capabilities) = &msg.capabilities {
+        list_len("capabilities", capabilities.len())?;
+        for capability in capabilities {
+            field("capability", capability)?;
+        }
+    }
+
+    if let Some(known_peers) = &msg.known_peers {
+        list_len("known peers", known_peers.len())?;
+        for (name, addr) in known_peers {
+            gossiped_username(name)?;
+            peer_addr(addr)?;
+        }
+    }
+    if let Some(exchange) = &msg.peer_exchange {
+        list_len(
+            "exchanged peers",
+            exchange.added.len() + exchange.left.len(),
+        )?;
+        for (name, addr) in &exchange.added {
+            gossiped_username(name)?;
+            peer_addr(addr)?;
+        }
+        for addr in &exchange.left {
+            peer_addr(addr)?;
+        }
+    }
+    Ok(())
+}
+
+// Counted in characters, like the limit on our own name, so short names in other
+// scripts fit too
+fn username(name: &str) -> Result<(), String> {
+    let len = name.chars().count();
+    if len == 0 || len > MAX_USERNAME_LEN {
+        return Err(format!("username is {len} characters"));
+    }
+    if name.chars().any(char::is_control) {
+        return Err("control characters in username".to_string());
+    }
+    Ok(())
+}
+
+// Names of peers others tell us about are empty for the ones they haven't heard from yet
+fn gossiped_username(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Ok(());
+    }
+    username(name)
+}
+
+fn field(name: &str, value: &str) -> Result<(), String> {
+    if value.len() > MAX_FIELD_LEN {
+        return Err(format!("{name} is {} bytes", value.len()));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(format!("control characters in {name}"));
+    }
+    Ok(())
+}
+
+fn list_len(name: &str, len: usize) -> Result<(), String> {
+    if len > MAX_LIST_LEN {
+        return Err(format!("{len} {name}"));
+    }
+    Ok(())
+}
+
+// A peer's address must be one we could actually send to
+fn peer_addr(addr: &str) -> Result<(), String> {
+    let parsed: SocketAddr = addr
+        .parse()
+        .map_err(|_| format!("invalid address {addr:?}"))?;
+    let ip = parsed.ip();
+    // The broadcast addresses of our own networks included, e.g. 192.168.1.255
+    let is_broadcast = match ip {
+        IpAddr::V4(ip) => {
+            ip.is_broadcast()
+                || interfaces::networks()
+                    .iter()
+                    .any(|network| network.broadcast == ip)
+        }
+        IpAddr::V6(_) => false,
+    };
+    if is_broadcast || ip.is_multicast() || ip.is_unspecified() || parsed.port() == 0 {
+        return Err(format!("unusable address {addr}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(sender: &str, content: &str) -> Message {
+        Message::new_chat(
+            sender.to_string(),
+            content.to_string(),
+            Some("192.0.2.1:10001".parse().unwrap()),
+        )
+    }
+
+    #[test]
+    fn ordinary_messages_pass() {
+        assert!(check(&chat("alice", "hi\nthere\tyou")).is_ok());
+        // Names are counted in characters, not bytes
+        assert!(check(&chat("東京のアリス", "こんにちは")).is_ok());
+    }
+
+    #[test]
+    fn oversized_fields_are_rejected() {
+        assert!(check(&chat("alice", &"x".repeat(MAX_CONTENT_LEN))).is_ok());
+        assert!(check(&chat("alice", &"x".repeat(MAX_CONTENT_LEN + 1))).is_err());
+        assert!(check(&chat(&"a".repeat(MAX_USERNAME_LEN + 1), "hi")).is_err());
+        assert!(check(&chat("", "hi")).is_err());
+
+        let msg = Message {
+            room: Some("r".repeat(MAX_FIELD_LEN + 1)),
+            ..chat("alice", "hi")
+        };
+        assert!(check(&msg).is_err());
+
+        let msg = Message {
+            known_peers: Some(vec![
+                (String::new(), "192.0.2.2:10001".to_string());
+                MAX_LIST_LEN + 1
+            ]),
+            ..chat("alice", "hi")
+        };
+        assert!(check(&msg).is_err());
+    }
+
+    #[test]
+    fn encrypted_content_may_be_bigger() {
+        let content = "ab".repeat(MAX_CONTENT_LEN);
+        assert!(check(&chat("alice", &content)).is_err());
+        let msg = Message {
+            enc: Some(true),
+            ..chat("alice", &content)
+        };
+        assert!(check(&msg).is_ok());
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        assert!(check(&chat("alice", "\x1b[2Jgotcha")).is_err());
+        assert!(check(&chat("alice", "fake\rline")).is_err());
+        assert!(check(&chat("al\x1bice", "hi")).is_err());
+        let msg = Message {
+            version: Some("1.0\x07".to_string()),
+            ..chat("alice", "hi")
+        };
+        assert!(check(&msg).is_err());
+    }
+
+    #[test]
+    fn addresses_must_be_usable() {
+        for addr in [
+            "255.255.255.255:10001",
+            "224.0.0.1:10001",
+            "0.0.0.0:10001",
+            "192.0.2.1:0",
+            "not an address",
+        ] {
+            let msg = Message {
+                sender_addr: Some(addr.to_string()),
+                ..chat("alice", "hi")
+            };
+            assert!(check(&msg).is_err(), "{addr}");
+
+            let msg = Message {
+                known_peers: Some(vec![("bob".to_string(), addr.to_string())]),
+                ..chat("alice", "hi")
+            };
+            assert!(check(&msg).is_err(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn gossip_may_leave_names_out() {
+        let msg = Message {
+            known_peers: Some(vec![(String::new(), "192.0.2.2:10001".to_string())]),
+            ..chat("alice", "hi")
+        };
+        assert!(check(&msg).is_ok());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::message::Message;
+use crate::net::transport::SharedTransport;
+use crate::peer::SharedPeerList;
+use crate::peer::peer_list::{PeerInfo, shared_name};
+use rand::Rng;
+use rand::seq::IndexedRandom;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time;
+
+// A round with one random peer happens every interval, plus up to the jitter,
+// so peers don't all reconcile at the same moment
+const ANTI_ENTROPY_INTERVAL: u64 = 30; // seconds
+const ANTI_ENTROPY_JITTER: u64 = 10; // seconds
+
+// Steps of a round: we send our digest; a peer that disagrees pushes its full list;
+// we push back ours if the peer was missing anything, and the round ends there
+const FULL_LIST: &str = "FULL";
+const FULL_LIST_REPLY: &str = "FULL_REPLY";
+
+/// Starts periodic push-pull rounds, which heal partitions where parts of the LAN
+/// only know some of the network
+pub fn start(
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+    peer_list: SharedPeerList,
+) {
+    tokio::spawn(async move {
+        loop {
+            let jitter = rand::rng().random_range(0..=ANTI_ENTROPY_JITTER);
+            time::sleep(Duration::from_secs(ANTI_ENTROPY_INTERVAL + jitter)).await;
+
+            let peers = peer_list.lock().await.get_peers();
+            let confirmed: Vec<&PeerInfo> =
+                peers.iter().filter(|peer| !peer.is_provisional).collect();
+            let target = confirmed.choose(&mut rand::rng()).map(|peer| peer.addr);
+            let known = known_peers(&peers, &username, local_addr);
+            let Some(target) = target else {
+                continue;
+            };
+
+            log::debug!("[AntiEntropy] Sending digest to {target}");
+            let msg = Message::new_peer_digest(username.clone(), local_addr, digest(&known), None);
+            if let Err(e) = transport.send_to(&msg, &target.to_string()).await {
+                log::error!("Error sending peer digest to {target}: {e}");
+            }
+        }
+    });
+}
+
+/// Handles a step of a push-pull round started by us or by the sender
+pub async fn handle_digest_message(
+    msg: &Message,
+    peer_list: &SharedPeerList,
+    transport: SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+) -> std::io::Result<()> {
+    let Some(sender_addr) = msg.sender_addr.clone() else {
+        return Ok(());
+    };
+    let known = known_peers(&peer_list.lock().await.get_peers(), username, local_addr);
+
+    let reply = match msg.content.as_str() {
+        FULL_LIST | FULL_LIST_REPLY => {
+            let theirs = msg.known_peers.clone().unwrap_or_default();
+            contact_unknown(&theirs, &known, &transport, username, local_addr).await;
+
+            // Only answer the first full list, and only if the sender is missing something
+            let their_addrs: HashSet<&String> = theirs.iter().map(|(_, addr)| addr).collect();
+            let sender_lacks = known.iter().any(|(_, addr)| !their_addrs.contains(addr));
+            (msg.content == FULL_LIST && sender_lacks).then_some(FULL_LIST_REPLY)
+        }
+        // A digest; nothing to do if we agree
+        their_digest => (their_digest != digest(&known)).then_some(FULL_LIST),
+    };
+
+    if let Some(step) = reply {
+        log::debug!("[AntiEntropy] Sending full peer list to {sender_addr}");
+        let msg = Message::new_peer_digest(
+            username.to_string(),
+            local_addr,
+            step.to_string(),
+            Some(known),
+        );
+        transport.send_to(&msg, &sender_addr).await?;
+    }
+    Ok(())
+}
+
+// Everyone we know, including ourselves, as (username, addr) sorted by address
+fn known_peers(
+    peers: &[PeerInfo],
+    username: &str,
+    local_addr: SocketAddr,
+) -> Vec<(String, String)> {
+    let mut known: Vec<(String, String)> = peers
+        .iter()
+        .filter(|peer| !peer.is_provisional)
+        .map(|peer| {
+            (
+                shared_name(&peer.username, peer.addr),
+                peer.addr.to_string(),
+            )
+        })
+        .collect();
+    known.push((username.to_string(), local_addr.to_string()));
+    known.sort_by(|a, b| a.1.cmp(&b.1));
+    known.dedup_by(|a, b| a.1 == b.1);
+    known
+}
+
+// Stable across builds and platforms, unlike std's hasher (64-bit FNV-1a over the addresses)
+fn digest(known: &[(String, String)]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (_, addr) in known {
+        for byte in addr.bytes().chain(std::iter::once(b',')) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+// Send a discovery to every listed peer we don't know, so it joins once it answers
+async fn contact_unknown(
+    theirs: &[(String, String)],
+    known: &[(String, String)],
+    transport: &SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+) {
+    let known_addrs: HashSet<&String> = known.iter().map(|(_, addr)| addr).collect();
+    let discovery_msg = Message::new_discovery(username.to_string(), local_addr);
+    for (name, addr) in theirs {
+        if known_addrs.contains(addr) || addr.parse::<SocketAddr>().is_err() {
+            continue;
+        }
+        log::debug!("[AntiEntropy] Contacting {name} ({addr}) learned from a peer");
+        if let Err(e) = transport.send_to(&discovery_msg, addr).await {
+            log::error!("Error contacting {addr}: {e}");
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::config;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+
+const BLOCKLIST_FILE: &str = "blocklist.toml";
+
+/// Usernames and IPs blocked with /block, kept in ~/.config/pung/blocklist.toml
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct BlocklistFile {
+    blocked: BTreeSet<String>,
+}
+
+static BLOCKED: LazyLock<Mutex<BTreeSet<String>>> = LazyLock::new(|| Mutex::new(load()));
+
+fn blocklist_file() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join(BLOCKLIST_FILE))
+}
+
+fn load() -> BTreeSet<String> {
+    let Some(path) = blocklist_file() else {
+        return BTreeSet::new();
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => match toml::from_str::<BlocklistFile>(&contents) {
+            Ok(file) => file.blocked,
+            Err(e) => {
+                say!("Warning: Could not parse {}: {e}", path.display());
+                BTreeSet::new()
+            }
+        },
+        Err(_) => BTreeSet::new(),
+    }
+}
+
+fn save(blocked: &BTreeSet<String>) -> std::io::Result<()> {
+    let path = blocklist_file()
+        .ok_or_else(|| std::io::Error::other("could not determine the home directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = BlocklistFile {
+        blocked: blocked.clone(),
+    };
+    let contents = toml::to_string(&file).map_err(std::io::Error::other)?;
+    std::fs::write(&path, contents)
+}
+
+/// Block a username or IP; returns false if it was already blocked
+pub fn block(target: &str) -> std::io::Result<bool> {
+    let mut blocked = BLOCKED
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    if !blocked.insert(target.to_string()) {
+        return Ok(false);
+    }
+    save(&blocked)?;
+    Ok(true)
+}
+
+/// Unblock a username or IP; returns false if it wasn't blocked
+pub fn unblock(target: &str) -> std::io::Result<bool> {
+    let mut blocked = BLOCKED
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    if !blocked.remove(target) {
+        return Ok(false);
+    }
+    save(&blocked)?;
+    Ok(true)
+}
+
+pub fn list() -> Vec<String> {
+    BLOCKED
+        .lock()
+        .map(|blocked| blocked.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Whether a peer is blocked, by its username or its IP
+pub fn is_blocked(username: &str, ip: Option<IpAddr>) -> bool {
+    BLOCKED.lock().is_ok_and(|blocked| {
+        blocked.contains(username) || ip.is_some_and(|ip| blocked.contains(&ip.to_string()))
+    })
+}
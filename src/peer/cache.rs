@@ -0,0 +1,128 @@
+use crate::DEFAULT_RECV_INIT_PORT;
+use crate::config;
+use crate::message::Message;
+use crate::net::transport::SharedTransport;
+use crate::peer::SharedPeerList;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time;
+
+const CACHE_FILE: &str = "peer_cache.toml";
+// The cache is written this often, and on exit
+const SAVE_INTERVAL: u64 = 60; // seconds
+// Peers not seen for this long are dropped from the cache
+const MAX_AGE: i64 = 7 * 24 * 3600; // seconds
+
+/// Peers from earlier runs, so a restart can contact them directly instead of
+/// waiting for a broadcast to reach them
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CacheFile {
+    peers: Vec<CachedPeer>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedPeer {
+    addr: String,
+    username: String,
+    last_seen: i64, // unix timestamp
+}
+
+fn cache_file() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join(CACHE_FILE))
+}
+
+fn load() -> Vec<CachedPeer> {
+    let Some(path) = cache_file() else {
+        return Vec::new();
+    };
+    let Ok(contents) = std::fs::read_to_string(&path) else {
+        return Vec::new();
+    };
+    match toml::from_str::<CacheFile>(&contents) {
+        Ok(file) => file.peers,
+        Err(e) => {
+            log::debug!("Ignoring unreadable peer cache {}: {e}", path.display());
+            Vec::new()
+        }
+    }
+}
+
+/// Write the current peers to the cache, keeping earlier ones that aren't too old
+pub async fn save(peer_list: &SharedPeerList) -> std::io::Result<()> {
+    let path = cache_file()
+        .ok_or_else(|| std::io::Error::other("could not determine the home directory"))?;
+    let now = chrono::Utc::now().timestamp();
+
+    let mut peers: Vec<CachedPeer> = peer_list
+        .lock()
+        .await
+        .get_peers()
+        .into_iter()
+        .filter(|peer| !peer.is_provisional)
+        .map(|peer| CachedPeer {
+            addr: peer.addr.to_string(),
+            username: peer.username,
+            last_seen: now - peer.last_seen.elapsed().as_secs() as i64,
+        })
+        .collect();
+    let current: HashSet<String> = peers.iter().map(|peer| peer.addr.clone()).collect();
+    peers.extend(
+        load()
+            .into_iter()
+            .filter(|peer| !current.contains(&peer.addr) && now - peer.last_seen < MAX_AGE),
+    );
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let contents = toml::to_string(&CacheFile { peers }).map_err(std::io::Error::other)?;
+    std::fs::write(&path, contents)
+}
+
+/// Send a discovery to every cached peer, on its last address and on its host's init
+/// port, then keep the cache up to date. Returns how many peers were contacted.
+pub async fn start(
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+    peer_list: SharedPeerList,
+) -> usize {
+    let now = chrono::Utc::now().timestamp();
+    let cached: Vec<SocketAddr> = load()
+        .into_iter()
+        .filter(|peer| now - peer.last_seen < MAX_AGE)
+        .filter_map(|peer| peer.addr.parse().ok())
+        .filter(|addr| *addr != local_addr)
+        .collect();
+
+    // Peers usually get a new port on restart, so their host's init port is tried too
+    let hosts: HashSet<IpAddr> = cached.iter().map(|addr| addr.ip()).collect();
+    let targets = cached.iter().copied().chain(
+        hosts
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, DEFAULT_RECV_INIT_PORT)),
+    );
+    let discovery_msg = Message::new_discovery(username, local_addr);
+    for addr in targets {
+        if let Err(e) = transport.send_to(&discovery_msg, &addr.to_string()).await {
+            log::debug!("Could not contact cached peer {addr}: {e}");
+        }
+    }
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(SAVE_INTERVAL));
+        // The first tick is immediate; there's nothing new to save yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = save(&peer_list).await {
+                log::error!("Error saving the peer cache: {e}");
+            }
+        }
+    });
+    cached.len()
+}
@@ -1,80 +1,350 @@
 use crate::DEFAULT_RECV_INIT_PORT;
+use crate::events::{self, Event};
 use crate::message::Message;
-use crate::net::sender;
-use crate::peer::SharedPeerList;
-use std::net::SocketAddr;
+use crate::mirror;
+use crate::net::transport::SharedTransport;
+use crate::net::{interfaces, resolver};
+use crate::peer::lifecycle::{self, PeerEvent};
+use crate::peer::{SharedPeerList, blocklist, known_keys, nick};
+use crate::ui::privacy;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::net::UdpSocket;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
 
-// Constants for discovery
-const BROADCAST_ADDR: &str = "255.255.255.255";
+// While we know nobody, retry discovery with a backoff between these bounds
+const DISCOVERY_MIN_RETRY: u64 = 2; // seconds
+const DISCOVERY_MAX_RETRY: u64 = 60; // seconds
+// Once peers are around, broadcasts only need to catch newcomers that missed ours
+const DISCOVERY_STABLE_INTERVAL: u64 = 900; // seconds
+const DISCOVERY_STABLE_JITTER: u64 = 120; // seconds
+// How often the peer list is checked for the switch between the two
+const DISCOVERY_CHECK_INTERVAL: u64 = 1; // seconds
+// Repeated discoveries from the same source within this window are ignored
+const DISCOVERY_REPEAT_WINDOW: u64 = 5; // seconds
+// How long to collect replies after the last broadcast of a burst
+const BURST_REPLY_WAIT: u64 = 3; // seconds
+// Replies are spread over this window, so a broadcast doesn't trigger a burst from every peer
+const DISCOVERY_REPLY_JITTER: u64 = 500; // milliseconds
 
-/// Starts the peer discovery process
+// Set by --room or /join; instances only peer with others in the same room
+static ROOM: Mutex<Option<String>> = Mutex::new(None);
+// Discovery broadcasts sent so far, periodic or from /b, for /stats
+static ROUNDS: AtomicU64 = AtomicU64::new(0);
+
+/// Only establish peering with instances that joined the same room; None leaves it
+pub fn join_room(room: Option<String>) {
+    if let Ok(mut current) = ROOM.lock() {
+        *current = room;
+    }
+}
+
+pub fn room() -> Option<String> {
+    ROOM.lock().ok().and_then(|room| room.clone())
+}
+
+/// Whether the message comes from an instance in our room (no room matches no room);
+/// the listeners drop everything else
+pub fn is_same_room(msg: &Message) -> bool {
+    msg.room == room()
+}
+
+/// Rate limits discovery handling per source, so `/b` and discovery replies bouncing
+/// between peers can't snowball into a broadcast storm on a large LAN
+#[derive(Default)]
+pub struct DiscoveryLimiter {
+    // Address the datagram came from -> when we last handled a discovery from it; what
+    // the message claims can be anything, so a flood could dodge the limit with it
+    last_handled: HashMap<SocketAddr, Instant>,
+}
+
+impl DiscoveryLimiter {
+    /// Whether a discovery from `source` should be handled, or is a repeat within the window
+    pub fn should_handle(&mut self, source: SocketAddr) -> bool {
+        let window = Duration::from_secs(DISCOVERY_REPEAT_WINDOW);
+        self.last_handled
+            .retain(|_, handled_at| handled_at.elapsed() < window);
+
+        if self.last_handled.contains_key(&source) {
+            return false;
+        }
+        self.last_handled.insert(source, Instant::now());
+        true
+    }
+}
+
+/// Starts the peer discovery process: an initial broadcast, then quick retries with
+/// backoff while nobody has answered, and rare jittered ones once peers are around
 pub async fn start_discovery(
-    socket: Arc<UdpSocket>,
+    transport: SharedTransport,
     username: String,
     local_addr: SocketAddr,
+    peer_list: SharedPeerList,
 ) -> std::io::Result<()> {
     // Send initial discovery message
-    send_discovery_message(socket, &username, local_addr).await?;
+    send_discovery_message(transport.clone(), &username, local_addr).await?;
+
+    tokio::spawn(async move {
+        let mut backoff = Duration::from_secs(DISCOVERY_MIN_RETRY);
+        let mut next_send = Instant::now() + backoff;
+        let mut was_alone = true;
+        let mut interval = time::interval(Duration::from_secs(DISCOVERY_CHECK_INTERVAL));
+        loop {
+            interval.tick().await;
+            let alone = peer_list
+                .lock()
+                .await
+                .get_peers()
+                .iter()
+                .all(|peer| peer.is_provisional);
+
+            // Everyone left; start looking again right away
+            if alone && !was_alone {
+                backoff = Duration::from_secs(DISCOVERY_MIN_RETRY);
+                next_send = Instant::now();
+            }
+            was_alone = alone;
+            if Instant::now() < next_send {
+                continue;
+            }
+
+            log::debug!("[Discovery] Sending periodic discovery broadcast");
+            if let Err(e) = send_discovery_message(transport.clone(), &username, local_addr).await {
+                log::error!("Error sending discovery broadcast: {e}");
+            }
+            next_send = Instant::now()
+                + if alone {
+                    let wait = backoff;
+                    backoff = (backoff * 2).min(Duration::from_secs(DISCOVERY_MAX_RETRY));
+                    wait
+                } else {
+                    let jitter = rand::rng().random_range(0..=DISCOVERY_STABLE_JITTER);
+                    Duration::from_secs(DISCOVERY_STABLE_INTERVAL + jitter)
+                };
+        }
+    });
 
     Ok(())
 }
 
+/// Sends `count` discovery broadcasts `interval` apart, then waits a little for answers;
+/// returns the usernames of the peers that replied
+pub async fn broadcast_burst(
+    transport: SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    count: u32,
+    interval: Duration,
+) -> std::io::Result<HashSet<String>> {
+    // Subscribe first, so early replies aren't missed
+    let mut events = events::subscribe();
+    for i in 0..count {
+        if i > 0 {
+            time::sleep(interval).await;
+        }
+        send_discovery_message(transport.clone(), username, local_addr).await?;
+    }
+
+    let mut repliers = HashSet::new();
+    let deadline = time::sleep(Duration::from_secs(BURST_REPLY_WAIT));
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(Event::DiscoveryReply(sender)) => {
+                    repliers.insert(sender);
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    Ok(repliers)
+}
+
+pub fn rounds() -> u64 {
+    ROUNDS.load(Ordering::SeqCst)
+}
+
 /// Sends a discovery message to the broadcast address on multiple ports
 pub async fn send_discovery_message(
-    socket: Arc<UdpSocket>,
+    transport: SharedTransport,
     username: &str,
     local_addr: SocketAddr,
 ) -> std::io::Result<()> {
-    let discovery_msg = Message::new_discovery(username.to_string(), local_addr);
-
-    // Broadcast to the default init port
-    let broadcast_addr = format!("{BROADCAST_ADDR}:{DEFAULT_RECV_INIT_PORT}");
-    sender::send_message(socket.clone(), &discovery_msg, &broadcast_addr).await?;
-
+    ROUNDS.fetch_add(1, Ordering::SeqCst);
     // Also broadcast to the local port that this peer is using
     // This helps reach peers that couldn't bind to the default init port
-    let local_port = local_addr.port();
-    if local_port != DEFAULT_RECV_INIT_PORT {
-        let alt_broadcast_addr = format!("{BROADCAST_ADDR}:{local_port}");
-        sender::send_message(socket.clone(), &discovery_msg, &alt_broadcast_addr).await?;
+    let mut ports = vec![DEFAULT_RECV_INIT_PORT];
+    if local_addr.port() != DEFAULT_RECV_INIT_PORT {
+        ports.push(local_addr.port());
+    }
+
+    // Broadcast on every network we're on, advertising our address on that network;
+    // the limited broadcast address only goes out of the default interface
+    let networks = interfaces::networks();
+    if networks.is_empty() {
+        let discovery_msg = Message::new_discovery(username.to_string(), local_addr);
+        for port in ports {
+            transport.broadcast(&discovery_msg, port).await?;
+        }
+        return Ok(());
+    }
+    for network in networks {
+        let advertised = SocketAddr::new(IpAddr::V4(network.ip), local_addr.port());
+        let discovery_msg = Message::new_discovery(username.to_string(), advertised);
+        for &port in &ports {
+            let target = SocketAddr::new(IpAddr::V4(network.broadcast), port);
+            if let Err(e) = transport.send_to(&discovery_msg, &target.to_string()).await {
+                log::error!("Error broadcasting discovery on {}: {e}", network.ip);
+            }
+        }
     }
 
     Ok(())
 }
 
-/// Handles an incoming discovery message
+/// Sends a discovery message straight to one host, for when broadcasts don't reach it
+/// The target may be a hostname; without a port, the default init port is used.
+pub async fn connect(
+    target: &str,
+    transport: SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    peer_list: &SharedPeerList,
+) -> std::io::Result<SocketAddr> {
+    let target = match target.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => target.to_string(),
+        _ => format!("{target}:{DEFAULT_RECV_INIT_PORT}"),
+    };
+    let addr = resolver::resolve(&target).await?;
+
+    // Keep the name the user knows the host by
+    if resolver::is_hostname(&target)
+        && let Some((host, _)) = target.rsplit_once(':')
+    {
+        peer_list
+            .lock()
+            .await
+            .remember_hostname(addr.ip(), host.to_string());
+    }
+
+    let discovery_msg = Message::new_discovery(username.to_string(), local_addr);
+    transport.send_to(&discovery_msg, &addr.to_string()).await?;
+
+    // Show the host as pending until it answers
+    peer_list.lock().await.add_provisional(addr);
+    Ok(addr)
+}
+
+/// Handles an incoming discovery message; `authentic` says it comes from the peer itself
+/// (from its host or signed with its key), which lets it rejoin within the grace period
 pub async fn handle_discovery_message(
     msg: &Message,
     peer_list: &SharedPeerList,
-    socket: Arc<UdpSocket>,
+    transport: SharedTransport,
     username: &str,
     local_addr: SocketAddr,
+    authentic: bool,
 ) -> std::io::Result<()> {
+    let sender_ip = msg
+        .sender_addr
+        .as_ref()
+        .and_then(|addr| SocketAddr::from_str(addr).ok())
+        .map(|addr| addr.ip());
+    if blocklist::is_blocked(&msg.sender, sender_ip) {
+        log::debug!("Ignoring discovery from blocked {}", msg.sender);
+        return Ok(());
+    }
+
+    #[allow(clippy::collapsible_if)]
     if let Some(addr_str) = &msg.sender_addr {
-        if let Ok(addr) = SocketAddr::from_str(addr_str) {
+        if let Ok(addr) = SocketAddr::from_str(addr_str)
+            // Our own broadcasts come back to us, possibly on every interface
+            && !interfaces::is_own_addr(addr, local_addr)
+        {
             // Add the peer to our list
             let mut peer_list = peer_list.lock().await;
 
             // Check if this is a new peer before printing a message
             let is_new = peer_list.find_username_by_addr(&addr).is_none();
+            // A peer we'd only heard of shows up itself for the first time
+            let first_hand = is_new || (msg.node_id.is_some() && peer_list.is_hearsay(&addr));
 
             // Always add or update the peer with their exact (username, IP, port)
             // This ensures proper uniqueness and prevents cross-refreshing
-            peer_list.add_or_update_peer(addr, msg.sender.clone());
+            // Only a direct discovery lifts the grace period, not gossip about the peer, nor
+            // someone claiming to be it
+            if authentic {
+                peer_list.forget_removal(&addr, msg.node_id.as_deref());
+            }
+            peer_list.add_or_update_peer(addr, msg.sender.clone(), msg.node_id.clone());
+            peer_list.set_tcp_port(&addr, msg.tcp_port);
+            peer_list.set_sleepy(&addr, msg.sleepy.unwrap_or(false));
+            peer_list.set_capabilities(&addr, msg.capabilities.clone());
+            known_keys::check_peer(&mut peer_list, &addr, msg);
+            peer_list.set_e2e_key(&addr, msg.public_key.as_deref(), msg.e2e_key.as_deref());
+            peer_list.set_advertised(
+                &addr,
+                msg.version.clone(),
+                msg.protocol_range,
+                msg.room.clone(),
+            );
 
-            // Only print a message if this is a new peer
+            if first_hand {
+                events::publish(Event::PeerDiscovered {
+                    username: msg.sender.clone(),
+                    node_id: msg.node_id.clone(),
+                    placeholder: false,
+                });
+            }
+
+            // Only print a message if this is a new peer (sleepy peers waking up return quietly)
             if is_new {
-                println!("### New peer discovered: {} ({})", msg.sender, addr);
+                if peer_list.take_napping(&addr) {
+                    log::debug!("Sleepy peer is back: {} ({})", msg.sender, addr);
+                } else {
+                    say!(
+                        "### New peer discovered: {} ({})",
+                        msg.sender,
+                        privacy::addr(addr, msg.node_id.as_deref())
+                    );
+                }
+                mirror::peer_event("discovered", &msg.sender, &addr.to_string());
+                lifecycle::record(PeerEvent::Discovered, &msg.sender, &addr.to_string());
+
+                // Name clashes: with another peer, or with us (the other user sees the same warning)
+                let own_name = nick::current().unwrap_or_else(|| username.to_string());
+                if msg.sender == own_name {
+                    say!(
+                        "### {} also goes by {own_name}; use /nick to tell yourselves apart",
+                        privacy::addr(addr, msg.node_id.as_deref())
+                    );
+                }
+                let namesakes = peer_list.count_namesakes(&msg.sender);
+                if namesakes > 1 {
+                    say!(
+                        "### {namesakes} peers go by {}; they're shown as {}#1, {}#2...",
+                        msg.sender,
+                        msg.sender,
+                        msg.sender
+                    );
+                }
             }
 
-            let socket_clone = socket.clone();
+            // A reply needs no answer, the peer already has us
+            if msg.is_discovery_reply() {
+                return Ok(());
+            }
 
             // Send a discovery response back to the peer
-            let response = Message::new_discovery(username.to_string(), local_addr);
-            sender::send_message(socket_clone.clone(), &response, addr_str).await?;
+            let response = Message::new_discovery_reply(username.to_string(), local_addr);
 
             // Always send our peer list to the new peer (even if it's just us)
             // This ensures complete peer discovery across the network
@@ -97,13 +367,31 @@ pub async fn handle_discovery_message(
                 peer_addrs.push(local_addr.to_string());
             }
 
-            // Send the peer list message
+            // Send the replies after a random delay, so peers answering the same broadcast
+            // don't all transmit at once
             let peer_list_msg =
                 Message::new_peer_list(username.to_string(), peer_addrs, local_addr);
-            sender::send_message(socket_clone.clone(), &peer_list_msg, addr_str).await?;
+            let jitter = rand::rng().random_range(0..=DISCOVERY_REPLY_JITTER);
+            // The peer list is the bigger one, staggered separately from the reply
+            let peer_list_jitter = rand::rng().random_range(0..=DISCOVERY_REPLY_JITTER);
+            let target = addr_str.clone();
+            tokio::spawn(async move {
+                time::sleep(Duration::from_millis(jitter)).await;
+                if let Err(e) = transport.send_to(&response, &target).await {
+                    log::error!("Error replying to discovery from {target}: {e}");
+                }
+                time::sleep(Duration::from_millis(peer_list_jitter)).await;
+                if let Err(e) = transport.send_to(&peer_list_msg, &target).await {
+                    log::error!("Error sending peer list to {target}: {e}");
+                }
+            });
 
             // Log that we shared our peer list
-            println!("@@@ Shared peer list with {} ({})", msg.sender, addr);
+            say!(
+                "@@@ Shared peer list with {} ({})",
+                msg.sender,
+                privacy::addr(addr, msg.node_id.as_deref())
+            );
         }
     }
 
@@ -114,14 +402,14 @@ pub async fn handle_discovery_message(
 pub async fn handle_peer_list_message(
     msg: &Message,
     peer_list: &SharedPeerList,
-    socket: Arc<UdpSocket>,
+    transport: SharedTransport,
     username: &str,
     local_addr: SocketAddr,
 ) -> std::io::Result<()> {
     // Parse the peer list from the message content
     let peer_addrs: Vec<&str> = msg.content.split(',').collect();
     let mut new_peers = false;
-    let socket_clone = socket.clone();
+    let transport_clone = transport.clone();
 
     // Add each peer to our list
     let mut peer_list_lock = peer_list.lock().await;
@@ -133,7 +421,7 @@ pub async fn handle_peer_list_message(
 
         if let Ok(addr) = SocketAddr::from_str(addr_str) {
             // Don't add ourselves
-            if addr == local_addr {
+            if interfaces::is_own_addr(addr, local_addr) {
                 continue;
             }
 
@@ -152,12 +440,20 @@ pub async fn handle_peer_list_message(
             if is_new {
                 // For new peers, use a temporary name until we learn their real username
                 let temp_name = format!("peer@{addr}");
-                peer_list_lock.add_or_update_peer(addr, temp_name);
+                mirror::peer_event("discovered", &temp_name, addr_str);
+                lifecycle::record(PeerEvent::Discovered, &temp_name, addr_str);
+                events::publish(Event::PeerDiscovered {
+                    username: temp_name.clone(),
+                    node_id: None,
+                    placeholder: true,
+                });
+                peer_list_lock.add_or_update_peer(addr, temp_name, None);
                 new_peers = true;
 
                 // Send a discovery message to this new peer
                 let discovery_msg = Message::new_discovery(username.to_string(), local_addr);
-                sender::send_message(socket_clone.clone(), &discovery_msg, &addr.to_string())
+                transport_clone
+                    .send_to(&discovery_msg, &addr.to_string())
                     .await?;
             }
         }
@@ -165,8 +461,30 @@ pub async fn handle_peer_list_message(
 
     // If we added new peers, log it
     if new_peers {
-        println!("### Discovered new peers from peer list");
+        say!("### Discovered new peers from peer list");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery_repeats_from_a_source_are_limited() {
+        let mut limiter = DiscoveryLimiter::default();
+        let alice: SocketAddr = "192.0.2.1:20001".parse().unwrap();
+        let bob: SocketAddr = "192.0.2.2:20001".parse().unwrap();
+        assert!(limiter.should_handle(alice));
+        assert!(!limiter.should_handle(alice));
+        assert!(limiter.should_handle(bob));
+
+        // Once the window has passed, the source is heard again
+        limiter.last_handled.insert(
+            alice,
+            Instant::now() - Duration::from_secs(DISCOVERY_REPEAT_WINDOW),
+        );
+        assert!(limiter.should_handle(alice));
+    }
+}
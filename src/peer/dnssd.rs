@@ -0,0 +1,185 @@
+use crate::features;
+use crate::net::transport::SharedTransport;
+use crate::net::{frame, interfaces, resolver};
+use crate::peer::{SharedPeerList, discovery};
+use hickory_resolver::TokioAsyncResolver;
+use hickory_resolver::proto::rr::{RData, RecordType};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time;
+
+// Wide-area DNS-SD service type (RFC 6763)
+const SERVICE: &str = "_pung-chat._udp";
+// Records published in DNS change rarely; look again every few minutes
+const BROWSE_INTERVAL: u64 = 300; // seconds
+
+/// An instance found by browsing, with what its TXT record says about it
+struct Instance {
+    // "host:port" from the SRV record
+    target: String,
+    // Protocol versions it speaks, from "proto=min-max"
+    protocols: Option<(u8, u8)>,
+    // Features it supports, from "caps=a,b"
+    capabilities: Option<Vec<String>>,
+}
+
+/// Browses `_pung-chat._udp.<domain>` and sends a discovery to every listed instance,
+/// so peers on other routed subnets can find each other without broadcasts
+pub fn start(
+    domain: String,
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+    peer_list: SharedPeerList,
+) {
+    tokio::spawn(async move {
+        let dns = match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(dns) => dns,
+            Err(e) => {
+                say!("@@@ DNS-SD browsing disabled, could not set up a resolver: {e}");
+                return;
+            }
+        };
+
+        let mut interval = time::interval(Duration::from_secs(BROWSE_INTERVAL));
+        loop {
+            interval.tick().await;
+            let instances = match browse(&dns, &domain).await {
+                Ok(instances) => instances,
+                Err(e) => {
+                    log::debug!("[DNS-SD] Browsing {SERVICE}.{domain} failed: {e}");
+                    continue;
+                }
+            };
+
+            for instance in instances {
+                let target = instance.target;
+                // Skip ourselves and peers we already have
+                let Ok(addr) = resolver::resolve(&target).await else {
+                    log::debug!("[DNS-SD] Could not resolve {target}");
+                    continue;
+                };
+                if interfaces::is_own_addr(addr, local_addr)
+                    || peer_list
+                        .lock()
+                        .await
+                        .find_username_by_addr(&addr)
+                        .is_some()
+                {
+                    continue;
+                }
+                // No point in contacting an instance we couldn't talk to
+                if let Some(protocols) = instance.protocols
+                    && frame::negotiate(protocols).is_none()
+                {
+                    log::debug!("[DNS-SD] Skipping {target}, it speaks protocol {protocols:?}");
+                    continue;
+                }
+                log::debug!("[DNS-SD] Contacting {target}");
+                match discovery::connect(
+                    &target,
+                    transport.clone(),
+                    &username,
+                    local_addr,
+                    &peer_list,
+                )
+                .await
+                {
+                    // Show what it supports while it's pending; heartbeats take over once it answers
+                    Ok(addr) => peer_list
+                        .lock()
+                        .await
+                        .set_capabilities(&addr, instance.capabilities),
+                    Err(e) => log::debug!("[DNS-SD] Could not contact {target}: {e}"),
+                }
+            }
+        }
+    });
+}
+
+// Follow the PTR records to each instance's SRV and TXT records
+async fn browse(
+    dns: &TokioAsyncResolver,
+    domain: &str,
+) -> Result<Vec<Instance>, hickory_resolver::error::ResolveError> {
+    let service = format!("{SERVICE}.{}.", domain.trim_end_matches('.'));
+    let pointers = dns.lookup(service.as_str(), RecordType::PTR).await?;
+
+    let mut instances = Vec::new();
+    for record in pointers.iter() {
+        let RData::PTR(name) = record else {
+            continue;
+        };
+        let srv = match dns.srv_lookup(name.0.clone()).await {
+            Ok(srv) => srv,
+            Err(e) => {
+                log::debug!("[DNS-SD] No SRV record for {}: {e}", name.0);
+                continue;
+            }
+        };
+        // TXT records are optional; instances without one are still contacted
+        let txt: Vec<(String, String)> = match dns.txt_lookup(name.0.clone()).await {
+            Ok(txt) => txt
+                .iter()
+                .flat_map(|record| record.txt_data().iter())
+                .filter_map(|entry| {
+                    let entry = String::from_utf8_lossy(entry);
+                    let (key, value) = entry.split_once('=')?;
+                    Some((key.to_ascii_lowercase(), value.to_string()))
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        let value = |key: &str| {
+            txt.iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.clone())
+        };
+        let protocols = value("proto").and_then(|range| {
+            let (min, max) = range.split_once('-')?;
+            Some((min.parse().ok()?, max.parse().ok()?))
+        });
+        let capabilities = value("caps").map(|caps| {
+            caps.split(',')
+                .filter(|cap| !cap.is_empty())
+                .map(str::to_string)
+                .collect()
+        });
+
+        instances.extend(srv.iter().map(|srv| {
+            let host = srv.target().to_utf8();
+            Instance {
+                target: format!("{}:{}", host.trim_end_matches('.'), srv.port()),
+                protocols,
+                capabilities: capabilities.clone(),
+            }
+        }));
+    }
+    Ok(instances)
+}
+
+/// Zone file records that publish us under `domain`, for adding to the DNS server by hand
+pub fn records(domain: &str, username: &str, local_addr: SocketAddr) -> Vec<String> {
+    let domain = domain.trim_end_matches('.');
+    // Instance and host names must be valid DNS labels
+    let label: String = username
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let instance = format!("{label}.{SERVICE}.{domain}.");
+    let host = format!("{label}.{domain}.");
+    let address_type = if local_addr.is_ipv4() { "A" } else { "AAAA" };
+    let (min_protocol, max_protocol) = frame::supported_range();
+    let txt = format!(
+        "\"user={username}\" \"proto={min_protocol}-{max_protocol}\" \"caps={}\"",
+        features::capabilities().join(",")
+    );
+
+    vec![
+        format!("{SERVICE}.{domain}. PTR {instance}"),
+        format!("{instance} SRV 0 0 {} {host}", local_addr.port()),
+        format!("{instance} TXT {txt}"),
+        format!("{host} {address_type} {}", local_addr.ip()),
+    ]
+}
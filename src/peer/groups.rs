@@ -0,0 +1,103 @@
+use crate::config;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+
+const GROUPS_FILE: &str = "groups.toml";
+
+/// Named groups of usernames for /g, kept in ~/.config/pung/groups.toml
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct GroupsFile {
+    groups: BTreeMap<String, BTreeSet<String>>,
+}
+
+type Groups = BTreeMap<String, BTreeSet<String>>;
+
+static GROUPS: LazyLock<Mutex<Groups>> = LazyLock::new(|| Mutex::new(load()));
+
+fn groups_file() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join(GROUPS_FILE))
+}
+
+fn load() -> Groups {
+    let Some(path) = groups_file() else {
+        return Groups::new();
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => match toml::from_str::<GroupsFile>(&contents) {
+            Ok(file) => file.groups,
+            Err(e) => {
+                say!("Warning: Could not parse {}: {e}", path.display());
+                Groups::new()
+            }
+        },
+        Err(_) => Groups::new(),
+    }
+}
+
+fn save(groups: &Groups) -> std::io::Result<()> {
+    let path = groups_file()
+        .ok_or_else(|| std::io::Error::other("could not determine the home directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = GroupsFile {
+        groups: groups.clone(),
+    };
+    let contents = toml::to_string(&file).map_err(std::io::Error::other)?;
+    std::fs::write(&path, contents)
+}
+
+/// Add usernames to a group, creating it if needed; returns how many weren't in it yet
+pub fn add(group: &str, usernames: &[&str]) -> std::io::Result<usize> {
+    let mut groups = GROUPS
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let members = groups.entry(group.to_string()).or_default();
+    let added = usernames
+        .iter()
+        .filter(|username| members.insert(username.to_string()))
+        .count();
+    save(&groups)?;
+    Ok(added)
+}
+
+/// Remove usernames from a group, or the whole group if none are given; a group that
+/// ends up empty is removed too. Returns false if there's no such group.
+pub fn remove(group: &str, usernames: &[&str]) -> std::io::Result<bool> {
+    let mut groups = GROUPS
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let Some(members) = groups.get_mut(group) else {
+        return Ok(false);
+    };
+    for username in usernames {
+        members.remove(*username);
+    }
+    if usernames.is_empty() || members.is_empty() {
+        groups.remove(group);
+    }
+    save(&groups)?;
+    Ok(true)
+}
+
+pub fn members(group: &str) -> Option<BTreeSet<String>> {
+    GROUPS
+        .lock()
+        .ok()
+        .and_then(|groups| groups.get(group).cloned())
+}
+
+pub fn list() -> Vec<(String, Vec<String>)> {
+    GROUPS
+        .lock()
+        .map(|groups| {
+            groups
+                .iter()
+                .map(|(group, members)| (group.clone(), members.iter().cloned().collect()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
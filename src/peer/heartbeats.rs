@@ -1,34 +1,193 @@
-use crate::message::Message;
-use crate::net::sender;
-use crate::peer::SharedPeerList;
+use crate::events::{self, Event};
+use crate::message::{Message, Presence};
+use crate::mirror;
+use crate::net::interfaces;
+use crate::net::transport::SharedTransport;
+use crate::peer::lifecycle::{self, PeerEvent};
+use crate::peer::{SharedPeerList, blocklist, known_keys};
+use crate::ui::privacy;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::UdpSocket;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::time;
 
 // Constants for heartbeat
-const HEARTBEAT_INTERVAL: u64 = 6; // seconds
-const PEER_TIMEOUT: u64 = 15; // seconds
-const REMOVED_PEER_GRACE_PERIOD: u64 = 30; // seconds - don't re-add peers that were removed within this time
+const NAT_KEEPALIVE_INTERVAL: u64 = 2; // seconds - short enough to hold even aggressive NAT mappings open
+const SLEEPY_PEER_TIMEOUT: u64 = 600; // seconds - long enough to ride out a laptop's nap
+
+// Defaults of the heartbeat timing, which --heartbeat-interval and friends can change
+pub const DEFAULT_HEARTBEAT_INTERVAL: u64 = 6; // seconds
+pub const DEFAULT_PEER_TIMEOUT: u64 = 15; // seconds
+pub const DEFAULT_REMOVED_PEER_GRACE_PERIOD: u64 = 30; // seconds - don't re-add peers that were removed within this time
+// Slower than this, a peer may as well not be there
+const MAX_HEARTBEAT_INTERVAL: u64 = 3600; // seconds
+
+// Up to this many peers, every peer gets every heartbeat round; beyond it, each round
+// only goes to a share of them, so a big LAN isn't flooded with heartbeats
+const FULL_RATE_PEERS: usize = 16;
+
+// How many of our latest heartbeat rounds can be matched with an echo, for the RTT
+const RTT_ROUNDS: usize = 10;
+
+// Sequence number of our next heartbeat round
+static HEARTBEAT_SEQ: AtomicU32 = AtomicU32::new(0);
+// How long each peer waited between our heartbeats, as of the latest round
+static HEARTBEAT_EVERY: AtomicU64 = AtomicU64::new(DEFAULT_HEARTBEAT_INTERVAL);
+// (seq, when it was sent) of our latest rounds
+static SENT_ROUNDS: LazyLock<Mutex<VecDeque<(u32, Instant)>>> =
+    LazyLock::new(|| Mutex::new(VecDeque::new()));
+
+static ADVERTISE_SLEEPY: OnceLock<bool> = OnceLock::new();
+static TIMING: Mutex<Option<Timing>> = Mutex::new(None);
+// Our availability, as set with /status
+static PRESENCE: LazyLock<Mutex<Presence>> = LazyLock::new(|| Mutex::new(Presence::default()));
+
+/// How often heartbeats go out and how long peers may stay silent
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    pub interval: u64,     // seconds
+    pub timeout: u64,      // seconds
+    pub grace_period: u64, // seconds
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            timeout: DEFAULT_PEER_TIMEOUT,
+            grace_period: DEFAULT_REMOVED_PEER_GRACE_PERIOD,
+        }
+    }
+}
+
+impl Timing {
+    // A peer has to miss a couple of heartbeats in a row before it times out, so the
+    // timeout must be at least 2.5 intervals
+    pub fn validate(&self) -> Result<(), String> {
+        validate_interval(self.interval)?;
+        let too_short = match (self.timeout.checked_mul(2), self.interval.checked_mul(5)) {
+            (Some(timeout), Some(interval)) => timeout < interval,
+            _ => true,
+        };
+        if too_short {
+            return Err(format!(
+                "a peer timeout of {}s is too short for a heartbeat interval of {}s (at least {}s)",
+                self.timeout,
+                self.interval,
+                (self.interval * 5).div_ceil(2)
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn validate_interval(interval: u64) -> Result<(), String> {
+    if !(1..=MAX_HEARTBEAT_INTERVAL).contains(&interval) {
+        return Err(format!(
+            "the heartbeat interval must be between 1 and {MAX_HEARTBEAT_INTERVAL} seconds"
+        ));
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "every {}s, timeout {}s, grace period {}s",
+            self.interval, self.timeout, self.grace_period
+        )
+    }
+}
+
+/// Use other heartbeat timing than the defaults
+pub fn configure(timing: Timing) -> Result<(), String> {
+    timing.validate()?;
+    if let Ok(mut current) = TIMING.lock() {
+        *current = Some(timing);
+    }
+    Ok(())
+}
+
+pub fn timing() -> Timing {
+    TIMING
+        .lock()
+        .ok()
+        .and_then(|timing| *timing)
+        .unwrap_or_default()
+}
+
+/// How long a peer may go without a heartbeat from us, with the interval and the share
+/// of peers each round goes to
+pub fn heartbeat_every() -> u64 {
+    HEARTBEAT_EVERY
+        .load(Ordering::Relaxed)
+        .max(timing().interval)
+}
+
+/// Send heartbeats at another interval from the next round on; heartbeats tell peers
+/// about it, so they stretch their timeout for us. Our own timeout stays.
+pub fn set_interval(interval: u64) -> Result<Timing, String> {
+    validate_interval(interval)?;
+    let timing = Timing {
+        interval,
+        ..timing()
+    };
+    if let Ok(mut current) = TIMING.lock() {
+        *current = Some(timing);
+    }
+    Ok(timing)
+}
+
+// Switch a timer over to an interval changed with /set since the timer was made
+fn follow_interval(interval: &mut time::Interval) {
+    let period = Duration::from_secs(timing().interval);
+    if interval.period() != period {
+        *interval = time::interval_at(time::Instant::now() + period, period);
+    }
+}
+
+/// Advertise ourselves as sleepy, so peers give us a longer timeout
+pub fn advertise_sleepy() {
+    let _ = ADVERTISE_SLEEPY.set(true);
+}
+
+pub fn advertises_sleepy() -> bool {
+    ADVERTISE_SLEEPY.get().copied().unwrap_or(false)
+}
+
+/// Tell peers whether we're around, with the next heartbeat
+pub fn set_presence(presence: Presence) {
+    if let Ok(mut current) = PRESENCE.lock() {
+        *current = presence;
+    }
+}
+
+pub fn presence() -> Presence {
+    PRESENCE
+        .lock()
+        .map(|presence| presence.clone())
+        .unwrap_or_default()
+}
 
 /// Starts the heartbeat mechanism to maintain peer liveness
 pub async fn start_heartbeat(
-    socket: Arc<UdpSocket>,
+    transport: SharedTransport,
     username: String,
     local_addr: SocketAddr,
     peer_list: SharedPeerList,
 ) -> std::io::Result<()> {
     // Start heartbeat sender
+    let transport_clone = transport.clone();
     let username_clone = username.clone();
     let peer_list_clone = peer_list.clone();
     tokio::spawn(async move {
-        let socket_clone = socket.clone();
-
         // Send a heartbeat immediately when starting
         log::debug!("[Heartbeat] Sending initial heartbeat");
         if let Err(e) = send_heartbeats(
-            socket_clone.clone(),
+            transport_clone.clone(),
             &username_clone,
             local_addr,
             &peer_list_clone,
@@ -39,13 +198,14 @@ pub async fn start_heartbeat(
         }
 
         // Then set up the regular interval for subsequent heartbeats
-        let mut interval = time::interval(Duration::from_secs(HEARTBEAT_INTERVAL));
+        let mut interval = time::interval(Duration::from_secs(timing().interval));
 
         loop {
             interval.tick().await;
+            follow_interval(&mut interval);
             log::debug!("[Heartbeat] Sending heartbeats");
             if let Err(e) = send_heartbeats(
-                socket_clone.clone(),
+                transport_clone.clone(),
                 &username_clone,
                 local_addr,
                 &peer_list_clone,
@@ -57,6 +217,28 @@ pub async fn start_heartbeat(
         }
     });
 
+    // Start NAT keep-alive sender
+    let transport_clone = transport.clone();
+    let username_clone = username.clone();
+    let peer_list_clone = peer_list.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(NAT_KEEPALIVE_INTERVAL));
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = send_nat_keepalives(
+                transport_clone.clone(),
+                &username_clone,
+                local_addr,
+                &peer_list_clone,
+            )
+            .await
+            {
+                log::error!("Error sending NAT keep-alives: {e}");
+            }
+        }
+    });
+
     // Start peer timeout checker
     let peer_list_clone = peer_list.clone();
     tokio::spawn(async move {
@@ -64,10 +246,11 @@ pub async fn start_heartbeat(
         check_peer_timeouts(&peer_list_clone).await;
 
         // Then set up the regular interval for subsequent checks
-        let mut interval = time::interval(Duration::from_secs(HEARTBEAT_INTERVAL));
+        let mut interval = time::interval(Duration::from_secs(timing().interval));
 
         loop {
             interval.tick().await;
+            follow_interval(&mut interval);
             check_peer_timeouts(&peer_list_clone).await;
         }
     });
@@ -77,37 +260,94 @@ pub async fn start_heartbeat(
 
 /// Sends heartbeat messages to all known peers
 async fn send_heartbeats(
-    socket: Arc<UdpSocket>,
+    transport: SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    peer_list: &SharedPeerList,
+) -> std::io::Result<()> {
+    let mut peers = peer_list.lock().await.get_peers();
+    // A stable order, so each peer keeps its turn from round to round
+    peers.sort_by_key(|peer| peer.addr);
+    // Every peer gets one heartbeat every `stride` rounds; membership still spreads
+    // through peer exchange and anti-entropy in between
+    let stride = peers.len().div_ceil(FULL_RATE_PEERS).max(1);
+
+    let seq = HEARTBEAT_SEQ.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut rounds) = SENT_ROUNDS.lock() {
+        rounds.push_back((seq, Instant::now()));
+        if rounds.len() > RTT_ROUNDS {
+            rounds.pop_front();
+        }
+    }
+    let interval = timing().interval;
+    HEARTBEAT_EVERY.store((stride as u64).saturating_mul(interval), Ordering::Relaxed);
+    let heartbeat_msg = Message {
+        // Peers stretch their timeout for us and don't count skipped rounds as lost; they
+        // need to know about an interval other than the default too, or time us out
+        heartbeat_every: (stride > 1 || interval != DEFAULT_HEARTBEAT_INTERVAL).then(|| {
+            let seconds = (stride as u64).saturating_mul(interval);
+            (stride as u32, u32::try_from(seconds).unwrap_or(u32::MAX))
+        }),
+        ..Message::new_heartbeat(username.to_string(), local_addr, seq)
+    };
+    // Send heartbeat to each peer whose turn it is, except blocked ones, so they time us out
+    for peer in peers
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % stride == seq as usize % stride)
+        .map(|(_, peer)| peer)
+        .filter(|peer| !blocklist::is_blocked(&peer.username, Some(peer.addr.ip())))
+    {
+        // Echo the peer's latest heartbeat, so it can tell how long the round trip took
+        let msg = Message {
+            heartbeat_echo: peer
+                .last_heartbeat_seq
+                .zip(peer.last_heartbeat_at)
+                .map(|(seq, at)| (seq, at.elapsed().as_millis() as u32)),
+            ..heartbeat_msg.clone()
+        };
+        transport.send_to(&msg, &peer.addr.to_string()).await?;
+    }
+    Ok(())
+}
+
+/// Sends small keep-alive datagrams to peers behind a NAT so the mapping stays open
+async fn send_nat_keepalives(
+    transport: SharedTransport,
     username: &str,
     local_addr: SocketAddr,
     peer_list: &SharedPeerList,
 ) -> std::io::Result<()> {
-    // Gather known peers as (username, addr) pairs, skipping self
-    let peers = {
+    // The mapping is for the address the peer's packets come from, not the one it
+    // advertises from behind the NAT
+    let nat_peers: Vec<SocketAddr> = {
         let peer_list = peer_list.lock().await;
         peer_list
             .get_peers()
             .into_iter()
-            .map(|p| (p.username.clone(), p.addr.to_string()))
-            .collect::<Vec<_>>()
+            .filter_map(|p| p.nat_source)
+            .collect()
     };
+    if nat_peers.is_empty() {
+        return Ok(());
+    }
 
-    let heartbeat_msg = Message::new_heartbeat(username.to_string(), local_addr, peers.clone());
-    let socket_clone = socket.clone();
-    // Send heartbeat to each peer
-    for (_, peer_addr_str) in peers {
-        if let Ok(peer_addr) = peer_addr_str.parse::<SocketAddr>() {
-            sender::send_message(socket_clone.clone(), &heartbeat_msg, &peer_addr.to_string())
-                .await?;
-        }
+    let keepalive_msg = Message::new_keepalive(username.to_string(), local_addr);
+    for peer_addr in nat_peers {
+        log::debug!("[KeepAlive] Sending NAT keep-alive to: {peer_addr}");
+        transport
+            .send_to(&keepalive_msg, &peer_addr.to_string())
+            .await?;
     }
     Ok(())
 }
 
 /// Checks for peers that haven't been seen recently and removes them
 async fn check_peer_timeouts(peer_list: &SharedPeerList) {
-    let timeout = Duration::from_secs(PEER_TIMEOUT);
-    let cleanup_age = Duration::from_secs(REMOVED_PEER_GRACE_PERIOD * 2); // Clean up entries after twice the grace period
+    let timing = timing();
+    let timeout = Duration::from_secs(timing.timeout);
+    let sleepy_timeout = Duration::from_secs(SLEEPY_PEER_TIMEOUT.max(timing.timeout));
+    let cleanup_age = Duration::from_secs(timing.grace_period * 2); // Clean up entries after twice the grace period
 
     // Each (username, IP, port) combination is treated as a unique peer
     // No consolidation is performed - this allows multiple instances on the same machine
@@ -115,7 +355,7 @@ async fn check_peer_timeouts(peer_list: &SharedPeerList) {
     // Then remove stale peers and clean up old entries from the recently removed list
     let stale_peers = {
         let mut peer_list = peer_list.lock().await;
-        let removed = peer_list.remove_stale_peers(timeout);
+        let removed = peer_list.remove_stale_peers(timeout, sleepy_timeout);
 
         // Clean up old entries from the recently removed list
         peer_list.clean_removed_list(cleanup_age);
@@ -124,42 +364,122 @@ async fn check_peer_timeouts(peer_list: &SharedPeerList) {
     };
 
     // Log removed peers
-    for username in stale_peers {
-        println!("### Peer timed out and was removed: {username}");
+    for peer in stale_peers {
+        // Never really was a peer
+        if peer.is_provisional {
+            say!(
+                "### No answer from {}, removed it",
+                privacy::addr(peer.addr, peer.node_id.as_deref())
+            );
+            continue;
+        }
+        mirror::peer_event("timed_out", &peer.username, &peer.addr.to_string());
+        lifecycle::record(PeerEvent::TimedOut, &peer.username, &peer.addr.to_string());
+        // Sleepy peers come and go all the time, don't bother the user about it
+        if peer.is_sleepy {
+            log::debug!("Sleepy peer timed out: {} ({})", peer.username, peer.addr);
+            continue;
+        }
+        say!(
+            "### Peer timed out and was removed: {} ({})",
+            peer.username,
+            privacy::addr(peer.addr, peer.node_id.as_deref())
+        );
+        events::publish(Event::PeerLeft);
     }
 }
 
-/// Handles an incoming heartbeat message
+/// Handles an incoming heartbeat message; like a discovery, only an `authentic` one lets a
+/// removed peer back in within the grace period
 pub async fn handle_heartbeat_message(
     msg: &Message,
+    source_addr: SocketAddr,
     peer_list: &SharedPeerList,
+    local_addr: Option<SocketAddr>,
+    authentic: bool,
 ) -> std::io::Result<()> {
+    #[allow(clippy::collapsible_if)]
     if let Some(addr_str) = &msg.sender_addr {
         if let Ok(addr) = addr_str.parse::<SocketAddr>() {
             let mut peer_list = peer_list.lock().await;
 
             // Always add or update the sender with the exact (username, IP, port)
             // This is the only peer we know for sure is active (since we just received a message from it)
-            peer_list.add_or_update_peer(addr, msg.sender.clone());
+            if authentic {
+                peer_list.forget_removal(&addr, msg.node_id.as_deref());
+            }
+            peer_list.add_or_update_peer(addr, msg.sender.clone(), msg.node_id.clone());
+
+            // If the datagram came from a different IP than the one advertised, something in
+            // between is translating addresses; unless we can reach the advertised one
+            // directly, which a peer on several networks sending from another one of them is
+            let behind_nat =
+                source_addr.ip() != addr.ip() && interfaces::local_ip_for(addr.ip()).is_none();
+            peer_list.set_nat_source(&addr, behind_nat.then_some(source_addr));
+            peer_list.set_tcp_port(&addr, msg.tcp_port);
+            peer_list.set_sleepy(&addr, msg.sleepy.unwrap_or(false));
+            peer_list.set_capabilities(&addr, msg.capabilities.clone());
+            known_keys::check_peer(&mut peer_list, &addr, msg);
+            peer_list.set_e2e_key(&addr, msg.public_key.as_deref(), msg.e2e_key.as_deref());
+            peer_list.set_advertised(
+                &addr,
+                msg.version.clone(),
+                msg.protocol_range,
+                msg.room.clone(),
+            );
+            peer_list.set_presence(&addr, msg.presence.clone());
+            peer_list.take_napping(&addr);
+            peer_list.set_heartbeat_every(&addr, msg.heartbeat_every);
+            if let Some(seq) = msg.heartbeat_seq {
+                peer_list.record_heartbeat_seq(&addr, seq);
+            }
+            if let Some((seq, held_ms)) = msg.heartbeat_echo
+                && let Some(rtt) = round_trip(seq, held_ms)
+            {
+                peer_list.record_rtt(&addr, rtt);
+            }
 
+            // Older peers still list their known peers in heartbeats, newer ones use peer exchange
             // IMPORTANT: We do NOT update the last_seen timestamp for peers in the known_peers list
             // We only use known_peers to discover new peers, not to refresh existing ones
             // This ensures that when a peer is closed, it will be properly removed after timeout
             if let Some(known_peers) = &msg.known_peers {
                 for (peer_name, peer_addr_str) in known_peers {
                     if let Ok(peer_addr) = peer_addr_str.parse::<SocketAddr>() {
+                        // Peers list us too, on whichever of our addresses they know
+                        if local_addr.is_some_and(|local| interfaces::is_own_addr(peer_addr, local))
+                        {
+                            continue;
+                        }
+
                         // Only add this peer if it's new (not already in our list) AND not recently removed
                         // This prevents both refreshing inactive peers and re-adding zombie peers
                         let is_new = peer_list.find_username_by_addr(&peer_addr).is_none();
-                        let grace_period = Duration::from_secs(REMOVED_PEER_GRACE_PERIOD);
+                        let grace_period = Duration::from_secs(timing().grace_period);
                         let was_recently_removed =
                             peer_list.was_recently_removed(&peer_addr, grace_period);
 
                         if is_new && !was_recently_removed {
-                            println!(
-                                "### Discovered new peer from heartbeat: {peer_name} ({peer_addr})"
+                            if peer_list.take_napping(&peer_addr) {
+                                log::debug!("Sleepy peer is back: {peer_name} ({peer_addr})");
+                            } else {
+                                say!(
+                                    "### Discovered new peer from heartbeat: {peer_name} ({})",
+                                    privacy::addr(peer_addr, None)
+                                );
+                            }
+                            peer_list.add_or_update_peer(peer_addr, peer_name.clone(), None);
+                            mirror::peer_event("discovered", peer_name, &peer_addr.to_string());
+                            lifecycle::record(
+                                PeerEvent::Discovered,
+                                peer_name,
+                                &peer_addr.to_string(),
                             );
-                            peer_list.add_or_update_peer(peer_addr, peer_name.clone());
+                            events::publish(Event::PeerDiscovered {
+                                username: peer_name.clone(),
+                                node_id: None,
+                                placeholder: true,
+                            });
                         } else if was_recently_removed {
                             log::debug!(
                                 "Ignoring recently removed peer: {peer_name} ({peer_addr})"
@@ -173,3 +493,173 @@ pub async fn handle_heartbeat_message(
 
     Ok(())
 }
+
+// Time since we sent round `seq`, minus how long the peer held on to it before echoing
+fn round_trip(seq: u32, held_ms: u32) -> Option<Duration> {
+    let rounds = SENT_ROUNDS.lock().ok()?;
+    let (_, sent_at) = rounds.iter().find(|(round, _)| *round == seq)?;
+    sent_at
+        .elapsed()
+        .checked_sub(Duration::from_millis(u64::from(held_ms)))
+}
+
+/// Handles a peer announcing that it's leaving, so it's removed without waiting for a timeout;
+/// only the peer itself can say so (`authentic`: from its host or signed with its key)
+pub async fn handle_goodbye_message(msg: &Message, peer_list: &SharedPeerList, authentic: bool) {
+    let Some(addr) = msg
+        .sender_addr
+        .as_ref()
+        .and_then(|addr_str| addr_str.parse::<SocketAddr>().ok())
+    else {
+        return;
+    };
+    if !authentic {
+        log::debug!("Ignoring goodbye for {addr} from someone else");
+        return;
+    }
+
+    let removed = {
+        let mut peer_list = peer_list.lock().await;
+        let removed = peer_list.remove_peer(&addr);
+        if !removed.is_empty() {
+            peer_list.record_left(addr);
+        }
+        removed
+    };
+    for peer in removed {
+        say!(
+            "### Peer left: {} ({})",
+            peer.username,
+            privacy::addr(peer.addr, peer.node_id.as_deref())
+        );
+        mirror::peer_event("left", &peer.username, &peer.addr.to_string());
+        lifecycle::record(PeerEvent::Left, &peer.username, &peer.addr.to_string());
+        events::publish(Event::PeerLeft);
+    }
+}
+
+/// Handles a peer changing its username with /nick, so its entry is updated in place;
+/// like a goodbye, only the peer itself can do that
+pub async fn handle_rename_message(msg: &Message, peer_list: &SharedPeerList, authentic: bool) {
+    let Some(addr) = msg
+        .sender_addr
+        .as_ref()
+        .and_then(|addr_str| addr_str.parse::<SocketAddr>().ok())
+    else {
+        return;
+    };
+    if !authentic {
+        log::debug!("Ignoring rename of {addr} from someone else");
+        return;
+    }
+    if msg.sender.is_empty() || msg.sender.contains(':') {
+        return;
+    }
+
+    let previous = peer_list.lock().await.rename(&addr, &msg.sender);
+    if let Some(previous) = previous
+        && previous != msg.sender
+    {
+        say!(
+            "### {previous} is now known as {} ({})",
+            msg.sender,
+            privacy::addr(addr, msg.node_id.as_deref())
+        );
+        mirror::peer_event("renamed", &msg.sender, &addr.to_string());
+        lifecycle::record(
+            PeerEvent::Renamed,
+            &format!("{previous} -> {}", msg.sender),
+            &addr.to_string(),
+        );
+    }
+}
+
+/// Tells every known peer about our new username
+pub async fn send_rename(
+    transport: &SharedTransport,
+    username: &str,
+    previous: &str,
+    local_addr: SocketAddr,
+    peer_list: &SharedPeerList,
+) {
+    let peers = peer_list.lock().await.get_peers();
+    let rename_msg = Message::new_rename(username.to_string(), previous.to_string(), local_addr);
+    for peer in peers {
+        if let Err(e) = transport.send_to(&rename_msg, &peer.addr.to_string()).await {
+            log::error!("Error sending rename to {}: {e}", peer.addr);
+        }
+    }
+}
+
+/// Tells every known peer we're leaving
+pub async fn send_goodbyes(
+    transport: &SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    peer_list: &SharedPeerList,
+) {
+    let peers = peer_list.lock().await.get_peers();
+    let goodbye_msg = Message::new_goodbye(username.to_string(), local_addr);
+    for peer in peers {
+        if let Err(e) = transport
+            .send_to(&goodbye_msg, &peer.addr.to_string())
+            .await
+        {
+            log::error!("Error sending goodbye to {}: {e}", peer.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerList;
+
+    fn every(interval: u64, timeout: u64) -> Timing {
+        Timing {
+            interval,
+            timeout,
+            ..Timing::default()
+        }
+    }
+
+    #[test]
+    fn timeouts_must_cover_two_and_a_half_intervals() {
+        assert!(Timing::default().validate().is_ok());
+        assert!(every(10, 25).validate().is_ok());
+        assert!(every(10, 24).validate().is_err());
+        assert!(every(1, 3).validate().is_ok());
+        assert!(every(1, 2).validate().is_err());
+    }
+
+    #[test]
+    fn heartbeat_intervals_are_bounded() {
+        assert!(every(0, 15).validate().is_err());
+        assert!(
+            every(MAX_HEARTBEAT_INTERVAL, MAX_HEARTBEAT_INTERVAL * 3)
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            every(MAX_HEARTBEAT_INTERVAL + 1, u64::MAX / 2)
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn peers_with_a_slower_interval_get_a_longer_timeout() {
+        let slow: SocketAddr = "198.51.100.1:10001".parse().unwrap();
+        let usual: SocketAddr = "198.51.100.2:10001".parse().unwrap();
+        let mut peer_list = PeerList::new();
+        peer_list.add_or_update_peer(slow, "slow".to_string(), None);
+        peer_list.add_or_update_peer(usual, "usual".to_string(), None);
+        // What a peer with --heartbeat-interval 60 tells us
+        peer_list.set_heartbeat_every(&slow, Some((1, 60)));
+
+        std::thread::sleep(Duration::from_millis(10));
+        let removed = peer_list.remove_stale_peers(Duration::ZERO, Duration::ZERO);
+        let removed: Vec<SocketAddr> = removed.iter().map(|peer| peer.addr).collect();
+        assert_eq!(removed, vec![usual]);
+    }
+}
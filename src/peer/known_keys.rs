@@ -0,0 +1,210 @@
+use crate::config;
+use crate::message::Message;
+use crate::peer::PeerList;
+use crate::ui::privacy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+
+const KNOWN_KEYS_FILE: &str = "known_keys.toml";
+
+/// The key each peer had when we first saw it, kept in ~/.config/pung/known_keys.toml
+/// and keyed by node ID (or username, for peers without one), and the key that first
+/// used each username, which owns it from then on
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct KnownKeysFile {
+    peers: BTreeMap<String, KnownKey>,
+    names: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KnownKey {
+    key: String,
+    username: String, // Just to tell entries apart when reading the file
+    verified: bool,   // Fingerprint compared with /verify
+}
+
+/// How a peer's key compares to the one we pinned for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    // First time we see the peer; its key is pinned now
+    New,
+    Known,
+    Verified,
+    // Differs from the pinned key; someone may be impersonating the peer
+    Changed,
+}
+
+static KNOWN: LazyLock<Mutex<KnownKeysFile>> = LazyLock::new(|| Mutex::new(load()));
+
+fn known_keys_file() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join(KNOWN_KEYS_FILE))
+}
+
+fn load() -> KnownKeysFile {
+    let Some(path) = known_keys_file() else {
+        return KnownKeysFile::default();
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => match toml::from_str::<KnownKeysFile>(&contents) {
+            Ok(file) => file,
+            Err(e) => {
+                say!("Warning: Could not parse {}: {e}", path.display());
+                KnownKeysFile::default()
+            }
+        },
+        Err(_) => KnownKeysFile::default(),
+    }
+}
+
+fn save(file: &KnownKeysFile) -> std::io::Result<()> {
+    let path = known_keys_file()
+        .ok_or_else(|| std::io::Error::other("could not determine the home directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let contents = toml::to_string(file).map_err(std::io::Error::other)?;
+    std::fs::write(&path, contents)
+}
+
+/// What the known keys are keyed by: the node ID, which survives renames, or the username
+pub fn peer_id<'a>(node_id: Option<&'a str>, username: &'a str) -> &'a str {
+    node_id.unwrap_or(username)
+}
+
+/// Compare a peer's key with the one pinned for it, pinning it if it's the first we see
+pub fn check(peer_id: &str, username: &str, key: &str) -> Trust {
+    let Ok(mut known) = KNOWN.lock() else {
+        return Trust::Known;
+    };
+    match known.peers.get(peer_id) {
+        Some(pinned) if pinned.key != key => Trust::Changed,
+        Some(pinned) if pinned.verified => Trust::Verified,
+        Some(_) => Trust::Known,
+        None => {
+            known.peers.insert(
+                peer_id.to_string(),
+                KnownKey {
+                    key: key.to_string(),
+                    username: username.to_string(),
+                    verified: false,
+                },
+            );
+            if let Err(e) = save(&known) {
+                log::error!("Could not save the known keys: {e}");
+            }
+            Trust::New
+        }
+    }
+}
+
+/// The key pinned for a peer, if we've seen it before
+pub fn pinned(peer_id: &str) -> Option<String> {
+    KNOWN
+        .lock()
+        .ok()
+        .and_then(|known| known.peers.get(peer_id).map(|pinned| pinned.key.clone()))
+}
+
+/// Mark a key as verified by the user, pinning it in place of any earlier one; the
+/// peer's username goes to it too
+pub fn verify(peer_id: &str, username: &str, key: &str) -> std::io::Result<()> {
+    let mut known = KNOWN
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    known.names.insert(username.to_string(), key.to_string());
+    known.peers.insert(
+        peer_id.to_string(),
+        KnownKey {
+            key: key.to_string(),
+            username: username.to_string(),
+            verified: true,
+        },
+    );
+    save(&known)
+}
+
+/// Give a username to the first key that uses it; says whether `key` owns the name
+fn claim_name(username: &str, key: &str) -> bool {
+    let Ok(mut known) = KNOWN.lock() else {
+        return true;
+    };
+    if let Some(owner) = known.names.get(username) {
+        return owner == key;
+    }
+    known.names.insert(username.to_string(), key.to_string());
+    if let Err(e) = save(&known) {
+        log::error!("Could not save the known keys: {e}");
+    }
+    true
+}
+
+/// The key that owns a username, if anyone used it yet
+pub fn name_owner(username: &str) -> Option<String> {
+    KNOWN
+        .lock()
+        .ok()
+        .and_then(|known| known.names.get(username).cloned())
+}
+
+/// Check the key a discovery or heartbeat carries before the peer gets it, flagging the
+/// peer and warning the first time it doesn't match the key pinned for it or its name; a
+/// key that doesn't match is only offered for /verify, never used. Unsigned peers using a
+/// name that belongs to a key are flagged too.
+pub fn check_peer(peer_list: &mut PeerList, addr: &SocketAddr, msg: &Message) {
+    let Some(key) = &msg.public_key else {
+        let name_taken = name_owner(&msg.sender).is_some();
+        if peer_list.set_name_taken(addr, name_taken) && name_taken {
+            say!(
+                "### WARNING: {} calls itself {} without signing, but that name belongs to a key! It's shown as {} (impostor?)",
+                privacy::addr(addr, msg.node_id.as_deref()),
+                msg.sender,
+                msg.sender
+            );
+        }
+        return;
+    };
+    let mut trusted = match check(
+        peer_id(msg.node_id.as_deref(), &msg.sender),
+        &msg.sender,
+        key,
+    ) {
+        Trust::Changed => {
+            if peer_list.flag_key_change(addr) {
+                say!(
+                    "### WARNING: {} ({}) has a different identity key than when you first saw it! Someone may be impersonating them; compare fingerprints with /verify {}",
+                    msg.sender,
+                    privacy::addr(addr, msg.node_id.as_deref()),
+                    msg.sender
+                );
+            }
+            false
+        }
+        Trust::Verified => {
+            peer_list.set_verified(addr, key);
+            true
+        }
+        Trust::New | Trust::Known => true,
+    };
+    // A key change was just reported; the name it claims is part of the same story
+    if trusted && !peer_list.has_key_changed(addr) {
+        let owns_name = claim_name(&msg.sender, key);
+        if peer_list.set_name_taken(addr, !owns_name) && !owns_name {
+            say!(
+                "### WARNING: {} calls itself {}, but that name belongs to another key! It's shown as {} (impostor?) until you /verify it",
+                privacy::addr(addr, msg.node_id.as_deref()),
+                msg.sender,
+                msg.sender
+            );
+        }
+        trusted = owns_name;
+    }
+    if trusted {
+        peer_list.set_public_key(addr, key);
+    } else {
+        peer_list.offer_public_key(addr, key);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::ui::privacy;
+use crate::utils;
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex};
+
+// Oldest events are dropped beyond this
+const MAX_EVENTS: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    Discovered,
+    // Came back after timing out or leaving
+    Rejoined,
+    Renamed,
+    TimedOut,
+    Left,
+    Blocked,
+    // Dropped with /forget
+    Forgotten,
+}
+
+impl PeerEvent {
+    fn name(self) -> &'static str {
+        match self {
+            PeerEvent::Discovered => "discovered",
+            PeerEvent::Rejoined => "rejoined",
+            PeerEvent::Renamed => "renamed",
+            PeerEvent::TimedOut => "timed out",
+            PeerEvent::Left => "left",
+            PeerEvent::Blocked => "blocked",
+            PeerEvent::Forgotten => "forgotten",
+        }
+    }
+}
+
+struct Entry {
+    timestamp: i64,
+    event: PeerEvent,
+    username: String,
+    addr: String,
+}
+
+/// What happened to which peer when, for /events
+static EVENTS: LazyLock<Mutex<VecDeque<Entry>>> = LazyLock::new(|| Mutex::new(VecDeque::new()));
+
+/// Record a peer event; a discovered peer whose last event was leaving or timing out
+/// is recorded as rejoined
+pub fn record(event: PeerEvent, username: &str, addr: &str) {
+    let Ok(mut events) = EVENTS.lock() else {
+        return;
+    };
+    let event = match event {
+        PeerEvent::Discovered
+            if events
+                .iter()
+                .rev()
+                .find(|entry| entry.username == username)
+                .is_some_and(|entry| {
+                    matches!(
+                        entry.event,
+                        PeerEvent::TimedOut | PeerEvent::Left | PeerEvent::Forgotten
+                    )
+                }) =>
+        {
+            PeerEvent::Rejoined
+        }
+        event => event,
+    };
+    events.push_back(Entry {
+        timestamp: chrono::Utc::now().timestamp(),
+        event,
+        username: username.to_string(),
+        addr: addr.to_string(),
+    });
+    if events.len() > MAX_EVENTS {
+        events.pop_front();
+    }
+}
+
+/// The latest `count` events, oldest first
+pub fn recent(count: usize) -> Vec<String> {
+    let Ok(events) = EVENTS.lock() else {
+        return Vec::new();
+    };
+    events
+        .iter()
+        .skip(events.len().saturating_sub(count))
+        .map(|entry| {
+            // Placeholder names carry the address too, which privacy mode hides as well
+            let addr = privacy::addr(&entry.addr, None);
+            let username = if entry.username == format!("peer@{}", entry.addr) {
+                format!("peer@{addr}")
+            } else {
+                entry.username.clone()
+            };
+            format!(
+                "{} {:10} {username} ({addr})",
+                utils::display_time_from_timestamp(entry.timestamp),
+                entry.event.name(),
+            )
+        })
+        .collect()
+}
@@ -1,6 +1,21 @@
+pub mod anti_entropy;
+pub mod blocklist;
+pub mod cache;
 pub mod discovery;
+pub mod dnssd;
+pub mod groups;
 pub mod heartbeats;
+pub mod known_keys;
+pub mod lifecycle;
+pub mod nick;
+pub mod node_id;
 pub mod peer_list;
+pub mod pex;
+pub mod rendezvous;
+pub mod sas;
+pub mod scan;
+pub mod ssdp;
+pub mod static_peers;
 
 // Re-export the peer list types for backward compatibility
 pub use peer_list::{PeerList, SharedPeerList};
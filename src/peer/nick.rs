@@ -0,0 +1,15 @@
+use std::sync::{LazyLock, Mutex};
+
+// Set by /nick. Background tasks were handed the startup username, so messages pick
+// the new one up here when they're built.
+static NICK: LazyLock<Mutex<Option<String>>> = LazyLock::new(|| Mutex::new(None));
+
+pub fn set(username: String) {
+    if let Ok(mut nick) = NICK.lock() {
+        *nick = Some(username);
+    }
+}
+
+pub fn current() -> Option<String> {
+    NICK.lock().ok().and_then(|nick| nick.clone())
+}
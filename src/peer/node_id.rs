@@ -0,0 +1,27 @@
+use crate::net::identity;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+// ID of this instance, derived from our identity key and the port we receive on: it
+// survives restarts and renames, tells apart instances sharing a host (and so a key),
+// and nobody can claim it without signing with our key; every message we send carries it
+static NODE_ID: OnceLock<String> = OnceLock::new();
+
+/// Derive our node ID for the port we receive on; to be called before sending anything
+pub fn init(port: u16) {
+    let _ = NODE_ID.set(derive(&identity::public_key(), port));
+}
+
+/// The node ID of the peer with this key that receives on this port
+pub fn derive(public_key: &str, port: u16) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"pung node id")
+        .chain_update(public_key)
+        .chain_update(port.to_be_bytes())
+        .finalize();
+    hex::encode(&digest[..12])
+}
+
+pub fn current() -> &'static str {
+    NODE_ID.get_or_init(|| derive(&identity::public_key(), 0))
+}
@@ -1,5 +1,8 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use crate::features::Feature;
+use crate::message::Presence;
+use crate::ui::privacy;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
@@ -7,19 +10,212 @@ use tokio::sync::Mutex;
 // Peer information structure
 #[derive(Debug, Clone)]
 pub struct PeerInfo {
+    // Both can change while the peer stays the same, when it shares its node ID
     pub addr: SocketAddr,
     pub username: String,
+    pub node_id: Option<String>,
     pub last_seen: Instant,
+    // Where the peer's packets come from, when that's another IP than it advertises and
+    // it isn't on one of our networks, i.e. it sits behind a NAT whose mapping we should
+    // keep open
+    pub nat_source: Option<SocketAddr>,
+    // TCP side-channel port advertised by the peer, if any
+    pub tcp_port: Option<u16>,
+    // Sleepy peers (e.g. laptops that suspend often) get a longer timeout and no timeout notices,
+    // either because they advertise it or because the user marked them with /sleepy
+    pub is_sleepy: bool,
+    // Heartbeat sequence tracking, to estimate packet loss on the link
+    pub last_heartbeat_seq: Option<u32>,
+    pub heartbeats_expected: u32,
+    pub heartbeats_received: u32,
+    // (rounds, seconds) between the peer's heartbeats to us, if it samples or changed the interval
+    pub heartbeat_every: Option<(u32, u32)>,
+    // Features the peer advertised; None for peers that predate capability advertising
+    pub capabilities: Option<Vec<String>>,
+    // Name the user reached this peer's host by (e.g. via /connect), if any
+    pub hostname: Option<String>,
+    // We accepted unencrypted traffic from this peer (allow_plaintext)
+    pub is_plaintext: bool,
+    // Added by /connect and not confirmed by the peer yet
+    pub is_provisional: bool,
+    pub first_seen: Instant,
+    // When the peer's latest heartbeat arrived, echoed back so it can measure its RTT
+    pub last_heartbeat_at: Option<Instant>,
+    // Round-trip time measured from the peer echoing our heartbeats
+    pub rtt: Option<Duration>,
+    // Smoothed gap between consecutive heartbeats and how much it wobbles
+    pub heartbeat_gap: Option<Duration>,
+    pub heartbeat_jitter: Option<Duration>,
+    // Link quality from loss, RTT and jitter; None until there's enough to judge
+    pub health: Option<Health>,
+    // Protocol versions the peer speaks, as advertised
+    pub protocol_range: Option<(u8, u8)>,
+    // Room the peer joined with --room
+    pub room: Option<String>,
+    // pung release the peer runs
+    pub version: Option<String>,
+    // Ed25519 key the peer signs its messages with
+    pub public_key: Option<String>,
+    // Key the peer presented that clashes with the one pinned for it or its name; it's
+    // not used until the user settles it with /verify
+    pub offered_key: Option<String>,
+    // Ephemeral X25519 key the peer advertised last, which content for it is sealed to
+    // (with public_key); it replaces it every few minutes
+    pub e2e_key: Option<String>,
+    // When the peer first advertised e2e_key, i.e. when it last rotated it
+    pub e2e_key_since: Option<Instant>,
+    // The user compared fingerprints with /verify
+    pub is_verified: bool,
+    // The key differs from the one pinned when we first saw the peer
+    pub key_changed: bool,
+    // The username the peer goes by belongs to another key
+    pub name_taken: bool,
+    pub chats_received: u32,
+    // Last name the peer's chat claimed, if it differs from the one we know it by
+    pub claimed_username: Option<String>,
+    // Availability from the peer's heartbeats; None for peers that don't share it
+    pub presence: Option<Presence>,
+}
+
+/// The name to share for a peer with others: none (an empty one) for a placeholder, whose
+/// made-up name only means something to us
+pub fn shared_name(username: &str, addr: SocketAddr) -> String {
+    if username == format!("peer@{addr}") {
+        String::new()
+    } else {
+        username.to_string()
+    }
+}
+
+// Upper bound on the peer list, so gossip full of fake entries can't exhaust memory
+// or multiply our heartbeats
+pub const DEFAULT_MAX_PEERS: usize = 256;
+
+// How many peer list changes are kept for incremental exchange (PEX); peers that are
+// further behind get the full list instead
+const CHANGE_LOG_SIZE: usize = 256;
+
+/// A change to the peer list, as shared with other peers
+#[derive(Debug, Clone)]
+pub enum PeerChange {
+    // (username, addr)
+    Added(String, SocketAddr),
+    // The peer said goodbye; timeouts aren't shared, the link may only be down for us
+    Left(SocketAddr),
+}
+
+// Once this many heartbeats are expected, the counters are halved so old loss fades out
+const LOSS_WINDOW: u32 = 100;
+
+// Thresholds of the health score; any one of them is enough to downgrade a peer
+const DEGRADED_LOSS: u32 = 5; // percent
+const FAILING_LOSS: u32 = 20; // percent
+const DEGRADED_RTT: Duration = Duration::from_millis(150);
+const FAILING_RTT: Duration = Duration::from_millis(500);
+const DEGRADED_JITTER: Duration = Duration::from_secs(1);
+const FAILING_JITTER: Duration = Duration::from_secs(3);
+
+/// How healthy the link to a peer looks, as shown in /peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Good,
+    Degraded,
+    Failing,
+}
+
+impl Health {
+    pub fn name(self) -> &'static str {
+        match self {
+            Health::Good => "good",
+            Health::Degraded => "degraded",
+            Health::Failing => "failing",
+        }
+    }
+}
+
+impl PeerInfo {
+    // Whether the peer has a feature active; peers that predate capability advertising
+    // are assumed to have everything
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_none_or(|capabilities| capabilities.iter().any(|name| name == feature.name()))
+    }
+
+    // The worst of what loss, RTT and heartbeat jitter say about the link
+    fn assess_health(&self) -> Option<Health> {
+        let loss = self.loss_percent()?;
+        let rtt = self.rtt.unwrap_or_default();
+        let jitter = self.heartbeat_jitter.unwrap_or_default();
+        if loss >= FAILING_LOSS || rtt >= FAILING_RTT || jitter >= FAILING_JITTER {
+            Some(Health::Failing)
+        } else if loss >= DEGRADED_LOSS || rtt >= DEGRADED_RTT || jitter >= DEGRADED_JITTER {
+            Some(Health::Degraded)
+        } else {
+            Some(Health::Good)
+        }
+    }
+
+    // Fold the gap since the previous heartbeat into the smoothed gap and jitter
+    fn record_heartbeat_gap(&mut self, gap: Duration) {
+        let Some(average) = self.heartbeat_gap else {
+            self.heartbeat_gap = Some(gap);
+            return;
+        };
+        let deviation = gap.abs_diff(average);
+        self.heartbeat_gap = Some(average * 7 / 8 + gap / 8);
+        self.heartbeat_jitter = Some(match self.heartbeat_jitter {
+            Some(jitter) => jitter * 7 / 8 + deviation / 8,
+            None => deviation,
+        });
+    }
+
+    pub fn is_behind_nat(&self) -> bool {
+        self.nat_source.is_some()
+    }
+
+    // Stand-in for a peer we only heard of (from /connect or gossip) and that hasn't
+    // told us its name yet
+    pub fn is_placeholder(&self) -> bool {
+        self.is_provisional || self.username == format!("peer@{}", self.addr)
+    }
+
+    // The key /verify and /sas compare: one the peer offered instead of its pinned key,
+    // otherwise the one it signs with
+    pub fn key_to_verify(&self) -> Option<&String> {
+        self.offered_key.as_ref().or(self.public_key.as_ref())
+    }
+
+    // Percentage of heartbeats that never arrived, once there's something to go on
+    pub fn loss_percent(&self) -> Option<u32> {
+        if self.heartbeats_expected < 2 {
+            return None;
+        }
+        let lost = self
+            .heartbeats_expected
+            .saturating_sub(self.heartbeats_received);
+        Some(lost * 100 / self.heartbeats_expected)
+    }
 }
 
 // PeerList to track all known peers
 #[derive(Debug, Clone)]
 pub struct PeerList {
-    // Use a combination of username and address as the key to prevent username conflicts
+    // Keyed by node ID; peers that predate node IDs by username and address
     peers: HashMap<String, PeerInfo>,
     // Track recently removed peers to prevent zombie peers from being re-added
-    // The key is the socket address as a string, and the value is the time when the peer was removed
-    recently_removed: HashMap<String, Instant>,
+    // Keyed like `peers`, with the address the peer had and the time when it was removed
+    recently_removed: HashMap<String, (SocketAddr, Instant)>,
+    // Usernames the user marked as sleepy; kept across removals so the mark survives a nap
+    sleepy_usernames: HashSet<String>,
+    // Addresses of sleepy peers that timed out, so their return can be announced quietly
+    napping: HashSet<String>,
+    // Hostnames the user contacted, by resolved IP, so peers on that host can be labelled
+    hostnames: HashMap<IpAddr, String>,
+    // Bumped with every shared change; the log holds the changes with their generation
+    generation: u64,
+    changes: VecDeque<(u64, PeerChange)>,
+    max_peers: usize,
 }
 
 impl PeerList {
@@ -27,15 +223,30 @@ impl PeerList {
         PeerList {
             peers: HashMap::new(),
             recently_removed: HashMap::new(),
+            sleepy_usernames: HashSet::new(),
+            napping: HashSet::new(),
+            hostnames: HashMap::new(),
+            generation: 0,
+            changes: VecDeque::new(),
+            max_peers: DEFAULT_MAX_PEERS,
         }
     }
 
-    // Generate a unique key for a peer based on username and address
-    fn generate_peer_key(username: &str, addr: &SocketAddr) -> String {
-        format!("{username}@{addr}")
+    // Generate a unique key for a peer: its node ID, or username and address for peers
+    // that don't share one
+    fn generate_peer_key(username: &str, addr: &SocketAddr, node_id: Option<&str>) -> String {
+        match node_id {
+            Some(node_id) => node_id.to_string(),
+            None => format!("{username}@{addr}"),
+        }
     }
 
-    pub fn add_or_update_peer(&mut self, addr: SocketAddr, username: String) {
+    pub fn add_or_update_peer(
+        &mut self,
+        addr: SocketAddr,
+        username: String,
+        node_id: Option<String>,
+    ) {
         // If username is empty or just an IP address, generate a better name
         let username = if username.is_empty() || username.contains(':') {
             format!("anonymous@{addr}")
@@ -55,23 +266,177 @@ impl PeerList {
         }
 
         // Generate a unique key for this peer
-        let key = Self::generate_peer_key(&username, &addr);
+        let key = Self::generate_peer_key(&username, &addr, node_id.as_deref());
+
+        // Entries for this address from before we learned the node ID (placeholders, peers
+        // from gossip) are the same peer; the first one becomes its entry if it has none yet
+        if node_id.is_some() {
+            let unidentified: Vec<String> = self
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.addr == addr && peer.node_id.is_none())
+                .map(|(key, _)| key.clone())
+                .collect();
+            for unidentified_key in unidentified {
+                if let Some(mut peer) = self.peers.remove(&unidentified_key)
+                    && !self.peers.contains_key(&key)
+                {
+                    peer.node_id = node_id.clone();
+                    peer.is_provisional = false;
+                    self.peers.insert(key.clone(), peer);
+                }
+            }
+        }
 
-        // Check if we already have this exact peer (by username and address)
+        // Check if we already have this peer
         if let Some(existing_peer) = self.peers.get_mut(&key) {
-            // Just update the last_seen time
             existing_peer.last_seen = Instant::now();
+            // Same node under a new address or name (restart, /nick): update it in place
+            if existing_peer.addr != addr || existing_peer.username != username {
+                log::debug!(
+                    "Peer {} ({}) is now {username} ({addr})",
+                    existing_peer.username,
+                    existing_peer.addr
+                );
+                existing_peer.addr = addr;
+                existing_peer.username = username.clone();
+                existing_peer.hostname = self.hostnames.get(&addr.ip()).cloned();
+                self.record_change(PeerChange::Added(username, addr));
+            }
         } else {
+            if self.peers.len() >= self.max_peers {
+                self.evict_one();
+            }
             // Add the new peer (do NOT merge or remove by address only)
+            self.record_change(PeerChange::Added(username.clone(), addr));
+            let is_sleepy = self.sleepy_usernames.contains(&username);
+            let hostname = self.hostnames.get(&addr.ip()).cloned();
             self.peers.insert(
                 key,
                 PeerInfo {
                     addr,
                     username,
+                    node_id,
                     last_seen: Instant::now(),
+                    nat_source: None,
+                    tcp_port: None,
+                    is_sleepy,
+                    last_heartbeat_seq: None,
+                    heartbeats_expected: 0,
+                    heartbeats_received: 0,
+                    heartbeat_every: None,
+                    capabilities: None,
+                    hostname,
+                    is_plaintext: false,
+                    is_provisional: false,
+                    first_seen: Instant::now(),
+                    last_heartbeat_at: None,
+                    rtt: None,
+                    heartbeat_gap: None,
+                    heartbeat_jitter: None,
+                    health: None,
+                    protocol_range: None,
+                    room: None,
+                    version: None,
+                    public_key: None,
+                    offered_key: None,
+                    e2e_key: None,
+                    e2e_key_since: None,
+                    is_verified: false,
+                    key_changed: false,
+                    name_taken: false,
+                    chats_received: 0,
+                    claimed_username: None,
+                    presence: None,
                 },
             );
+            // The host answered, so its placeholder from /connect is no longer needed
+            self.peers
+                .retain(|_, peer| !(peer.is_provisional && peer.addr.ip() == addr.ip()));
+        }
+    }
+
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.max_peers = max_peers.max(1);
+    }
+
+    // Make room for a new peer: drop a placeholder (from /connect or gossip) if there is
+    // one, then peers that never identified themselves with a node ID or key, and only
+    // then the peer we haven't heard from the longest, so a gossip flood can't push out
+    // real peers
+    fn evict_one(&mut self) {
+        let victim = self
+            .peers
+            .iter()
+            .min_by_key(|(_, peer)| {
+                (
+                    !peer.is_placeholder(),
+                    peer.node_id.is_some() || peer.public_key.is_some(),
+                    peer.last_seen,
+                )
+            })
+            .map(|(key, _)| key.clone());
+        if let Some((key, peer)) = victim.and_then(|key| self.peers.remove_entry(&key)) {
+            log::debug!(
+                "Peer list full ({} peers), dropped {} ({})",
+                self.max_peers,
+                peer.username,
+                peer.addr
+            );
+            self.recently_removed
+                .insert(key, (peer.addr, Instant::now()));
+        }
+    }
+
+    // Add a placeholder for an address the user contacted directly, until the peer answers
+    pub fn add_provisional(&mut self, addr: SocketAddr) {
+        if self.find_username_by_addr(&addr).is_some() {
+            return;
         }
+        if self.peers.len() >= self.max_peers {
+            self.evict_one();
+        }
+        let username = format!("peer@{addr}");
+        let hostname = self.hostnames.get(&addr.ip()).cloned();
+        self.peers.insert(
+            Self::generate_peer_key(&username, &addr, None),
+            PeerInfo {
+                addr,
+                username,
+                node_id: None,
+                last_seen: Instant::now(),
+                nat_source: None,
+                tcp_port: None,
+                is_sleepy: false,
+                last_heartbeat_seq: None,
+                heartbeats_expected: 0,
+                heartbeats_received: 0,
+                heartbeat_every: None,
+                capabilities: None,
+                hostname,
+                is_plaintext: false,
+                is_provisional: true,
+                first_seen: Instant::now(),
+                last_heartbeat_at: None,
+                rtt: None,
+                heartbeat_gap: None,
+                heartbeat_jitter: None,
+                health: None,
+                protocol_range: None,
+                room: None,
+                version: None,
+                public_key: None,
+                offered_key: None,
+                e2e_key: None,
+                e2e_key_since: None,
+                is_verified: false,
+                key_changed: false,
+                name_taken: false,
+                chats_received: 0,
+                claimed_username: None,
+                presence: None,
+            },
+        );
     }
 
     pub fn get_peers(&self) -> Vec<PeerInfo> {
@@ -89,42 +454,549 @@ impl PeerList {
         None
     }
 
-    pub fn remove_stale_peers(&mut self, timeout: Duration) -> Vec<String> {
+    // Whether we only know of the peer at this address from others: a placeholder, or a
+    // name gossip gave us without a node ID
+    pub fn is_hearsay(&self, addr: &SocketAddr) -> bool {
+        self.peers
+            .values()
+            .find(|peer| peer.addr == *addr)
+            .is_some_and(|peer| peer.is_placeholder() || peer.node_id.is_none())
+    }
+
+    // Refresh a known peer's last_seen; placeholders stay pending until the peer answers
+    pub fn mark_alive(&mut self, addr: &SocketAddr) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr && !peer.is_provisional {
+                peer.last_seen = Instant::now();
+            }
+        }
+    }
+
+    // How each peer is shown: its username, or name#1, name#2... when several peers use
+    // the same name, numbered in the order we first saw them, and "name (impostor?)" when
+    // the name belongs to another key
+    pub fn display_names(&self) -> HashMap<SocketAddr, String> {
+        let mut by_name: HashMap<&str, Vec<&PeerInfo>> = HashMap::new();
+        for peer in self.peers.values() {
+            by_name.entry(&peer.username).or_default().push(peer);
+        }
+        let mut names = HashMap::new();
+        // Peers using a name that belongs to another key never get to show it plainly
+        by_name.retain(|_, namesakes| {
+            namesakes.retain(|peer| {
+                if peer.name_taken {
+                    names.insert(peer.addr, format!("{} (impostor?)", peer.username));
+                }
+                !peer.name_taken
+            });
+            !namesakes.is_empty()
+        });
+        for (username, mut namesakes) in by_name {
+            if namesakes.len() == 1 {
+                let peer = namesakes[0];
+                // Placeholder names of peers we haven't heard from carry their address
+                let name = if peer.is_placeholder() {
+                    format!("peer@{}", privacy::addr(peer.addr, peer.node_id.as_deref()))
+                } else {
+                    username.to_string()
+                };
+                names.insert(peer.addr, name);
+                continue;
+            }
+            namesakes.sort_by_key(|peer| (peer.first_seen, peer.addr));
+            for (i, peer) in namesakes.iter().enumerate() {
+                names.insert(peer.addr, format!("{username}#{}", i + 1));
+            }
+        }
+        names
+    }
+
+    // Peers matching what the user typed: a username, a display name (name#2) or an address
+    pub fn find_matching(&self, target: &str) -> Vec<PeerInfo> {
+        let display_names = self.display_names();
+        self.get_peers()
+            .into_iter()
+            .filter(|peer| {
+                peer.username == target
+                    || peer.addr.to_string() == target
+                    || display_names
+                        .get(&peer.addr)
+                        .is_some_and(|name| name == target)
+            })
+            .collect()
+    }
+
+    pub fn count_namesakes(&self, username: &str) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.username == username)
+            .count()
+    }
+
+    // Record where a peer's packets come from, if that looks like a NAT
+    pub fn set_nat_source(&mut self, addr: &SocketAddr, source: Option<SocketAddr>) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.nat_source = source;
+            }
+        }
+    }
+
+    // Record the TCP side-channel port a peer advertised
+    pub fn set_tcp_port(&mut self, addr: &SocketAddr, tcp_port: Option<u16>) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.tcp_port = tcp_port;
+            }
+        }
+    }
+
+    // Remember the name the user used for a host, labelling current and future peers on it
+    pub fn remember_hostname(&mut self, ip: IpAddr, hostname: String) {
+        for peer in self.peers.values_mut() {
+            if peer.addr.ip() == ip {
+                peer.hostname = Some(hostname.clone());
+            }
+        }
+        self.hostnames.insert(ip, hostname);
+    }
+
+    // Record whether a peer's traffic arrives unencrypted
+    pub fn set_plaintext(&mut self, addr: &SocketAddr, is_plaintext: bool) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.is_plaintext = is_plaintext;
+            }
+        }
+    }
+
+    // Record the features a peer advertised
+    pub fn set_capabilities(&mut self, addr: &SocketAddr, capabilities: Option<Vec<String>>) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.capabilities = capabilities.clone();
+            }
+        }
+    }
+
+    // Record the release, protocol versions and room a peer advertised
+    pub fn set_advertised(
+        &mut self,
+        addr: &SocketAddr,
+        version: Option<String>,
+        protocol_range: Option<(u8, u8)>,
+        room: Option<String>,
+    ) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.version = version.clone();
+                peer.protocol_range = protocol_range;
+                peer.room = room.clone();
+            }
+        }
+    }
+
+    // Count a chat from a peer, noting the name it claimed if that's not the one we know
+    pub fn record_chat(&mut self, addr: &SocketAddr, claimed_username: &str) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.chats_received += 1;
+                if peer.username != claimed_username {
+                    peer.claimed_username = Some(claimed_username.to_string());
+                }
+            }
+        }
+    }
+
+    pub fn set_public_key(&mut self, addr: &SocketAddr, public_key: &str) {
+        for peer in self.peers.values_mut() {
+            // A peer's key is only ever set once; another key showing up for it is flagged
+            // (known_keys::check_peer), never silently adopted
+            if peer.addr == *addr && peer.public_key.is_none() {
+                peer.public_key = Some(public_key.to_string());
+            }
+        }
+    }
+
+    // The key the peer at this address signs with, if we know it
+    pub fn public_key_of(&self, addr: &SocketAddr) -> Option<String> {
+        self.peers
+            .values()
+            .find(|peer| peer.addr == *addr)
+            .and_then(|peer| peer.public_key.clone())
+    }
+
+    // Hold on to a key we didn't take, so the user can still /verify it
+    pub fn offer_public_key(&mut self, addr: &SocketAddr, public_key: &str) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr && peer.public_key.as_deref() != Some(public_key) {
+                peer.offered_key = Some(public_key.to_string());
+            }
+        }
+    }
+
+    // The keys of the peers at this address or with this node ID, which whoever sends as
+    // them has to sign with
+    pub fn keys_of(&self, addr: Option<&SocketAddr>, node_id: Option<&str>) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .peers
+            .values()
+            .filter(|peer| {
+                addr.is_some_and(|addr| peer.addr == *addr)
+                    || node_id.is_some_and(|node_id| peer.node_id.as_deref() == Some(node_id))
+            })
+            .filter_map(|peer| peer.public_key.clone().or(peer.offered_key.clone()))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    // Whether whoever signs with `key` speaks for the peers at this address: they all
+    // sign with it, apart from placeholders that haven't told us any key yet
+    pub fn speaks_for(&self, addr: &SocketAddr, key: &str) -> bool {
+        self.peers
+            .values()
+            .filter(|peer| peer.addr == *addr)
+            .all(|peer| match &peer.public_key {
+                Some(public_key) => public_key == key,
+                None => peer.is_placeholder(),
+            })
+    }
+
+    // The user checked the peer's key, which also settles a key change: the key they
+    // checked is the one used from now on
+    pub fn set_verified(&mut self, addr: &SocketAddr, public_key: &str) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                if peer.public_key.as_deref() != Some(public_key) {
+                    peer.public_key = Some(public_key.to_string());
+                    peer.e2e_key = None;
+                }
+                peer.offered_key = None;
+                peer.is_verified = true;
+                peer.key_changed = false;
+                peer.name_taken = false;
+            }
+        }
+    }
+
+    // Flag a peer whose key differs from the pinned one; false if it already was
+    pub fn flag_key_change(&mut self, addr: &SocketAddr) -> bool {
+        let mut flagged = false;
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr && !peer.key_changed {
+                peer.key_changed = true;
+                peer.is_verified = false;
+                flagged = true;
+            }
+        }
+        flagged
+    }
+
+    pub fn has_key_changed(&self, addr: &SocketAddr) -> bool {
+        self.peers
+            .values()
+            .any(|peer| peer.addr == *addr && peer.key_changed)
+    }
+
+    // Record whether the peer's username belongs to another key; true if that changed
+    pub fn set_name_taken(&mut self, addr: &SocketAddr, name_taken: bool) -> bool {
+        let mut changed = false;
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr && peer.name_taken != name_taken {
+                peer.name_taken = name_taken;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Take the ephemeral key a peer advertised, from a message signed with the key it
+    // signs with; one signed with any other key could be anyone's
+    pub fn set_e2e_key(
+        &mut self,
+        addr: &SocketAddr,
+        signing_key: Option<&str>,
+        e2e_key: Option<&str>,
+    ) {
+        let (Some(signing_key), Some(e2e_key)) = (signing_key, e2e_key) else {
+            return;
+        };
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr
+                && peer.public_key.as_deref() == Some(signing_key)
+                && peer.e2e_key.as_deref() != Some(e2e_key)
+            {
+                peer.e2e_key = Some(e2e_key.to_string());
+                peer.e2e_key_since = Some(Instant::now());
+            }
+        }
+    }
+
+    pub fn set_presence(&mut self, addr: &SocketAddr, presence: Option<Presence>) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.presence = presence.clone();
+            }
+        }
+    }
+
+    pub fn record_rtt(&mut self, addr: &SocketAddr, rtt: Duration) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.rtt = Some(rtt);
+                peer.health = peer.assess_health();
+            }
+        }
+    }
+
+    // Record whether a peer advertises itself as sleepy
+    pub fn set_sleepy(&mut self, addr: &SocketAddr, advertised: bool) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.is_sleepy = advertised || self.sleepy_usernames.contains(&peer.username);
+            }
+        }
+    }
+
+    pub fn set_heartbeat_every(&mut self, addr: &SocketAddr, every: Option<(u32, u32)>) {
+        for peer in self.peers.values_mut() {
+            if peer.addr == *addr {
+                peer.heartbeat_every = every;
+            }
+        }
+    }
+
+    // Account for a heartbeat; gaps in the sequence count as lost heartbeats
+    pub fn record_heartbeat_seq(&mut self, addr: &SocketAddr, seq: u32) {
+        for peer in self.peers.values_mut() {
+            if peer.addr != *addr {
+                continue;
+            }
+            // Peers that only send us every few rounds skip those sequence numbers
+            let stride = peer.heartbeat_every.map_or(1, |(rounds, _)| rounds.max(1));
+            match peer.last_heartbeat_seq {
+                // In order (possibly after a gap)
+                Some(last) if seq > last => {
+                    // Only back-to-back heartbeats say something about their regularity
+                    if seq - last == stride
+                        && let Some(previous) = peer.last_heartbeat_at
+                    {
+                        peer.record_heartbeat_gap(previous.elapsed());
+                    }
+                    peer.heartbeats_expected += (seq - last).div_ceil(stride);
+                    peer.heartbeats_received += 1;
+                    peer.last_heartbeat_seq = Some(seq);
+                    peer.last_heartbeat_at = Some(Instant::now());
+                }
+                // Late or duplicated heartbeat, already counted as lost
+                Some(last) if last - seq < LOSS_WINDOW => {}
+                // First heartbeat, or the peer restarted and its sequence began again
+                _ => {
+                    peer.heartbeats_expected = 1;
+                    peer.heartbeats_received = 1;
+                    peer.last_heartbeat_seq = Some(seq);
+                    peer.last_heartbeat_at = Some(Instant::now());
+                }
+            }
+            if peer.heartbeats_expected > LOSS_WINDOW {
+                peer.heartbeats_expected /= 2;
+                peer.heartbeats_received /= 2;
+            }
+            peer.health = peer.assess_health();
+        }
+    }
+
+    // Mark (or unmark) every peer with the given username as sleepy
+    // Returns the number of currently known peers affected
+    pub fn mark_sleepy(&mut self, username: &str, sleepy: bool) -> usize {
+        if sleepy {
+            self.sleepy_usernames.insert(username.to_string());
+        } else {
+            self.sleepy_usernames.remove(username);
+        }
+
+        let mut affected = 0;
+        for peer in self.peers.values_mut() {
+            if peer.username == username {
+                peer.is_sleepy = sleepy;
+                affected += 1;
+            }
+        }
+        affected
+    }
+
+    pub fn is_marked_sleepy(&self, username: &str) -> bool {
+        self.sleepy_usernames.contains(username)
+    }
+
+    // Give the peer at this address a new username, keeping everything else we know about it
+    // Returns the username it had, or None if there's no such peer
+    pub fn rename(&mut self, addr: &SocketAddr, username: &str) -> Option<String> {
+        let key = self
+            .peers
+            .iter()
+            .find(|(_, peer)| peer.addr == *addr && !peer.is_provisional)
+            .map(|(key, _)| key.clone())?;
+        let mut peer = self.peers.remove(&key)?;
+        let previous = std::mem::replace(&mut peer.username, username.to_string());
+        peer.is_sleepy = peer.is_sleepy || self.sleepy_usernames.contains(username);
+        self.peers.insert(
+            Self::generate_peer_key(username, addr, peer.node_id.as_deref()),
+            peer,
+        );
+        self.record_change(PeerChange::Added(username.to_string(), *addr));
+        Some(previous)
+    }
+
+    // Check whether a sleepy peer at this address timed out earlier, forgetting it if so
+    pub fn take_napping(&mut self, addr: &SocketAddr) -> bool {
+        self.napping.remove(&addr.to_string())
+    }
+
+    // Remove every peer at this address right away (e.g. it said goodbye)
+    pub fn remove_peer(&mut self, addr: &SocketAddr) -> Vec<PeerInfo> {
+        let keys: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|(_, info)| info.addr == *addr)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut removed = Vec::with_capacity(keys.len());
+        for key in &keys {
+            if let Some(info) = self.peers.remove(key) {
+                // Heartbeats from others may still list it, don't let them bring it back
+                self.recently_removed
+                    .insert(key.clone(), (info.addr, Instant::now()));
+                removed.push(info);
+            }
+        }
+        removed
+    }
+
+    // Drop peers by username or address ("all" for every peer), as if they timed out, so
+    // gossip doesn't bring them right back
+    pub fn forget(&mut self, target: &str) -> Vec<PeerInfo> {
         let now = Instant::now();
-        let stale_peers: Vec<(String, SocketAddr)> = self
+        let keys: Vec<String> = self
             .peers
             .iter()
-            .filter(|(_, info)| now.duration_since(info.last_seen) > timeout)
-            .map(|(username, info)| (username.clone(), info.addr))
+            .filter(|(_, info)| {
+                target == "all" || info.username == target || info.addr.to_string() == target
+            })
+            .map(|(key, _)| key.clone())
             .collect();
 
-        for (username, addr) in &stale_peers {
-            self.peers.remove(username);
-            // Add to recently removed peers
-            self.recently_removed.insert(addr.to_string(), now);
+        let mut forgotten = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(info) = self.peers.remove(&key) {
+                self.recently_removed.insert(key, (info.addr, now));
+                forgotten.push(info);
+            }
         }
+        forgotten
+    }
 
-        // Return just the usernames for backward compatibility
-        stale_peers
-            .into_iter()
-            .map(|(username, _)| username)
-            .collect()
+    fn record_change(&mut self, change: PeerChange) {
+        self.generation += 1;
+        self.changes.push_back((self.generation, change));
+        if self.changes.len() > CHANGE_LOG_SIZE {
+            self.changes.pop_front();
+        }
     }
 
-    // Check if a peer was recently removed (within the grace period)
-    pub fn was_recently_removed(&self, addr: &SocketAddr, grace_period: Duration) -> bool {
-        if let Some(removed_time) = self.recently_removed.get(&addr.to_string()) {
-            let now = Instant::now();
-            return now.duration_since(*removed_time) < grace_period;
+    // Share that a peer left on its own, so others drop it without waiting for a timeout
+    pub fn record_left(&mut self, addr: SocketAddr) {
+        self.record_change(PeerChange::Left(addr));
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Changes after `since`, or None if they're no longer all in the log
+    pub fn changes_since(&self, since: u64) -> Option<Vec<PeerChange>> {
+        let oldest = self
+            .changes
+            .front()
+            .map_or(self.generation + 1, |(generation, _)| *generation);
+        if since + 1 < oldest {
+            return None;
         }
-        false
+        Some(
+            self.changes
+                .iter()
+                .filter(|(generation, _)| *generation > since)
+                .map(|(_, change)| change.clone())
+                .collect(),
+        )
+    }
+
+    // Sleepy peers are only considered stale after `sleepy_timeout`
+    pub fn remove_stale_peers(
+        &mut self,
+        timeout: Duration,
+        sleepy_timeout: Duration,
+    ) -> Vec<PeerInfo> {
+        let now = Instant::now();
+        let stale_keys: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|(_, info)| {
+                let timeout = if info.is_sleepy {
+                    sleepy_timeout
+                } else {
+                    timeout
+                };
+                // Peers that heartbeat us less often get as long as they'd need for it
+                let timeout = info.heartbeat_every.map_or(timeout, |(_, seconds)| {
+                    timeout.max(Duration::from_secs(u64::from(seconds) * 5 / 2))
+                });
+                now.duration_since(info.last_seen) > timeout
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut removed = Vec::with_capacity(stale_keys.len());
+        for key in &stale_keys {
+            if let Some(info) = self.peers.remove(key) {
+                // Add to recently removed peers
+                self.recently_removed.insert(key.clone(), (info.addr, now));
+                if info.is_sleepy {
+                    self.napping.insert(info.addr.to_string());
+                }
+                removed.push(info);
+            }
+        }
+
+        removed
+    }
+
+    // Check if a peer at this address was recently removed (within the grace period)
+    pub fn was_recently_removed(&self, addr: &SocketAddr, grace_period: Duration) -> bool {
+        let now = Instant::now();
+        self.recently_removed
+            .values()
+            .any(|(removed_addr, removed_time)| {
+                removed_addr == addr && now.duration_since(*removed_time) < grace_period
+            })
+    }
+
+    // The peer itself contacted us again (e.g. it restarted within the grace period), so
+    // gossip about it no longer has to be ignored
+    pub fn forget_removal(&mut self, addr: &SocketAddr, node_id: Option<&str>) {
+        self.recently_removed.retain(|key, (removed_addr, _)| {
+            removed_addr != addr && node_id.is_none_or(|node_id| key != node_id)
+        });
     }
 
     // Clean up old entries from the recently_removed list
     pub fn clean_removed_list(&mut self, max_age: Duration) {
         let now = Instant::now();
         self.recently_removed
-            .retain(|_, removed_time| now.duration_since(*removed_time) < max_age);
+            .retain(|_, (_, removed_time)| now.duration_since(*removed_time) < max_age);
     }
 }
 
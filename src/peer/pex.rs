@@ -0,0 +1,303 @@
+use crate::events::{self, Event};
+use crate::message::{Message, PeerExchange};
+use crate::mirror;
+use crate::net::interfaces;
+use crate::net::transport::SharedTransport;
+use crate::peer::lifecycle::{self, PeerEvent};
+use crate::peer::peer_list::{PeerChange, shared_name};
+use crate::peer::{PeerList, SharedPeerList};
+use crate::ui::privacy;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::time;
+
+// How often peers that are behind on our changes get a diff
+const PEX_INTERVAL: u64 = 6; // seconds
+
+#[derive(Default)]
+struct PexState {
+    // Generation of our peer list each peer has acknowledged
+    acked: HashMap<SocketAddr, u64>,
+    // Generation of each peer's list we're at
+    received: HashMap<SocketAddr, u64>,
+}
+
+static STATE: LazyLock<Mutex<PexState>> = LazyLock::new(|| Mutex::new(PexState::default()));
+
+/// Starts sending peer list changes to peers that haven't acknowledged them yet.
+/// Only differences are sent, so a stable network causes no peer exchange traffic at all.
+pub fn start(
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+    peer_list: SharedPeerList,
+) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(PEX_INTERVAL));
+        loop {
+            interval.tick().await;
+
+            let outgoing: Vec<(SocketAddr, PeerExchange)> = {
+                let peer_list = peer_list.lock().await;
+                let Ok(mut state) = STATE.lock() else {
+                    continue;
+                };
+                let peers = peer_list.get_peers();
+                // Forget peers that are gone; they start over with a full list if they return
+                state
+                    .acked
+                    .retain(|addr, _| peers.iter().any(|peer| peer.addr == *addr));
+                state
+                    .received
+                    .retain(|addr, _| peers.iter().any(|peer| peer.addr == *addr));
+
+                peers
+                    .iter()
+                    .filter(|peer| !peer.is_provisional)
+                    .filter_map(|peer| {
+                        let acked = state.acked.get(&peer.addr).copied();
+                        diff_for(&peer_list, acked).map(|diff| (peer.addr, diff))
+                    })
+                    .collect()
+            };
+
+            for (addr, diff) in outgoing {
+                log::debug!(
+                    "[PEX] Sending generations {}..{} to {addr}",
+                    diff.from_generation,
+                    diff.to_generation
+                );
+                let msg = Message::new_peer_exchange(username.clone(), local_addr, diff);
+                if let Err(e) = transport.send_to(&msg, &addr.to_string()).await {
+                    log::error!("Error sending peer exchange to {addr}: {e}");
+                }
+            }
+        }
+    });
+}
+
+// What a peer that acknowledged `acked` is missing, or None if it's up to date
+fn diff_for(peer_list: &PeerList, acked: Option<u64>) -> Option<PeerExchange> {
+    let to_generation = peer_list.generation();
+    if acked == Some(to_generation) {
+        return None;
+    }
+
+    let changes = acked.and_then(|acked| peer_list.changes_since(acked).map(|c| (acked, c)));
+    let diff = match changes {
+        Some((from_generation, changes)) => {
+            let mut added = Vec::new();
+            let mut left = Vec::new();
+            for change in changes {
+                match change {
+                    PeerChange::Added(username, addr) => {
+                        added.push((shared_name(&username, addr), addr.to_string()))
+                    }
+                    PeerChange::Left(addr) => left.push(addr.to_string()),
+                }
+            }
+            PeerExchange {
+                from_generation,
+                to_generation,
+                added,
+                left,
+                is_ack: false,
+            }
+        }
+        // New peer, or too far behind for the change log: send everything
+        None => PeerExchange {
+            from_generation: 0,
+            to_generation,
+            added: peer_list
+                .get_peers()
+                .into_iter()
+                .filter(|peer| !peer.is_provisional)
+                .map(|peer| {
+                    (
+                        shared_name(&peer.username, peer.addr),
+                        peer.addr.to_string(),
+                    )
+                })
+                .collect(),
+            left: Vec::new(),
+            is_ack: false,
+        },
+    };
+    Some(diff)
+}
+
+/// Applies a peer's changes and acknowledges them, or records its acknowledgement.
+/// Unless the message is `authentic` (from the sender's host or signed with its key),
+/// acknowledgements and peers that left are ignored; anyone could have made those up.
+pub async fn handle_peer_exchange_message(
+    msg: &Message,
+    peer_list: &SharedPeerList,
+    transport: SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    authentic: bool,
+) -> std::io::Result<()> {
+    let (Some(diff), Some(sender)) = (
+        &msg.peer_exchange,
+        msg.sender_addr
+            .as_ref()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok()),
+    ) else {
+        return Ok(());
+    };
+
+    if diff.is_ack {
+        if authentic && let Ok(mut state) = STATE.lock() {
+            state.acked.insert(sender, diff.to_generation);
+        }
+        return Ok(());
+    }
+
+    // Changes only apply on top of what we have; after a gap, our ack asks for a resend
+    let current = STATE
+        .lock()
+        .ok()
+        .and_then(|state| state.received.get(&sender).copied());
+    let applies = diff.from_generation == 0 || current == Some(diff.from_generation);
+    let at_generation = if applies {
+        apply(
+            diff, msg, peer_list, &transport, username, local_addr, authentic,
+        )
+        .await;
+        if let Ok(mut state) = STATE.lock() {
+            state.received.insert(sender, diff.to_generation);
+        }
+        diff.to_generation
+    } else {
+        log::debug!(
+            "[PEX] Gap from {sender}: at {current:?}, got {}..{}",
+            diff.from_generation,
+            diff.to_generation
+        );
+        // Changes are safe to apply twice, so resending from an older generation is fine
+        current.unwrap_or(0)
+    };
+
+    let ack = PeerExchange {
+        from_generation: 0,
+        to_generation: at_generation,
+        added: Vec::new(),
+        left: Vec::new(),
+        is_ack: true,
+    };
+    let ack_msg = Message::new_peer_exchange(username.to_string(), local_addr, ack);
+    transport.send_to(&ack_msg, &sender.to_string()).await
+}
+
+async fn apply(
+    diff: &PeerExchange,
+    msg: &Message,
+    peer_list: &SharedPeerList,
+    transport: &SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    authentic: bool,
+) {
+    // Peers that left on their own are dropped right away, if we can tell who says so
+    let left: &[String] = if authentic { &diff.left } else { &[] };
+    for addr in left
+        .iter()
+        .filter_map(|addr| addr.parse::<SocketAddr>().ok())
+    {
+        let removed = peer_list.lock().await.remove_peer(&addr);
+        for peer in removed {
+            say!(
+                "### Peer left: {} ({}), via {}",
+                peer.username,
+                privacy::addr(peer.addr, peer.node_id.as_deref()),
+                msg.sender
+            );
+            mirror::peer_event("left", &peer.username, &peer.addr.to_string());
+            lifecycle::record(PeerEvent::Left, &peer.username, &peer.addr.to_string());
+            events::publish(Event::PeerLeft);
+        }
+    }
+
+    // New peers get a discovery and join once they answer
+    let discovery_msg = Message::new_discovery(username.to_string(), local_addr);
+    for (name, addr) in &diff.added {
+        let Ok(addr) = addr.parse::<SocketAddr>() else {
+            continue;
+        };
+        if interfaces::is_own_addr(addr, local_addr)
+            || peer_list
+                .lock()
+                .await
+                .find_username_by_addr(&addr)
+                .is_some()
+        {
+            continue;
+        }
+        log::debug!(
+            "[PEX] Contacting {name} ({addr}) learned from {}",
+            msg.sender
+        );
+        if let Err(e) = transport.send_to(&discovery_msg, &addr.to_string()).await {
+            log::error!("Error contacting {addr}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([198, 51, 100, n], 10001))
+    }
+
+    #[test]
+    fn new_peers_get_the_whole_list_and_others_only_changes() {
+        let mut peer_list = PeerList::new();
+        peer_list.add_or_update_peer(addr(1), "alice".to_string(), None);
+        peer_list.add_or_update_peer(addr(2), "bob".to_string(), None);
+
+        let full = diff_for(&peer_list, None).unwrap();
+        assert_eq!(full.from_generation, 0);
+        assert_eq!(full.to_generation, peer_list.generation());
+        let mut added = full.added.clone();
+        added.sort();
+        assert_eq!(
+            added,
+            vec![
+                ("alice".to_string(), addr(1).to_string()),
+                ("bob".to_string(), addr(2).to_string()),
+            ]
+        );
+        // Nothing to send once a peer is up to date
+        let acked = full.to_generation;
+        assert!(diff_for(&peer_list, Some(acked)).is_none());
+
+        peer_list.remove_peer(&addr(2));
+        peer_list.record_left(addr(2));
+        peer_list.add_or_update_peer(addr(3), "carol".to_string(), None);
+        let diff = diff_for(&peer_list, Some(acked)).unwrap();
+        assert_eq!(diff.from_generation, acked);
+        assert_eq!(diff.to_generation, peer_list.generation());
+        assert_eq!(diff.added, vec![("carol".to_string(), addr(3).to_string())]);
+        assert_eq!(diff.left, vec![addr(2).to_string()]);
+        assert!(!diff.is_ack);
+    }
+
+    #[test]
+    fn peers_too_far_behind_get_the_whole_list_again() {
+        let mut peer_list = PeerList::new();
+        peer_list.add_or_update_peer(addr(1), "alice".to_string(), None);
+        let acked = peer_list.generation();
+        // More changes than the log keeps
+        for _ in 0..1000 {
+            peer_list.record_left(addr(2));
+        }
+        let diff = diff_for(&peer_list, Some(acked)).unwrap();
+        assert_eq!(diff.from_generation, 0);
+        assert_eq!(diff.added, vec![("alice".to_string(), addr(1).to_string())]);
+        assert!(diff.left.is_empty());
+    }
+}
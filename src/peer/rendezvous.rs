@@ -0,0 +1,192 @@
+use crate::features::{self, Feature};
+use crate::message::{Message, MessageType};
+use crate::net::noise::{self, NoiseLayer, Opened};
+use crate::net::stats::{NetStats, SharedNetStats};
+use crate::net::transport::{SharedTransport, UdpTransport};
+use crate::net::{auth, flood, frame, validate};
+use crate::net::{psk, resolver};
+use crate::peer::discovery::DiscoveryLimiter;
+use crate::utils;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+// Clients register again well before their registration runs out
+const REGISTER_INTERVAL: u64 = 60; // seconds
+const REGISTRATION_TTL: u64 = 180; // seconds
+// Beyond this many clients, the one that registered longest ago makes room
+const MAX_REGISTRATIONS: usize = 1024;
+const SERVER_NAME: &str = "rendezvous";
+
+struct Registration {
+    username: String,
+    network: Option<String>,
+    room: Option<String>,
+    registered_at: Instant,
+}
+
+/// Runs a rendezvous server: no chat, it only records the clients that register with it
+/// and answers each registration with the other clients currently online
+pub async fn serve(port: u16) -> std::io::Result<()> {
+    let socket =
+        Arc::new(UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)).await?);
+    let local_ip = utils::get_local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let local_addr = SocketAddr::new(local_ip, port);
+
+    let net_stats: SharedNetStats = Arc::new(std::sync::Mutex::new(NetStats::new()));
+    let mut transport: SharedTransport = Arc::new(UdpTransport::new(socket.clone(), net_stats));
+    // Answers are encrypted like any other unicast, so clients don't have to allow plaintext
+    if features::is_active(Feature::Encryption) {
+        match NoiseLayer::install(transport.clone(), local_addr, false) {
+            Ok(layer) => transport = layer,
+            Err(e) => say!("Warning: Could not set up encryption, answering unencrypted: {e}"),
+        }
+    }
+    say!("@@@ Rendezvous server listening on {local_addr}");
+
+    // Registered client address -> its latest registration
+    let mut registrations: HashMap<SocketAddr, Registration> = HashMap::new();
+    // Each source gets one answer per few seconds, whatever it sends
+    let mut limiter = DiscoveryLimiter::default();
+    let mut expiry = time::interval(Duration::from_secs(REGISTER_INTERVAL));
+    let mut buf = vec![0u8; frame::MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, source) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            _ = expiry.tick() => {
+                expire(&mut registrations);
+                continue;
+            }
+        };
+        let Some(packet) = psk::open(&buf[..len]) else {
+            continue;
+        };
+        let packet = packet.as_ref();
+        let frame_bytes = if noise::is_noise_packet(packet) {
+            let Some(layer) = noise::layer() else {
+                continue;
+            };
+            match layer.open(packet, source).await {
+                Opened::Frame(frame, _) => frame,
+                Opened::Handshake => continue,
+                Opened::Rejected(reason) => {
+                    log::debug!("[Rendezvous] Rejected packet from {source}: {reason}");
+                    continue;
+                }
+            }
+        } else {
+            packet.to_vec()
+        };
+        let Ok(msg) = frame::decode(&frame_bytes) else {
+            continue;
+        };
+        if !matches!(msg.msg_type, MessageType::Discovery)
+            || !flood::allow(source, &msg.msg_type)
+            || !limiter.should_handle(source)
+            || validate::check(&msg).is_err()
+            || !auth::verify(&msg)
+        {
+            continue;
+        }
+        let Some(advertised) = msg
+            .sender_addr
+            .as_ref()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+        else {
+            continue;
+        };
+        // Clients behind a VPN or NAT may not know the address others reach them at,
+        // so trust the address the registration came from, on the advertised port
+        let client = SocketAddr::new(source.ip(), advertised.port());
+
+        expire(&mut registrations);
+        if !registrations.contains_key(&client)
+            && registrations.len() >= MAX_REGISTRATIONS
+            && let Some(oldest) = registrations
+                .iter()
+                .min_by_key(|(_, registration)| registration.registered_at)
+                .map(|(addr, _)| *addr)
+        {
+            registrations.remove(&oldest);
+        }
+        let registration = Registration {
+            username: msg.sender.clone(),
+            network: msg.network.clone(),
+            room: msg.room.clone(),
+            registered_at: Instant::now(),
+        };
+        if registrations.insert(client, registration).is_none() {
+            say!("### Registered: {} ({client})", msg.sender);
+        }
+
+        // Clients only hear about others on their network and in their room, in a reply
+        // for that network and room
+        let online: Vec<String> = registrations
+            .iter()
+            .filter(|(addr, registration)| {
+                **addr != client
+                    && registration.network == msg.network
+                    && registration.room == msg.room
+            })
+            .map(|(addr, _)| addr.to_string())
+            .collect();
+        let reply = Message {
+            network: msg.network.clone(),
+            room: msg.room.clone(),
+            ..Message::new_peer_list(SERVER_NAME.to_string(), online, local_addr)
+        };
+        // Back where the registration came from, which a NAT in between lets through;
+        // the advertised port is only what the client claims
+        if let Err(e) = transport.send_to(&reply, &source.to_string()).await {
+            log::error!("[Rendezvous] Error answering {source}: {e}");
+        }
+    }
+}
+
+// Drop the registrations that weren't renewed in time
+fn expire(registrations: &mut HashMap<SocketAddr, Registration>) {
+    let ttl = Duration::from_secs(REGISTRATION_TTL);
+    registrations.retain(|addr, registration| {
+        let alive = registration.registered_at.elapsed() < ttl;
+        if !alive {
+            say!(
+                "### Registration expired: {} ({addr})",
+                registration.username
+            );
+        }
+        alive
+    });
+}
+
+/// Registers with a rendezvous server now and periodically; its answers list the other
+/// registered clients, which are then contacted like peers from any other peer list. The
+/// transport should send from the receive socket, where the server's answers go.
+pub fn register(
+    target: String,
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(REGISTER_INTERVAL));
+        loop {
+            interval.tick().await;
+            // Built every time, so a /nick shows up with the next registration
+            let msg = Message::new_discovery(username.clone(), local_addr);
+            let addr = match resolver::resolve(&target).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    log::debug!("[Rendezvous] Could not resolve {target}: {e}");
+                    continue;
+                }
+            };
+            log::debug!("[Rendezvous] Registering with {addr}");
+            if let Err(e) = transport.send_to(&msg, &addr.to_string()).await {
+                log::error!("Error registering with rendezvous server {addr}: {e}");
+            }
+        }
+    });
+}
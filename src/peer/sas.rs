@@ -0,0 +1,283 @@
+use crate::message::Message;
+use crate::net::identity;
+use crate::net::transport::SharedTransport;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time;
+
+// How long /sas waits for the peer to run it too; also how long a peer's side of an
+// exchange is kept for us to join
+const SAS_TIMEOUT: u64 = 60; // seconds
+// Exchanges kept at once, so peers can't fill memory with commitments nobody asked for
+const MAX_EXCHANGES: usize = 16;
+
+/// What the peer sent in one /sas exchange with it
+struct FromPeer {
+    commitment: Option<[u8; 32]>,
+    nonce: Option<[u8; 32]>,
+    started: Instant,
+    // Set while /sas runs with the peer, woken up with every step it sends
+    waiting: Option<Arc<Notify>>,
+}
+
+impl FromPeer {
+    fn new() -> Self {
+        FromPeer {
+            commitment: None,
+            nonce: None,
+            started: Instant::now(),
+            waiting: None,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.started.elapsed() > Duration::from_secs(SAS_TIMEOUT)
+    }
+}
+
+// By the peer's receiving address
+static EXCHANGES: LazyLock<Mutex<HashMap<SocketAddr, FromPeer>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+enum Step {
+    Commit([u8; 32]),
+    Reveal([u8; 32]),
+}
+
+impl Step {
+    fn parse(content: &str) -> Option<Step> {
+        let (kind, value) = content.split_once(' ')?;
+        let value: [u8; 32] = hex::decode(value).ok()?.try_into().ok()?;
+        match kind {
+            "commit" => Some(Step::Commit(value)),
+            "reveal" => Some(Step::Reveal(value)),
+            _ => None,
+        }
+    }
+}
+
+/// Runs our side of a /sas exchange with the peer at `peer_addr`, which signs with
+/// `peer_key`: commit to a nonce, reveal it once the peer has committed to its own, and
+/// derive the emoji from both. Waits for the peer to run /sas too.
+pub async fn exchange(
+    transport: &SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    peer_key: &str,
+) -> Result<Vec<(&'static str, &'static str)>, String> {
+    let mut own_nonce = [0u8; 32];
+    rand::rng().fill_bytes(&mut own_nonce);
+    let waiting = Arc::new(Notify::new());
+    {
+        let mut exchanges = EXCHANGES.lock().map_err(|e| e.to_string())?;
+        // The peer may have started first; whatever it sent for an earlier exchange is gone
+        let from_peer = exchanges.entry(peer_addr).or_insert_with(FromPeer::new);
+        if from_peer.is_expired() {
+            *from_peer = FromPeer::new();
+        }
+        from_peer.waiting = Some(waiting.clone());
+    }
+
+    let result = run(
+        transport, username, local_addr, peer_addr, peer_key, &own_nonce, &waiting,
+    )
+    .await;
+    // Win or lose, the next /sas starts over with new nonces
+    if let Ok(mut exchanges) = EXCHANGES.lock() {
+        exchanges.remove(&peer_addr);
+    }
+    result
+}
+
+async fn run(
+    transport: &SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    peer_key: &str,
+    own_nonce: &[u8; 32],
+    waiting: &Notify,
+) -> Result<Vec<(&'static str, &'static str)>, String> {
+    let commitment = identity::sas_commitment(&identity::public_key(), own_nonce);
+    send(
+        transport,
+        username,
+        local_addr,
+        peer_addr,
+        "commit",
+        &commitment,
+    )
+    .await?;
+
+    let deadline = time::Instant::now() + Duration::from_secs(SAS_TIMEOUT);
+    let mut revealed = false;
+    loop {
+        let (commitment, nonce) = {
+            let exchanges = EXCHANGES.lock().map_err(|e| e.to_string())?;
+            let from_peer = exchanges.get(&peer_addr).ok_or("exchange cancelled")?;
+            (from_peer.commitment, from_peer.nonce)
+        };
+        // Only once the peer can't change its nonce anymore may it learn ours
+        if let Some(commitment) = commitment {
+            if !revealed {
+                send(
+                    transport, username, local_addr, peer_addr, "reveal", own_nonce,
+                )
+                .await?;
+                revealed = true;
+            }
+            if let Some(nonce) = nonce {
+                if identity::sas_commitment(peer_key, &nonce) != commitment {
+                    return Err("the peer revealed another nonce than it committed to".to_string());
+                }
+                return Ok(identity::sas(peer_key, own_nonce, &nonce));
+            }
+        }
+        time::timeout_at(deadline, waiting.notified())
+            .await
+            .map_err(|_| "the peer didn't run /sas in time".to_string())?;
+    }
+}
+
+async fn send(
+    transport: &SharedTransport,
+    username: &str,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    kind: &str,
+    value: &[u8; 32],
+) -> Result<(), String> {
+    let msg = Message::new_sas(
+        username.to_string(),
+        format!("{kind} {}", hex::encode(value)),
+        local_addr,
+    );
+    transport
+        .send_to(&msg, &peer_addr.to_string())
+        .await
+        .map_err(|e| format!("could not reach the peer: {e}"))
+}
+
+/// Handles a step of a peer's /sas exchange with us
+pub fn handle_sas_message(msg: &Message) {
+    let Some(addr) = msg
+        .sender_addr
+        .as_ref()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+    else {
+        return;
+    };
+    let Some(step) = Step::parse(&msg.content) else {
+        log::debug!("Ignoring a malformed /sas step from {addr}");
+        return;
+    };
+    let unasked = match EXCHANGES.lock() {
+        Ok(mut exchanges) => record(&mut exchanges, addr, step),
+        Err(_) => return,
+    };
+    if unasked {
+        say!(
+            "@@@ {} wants to verify your keys: run /sas {} to compare emoji",
+            msg.sender,
+            msg.sender
+        );
+    }
+}
+
+// Keep a step for the exchange with the peer at `addr`; true if it opens an exchange we
+// aren't running yet. Only the first commitment and nonce of an exchange count: a
+// commitment that came after ours was revealed could have been picked to match it.
+fn record(exchanges: &mut HashMap<SocketAddr, FromPeer>, addr: SocketAddr, step: Step) -> bool {
+    if !exchanges.contains_key(&addr) && exchanges.len() >= MAX_EXCHANGES {
+        exchanges.retain(|_, from_peer| from_peer.waiting.is_some() || !from_peer.is_expired());
+        if exchanges.len() >= MAX_EXCHANGES {
+            return false;
+        }
+    }
+    let from_peer = exchanges.entry(addr).or_insert_with(FromPeer::new);
+    if from_peer.waiting.is_none() && from_peer.is_expired() {
+        *from_peer = FromPeer::new();
+    }
+    match step {
+        Step::Commit(commitment) if from_peer.commitment.is_none() => {
+            from_peer.commitment = Some(commitment);
+        }
+        Step::Reveal(nonce) if from_peer.nonce.is_none() => from_peer.nonce = Some(nonce),
+        _ => return false,
+    }
+    match &from_peer.waiting {
+        Some(waiting) => {
+            waiting.notify_one();
+            false
+        }
+        None => matches!(step, Step::Commit(_)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: usize) -> SocketAddr {
+        SocketAddr::from(([198, 51, 100, n as u8], 10001))
+    }
+
+    #[test]
+    fn only_the_first_commitment_of_an_exchange_counts() {
+        let mut exchanges = HashMap::new();
+        assert!(record(&mut exchanges, addr(1), Step::Commit([1; 32])));
+        // A second one, e.g. picked after seeing our nonce, doesn't replace it
+        assert!(!record(&mut exchanges, addr(1), Step::Commit([2; 32])));
+        assert!(!record(&mut exchanges, addr(1), Step::Reveal([3; 32])));
+        assert!(!record(&mut exchanges, addr(1), Step::Reveal([4; 32])));
+        assert_eq!(exchanges[&addr(1)].commitment, Some([1; 32]));
+        assert_eq!(exchanges[&addr(1)].nonce, Some([3; 32]));
+    }
+
+    #[test]
+    fn steps_wake_up_a_running_exchange() {
+        let mut exchanges = HashMap::new();
+        let waiting = Arc::new(Notify::new());
+        exchanges.insert(
+            addr(1),
+            FromPeer {
+                waiting: Some(waiting.clone()),
+                ..FromPeer::new()
+            },
+        );
+        // Not news to the user, who's running /sas with the peer
+        assert!(!record(&mut exchanges, addr(1), Step::Commit([1; 32])));
+        let woken = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(async { time::timeout(Duration::from_secs(1), waiting.notified()).await });
+        assert!(woken.is_ok());
+    }
+
+    #[test]
+    fn exchanges_nobody_asked_for_are_capped() {
+        let mut exchanges = HashMap::new();
+        for n in 0..MAX_EXCHANGES + 10 {
+            record(&mut exchanges, addr(n), Step::Commit([1; 32]));
+        }
+        assert_eq!(exchanges.len(), MAX_EXCHANGES);
+    }
+
+    #[test]
+    fn steps_are_parsed_strictly() {
+        let commitment = format!("commit {}", hex::encode([1u8; 32]));
+        assert!(matches!(
+            Step::parse(&commitment),
+            Some(Step::Commit([1, ..]))
+        ));
+        assert!(Step::parse("reveal nothing").is_none());
+        assert!(Step::parse(&format!("reveal {}", hex::encode([1u8; 16]))).is_none());
+        assert!(Step::parse(&format!("guess {}", hex::encode([1u8; 32]))).is_none());
+    }
+}
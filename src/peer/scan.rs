@@ -0,0 +1,94 @@
+use crate::message::Message;
+use crate::net::interfaces;
+use crate::net::transport::SharedTransport;
+use crate::utils::PortRange;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::time;
+
+// Probes are sent in small batches, capped at this rate to stay gentle on the LAN
+const SCAN_RATE: u64 = 1000; // probes per second
+const SCAN_TICK: u64 = 100; // milliseconds
+
+static SCANNING: AtomicBool = AtomicBool::new(false);
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts probing every port of `ports` on every host of our /24 networks with a
+/// discovery, for networks that block both broadcast and multicast. Peers that are
+/// found answer the probe and join like after any other discovery.
+/// Returns the number of probes it will send, or None if a scan is already running.
+pub fn start(
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+    ports: PortRange,
+) -> Option<u64> {
+    if SCANNING.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    STOP_REQUESTED.store(false, Ordering::SeqCst);
+
+    let hosts = hosts();
+    let total = hosts.len() as u64 * u64::from(ports.max - ports.min + 1);
+    tokio::spawn(async move {
+        let msg = Message::new_discovery(username, local_addr);
+        let per_tick = SCAN_RATE * SCAN_TICK / 1000;
+        let mut sent: u64 = 0;
+        let mut interval = time::interval(Duration::from_millis(SCAN_TICK));
+
+        loop {
+            interval.tick().await;
+            if STOP_REQUESTED.load(Ordering::SeqCst) {
+                say!("@@@ Scan stopped after {sent} of {total} probes");
+                break;
+            }
+            if sent == total {
+                say!("@@@ Scan finished, sent {sent} probes");
+                break;
+            }
+            // Go through the hosts for each port, so no single host gets all probes at once
+            let batch_end = total.min(sent + per_tick);
+            for probe in sent..batch_end {
+                let ip = hosts[(probe % hosts.len() as u64) as usize];
+                let port = ports.min + (probe / hosts.len() as u64) as u16;
+                let addr = SocketAddr::new(ip, port);
+                if let Err(e) = transport.probe(&msg, &addr.to_string()).await {
+                    log::debug!("[Scan] Error probing {addr}: {e}");
+                }
+            }
+            sent = batch_end;
+        }
+        SCANNING.store(false, Ordering::SeqCst);
+    });
+    Some(total)
+}
+
+/// Stop a running scan; returns false if none is running
+pub fn stop() -> bool {
+    if !SCANNING.load(Ordering::SeqCst) {
+        return false;
+    }
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+    true
+}
+
+/// How long a scan of `probes` probes takes at the scan rate
+pub fn duration_of(probes: u64) -> Duration {
+    Duration::from_secs(probes.div_ceil(SCAN_RATE))
+}
+
+// Every other host in the /24 around each of our addresses
+fn hosts() -> Vec<IpAddr> {
+    let mut hosts: Vec<IpAddr> = Vec::new();
+    for network in interfaces::networks() {
+        let base = u32::from(network.ip) & 0xffff_ff00;
+        for host in 1..=254 {
+            let ip = IpAddr::V4(Ipv4Addr::from(base | host));
+            if ip != IpAddr::V4(network.ip) && !hosts.contains(&ip) {
+                hosts.push(ip);
+            }
+        }
+    }
+    hosts
+}
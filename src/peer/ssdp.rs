@@ -0,0 +1,250 @@
+use crate::net::interfaces;
+use crate::net::transport::SharedTransport;
+use crate::peer::{SharedPeerList, discovery};
+use rand::Rng;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+const SEARCH_TARGET: &str = "urn:pung:chat:1";
+// We announce ourselves this often; the advertised max-age covers a few missed ones
+const ANNOUNCE_INTERVAL: u64 = 60; // seconds
+const MAX_AGE: u64 = 300; // seconds
+// Responders pick a delay up to this before answering a search (MX header)
+const SEARCH_MX: u64 = 2; // seconds
+// Longer delays searchers ask for are cut to this, as the spec says
+const MAX_MX: u64 = 5; // seconds
+
+/// Starts the SSDP backend: announces us on the SSDP multicast group, searches for
+/// other instances once, and sends a discovery to every instance that shows up there.
+/// Useful on routers that pass SSDP multicast but filter our broadcasts.
+pub fn start(
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+    peer_list: SharedPeerList,
+) {
+    let socket = match bind_multicast() {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            say!("@@@ SSDP discovery disabled, could not join the multicast group: {e}");
+            return;
+        }
+    };
+    let group = SocketAddr::V4(SocketAddrV4::new(SSDP_ADDR, SSDP_PORT));
+    let contact = Contact {
+        transport,
+        username,
+        local_addr,
+        peer_list,
+    };
+
+    // Search first, so running instances answer right away
+    let search_contact = contact.clone();
+    tokio::spawn(async move {
+        if let Err(e) = search(group, &search_contact).await {
+            log::error!("[SSDP] Error searching: {e}");
+        }
+    });
+
+    // Then announce ourselves, on every network we're on
+    let announce_socket = socket.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(ANNOUNCE_INTERVAL));
+        loop {
+            interval.tick().await;
+            announce(&announce_socket, group, local_addr).await;
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, source) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    log::error!("[SSDP] Error receiving: {e}");
+                    continue;
+                }
+            };
+            let Ok(text) = std::str::from_utf8(&buf[..len]) else {
+                continue;
+            };
+            let Some((start_line, headers)) = parse(text) else {
+                continue;
+            };
+
+            // Somebody is searching for us (or for everything)
+            if start_line.starts_with("M-SEARCH") {
+                let target = header(&headers, "ST").unwrap_or_default();
+                if target == SEARCH_TARGET || target == "ssdp:all" {
+                    // Answer after a random part of the delay the searcher allows, so it
+                    // isn't swamped by everyone answering at once
+                    let mx = header(&headers, "MX")
+                        .and_then(|mx| mx.parse::<u64>().ok())
+                        .unwrap_or(1)
+                        .clamp(1, MAX_MX);
+                    let delay = Duration::from_millis(rand::rng().random_range(0..mx * 1000));
+                    let ip = interfaces::local_ip_for(source.ip()).unwrap_or(local_addr.ip());
+                    let response = search_response(SocketAddr::new(ip, local_addr.port()));
+                    let socket = socket.clone();
+                    tokio::spawn(async move {
+                        time::sleep(delay).await;
+                        if let Err(e) = socket.send_to(response.as_bytes(), source).await {
+                            log::error!("[SSDP] Error answering search from {source}: {e}");
+                        }
+                    });
+                }
+                continue;
+            }
+
+            contact.handle_advertisement(&headers).await;
+        }
+    });
+}
+
+// What it takes to contact the instances we hear of
+#[derive(Clone)]
+struct Contact {
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+    peer_list: SharedPeerList,
+}
+
+impl Contact {
+    // An announcement or an answer to our search; anything else on the group isn't for us
+    async fn handle_advertisement(&self, headers: &[(String, &str)]) {
+        let is_ours = header(headers, "NT")
+            .or_else(|| header(headers, "ST"))
+            .is_some_and(|target| target == SEARCH_TARGET);
+        let is_alive = header(headers, "NTS").is_none_or(|nts| nts == "ssdp:alive");
+        let endpoint = header(headers, "LOCATION")
+            .and_then(|location| location.strip_prefix("pung://"))
+            .and_then(|addr| addr.parse::<SocketAddr>().ok());
+        let (true, true, Some(endpoint)) = (is_ours, is_alive, endpoint) else {
+            return;
+        };
+        if interfaces::is_own_addr(endpoint, self.local_addr)
+            || self
+                .peer_list
+                .lock()
+                .await
+                .find_username_by_addr(&endpoint)
+                .is_some()
+        {
+            return;
+        }
+
+        log::debug!("[SSDP] Contacting {endpoint}");
+        if let Err(e) = discovery::connect(
+            &endpoint.to_string(),
+            self.transport.clone(),
+            &self.username,
+            self.local_addr,
+            &self.peer_list,
+        )
+        .await
+        {
+            log::debug!("[SSDP] Could not contact {endpoint}: {e}");
+        }
+    }
+}
+
+// Search from a socket of its own, as SSDP expects: the answers come back to it, not to
+// the shared port 1900 other SSDP users may have bound too
+async fn search(group: SocketAddr, contact: &Contact) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).await?;
+    socket.send_to(search_request().as_bytes(), group).await?;
+
+    let deadline = time::Instant::now() + Duration::from_secs(SEARCH_MX + 1);
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, _) = received?;
+        if let Some((_, headers)) = std::str::from_utf8(&buf[..len]).ok().and_then(parse) {
+            contact.handle_advertisement(&headers).await;
+        }
+    }
+    Ok(())
+}
+
+// A NOTIFY on each of our networks, with our address on that network
+async fn announce(socket: &UdpSocket, group: SocketAddr, local_addr: SocketAddr) {
+    let networks = interfaces::networks();
+    if networks.is_empty() {
+        if let Err(e) = socket.send_to(notify(local_addr).as_bytes(), group).await {
+            log::error!("[SSDP] Error sending announcement: {e}");
+        }
+        return;
+    }
+    for network in networks {
+        let endpoint = SocketAddr::new(network.ip.into(), local_addr.port());
+        if let Err(e) = SockRef::from(socket).set_multicast_if_v4(&network.ip) {
+            log::debug!("[SSDP] Could not announce on {}: {e}", network.ip);
+            continue;
+        }
+        if let Err(e) = socket.send_to(notify(endpoint).as_bytes(), group).await {
+            log::error!("[SSDP] Error sending announcement on {}: {e}", network.ip);
+        }
+    }
+}
+
+// Other SSDP users (UPnP routers, media servers) share the port, so it's bound with SO_REUSEADDR
+fn bind_multicast() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, SSDP_PORT)).into())?;
+
+    let networks = interfaces::networks();
+    if networks.is_empty() {
+        socket.join_multicast_v4(&SSDP_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    }
+    for network in networks {
+        if let Err(e) = socket.join_multicast_v4(&SSDP_ADDR, &network.ip) {
+            log::debug!("[SSDP] Could not join the group on {}: {e}", network.ip);
+        }
+    }
+    UdpSocket::from_std(socket.into())
+}
+
+fn search_request() -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}:{SSDP_PORT}\r\nMAN: \"ssdp:discover\"\r\nMX: {SEARCH_MX}\r\nST: {SEARCH_TARGET}\r\n\r\n"
+    )
+}
+
+fn notify(endpoint: SocketAddr) -> String {
+    format!(
+        "NOTIFY * HTTP/1.1\r\nHOST: {SSDP_ADDR}:{SSDP_PORT}\r\nCACHE-CONTROL: max-age={MAX_AGE}\r\nLOCATION: pung://{endpoint}\r\nNT: {SEARCH_TARGET}\r\nNTS: ssdp:alive\r\nUSN: pung:{endpoint}::{SEARCH_TARGET}\r\n\r\n"
+    )
+}
+
+fn search_response(endpoint: SocketAddr) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={MAX_AGE}\r\nEXT:\r\nLOCATION: pung://{endpoint}\r\nST: {SEARCH_TARGET}\r\nUSN: pung:{endpoint}::{SEARCH_TARGET}\r\n\r\n"
+    )
+}
+
+// Split an SSDP message into its start line and (upper-cased name, value) headers
+fn parse(text: &str) -> Option<(&str, Vec<(String, &str)>)> {
+    let mut lines = text.lines();
+    let start_line = lines.next()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_uppercase(), value.trim()))
+        .collect();
+    Some((start_line, headers))
+}
+
+fn header<'a>(headers: &[(String, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header_name, _)| header_name == name)
+        .map(|(_, value)| *value)
+}
@@ -0,0 +1,101 @@
+use crate::config;
+use crate::net::resolver;
+use crate::net::transport::SharedTransport;
+use crate::peer::{SharedPeerList, discovery};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time;
+
+const PEERS_FILE: &str = "peers.toml";
+// How often static peers that aren't in the peer list are contacted again
+const RECONTACT_INTERVAL: u64 = 60; // seconds
+
+/// Known hosts from ~/.config/pung/peers.toml, contacted directly instead of relying
+/// on broadcasts reaching them, e.g.
+///
+/// peers = ["nas.local", "192.168.1.20:9487"]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct PeersFile {
+    peers: Vec<String>,
+}
+
+fn peers_file() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join(PEERS_FILE))
+}
+
+/// Load the static peers, or nothing if the file is missing or invalid
+pub fn load() -> Vec<String> {
+    let Some(path) = peers_file() else {
+        return Vec::new();
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => match toml::from_str::<PeersFile>(&contents) {
+            Ok(file) => file.peers,
+            Err(e) => {
+                say!("Warning: Could not parse {}: {e}", path.display());
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Write the given hosts as the static peers, returning the file's path
+pub fn save(peers: Vec<String>) -> std::io::Result<PathBuf> {
+    let path = peers_file()
+        .ok_or_else(|| std::io::Error::other("could not determine the home directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let contents = toml::to_string(&PeersFile { peers }).map_err(std::io::Error::other)?;
+    std::fs::write(&path, contents)?;
+    Ok(path)
+}
+
+/// Contact every static peer now, and again whenever one is missing from the peer list
+pub fn start(
+    targets: Vec<String>,
+    transport: SharedTransport,
+    username: String,
+    local_addr: SocketAddr,
+    peer_list: SharedPeerList,
+) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(RECONTACT_INTERVAL));
+        loop {
+            interval.tick().await;
+            for target in &targets {
+                if is_connected(target, &peer_list).await {
+                    continue;
+                }
+                if let Err(e) =
+                    discovery::connect(target, transport.clone(), &username, local_addr, &peer_list)
+                        .await
+                {
+                    log::debug!("Could not contact static peer {target}: {e}");
+                }
+            }
+        }
+    });
+}
+
+// Whether any known peer lives on the target's host
+async fn is_connected(target: &str, peer_list: &SharedPeerList) -> bool {
+    // Resolving needs a port, any will do
+    let host = match target.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => target,
+    };
+    let Ok(addr) = resolver::resolve(&format!("{host}:0")).await else {
+        return false;
+    };
+    peer_list
+        .lock()
+        .await
+        .get_peers()
+        .iter()
+        .any(|peer| peer.addr.ip() == addr.ip())
+}
@@ -0,0 +1,161 @@
+use crate::events::{self, Event as AppEvent};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Events that can make a sound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Event {
+    Message,
+    Mention,
+    Join,
+    Leave,
+}
+
+impl Event {
+    pub const ALL: &[Event] = &[Event::Message, Event::Mention, Event::Join, Event::Leave];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Message => "message",
+            Event::Mention => "mention",
+            Event::Join => "join",
+            Event::Leave => "leave",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Event> {
+        Event::ALL
+            .iter()
+            .copied()
+            .find(|event| event.name() == name)
+    }
+
+    /// Its app_state key, shown by /set
+    pub fn pref_key(self) -> &'static str {
+        match self {
+            Event::Message => "pref:alert.message",
+            Event::Mention => "pref:alert.mention",
+            Event::Join => "pref:alert.join",
+            Event::Leave => "pref:alert.leave",
+        }
+    }
+}
+
+/// What an event sounds like: nothing, the terminal bell, or a shell command (e.g. one
+/// that plays a sound file)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sound {
+    Off,
+    Bell,
+    Command(String),
+}
+
+impl Sound {
+    pub fn parse(value: &str) -> Sound {
+        match value.trim() {
+            "" | "off" => Sound::Off,
+            "bell" => Sound::Bell,
+            command => Sound::Command(command.to_string()),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Sound::Off => "off".to_string(),
+            Sound::Bell => "bell".to_string(),
+            Sound::Command(command) => command.clone(),
+        }
+    }
+}
+
+// Whether an alert command is running; events while it is make no sound, so a burst of
+// chat doesn't start a shell for every message
+static COMMAND_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// /set bell off keeps the terminal bell quiet, whichever events are set to ring it
+static BELL: AtomicBool = AtomicBool::new(true);
+
+// Only mentions ring until told otherwise
+static SOUNDS: LazyLock<Mutex<HashMap<Event, Sound>>> =
+    LazyLock::new(|| Mutex::new(HashMap::from([(Event::Mention, Sound::Bell)])));
+
+pub fn set(event: Event, sound: Sound) {
+    if let Ok(mut sounds) = SOUNDS.lock() {
+        sounds.insert(event, sound);
+    }
+}
+
+pub fn set_bell(enabled: bool) {
+    BELL.store(enabled, Ordering::SeqCst);
+}
+
+pub fn bell() -> bool {
+    BELL.load(Ordering::SeqCst)
+}
+
+pub fn get(event: Event) -> Sound {
+    SOUNDS
+        .lock()
+        .ok()
+        .and_then(|sounds| sounds.get(&event).cloned())
+        .unwrap_or(Sound::Off)
+}
+
+/// Sound alerts for app events from now on
+pub fn start() {
+    let mut receiver = events::subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            match event {
+                AppEvent::ChatReceived { mentions_us: true } => play(Event::Mention),
+                AppEvent::ChatReceived { mentions_us: false } => play(Event::Message),
+                // Only peers that show up themselves, not the ones gossip tells us about
+                AppEvent::PeerDiscovered {
+                    placeholder: false, ..
+                } => play(Event::Join),
+                AppEvent::PeerDiscovered {
+                    placeholder: true, ..
+                } => {}
+                AppEvent::PeerLeft => play(Event::Leave),
+                AppEvent::ChatSent | AppEvent::CommandRun(_) | AppEvent::DiscoveryReply(_) => {}
+            }
+        }
+    });
+}
+
+// Make the event's sound, if it has one
+fn play(event: Event) {
+    match get(event) {
+        Sound::Off => {}
+        Sound::Bell if !bell() => {}
+        Sound::Bell => {
+            print!("\x07");
+            let _ = std::io::stdout().flush();
+        }
+        Sound::Command(_) if COMMAND_RUNNING.swap(true, Ordering::SeqCst) => {}
+        Sound::Command(command) => {
+            // Waited for off the async threads, so it doesn't linger as a zombie
+            tokio::task::spawn_blocking(move || {
+                let status = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .stdin(std::process::Stdio::null())
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .status();
+                if let Err(e) = status {
+                    log::debug!("Could not run alert command '{command}': {e}");
+                }
+                COMMAND_RUNNING.store(false, Ordering::SeqCst);
+            });
+        }
+    }
+}
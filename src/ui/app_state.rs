@@ -16,7 +16,7 @@ pub fn show_static_state(app_state: &DashMap<&str, String>) {
         .into_iter()
         .map(|entry| {
             format!(
-                "{:15} = {}",
+                "{:18} = {}",
                 entry
                     .key()
                     .replace("static:", "")
@@ -35,6 +35,7 @@ pub fn show_tips() {
     let startup_message: Vec<String> = vec![
         "1) use [/h] to show available commands".to_string(),
         "2) use [/v] to show version and check for updates".to_string(),
+        "3) new here? [/tour] walks you through the basics".to_string(),
     ];
     utils::display_message_block("Tips (/t)", startup_message);
 }
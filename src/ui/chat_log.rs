@@ -0,0 +1,76 @@
+use crate::utils;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// The file /log start appends chat to, until /log stop
+static LOG: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+/// Append every chat message, system message and peer event shown from now on to `path`,
+/// replacing any log already being written
+pub fn start(path: &Path) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    // Private conversations end up in it, so others can't read a log we create
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    let now = chrono::Utc::now().timestamp();
+    writeln!(
+        file,
+        "--- log started {} ---",
+        utils::iso_time_from_timestamp(now)
+    )?;
+    let mut log = LOG
+        .lock()
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    *log = Some((path.to_path_buf(), file));
+    Ok(())
+}
+
+/// Stop logging; returns the file that was being written, if any
+pub fn stop() -> Option<PathBuf> {
+    LOG.lock().ok()?.take().map(|(path, _)| path)
+}
+
+pub fn path() -> Option<PathBuf> {
+    LOG.lock().ok()?.as_ref().map(|(path, _)| path.clone())
+}
+
+/// Write a chat message to the log, if one is being written: its time, who it's from
+/// (e.g. "<team> alice" or "alice → you") and the address it came from
+pub fn record(timestamp: i64, from: &str, addr: Option<&str>, content: &str) {
+    let addr = addr.map(|addr| format!(" ({addr})")).unwrap_or_default();
+    write_line(timestamp, &format!("{from}{addr}: {content}"));
+}
+
+/// Write a system message or peer event to the log, if one is being written, as shown
+pub fn note(text: &str) {
+    write_line(chrono::Utc::now().timestamp(), text);
+}
+
+fn write_line(timestamp: i64, text: &str) {
+    let failed = {
+        let Ok(mut log) = LOG.lock() else {
+            return;
+        };
+        let Some((path, file)) = log.as_mut() else {
+            return;
+        };
+        let time = utils::iso_time_from_timestamp(timestamp);
+        match writeln!(file, "{time} {text}") {
+            Ok(()) => return,
+            Err(e) => {
+                let failed = format!("{}: {e}", path.display());
+                *log = None;
+                failed
+            }
+        }
+    };
+    // Only once the log is unlocked: this message is written to it too
+    say!("@@@ Stopped logging to {failed}");
+}
@@ -1,48 +1,236 @@
+use crate::DEFAULT_RECEIVE_PORT_RANGE;
 use crate::MAX_USERNAME_LEN;
 use crate::VERSION;
-use crate::peer::{SharedPeerList, discovery};
-use crate::ui;
-use crate::utils;
+use crate::features::{self, Feature};
+use crate::logger;
+use crate::message::{Availability, Presence};
+use crate::net::share::{self, ShareSource};
+use crate::net::stats::SharedNetStats;
+use crate::net::stream::{self, StreamSender};
+use crate::net::transport::SharedTransport;
+use crate::net::{ban, e2e, flood, identity, noise, tcp};
+use crate::peer::lifecycle::{self, PeerEvent};
+use crate::peer::peer_list::{Health, PeerInfo};
+use crate::peer::{
+    SharedPeerList, blocklist, discovery, dnssd, groups, heartbeats, known_keys, nick, scan,
+    static_peers,
+};
+use crate::ui::{self, alert, chat_log, mute, notify, privacy, session, theme};
+use crate::utils::{self, PortRange};
 use dashmap::DashMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::net::UdpSocket;
+use std::time::Duration;
+
+// Limits for /b bursts
+const MAX_BURST_COUNT: u32 = 10;
+const DEFAULT_BURST_INTERVAL: u64 = 1; // seconds
+const MAX_BURST_INTERVAL: u64 = 10; // seconds
+// Longer status notes would crowd /peers
+const MAX_STATUS_LEN: usize = 40;
+// /peers shows this many peers at a time
+const PEERS_PAGE_SIZE: usize = 20;
+// How many events /events shows without a count
+const DEFAULT_EVENT_COUNT: usize = 20;
+
+/// Every command, for tab completion; includes the ones the main loop handles itself
+pub const COMMANDS: &[&str] = &[
+    "/ban",
+    "/block",
+    "/broadcast",
+    "/clear",
+    "/connect",
+    "/debug",
+    "/dnssd",
+    "/events",
+    "/features",
+    "/flood",
+    "/forget",
+    "/g",
+    "/group",
+    "/help",
+    "/join",
+    "/log",
+    "/msg",
+    "/mute",
+    "/netstat",
+    "/nick",
+    "/notify",
+    "/panic",
+    "/peers",
+    "/privacy",
+    "/quit",
+    "/react",
+    "/rekey",
+    "/sas",
+    "/scan",
+    "/set",
+    "/share",
+    "/sleepy",
+    "/state",
+    "/stats",
+    "/status",
+    "/stream",
+    "/theme",
+    "/tips",
+    "/tour",
+    "/unban",
+    "/unblock",
+    "/unmute",
+    "/verify",
+    "/version",
+    "/whois",
+];
 
 pub async fn handle_command(
     input_line: &str,
     peer_list: SharedPeerList,
-    socket: Option<Arc<UdpSocket>>,
+    transport: Option<SharedTransport>,
     username: Option<String>,
     local_addr: Option<SocketAddr>,
     app_state: Arc<DashMap<&str, String>>,
+    net_stats: SharedNetStats,
 ) -> Option<String> {
     // Extract the command part (first word) for matching
     let command = input_line.split_whitespace().next().unwrap_or("");
 
     match command {
         "/peers" | "/p" => {
-            let peers = peer_list.lock().await.get_peers();
+            let (mut peers, display_names) = {
+                let peer_list = peer_list.lock().await;
+                (peer_list.get_peers(), peer_list.display_names())
+            };
+            if input_line.split_whitespace().nth(1) == Some("save") {
+                // Save hosts rather than addresses; receive ports change on every start
+                let mut hosts: Vec<String> = peers
+                    .iter()
+                    .map(|peer| {
+                        peer.hostname
+                            .clone()
+                            .unwrap_or_else(|| peer.addr.ip().to_string())
+                    })
+                    .collect();
+                hosts.sort();
+                hosts.dedup();
+                if hosts.is_empty() {
+                    return Some("@@@ No peers to save.".to_string());
+                }
+                let count = hosts.len();
+                return match static_peers::save(hosts) {
+                    Ok(path) => Some(format!(
+                        "@@@ Saved {count} static peer(s) to {}",
+                        path.display()
+                    )),
+                    Err(e) => Some(format!("@@@ Could not save static peers: {e}")),
+                };
+            }
             if peers.is_empty() {
-                Some("@@@ No peers connected.".to_string())
-            } else {
-                utils::display_message_block(
-                    "Peers (/p)",
-                    peers
-                        .iter()
-                        .enumerate() // Add enumeration to get index
-                        .map(|(i, peer)| {
-                            format!(
-                                "{}) {:15} @ {:20} ({}s ago)",
-                                i + 1, // Add 1 to make it 1-based instead of 0-based
-                                peer.username,
-                                peer.addr,
-                                peer.last_seen.elapsed().as_secs()
-                            )
+                return Some("@@@ No peers connected.".to_string());
+            }
+
+            // e.g. /p sort:last_seen filter:room=ops page:2
+            let usage = "@@@ Usage: /p [sort:name|addr|last_seen|first_seen|rtt|loss] [filter:name|room|health|status|version=<value>] [page:<n>]";
+            let mut sort = "name";
+            let mut page = 1;
+            for arg in input_line.split_whitespace().skip(1) {
+                if let Some(key) = arg.strip_prefix("sort:") {
+                    sort = key;
+                } else if let Some((field, value)) =
+                    arg.strip_prefix("filter:").and_then(|f| f.split_once('='))
+                {
+                    let mut unknown_field = false;
+                    peers.retain(|peer| {
+                        peer_matches(peer, field, value).unwrap_or_else(|| {
+                            unknown_field = true;
+                            false
                         })
-                        .collect(),
-                );
-                None
+                    });
+                    if unknown_field {
+                        return Some(usage.to_string());
+                    }
+                } else if let Some(n) = arg.strip_prefix("page:").and_then(|n| n.parse().ok())
+                    && n > 0
+                {
+                    page = n;
+                } else {
+                    return Some(usage.to_string());
+                }
             }
+            if !sort_peers(&mut peers, sort) {
+                return Some(usage.to_string());
+            }
+            if peers.is_empty() {
+                return Some("@@@ No peers match.".to_string());
+            }
+            let pages = peers.len().div_ceil(PEERS_PAGE_SIZE);
+            if page > pages {
+                return Some(format!("@@@ There are only {pages} page(s) of peers."));
+            }
+
+            let first = (page - 1) * PEERS_PAGE_SIZE;
+            let mut lines: Vec<String> = peers
+                .iter()
+                .enumerate() // Add enumeration to get index
+                .skip(first)
+                .take(PEERS_PAGE_SIZE)
+                .map(|(i, peer)| {
+                    format!(
+                        "{}) {} {:15} @ {:20} ({}s ago, loss {}){}{}{}{}{}{}{}{}{}",
+                        i + 1, // Add 1 to make it 1-based instead of 0-based
+                        health_dot(peer.health),
+                        display_names.get(&peer.addr).unwrap_or(&peer.username),
+                        privacy::addr(peer.addr, peer.node_id.as_deref()),
+                        peer.last_seen.elapsed().as_secs(),
+                        peer.loss_percent()
+                            .map(|loss| format!("{loss}%"))
+                            .unwrap_or_else(|| "?".to_string()),
+                        peer.presence
+                            .as_ref()
+                            .map(|presence| format!(" {}", presence_tag(presence)))
+                            .unwrap_or_default(),
+                        peer.version
+                            .as_ref()
+                            .map(|version| format!(" [v{version}]"))
+                            .unwrap_or_default(),
+                        if peer.is_behind_nat() { " [NAT]" } else { "" },
+                        if peer.is_sleepy { " [sleepy]" } else { "" },
+                        if peer.is_provisional {
+                            " [pending]"
+                        } else {
+                            ""
+                        },
+                        if peer.is_plaintext {
+                            " [unencrypted]"
+                        } else {
+                            ""
+                        },
+                        if blocklist::is_blocked(&peer.username, Some(peer.addr.ip())) {
+                            " [blocked]"
+                        } else {
+                            ""
+                        },
+                        if peer.key_changed {
+                            " [KEY CHANGED]"
+                        } else if peer.is_verified {
+                            " [verified]"
+                        } else {
+                            ""
+                        },
+                        peer.hostname
+                            .as_ref()
+                            .map(|host| format!(" [{host}]"))
+                            .unwrap_or_default()
+                    )
+                })
+                .collect();
+            if pages > 1 {
+                lines.push(format!(
+                    "page {page} of {pages}; /p page:<n> for the others"
+                ));
+            }
+            utils::display_message_block("Peers (/p)", lines);
+            None
         }
         "/quit" | "/q" => Some("exit".to_string()),
         "/help" | "/h" => {
@@ -51,18 +239,84 @@ pub async fn handle_command(
                 format!("    -u <username>         ─ Sets the username for chat; max length: {MAX_USERNAME_LEN}").to_string(),
                 "    -r <receive-port>     ─ Sets the port for receiving messages (random if not specified)".to_string(),
                 "    -w <width>            ─ Sets the terminal width for message display (default: 80)".to_string(),
+                "    -c <codec>            ─ Sets the wire codec: bincode, json or cbor (default: bincode)".to_string(),
+                "    --receive-port-range  ─ Range random receive ports are picked from (default: 10000-20000)".to_string(),
+                "    --send-port-range     ─ Range random send ports are picked from (default: 20001-30000)".to_string(),
+                "    --network <id>        ─ Drops messages from other network IDs (default: public)".to_string(),
+                "    --secret <phrase>     ─ Only peers with instances that know the same secret".to_string(),
+                "    --psk <passphrase>    ─ Encrypts all traffic with a key derived from the passphrase".to_string(),
+                "    --room <room>         ─ Only peers with instances in the same room".to_string(),
+                "    --room-password <pw>  ─ Encrypts the room's traffic; only instances with the password see it".to_string(),
+                "    --bind <ip>           ─ Binds the sockets to one local address instead of all interfaces".to_string(),
+                "    --dnssd-domain <dom>  ─ Finds peers through DNS-SD records in a domain, across subnets".to_string(),
+                "    --rendezvous <addr>   ─ Finds peers through a rendezvous server, e.g. across VPNs".to_string(),
+                "    --scan-on-start       ─ Probes the receive port range on your /24 right away (see /scan)".to_string(),
+                "    --max-peers <count>   ─ Caps the peer list, dropping the least recently heard (default: 256)".to_string(),
+                "    --heartbeat-interval  ─ Seconds between heartbeats (default: 6)".to_string(),
+                "    --peer-timeout <s>    ─ Seconds before a silent peer is dropped, >= 2.5 intervals (default: 15)".to_string(),
+                "    --grace-period <s>    ─ Seconds a removed peer isn't re-added from others' lists (default: 30)".to_string(),
+                "    --dscp <class>        ─ Marks outgoing packets with a DSCP class, e.g. AF21 or EF".to_string(),
+                "    --syslog              ─ Mirrors chat and peer events to syslog/journald".to_string(),
+                "    --sleepy              ─ Asks peers for a longer timeout, for machines that suspend often".to_string(),
+                "    --tz <tz>             ─ Timezone for message times: local (default), utc or an offset like +8".to_string(),
+                "    --privacy             ─ Never shows peer addresses, only a short hash of each peer's node ID".to_string(),
+                "    --tui                 ─ Full-screen layout with a chat pane, peer sidebar and input box".to_string(),
+                "    --simulate <spec>     ─ Simulates a bad network, e.g. loss=10%,delay=50ms,jitter=20ms".to_string(),
+                "    --disable-features    ─ Switches off optional features, e.g. stream,side-channel".to_string(),
                 "".to_string(),
                 "    Example:".to_string(),
                 "        ./pung -u pungman -w 90".to_string(),
+                "        ./pung rendezvous --port 9500   (runs a rendezvous server, no chat)".to_string(),
                 "".to_string(),
                 "".to_string(),
                 "Available commands:".to_string(),
-                "    /[ b | broadcast ]    ─ Manually send a discovery broadcast to find peers".to_string(),
+                "    /ban <ip|cidr> | list ─ Drop all traffic from an IP or subnet, e.g. /ban 10.0.0.0/8".to_string(),
+                "    /block [user|ip]      ─ Ignore a peer's chat and discovery, or list blocked peers".to_string(),
+                "    /[ b | broadcast ]    ─ Send a discovery broadcast and report who replies".to_string(),
+                "    /b [count] [interval] ─ Send a burst of broadcasts, interval seconds apart (default: 1)".to_string(),
+                "    /clear [counters]     ─ Clear the screen; with counters, also reset the message and traffic counters".to_string(),
+                "    /connect <host>       ─ Contact a peer (host or host:port) when broadcasts don't reach it".to_string(),
+                "    /debug [level] [chat] ─ Log at trace, debug, info or off, to stderr or with chat into the chat view".to_string(),
+                "    /dnssd                ─ Show the DNS records that publish you under --dnssd-domain".to_string(),
+                "    /events [count]       ─ Show the latest peer events: joins, renames, timeouts... (default: 20)".to_string(),
+                "    /features [on|off f]  ─ Show optional features and which peers support them, or switch feature f".to_string(),
+                "    /flood                ─ Show sources ignored for flooding us with messages".to_string(),
+                "    /forget <user|all>    ─ Drop peers from the list now instead of waiting for a timeout".to_string(),
+                "    /g <group> <message>  ─ Send a message to the online members of a group".to_string(),
+                "    /group add|remove     ─ Manage groups, e.g. /group add devs alice bob; /group lists them".to_string(),
                 "    /[ h | help ]         ─ Show this help message".to_string(),
+                "    /join [room]          ─ Switch rooms, asking for the room's password; without a room, leave it".to_string(),
+                "    /log start <f> | stop ─ Append chat and events to file f, with full times and sender addresses".to_string(),
+                "    /msg <user> <message> ─ Send a private, end-to-end encrypted message to one peer".to_string(),
+                "    /mute [user] [time]   ─ Hide a peer's chat, e.g. /mute bob 10m (default: until /unmute)".to_string(),
+                "    /netstat              ─ Show traffic statistics per peer".to_string(),
+                "    /nick <username>      ─ Change your username without peers losing track of you".to_string(),
+                "    /notify <mode>        ─ Desktop notifications for DMs and @mentions: on, off or mentions-only".to_string(),
+                "    /panic [scope]        ─ Clear the screen, delete keys and history (keys, state or all) and quit".to_string(),
                 "    /[ p | peers ]        ─ Show list of connected peers".to_string(),
+                "    /p [sort|filter|page] ─ e.g. /p sort:last_seen filter:room=ops page:2 (sort: name, addr, rtt...)".to_string(),
+                "    /peers save           ─ Save the current peers to peers.toml, to contact them on startup".to_string(),
+                "    /privacy on|off       ─ Hide peer addresses, e.g. while sharing your screen".to_string(),
                 "    /[ q | quit ]         ─ Quit the application".to_string(),
+                "    /react [n] <emoji>    ─ React to the latest or nth latest message; 1-5 for 👍 ❤️ 😂 😮 🎉 (TUI: Alt+↑, then 1-5)".to_string(),
+                "    /rekey <user|all>     ─ Set up new encryption keys with a peer now, instead of hourly".to_string(),
+                "    /sas <user>           ─ Verify a peer by reading out emoji together, instead of a fingerprint".to_string(),
+                "    /set [key] [value]    ─ Change a preference, e.g. /set alert.mention bell; /set lists them all".to_string(),
+                "    /share start|stop     ─ Share what you type with peers (or /share tail <path>)".to_string(),
+                "    /sleepy <username>    ─ Toggle a longer, silent timeout for a peer that naps".to_string(),
+                "    /scan [stop]          ─ Probe the receive port range on your /24, if broadcasts are blocked".to_string(),
                 "    /[ s | state ]        ─ Show application state".to_string(),
+                "    /stats                ─ Show session statistics: messages, traffic, peers seen, uptime".to_string(),
+                "    /status [mode] [note] ─ Set yourself online, away or busy, with an optional note".to_string(),
+                "    /stream <command>     ─ Run a shell command and stream its output to peers".to_string(),
+                "    /theme [name]         ─ Switch color themes: dark, light, mono or your own from config.toml".to_string(),
                 "    /[ t | tips ]         ─ Show tips".to_string(),
+                "    /tour [stop]          ─ Take a step-by-step tour of the basics".to_string(),
+                "    /unban <ip|cidr>      ─ Lift a ban from /ban".to_string(),
+                "    /unblock <user|ip>    ─ Unblock a peer blocked with /block".to_string(),
+                "    /unmute <user>        ─ Show a muted peer's chat again".to_string(),
+                "    /verify <user>        ─ Compare key fingerprints with a peer, then /verify <user> confirm".to_string(),
+                "    /whois <user|addr>    ─ Show everything known about a peer".to_string(),
                 "    /[ v | version ]      ─ Show version and check for updates".to_string(),
                 "".to_string(),
                 "".to_string(),
@@ -73,26 +327,96 @@ pub async fn handle_command(
             None
         }
         "/broadcast" | "/b" => {
+            let mut args = input_line.split_whitespace().skip(1);
+            let count = match args.next().map(str::parse::<u32>) {
+                None => 1,
+                Some(Ok(count)) if (1..=MAX_BURST_COUNT).contains(&count) => count,
+                _ => {
+                    return Some(format!(
+                        "@@@ Usage: /b [count] [interval] (count 1-{MAX_BURST_COUNT}, interval in seconds)"
+                    ));
+                }
+            };
+            let interval = match args.next().map(str::parse::<u64>) {
+                None => DEFAULT_BURST_INTERVAL,
+                Some(Ok(interval)) if interval <= MAX_BURST_INTERVAL => interval,
+                _ => {
+                    return Some(format!(
+                        "@@@ Usage: /b [count] [interval] (interval 0-{MAX_BURST_INTERVAL} seconds)"
+                    ));
+                }
+            };
+
             // Check if we have all the required parameters
-            if let (Some(socket), Some(username), Some(addr)) = (socket, username, local_addr) {
-                match discovery::start_discovery(socket, username, addr).await {
-                    Ok(_) => {
-                        Some("@@@ Discovery broadcast sent. Searching for peers...".to_string())
+            if let (Some(transport), Some(username), Some(addr)) = (transport, username, local_addr)
+            {
+                // Collect replies in the background, so the prompt stays usable
+                tokio::spawn(async move {
+                    match discovery::broadcast_burst(
+                        transport,
+                        &username,
+                        addr,
+                        count,
+                        Duration::from_secs(interval),
+                    )
+                    .await
+                    {
+                        Ok(repliers) if repliers.is_empty() => {
+                            say!("@@@ No replies to the discovery broadcast");
+                        }
+                        Ok(repliers) => {
+                            let mut names: Vec<String> = repliers.into_iter().collect();
+                            names.sort();
+                            say!(
+                                "@@@ {} peer(s) replied to the discovery broadcast: {}",
+                                names.len(),
+                                names.join(", ")
+                            );
+                        }
+                        Err(e) => say!("@@@ Failed to send discovery broadcast: {e}"),
                     }
-                    Err(e) => Some(format!("@@@ Failed to send discovery broadcast: {e}")),
-                }
+                });
+                Some(format!(
+                    "@@@ Sending {count} discovery broadcast(s). Searching for peers..."
+                ))
             } else {
                 Some("@@@ Cannot send broadcast: missing required parameters".to_string())
             }
         }
+        "/scan" => {
+            if input_line.split_whitespace().nth(1) == Some("stop") {
+                return Some(if scan::stop() {
+                    "@@@ Stopping the scan...".to_string()
+                } else {
+                    "@@@ No scan is running".to_string()
+                });
+            }
+            let (Some(transport), Some(username), Some(addr)) = (transport, username, local_addr)
+            else {
+                return Some("@@@ Cannot scan: missing required parameters".to_string());
+            };
+            let ports = app_state
+                .get("static:receive_port_range")
+                .and_then(|range| PortRange::parse(&range).ok())
+                .unwrap_or(DEFAULT_RECEIVE_PORT_RANGE);
+            match scan::start(transport, username, addr, ports) {
+                Some(probes) => Some(format!(
+                    "@@@ Scanning ports {ports} on the local /24 ({probes} probes, about {} min); /scan stop to cancel",
+                    scan::duration_of(probes).as_secs().div_ceil(60)
+                )),
+                None => Some("@@@ A scan is already running; /scan stop to cancel it".to_string()),
+            }
+        }
         "/version" | "/v" => {
             // Don't check for updates if we're running from source
+            #[allow(clippy::collapsible_if)]
             if VERSION != "0.0.0" {
                 if let Some(latest_version) = utils::check_for_updates(VERSION).await {
                     let mut new_version_message: Vec<String> = vec![];
                     new_version_message.push("New version available!".to_string());
                     new_version_message
                         .push(format!("- Update: [{VERSION}] -> [{latest_version}]"));
+                    new_version_message.push("- Release signature: verified".to_string());
                     new_version_message.push("".to_string());
                     new_version_message.push("Download the latest version from:".to_string());
                     new_version_message
@@ -105,6 +429,1017 @@ pub async fn handle_command(
             }
             Some(format!("@@@ Version: {VERSION}"))
         }
+        "/clear" => {
+            let reset_counters = match input_line.split_whitespace().nth(1) {
+                None => false,
+                Some("counters") => true,
+                Some(_) => return Some("@@@ Usage: /clear [counters]".to_string()),
+            };
+            // The unread count resets with any command; the status bar redraws on its next tick
+            ui::wipe::clear_terminal();
+            if !reset_counters {
+                return None;
+            }
+            session::reset();
+            match net_stats.lock() {
+                Ok(mut stats) => {
+                    stats.reset();
+                    Some("@@@ Counters reset.".to_string())
+                }
+                Err(_) => Some("@@@ Traffic statistics are unavailable".to_string()),
+            }
+        }
+        "/connect" => {
+            let Some(target) = input_line.split_whitespace().nth(1) else {
+                return Some("@@@ Usage: /connect <host[:port]>".to_string());
+            };
+            if let (Some(transport), Some(username), Some(addr)) = (transport, username, local_addr)
+            {
+                match discovery::connect(target, transport, &username, addr, &peer_list).await {
+                    Ok(resolved) => Some(format!("@@@ Sent discovery to {target} ({resolved})")),
+                    Err(e) => Some(format!("@@@ Could not contact {target}: {e}")),
+                }
+            } else {
+                Some("@@@ Cannot connect: missing required parameters".to_string())
+            }
+        }
+        "/events" => {
+            let count = match input_line.split_whitespace().nth(1) {
+                Some(count) => match count.parse::<usize>() {
+                    Ok(count) => count,
+                    Err(_) => return Some("@@@ Usage: /events [count]".to_string()),
+                },
+                None => DEFAULT_EVENT_COUNT,
+            };
+            let events = lifecycle::recent(count);
+            if events.is_empty() {
+                return Some("@@@ No peer events yet".to_string());
+            }
+            utils::display_message_block("Peer events (/events)", events);
+            None
+        }
+        "/features" => {
+            let mut args = input_line.split_whitespace().skip(1);
+            match (args.next(), args.next()) {
+                (None, _) => {}
+                (Some(switch @ ("on" | "off")), Some(name)) => {
+                    let Some(feature) = Feature::by_name(name) else {
+                        let names: Vec<_> = Feature::ALL.iter().map(|f| f.name()).collect();
+                        return Some(format!(
+                            "@@@ Unknown feature {name} (available: {})",
+                            names.join(", ")
+                        ));
+                    };
+                    if let Err(e) = features::set_active(feature, switch == "on") {
+                        return Some(format!("@@@ Can't switch {name} {switch}: {e}"));
+                    }
+                    app_state.insert("static:features", features::capabilities().join(", "));
+                    if let Some(tcp_port) = tcp::advertised_port() {
+                        app_state.insert("static:tcp_port", tcp_port.to_string());
+                    } else {
+                        app_state.remove("static:tcp_port");
+                    }
+                    return Some(format!(
+                        "@@@ Switched {name} {switch}; peers learn of it with our next heartbeat"
+                    ));
+                }
+                _ => return Some("@@@ Usage: /features [on|off <feature>]".to_string()),
+            }
+            let mut lines: Vec<String> = Feature::ALL
+                .iter()
+                .map(|feature| {
+                    let status = if !feature.is_compiled() {
+                        "not compiled in"
+                    } else if features::is_active(*feature) {
+                        "active"
+                    } else {
+                        "disabled"
+                    };
+                    format!("{:15} {status}", feature.name())
+                })
+                .collect();
+
+            let peers = peer_list.lock().await.get_peers();
+            if !peers.is_empty() {
+                lines.push("".to_string());
+                lines.push("Peers:".to_string());
+                for peer in peers {
+                    let capabilities = match &peer.capabilities {
+                        Some(capabilities) if capabilities.is_empty() => "(none)".to_string(),
+                        Some(capabilities) => capabilities.join(", "),
+                        None => "(not advertised)".to_string(),
+                    };
+                    lines.push(format!("{:15} {capabilities}", peer.username));
+                }
+            }
+            utils::display_message_block("Features (/features)", lines);
+            None
+        }
+        "/log" => {
+            let usage = "@@@ Usage: /log start <path> | /log stop";
+            let rest = input_line
+                .trim_start()
+                .strip_prefix("/log")
+                .unwrap_or("")
+                .trim();
+            match rest.split_once(' ').unwrap_or((rest, "")) {
+                ("", _) => Some(match chat_log::path() {
+                    Some(path) => format!("@@@ Logging chat to {}", path.display()),
+                    None => "@@@ Not logging chat; /log start <path> does".to_string(),
+                }),
+                ("start", path) if !path.trim().is_empty() => {
+                    let path = PathBuf::from(path.trim());
+                    match chat_log::start(&path) {
+                        Ok(()) => Some(format!("@@@ Logging chat to {}", path.display())),
+                        Err(e) => Some(format!("@@@ Could not open {}: {e}", path.display())),
+                    }
+                }
+                ("stop", "") => Some(match chat_log::stop() {
+                    Some(path) => format!("@@@ Stopped logging to {}", path.display()),
+                    None => "@@@ Not logging chat.".to_string(),
+                }),
+                _ => Some(usage.to_string()),
+            }
+        }
+        "/debug" => {
+            let mut args = input_line.split_whitespace().skip(1);
+            let Some(level) = args.next() else {
+                return Some(match (logger::level(), logger::in_chat()) {
+                    (log::LevelFilter::Off, _) => "@@@ Logging off".to_string(),
+                    (level, true) => format!(
+                        "@@@ Logging at {} into the chat view",
+                        level.as_str().to_lowercase()
+                    ),
+                    (level, false) => {
+                        format!("@@@ Logging at {} to stderr", level.as_str().to_lowercase())
+                    }
+                });
+            };
+            let in_chat = match args.next() {
+                None => false,
+                Some("chat") => true,
+                Some(_) => {
+                    return Some("@@@ Usage: /debug [trace|debug|info|off] [chat]".to_string());
+                }
+            };
+            let Some(level) = logger::parse_level(level) else {
+                return Some("@@@ Usage: /debug [trace|debug|info|off] [chat]".to_string());
+            };
+            if in_chat && privacy::is_enabled() {
+                return Some(
+                    "@@@ Log lines show addresses; turn privacy mode off to log into the chat view"
+                        .to_string(),
+                );
+            }
+            logger::set(level, in_chat);
+            let name = level.as_str().to_lowercase();
+            if level == log::LevelFilter::Off {
+                Some("@@@ Logging off".to_string())
+            } else if in_chat {
+                Some(format!(
+                    "@@@ Logging at {name} into the chat view, as %%% lines"
+                ))
+            } else {
+                Some(format!("@@@ Logging at {name} to stderr"))
+            }
+        }
+        "/dnssd" => {
+            let Some(domain) = app_state
+                .get("static:dnssd_domain")
+                .map(|domain| domain.clone())
+            else {
+                return Some(
+                    "@@@ No DNS-SD domain set; start with --dnssd-domain <domain>".to_string(),
+                );
+            };
+            let (Some(username), Some(addr)) = (username, local_addr) else {
+                return Some(
+                    "@@@ Cannot show DNS-SD records: missing required parameters".to_string(),
+                );
+            };
+            let mut lines = vec![
+                format!("Add these to the {domain} zone so peers in other subnets find you."),
+                "Start with -r <receive-port> so the published port stays the same.".to_string(),
+                "".to_string(),
+            ];
+            lines.extend(dnssd::records(&domain, &username, addr));
+            utils::display_message_block("DNS-SD (/dnssd)", lines);
+            None
+        }
+        "/netstat" => {
+            let entries = match net_stats.lock() {
+                Ok(stats) => stats.entries(),
+                Err(_) => return Some("@@@ Traffic statistics are unavailable".to_string()),
+            };
+            if entries.is_empty() {
+                return Some("@@@ No traffic recorded yet.".to_string());
+            }
+            let mut lines = vec![format!(
+                "{:22} {:>10} {:>7} {:>10} {:>7} {:>6} {:>8}",
+                "peer", "sent", "pkts", "recv", "pkts", "errors", "last"
+            )];
+            for (addr, stats) in entries {
+                // Show the username when the address belongs to a known peer
+                let name = match addr.parse::<SocketAddr>() {
+                    Ok(socket_addr) => peer_list.lock().await.find_username_by_addr(&socket_addr),
+                    Err(_) => None,
+                };
+                lines.push(format!(
+                    "{:22} {:>10} {:>7} {:>10} {:>7} {:>6} {:>8}",
+                    name.unwrap_or_else(|| privacy::addr(&addr, None)),
+                    format_bytes(stats.bytes_sent),
+                    stats.packets_sent,
+                    format_bytes(stats.bytes_received),
+                    stats.packets_received,
+                    stats.decode_failures,
+                    stats
+                        .last_activity
+                        .map(|t| format!("{}s ago", t.elapsed().as_secs()))
+                        .unwrap_or_else(|| "-".to_string()),
+                ));
+            }
+            utils::display_message_block("Traffic (/netstat)", lines);
+            None
+        }
+        "/stats" => {
+            let (bytes_sent, bytes_received) = match net_stats.lock() {
+                Ok(stats) => {
+                    stats
+                        .entries()
+                        .iter()
+                        .fold((0, 0), |(sent, received), (_, traffic)| {
+                            (sent + traffic.bytes_sent, received + traffic.bytes_received)
+                        })
+                }
+                Err(_) => return Some("@@@ Traffic statistics are unavailable".to_string()),
+            };
+            let uptime = session::uptime().as_secs();
+            let lines = vec![
+                format!(
+                    "uptime            = {}h {:02}m {:02}s",
+                    uptime / 3600,
+                    uptime / 60 % 60,
+                    uptime % 60
+                ),
+                format!("messages sent     = {}", session::messages_sent()),
+                format!("messages received = {}", session::messages_received()),
+                format!("bytes sent        = {}", format_bytes(bytes_sent)),
+                format!("bytes received    = {}", format_bytes(bytes_received)),
+                format!(
+                    "peers seen        = {} ({} now)",
+                    session::peers_seen(),
+                    peer_list.lock().await.get_peers().len()
+                ),
+                format!("discovery rounds  = {}", discovery::rounds()),
+            ];
+            utils::display_message_block("Session (/stats)", lines);
+            None
+        }
+        "/whois" => {
+            let target = input_line.strip_prefix("/whois").unwrap_or("").trim();
+            if target.is_empty() {
+                return Some("@@@ Usage: /whois <username|address>".to_string());
+            }
+            let peers = peer_list.lock().await.find_matching(target);
+            if peers.is_empty() {
+                return Some(format!("@@@ No peer named {target}"));
+            }
+            let traffic = net_stats
+                .lock()
+                .map(|stats| stats.entries())
+                .unwrap_or_default();
+            for peer in peers {
+                let ago = |seconds: u64| format!("{seconds}s ago");
+                let mut flags = Vec::new();
+                for (set, flag) in [
+                    (peer.is_provisional, "pending"),
+                    (peer.is_behind_nat(), "NAT"),
+                    (peer.is_sleepy, "sleepy"),
+                    (peer.is_plaintext, "unencrypted"),
+                    (peer.is_verified, "verified"),
+                    (peer.key_changed, "KEY CHANGED"),
+                    (
+                        blocklist::is_blocked(&peer.username, Some(peer.addr.ip())),
+                        "blocked",
+                    ),
+                    (mute::is_muted(&peer.username), "muted"),
+                ] {
+                    if set {
+                        flags.push(flag);
+                    }
+                }
+                let (sent, received) = traffic
+                    .iter()
+                    .find(|(addr, _)| *addr == peer.addr.to_string())
+                    .map(|(_, stats)| (stats.packets_sent, stats.packets_received))
+                    .unwrap_or_default();
+
+                let lines = vec![
+                    format!(
+                        "{:14} : {}",
+                        "address",
+                        privacy::addr(peer.addr, peer.node_id.as_deref())
+                    ),
+                    format!("{:14} : {}", "username", peer.username),
+                    format!(
+                        "{:14} : {}",
+                        "node id",
+                        peer.node_id.as_deref().unwrap_or("?")
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "signing key",
+                        peer.public_key.as_deref().unwrap_or("? (unsigned)")
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "offered key",
+                        peer.offered_key
+                            .as_ref()
+                            .map_or("-".to_string(), |key| format!(
+                                "{key} (not used until /verify)"
+                            ))
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "end-to-end",
+                        // The key rotates every few minutes; one that stopped doing so
+                        // means we stopped hearing the peer's heartbeats
+                        match peer.e2e_key_since {
+                            Some(since) if e2e::can_seal_for(&peer) => format!(
+                                "yes, to a key it advertised {}",
+                                ago(since.elapsed().as_secs())
+                            ),
+                            _ => "no".to_string(),
+                        }
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "claimed as",
+                        peer.claimed_username.as_deref().unwrap_or("-")
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "hostname",
+                        peer.hostname.as_deref().unwrap_or("-")
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "first seen",
+                        ago(peer.first_seen.elapsed().as_secs())
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "last seen",
+                        ago(peer.last_seen.elapsed().as_secs())
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "rtt",
+                        peer.rtt
+                            .map(|rtt| format!("{} ms", rtt.as_millis()))
+                            .unwrap_or_else(|| "?".to_string())
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "loss",
+                        peer.loss_percent()
+                            .map(|loss| format!("{loss}%"))
+                            .unwrap_or_else(|| "?".to_string())
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "health",
+                        peer.health.map(Health::name).unwrap_or("?")
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "version",
+                        peer.version.as_deref().unwrap_or("?")
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "protocol",
+                        peer.protocol_range
+                            .map(|(min, max)| format!("v{min}-v{max}"))
+                            .unwrap_or_else(|| "?".to_string())
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "capabilities",
+                        peer.capabilities
+                            .as_ref()
+                            .map(|caps| caps.join(", "))
+                            .unwrap_or_else(|| "?".to_string())
+                    ),
+                    format!(
+                        "{:14} : {}",
+                        "presence",
+                        peer.presence
+                            .as_ref()
+                            .map(presence_tag)
+                            .unwrap_or_else(|| "?".to_string())
+                    ),
+                    format!("{:14} : {}", "room", peer.room.as_deref().unwrap_or("-")),
+                    format!("{:14} : {}", "chats", peer.chats_received),
+                    format!("{:14} : {sent} sent, {received} received", "packets"),
+                    format!(
+                        "{:14} : {}",
+                        "flags",
+                        if flags.is_empty() {
+                            "-".to_string()
+                        } else {
+                            flags.join(", ")
+                        }
+                    ),
+                ];
+                utils::display_message_block("Whois (/whois)", lines);
+            }
+            None
+        }
+        "/rekey" => {
+            let target = input_line.strip_prefix("/rekey").unwrap_or("").trim();
+            if target.is_empty() {
+                return Some("@@@ Usage: /rekey <username|address|all>".to_string());
+            }
+            let Some(layer) = noise::layer() else {
+                return Some("@@@ Encryption is off, there are no session keys".to_string());
+            };
+            let rekeyed = if target == "all" {
+                layer.rekey(None)
+            } else {
+                let peers = peer_list.lock().await.find_matching(target);
+                if peers.is_empty() {
+                    return Some(format!("@@@ No peer named {target}"));
+                }
+                peers
+                    .iter()
+                    .map(|peer| layer.rekey(Some(&peer.addr.to_string())))
+                    .sum()
+            };
+            Some(format!(
+                "@@@ Replaced {rekeyed} session(s); new keys are agreed on with the next message"
+            ))
+        }
+        "/verify" => {
+            let args: Vec<&str> = input_line.split_whitespace().skip(1).collect();
+            let (target, confirm) = match args.as_slice() {
+                [target] => (*target, false),
+                [target, "confirm"] => (*target, true),
+                _ => return Some("@@@ Usage: /verify <username|address> [confirm]".to_string()),
+            };
+            let peers = peer_list.lock().await.find_matching(target);
+            let peer = match peers.as_slice() {
+                [] => return Some(format!("@@@ No peer named {target}")),
+                [peer] => peer,
+                _ => {
+                    return Some(format!(
+                        "@@@ {target} matches several peers; use its name#n or address (see /peers)"
+                    ));
+                }
+            };
+            let Some(key) = peer.key_to_verify() else {
+                return Some(format!(
+                    "@@@ {target} doesn't sign its messages, there's no key to verify"
+                ));
+            };
+
+            if confirm {
+                let peer_id = known_keys::peer_id(peer.node_id.as_deref(), &peer.username);
+                return Some(match known_keys::verify(peer_id, &peer.username, key) {
+                    Ok(()) => {
+                        peer_list.lock().await.set_verified(&peer.addr, key);
+                        format!("@@@ Marked {target} as verified")
+                    }
+                    Err(e) => format!("@@@ Could not save the known keys: {e}"),
+                });
+            }
+            let mut lines = Vec::new();
+            if peer.key_changed {
+                lines.push("Its key CHANGED since you first saw it!".to_string());
+                lines.push("".to_string());
+            }
+            lines.push(format!("Fingerprint for you and {target}:"));
+            lines.push("".to_string());
+            lines.push(format!("    {}", identity::fingerprint(key)));
+            lines.push("".to_string());
+            lines.push(format!(
+                "{target} sees the same one with /verify <your name> if nobody is in between."
+            ));
+            lines.push("Compare them in person or over the phone, then:".to_string());
+            lines.push(format!("    /verify {target} confirm"));
+            utils::display_message_block("Verify (/verify)", lines);
+            None
+        }
+        "/block" => {
+            let target = input_line.strip_prefix("/block").unwrap_or("").trim();
+            if target.is_empty() {
+                let blocked = blocklist::list();
+                if blocked.is_empty() {
+                    return Some("@@@ Nobody is blocked. Usage: /block <username|ip>".to_string());
+                }
+                utils::display_message_block("Blocked (/block)", blocked);
+                return None;
+            }
+            Some(match blocklist::block(target) {
+                Ok(true) => {
+                    let addr = peer_list
+                        .lock()
+                        .await
+                        .get_peers()
+                        .into_iter()
+                        .find(|peer| {
+                            peer.username == target || peer.addr.ip().to_string() == target
+                        })
+                        .map_or("-".to_string(), |peer| peer.addr.to_string());
+                    lifecycle::record(PeerEvent::Blocked, target, &addr);
+                    format!("@@@ Blocked {target}; /unblock {target} to undo")
+                }
+                Ok(false) => format!("@@@ {target} is already blocked"),
+                Err(e) => format!("@@@ Could not save the blocklist: {e}"),
+            })
+        }
+        "/unblock" => {
+            let target = input_line.strip_prefix("/unblock").unwrap_or("").trim();
+            if target.is_empty() {
+                return Some("@@@ Usage: /unblock <username|ip>".to_string());
+            }
+            Some(match blocklist::unblock(target) {
+                Ok(true) => format!("@@@ Unblocked {target}"),
+                Ok(false) => format!("@@@ {target} is not blocked"),
+                Err(e) => format!("@@@ Could not save the blocklist: {e}"),
+            })
+        }
+        "/ban" => {
+            let target = input_line.strip_prefix("/ban").unwrap_or("").trim();
+            if target.is_empty() || target == "list" {
+                let banned = ban::list();
+                if banned.is_empty() {
+                    return Some("@@@ Nothing is banned. Usage: /ban <ip|cidr>".to_string());
+                }
+                utils::display_message_block("Banned (/ban)", banned);
+                return None;
+            }
+            let Some(subnet) = ban::Subnet::parse(target) else {
+                return Some(format!(
+                    "@@@ {target} is not an IP or subnet, e.g. 192.168.1.7 or 10.0.0.0/8"
+                ));
+            };
+            Some(match ban::ban(subnet) {
+                Ok(true) => {
+                    // Banned peers would only time out, as nothing they send gets through anymore
+                    let banned_peers: Vec<PeerInfo> = {
+                        let mut peer_list = peer_list.lock().await;
+                        let matching: Vec<SocketAddr> = peer_list
+                            .get_peers()
+                            .iter()
+                            .map(|peer| peer.addr)
+                            .filter(|addr| subnet.contains(addr.ip()))
+                            .collect();
+                        matching
+                            .iter()
+                            .flat_map(|addr| peer_list.remove_peer(addr))
+                            .collect()
+                    };
+                    for peer in &banned_peers {
+                        lifecycle::record(
+                            PeerEvent::Blocked,
+                            &peer.username,
+                            &peer.addr.to_string(),
+                        );
+                    }
+                    format!(
+                        "@@@ Banned {subnet}, dropped {} peer(s); /unban {subnet} to undo",
+                        banned_peers.len()
+                    )
+                }
+                Ok(false) => format!("@@@ {subnet} is already banned"),
+                Err(e) => format!("@@@ Could not save the banlist: {e}"),
+            })
+        }
+        "/unban" => {
+            let target = input_line.strip_prefix("/unban").unwrap_or("").trim();
+            let Some(subnet) = ban::Subnet::parse(target) else {
+                return Some("@@@ Usage: /unban <ip|cidr>, as listed by /ban list".to_string());
+            };
+            Some(match ban::unban(subnet) {
+                Ok(true) => format!("@@@ Unbanned {subnet}"),
+                Ok(false) => format!("@@@ {subnet} is not banned"),
+                Err(e) => format!("@@@ Could not save the banlist: {e}"),
+            })
+        }
+        "/flood" => {
+            let offenders = flood::offenders();
+            if offenders.is_empty() {
+                return Some("@@@ Nobody is flooding us".to_string());
+            }
+            utils::display_message_block("Flooding sources (/flood)", offenders);
+            None
+        }
+        "/forget" => {
+            let target = input_line.strip_prefix("/forget").unwrap_or("").trim();
+            if target.is_empty() {
+                return Some("@@@ Usage: /forget <username|address|all>".to_string());
+            }
+            let forgotten = peer_list.lock().await.forget(target);
+            if forgotten.is_empty() {
+                return Some(format!("@@@ No peer matches {target}"));
+            }
+            for peer in &forgotten {
+                lifecycle::record(PeerEvent::Forgotten, &peer.username, &peer.addr.to_string());
+            }
+            Some(format!(
+                "@@@ Forgot {} peer(s); they come back if they contact you directly",
+                forgotten.len()
+            ))
+        }
+        "/group" => {
+            let args: Vec<&str> = input_line.split_whitespace().skip(1).collect();
+            match args.as_slice() {
+                [] => {
+                    let groups = groups::list();
+                    if groups.is_empty() {
+                        return Some(
+                            "@@@ No groups yet. Usage: /group add <group> <username ...>"
+                                .to_string(),
+                        );
+                    }
+                    utils::display_message_block(
+                        "Groups (/group)",
+                        groups
+                            .into_iter()
+                            .map(|(group, members)| format!("{group:12} : {}", members.join(", ")))
+                            .collect(),
+                    );
+                    None
+                }
+                ["add", group, usernames @ ..] if !usernames.is_empty() => {
+                    Some(match groups::add(group, usernames) {
+                        Ok(added) => format!("@@@ Added {added} peer(s) to {group}"),
+                        Err(e) => format!("@@@ Could not save the groups: {e}"),
+                    })
+                }
+                ["remove", group, usernames @ ..] => Some(match groups::remove(group, usernames) {
+                    Ok(true) if usernames.is_empty() => format!("@@@ Removed group {group}"),
+                    Ok(true) => format!("@@@ Removed {} from {group}", usernames.join(", ")),
+                    Ok(false) => format!("@@@ There's no group {group}"),
+                    Err(e) => format!("@@@ Could not save the groups: {e}"),
+                }),
+                _ => Some(
+                    "@@@ Usage: /group [add <group> <username ...> | remove <group> [username ...]]"
+                        .to_string(),
+                ),
+            }
+        }
+        "/mute" => {
+            let mut args = input_line.split_whitespace().skip(1);
+            let Some(target) = args.next() else {
+                let muted = mute::list();
+                if muted.is_empty() {
+                    return Some(
+                        "@@@ Nobody is muted. Usage: /mute <username> [duration]".to_string(),
+                    );
+                }
+                let lines = muted
+                    .into_iter()
+                    .map(|(username, left)| match left {
+                        Some(left) => {
+                            format!("{username} ({} min left)", left.as_secs().div_ceil(60))
+                        }
+                        None => format!("{username} (until /unmute)"),
+                    })
+                    .collect();
+                utils::display_message_block("Muted (/mute)", lines);
+                return None;
+            };
+            let duration_arg = args.next();
+            let duration = match duration_arg.map(utils::parse_duration) {
+                None => None,
+                Some(Ok(duration)) => Some(duration),
+                Some(Err(e)) => return Some(format!("@@@ {e}")),
+            };
+            mute::mute(target, duration);
+            Some(match duration_arg {
+                Some(duration) => format!("@@@ Muted {target} for {duration}"),
+                None => format!("@@@ Muted {target}; /unmute {target} to undo"),
+            })
+        }
+        "/unmute" => {
+            let target = input_line.strip_prefix("/unmute").unwrap_or("").trim();
+            if target.is_empty() {
+                return Some("@@@ Usage: /unmute <username>".to_string());
+            }
+            Some(if mute::unmute(target) {
+                format!("@@@ Unmuted {target}")
+            } else {
+                format!("@@@ {target} is not muted")
+            })
+        }
+        "/notify" => {
+            if !notify::is_available() {
+                return Some(
+                    "@@@ This build has no desktop notifications; build it with --features notifications"
+                        .to_string(),
+                );
+            }
+            match input_line
+                .split_whitespace()
+                .nth(1)
+                .map(notify::Mode::parse)
+            {
+                None => Some(format!(
+                    "@@@ Notifications are {}. Usage: /notify on|off|mentions-only",
+                    notify::mode().name()
+                )),
+                Some(Some(mode)) => {
+                    notify::set_mode(mode);
+                    Some(format!(
+                        "@@@ Notifications {}; they only pop up after {}s without typing",
+                        mode.name(),
+                        notify::IDLE_AFTER
+                    ))
+                }
+                Some(None) => Some("@@@ Usage: /notify on|off|mentions-only".to_string()),
+            }
+        }
+        "/set" => {
+            let Some(key) = input_line.split_whitespace().nth(1) else {
+                let mut prefs: Vec<String> = app_state
+                    .iter()
+                    .filter(|entry| entry.key().starts_with("pref:"))
+                    .map(|entry| {
+                        format!(
+                            "{:18} = {}",
+                            entry.key().trim_start_matches("pref:"),
+                            entry.value()
+                        )
+                    })
+                    .collect();
+                prefs.sort();
+                utils::display_message_block("Preferences (/set)", prefs);
+                return None;
+            };
+            // Values may have spaces, e.g. an alert command
+            let value = input_line
+                .trim_start()
+                .strip_prefix("/set")
+                .and_then(|rest| rest.trim_start().strip_prefix(key))
+                .unwrap_or("")
+                .trim();
+            if value.is_empty() {
+                return Some("@@@ Usage: /set <key> <value>; /set lists them".to_string());
+            }
+            // Each takes effect right away; invalid values leave the current one
+            let applied = match key {
+                "terminal_width" => value
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid width '{value}'"))
+                    .and_then(utils::set_chat_width)
+                    .map(|()| ("pref:terminal_width", value.to_string())),
+                "tz" => utils::parse_time_zone(value).map(|setting| {
+                    utils::set_time_zone(setting);
+                    ("pref:tz", value.to_string())
+                }),
+                "time_format" => utils::parse_time_format(value).map(|setting| {
+                    utils::set_time_format(setting);
+                    ("pref:time_format", value.to_string())
+                }),
+                "theme" => {
+                    if theme::select(value) {
+                        Ok(("pref:theme", value.to_string()))
+                    } else {
+                        Err(format!("There's no theme {value}; /theme lists them"))
+                    }
+                }
+                "bell" => match value {
+                    "on" | "off" => {
+                        alert::set_bell(value == "on");
+                        Ok(("pref:bell", value.to_string()))
+                    }
+                    _ => Err("Usage: /set bell on|off".to_string()),
+                },
+                "heartbeat_interval" => value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid interval '{value}', in seconds"))
+                    .and_then(|interval| {
+                        heartbeats::set_interval(interval)
+                            .map_err(|e| format!("Can't use that interval: {e}"))
+                    })
+                    .map(|timing| {
+                        app_state.insert("static:heartbeat", timing.to_string());
+                        ("pref:heartbeat_interval", value.to_string())
+                    }),
+                _ => match key.strip_prefix("alert.").and_then(alert::Event::by_name) {
+                    Some(event) => {
+                        let sound = alert::Sound::parse(value);
+                        alert::set(event, sound.clone());
+                        Ok((event.pref_key(), sound.describe()))
+                    }
+                    None => Err(format!("Unknown preference {key}; /set lists them")),
+                },
+            };
+            match applied {
+                Ok((pref_key, value)) => {
+                    app_state.insert(pref_key, value.clone());
+                    Some(format!("@@@ {key} = {value}"))
+                }
+                Err(e) => Some(format!("@@@ {e}")),
+            }
+        }
+        "/theme" => {
+            let Some(name) = input_line.split_whitespace().nth(1) else {
+                let current = theme::current_name();
+                let names = theme::names()
+                    .into_iter()
+                    .map(|name| {
+                        if current.as_ref() == Some(&name) {
+                            format!("{name} (current)")
+                        } else {
+                            name
+                        }
+                    })
+                    .collect();
+                utils::display_message_block("Themes (/theme)", names);
+                return None;
+            };
+            if !theme::select(name) {
+                return Some(format!("@@@ There's no theme {name}; /theme lists them"));
+            }
+            app_state.insert("pref:theme", name.to_string());
+            Some(format!("@@@ Switched to the {name} theme"))
+        }
+        "/privacy" => match input_line.split_whitespace().nth(1) {
+            None => Some(format!(
+                "@@@ Privacy mode is {}. Usage: /privacy on|off",
+                if privacy::is_enabled() { "on" } else { "off" }
+            )),
+            Some("on") => {
+                privacy::set(true);
+                Some("@@@ Privacy mode on: peer addresses are shown as node-xxxxxx".to_string())
+            }
+            Some("off") => {
+                privacy::set(false);
+                Some("@@@ Privacy mode off: peer addresses are shown again".to_string())
+            }
+            Some(_) => Some("@@@ Usage: /privacy on|off".to_string()),
+        },
+        "/nick" => {
+            let Some(new_name) = input_line.split_whitespace().nth(1) else {
+                return Some("@@@ Usage: /nick <username>".to_string());
+            };
+            if new_name.contains(':') || new_name.chars().count() > MAX_USERNAME_LEN {
+                return Some(format!(
+                    "@@@ Usernames can't contain ':' and are at most {MAX_USERNAME_LEN} characters"
+                ));
+            }
+            let (Some(transport), Some(username), Some(addr)) = (transport, username, local_addr)
+            else {
+                return Some("@@@ Cannot rename: missing required parameters".to_string());
+            };
+            let previous = nick::current().unwrap_or(username);
+            if previous == new_name {
+                return Some(format!("@@@ You are already {new_name}"));
+            }
+            nick::set(new_name.to_string());
+            app_state.insert("static:username", new_name.to_string());
+            heartbeats::send_rename(&transport, new_name, &previous, addr, &peer_list).await;
+            Some(format!("@@@ You are now known as {new_name}"))
+        }
+        "/status" => {
+            let mut args = input_line.split_whitespace().skip(1);
+            let Some(state) = args.next() else {
+                return Some(format!(
+                    "@@@ Your status: {}. Usage: /status <online|away|busy> [note]",
+                    presence_tag(&heartbeats::presence())
+                ));
+            };
+            let Some(availability) = Availability::by_name(state) else {
+                return Some("@@@ Usage: /status <online|away|busy> [note]".to_string());
+            };
+            let text: String = args
+                .collect::<Vec<_>>()
+                .join(" ")
+                .chars()
+                .take(MAX_STATUS_LEN)
+                .collect();
+            let presence = Presence {
+                availability,
+                text: (!text.is_empty()).then_some(text),
+            };
+            let tag = presence_tag(&presence);
+            heartbeats::set_presence(presence);
+            Some(format!("@@@ Your status is now {tag}"))
+        }
+        "/sleepy" => {
+            let target = input_line.strip_prefix("/sleepy").unwrap_or("").trim();
+            if target.is_empty() {
+                return Some("@@@ Usage: /sleepy <username>".to_string());
+            }
+            let mut peer_list = peer_list.lock().await;
+            let sleepy = !peer_list.is_marked_sleepy(target);
+            let affected = peer_list.mark_sleepy(target, sleepy);
+            Some(match (sleepy, affected) {
+                (true, 0) => format!("@@@ {target} will be treated as sleepy once it shows up"),
+                (true, _) => format!("@@@ {target} is now treated as sleepy"),
+                (false, _) => format!("@@@ {target} is no longer treated as sleepy"),
+            })
+        }
+        "/stream" => {
+            let command = input_line
+                .strip_prefix("/stream")
+                .unwrap_or("")
+                .trim()
+                .to_string();
+            if !features::is_active(Feature::Stream) {
+                return Some("@@@ Streaming is disabled".to_string());
+            }
+            if command.is_empty() {
+                return Some("@@@ Usage: /stream <command>".to_string());
+            }
+            if let (Some(transport), Some(username), Some(addr)) = (transport, username, local_addr)
+            {
+                let stream_sender = StreamSender::new(transport, peer_list, username, addr);
+                let command_clone = command.clone();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        stream::stream_command_output(command_clone, stream_sender).await
+                    {
+                        log::error!("Error streaming command output: {e}");
+                    }
+                });
+                Some(format!("@@@ Streaming output of `{command}` to peers..."))
+            } else {
+                Some("@@@ Cannot stream: missing required parameters".to_string())
+            }
+        }
+        "/share" => {
+            if !features::is_active(Feature::Stream) {
+                return Some("@@@ Streaming is disabled".to_string());
+            }
+            let mut args = input_line.split_whitespace().skip(1);
+            let (source, title) = match args.next() {
+                Some("stop") => {
+                    return Some(if share::stop() {
+                        "@@@ Share session stopped.".to_string()
+                    } else {
+                        "@@@ No share session is running.".to_string()
+                    });
+                }
+                Some("start") => (ShareSource::Input, "shared session".to_string()),
+                Some("tail") => match args.next() {
+                    Some(path) => (
+                        ShareSource::Tail(PathBuf::from(path)),
+                        format!("shared session: tail {path}"),
+                    ),
+                    None => {
+                        return Some("@@@ Usage: /share tail <path> [username ...]".to_string());
+                    }
+                },
+                _ => {
+                    return Some(
+                        "@@@ Usage: /share start [username ...] | /share tail <path> [username ...] | /share stop"
+                            .to_string(),
+                    );
+                }
+            };
+            // Remaining arguments select the peers to share with
+            let recipients: Vec<String> = args.map(|name| name.to_string()).collect();
+
+            if let (Some(transport), Some(username), Some(addr)) = (transport, username, local_addr)
+            {
+                let mut stream_sender =
+                    StreamSender::new(transport, peer_list, username, addr).with_title(title);
+                let audience = if recipients.is_empty() {
+                    "all peers".to_string()
+                } else {
+                    recipients.join(", ")
+                };
+                if !recipients.is_empty() {
+                    stream_sender = stream_sender.with_recipients(recipients);
+                }
+                let typed = matches!(source, ShareSource::Input);
+                share::start(source, stream_sender);
+                Some(if typed {
+                    format!("@@@ Sharing what you type with {audience}; /share stop to end")
+                } else {
+                    format!("@@@ Sharing the file with {audience}; /share stop to end")
+                })
+            } else {
+                Some("@@@ Cannot share: missing required parameters".to_string())
+            }
+        }
+        "/tour" => {
+            if input_line.split_whitespace().nth(1) == Some("stop") {
+                if ui::tour::stop() {
+                    Some("@@@ Tour stopped.".to_string())
+                } else {
+                    Some("@@@ No tour is running.".to_string())
+                }
+            } else {
+                ui::tour::start(peer_list);
+                None
+            }
+        }
         "/tips" | "/t" => {
             ui::app_state::show_tips();
             None
@@ -125,3 +1460,193 @@ pub async fn handle_command(
         }
     }
 }
+
+// Order /peers by one of its sort keys; false if the key is unknown
+fn sort_peers(peers: &mut [PeerInfo], key: &str) -> bool {
+    match key {
+        "name" => peers.sort_by(|a, b| (&a.username, a.addr).cmp(&(&b.username, b.addr))),
+        "addr" => peers.sort_by_key(|peer| peer.addr),
+        // Most recently heard first
+        "last_seen" => peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen)),
+        "first_seen" => peers.sort_by_key(|peer| peer.first_seen),
+        // Unknown values last
+        "rtt" => peers.sort_by_key(|peer| (peer.rtt.is_none(), peer.rtt)),
+        "loss" => peers.sort_by_key(|peer| (peer.loss_percent().is_none(), peer.loss_percent())),
+        _ => return false,
+    }
+    true
+}
+
+// Whether a peer passes a /peers filter; None if the field is unknown
+fn peer_matches(peer: &PeerInfo, field: &str, value: &str) -> Option<bool> {
+    Some(match field {
+        "name" => peer.username.contains(value),
+        "room" => peer.room.as_deref() == Some(value),
+        "health" => peer.health.is_some_and(|health| health.name() == value),
+        "status" => peer
+            .presence
+            .as_ref()
+            .is_some_and(|presence| presence.availability.name() == value),
+        "version" => peer.version.as_deref() == Some(value),
+        _ => return None,
+    })
+}
+
+// Green, yellow or red by link health; grey while there's too little to judge
+fn health_dot(health: Option<Health>) -> String {
+    let color = match health {
+        Some(Health::Good) => 32,     // green
+        Some(Health::Degraded) => 33, // yellow
+        Some(Health::Failing) => 31,  // red
+        None => 90,                   // grey
+    };
+    utils::colorize("●", color)
+}
+
+// "[away: lunch]", colored by availability
+fn presence_tag(presence: &Presence) -> String {
+    let color = match presence.availability {
+        Availability::Online => 32, // green
+        Availability::Away => 33,   // yellow
+        Availability::Busy => 31,   // red
+    };
+    let tag = match &presence.text {
+        Some(text) => format!("[{}: {text}]", presence.availability.name()),
+        None => format!("[{}]", presence.availability.name()),
+    };
+    utils::colorize(&tag, color)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::stats::NetStats;
+    use crate::peer::PeerList;
+
+    fn peers(names: &[&str]) -> Vec<PeerInfo> {
+        let mut peer_list = PeerList::new();
+        for (i, name) in names.iter().enumerate() {
+            let addr = SocketAddr::from(([198, 51, 100, 10 - i as u8], 10001));
+            peer_list.add_or_update_peer(addr, name.to_string(), None);
+        }
+        // In the order given, not the map's
+        let mut peers = peer_list.get_peers();
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.addr));
+        peers
+    }
+
+    fn names(peers: &[PeerInfo]) -> Vec<&str> {
+        peers.iter().map(|peer| peer.username.as_str()).collect()
+    }
+
+    #[test]
+    fn peers_sort_the_same_way_every_time() {
+        let mut peers = peers(&["carol", "alice", "bob"]);
+        assert!(sort_peers(&mut peers, "name"));
+        assert_eq!(names(&peers), ["alice", "bob", "carol"]);
+        // The first one given has the highest address
+        assert!(sort_peers(&mut peers, "addr"));
+        assert_eq!(names(&peers), ["bob", "alice", "carol"]);
+        assert!(!sort_peers(&mut peers, "mood"));
+    }
+
+    #[test]
+    fn peers_without_a_measurement_sort_last() {
+        let mut peers = peers(&["alice", "bob", "carol"]);
+        peers[0].rtt = None;
+        peers[1].rtt = Some(Duration::from_millis(80));
+        peers[2].rtt = Some(Duration::from_millis(20));
+        assert!(sort_peers(&mut peers, "rtt"));
+        assert_eq!(names(&peers), ["carol", "bob", "alice"]);
+    }
+
+    #[test]
+    fn peers_are_filtered_by_field() {
+        let mut peers = peers(&["alice", "alina", "bob"]);
+        peers[0].room = Some("ops".to_string());
+        peers[2].presence = Some(Presence {
+            availability: Availability::Away,
+            text: None,
+        });
+        let matching = |field: &str, value: &str| -> Vec<&str> {
+            peers
+                .iter()
+                .filter(|peer| peer_matches(peer, field, value) == Some(true))
+                .map(|peer| peer.username.as_str())
+                .collect()
+        };
+        assert_eq!(matching("name", "ali"), ["alice", "alina"]);
+        assert_eq!(matching("room", "ops"), ["alice"]);
+        assert_eq!(matching("status", "away"), ["bob"]);
+        assert!(matching("version", "1.0.0").is_empty());
+        assert_eq!(peer_matches(&peers[0], "mood", "happy"), None);
+    }
+
+    async fn run(line: &str, app_state: &Arc<DashMap<&'static str, String>>) -> Option<String> {
+        handle_command(
+            line,
+            Arc::new(tokio::sync::Mutex::new(PeerList::new())),
+            None,
+            None,
+            None,
+            app_state.clone(),
+            Arc::new(std::sync::Mutex::new(NetStats::new())),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn set_applies_valid_preferences_right_away() {
+        let app_state = Arc::new(DashMap::new());
+        let said = run("/set bell off", &app_state).await;
+        assert_eq!(said.as_deref(), Some("@@@ bell = off"));
+        assert_eq!(app_state.get("pref:bell").unwrap().as_str(), "off");
+        assert!(!alert::bell());
+
+        // Alert commands have spaces in them
+        let said = run("/set alert.join  paplay /tmp/join.ogg ", &app_state).await;
+        assert_eq!(
+            said.as_deref(),
+            Some("@@@ alert.join = paplay /tmp/join.ogg")
+        );
+        assert_eq!(
+            alert::get(alert::Event::Join),
+            alert::Sound::Command("paplay /tmp/join.ogg".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn set_leaves_preferences_alone_on_bad_values() {
+        let app_state = Arc::new(DashMap::new());
+        app_state.insert("pref:tz", "local".to_string());
+
+        let said = run("/set tz somewhere", &app_state).await.unwrap();
+        assert!(said.starts_with("@@@ "), "{said}");
+        assert_eq!(app_state.get("pref:tz").unwrap().as_str(), "local");
+        run("/set terminal_width wide", &app_state).await;
+        run("/set heartbeat_interval 0", &app_state).await;
+        assert!(!app_state.contains_key("pref:terminal_width"));
+        assert!(!app_state.contains_key("pref:heartbeat_interval"));
+        assert!(!app_state.contains_key("static:heartbeat"));
+
+        let said = run("/set colour red", &app_state).await;
+        assert_eq!(
+            said.as_deref(),
+            Some("@@@ Unknown preference colour; /set lists them")
+        );
+        let said = run("/set theme", &app_state).await;
+        assert_eq!(
+            said.as_deref(),
+            Some("@@@ Usage: /set <key> <value>; /set lists them")
+        );
+        assert_eq!(app_state.len(), 1);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::peer::SharedPeerList;
+use crate::ui::commands::COMMANDS;
+use crate::ui::theme::{self, Role};
+use crate::ui::{notify, output};
+use crate::utils;
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::hint::{Hint, Hinter};
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Commands whose first argument is a peer
+const PEER_COMMANDS: &[&str] = &[
+    "/block", "/forget", "/msg", "/mute", "/rekey", "/sas", "/sleepy", "/unblock", "/unmute",
+    "/verify", "/whois",
+];
+
+/// Completes commands, peer names and rooms on tab, from the live peer list, and shows
+/// how much chat is waiting while a line is being typed
+pub struct LineHelper {
+    peer_list: SharedPeerList,
+    // The line and cursor as of the last hint, to tell keystrokes from redraws
+    last_edit: Mutex<(String, usize)>,
+    // Whether what's typed is a secret, shown as blanks
+    masking: AtomicBool,
+}
+
+impl LineHelper {
+    pub fn new(peer_list: SharedPeerList) -> Self {
+        Self {
+            peer_list,
+            last_edit: Mutex::new((String::new(), 0)),
+            masking: AtomicBool::new(false),
+        }
+    }
+
+    /// Show what's typed from now on as blanks, e.g. for a password, or as typed again
+    pub fn set_masking(&self, masking: bool) {
+        self.masking.store(masking, Ordering::SeqCst);
+    }
+
+    // Names as /peers shows them, which every peer command accepts
+    fn peer_names(&self) -> Vec<String> {
+        self.peer_list
+            .blocking_lock()
+            .display_names()
+            .into_values()
+            .collect()
+    }
+
+    // Rooms that peers are in
+    fn rooms(&self) -> Vec<String> {
+        self.peer_list
+            .blocking_lock()
+            .get_peers()
+            .into_iter()
+            .filter_map(|peer| peer.room)
+            .collect()
+    }
+}
+
+impl Completer for LineHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // Only the word up to the cursor is completed
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let candidates = match before.as_slice() {
+            [] if word.starts_with('/') => COMMANDS.iter().map(|c| c.to_string()).collect(),
+            [command] if PEER_COMMANDS.contains(command) => self.peer_names(),
+            ["/join"] => self.rooms(),
+            _ => Vec::new(),
+        };
+        let mut matches: Vec<String> = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .collect();
+        matches.sort();
+        matches.dedup();
+        let pairs = matches
+            .into_iter()
+            .map(|candidate| Pair {
+                replacement: format!("{candidate} "),
+                display: candidate,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+/// "▼ 3 new messages" after the cursor; unlike a plain String hint, it can't be accepted
+/// into the line
+pub struct NewMessages(String);
+
+impl Hint for NewMessages {
+    fn display(&self) -> &str {
+        &self.0
+    }
+
+    fn completion(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl Hinter for LineHelper {
+    type Hint = NewMessages;
+
+    // Called on every keystroke, which is also how output learns we're composing, and on
+    // every redraw after output is printed above the line, so the count keeps up with chat
+    // arriving between keystrokes
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<NewMessages> {
+        // A secret isn't chat being composed, and isn't kept around
+        if self.masking.load(Ordering::SeqCst) {
+            return None;
+        }
+        output::set_composing(!line.is_empty());
+        // Redraws leave the line as it was; a keystroke that changed it means the user is
+        // at the terminal, which notifications need to know
+        if let Ok(mut last_edit) = self.last_edit.lock()
+            && (last_edit.0 != line || last_edit.1 != pos)
+        {
+            *last_edit = (line.to_string(), pos);
+            notify::record_activity();
+        }
+        match output::new_count() {
+            0 => None,
+            1 => Some(NewMessages("  ▼ 1 new message".to_string())),
+            count => Some(NewMessages(format!("  ▼ {count} new messages"))),
+        }
+    }
+}
+
+impl Highlighter for LineHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if self.masking.load(Ordering::SeqCst) {
+            Cow::Owned(" ".repeat(utils::display_width(line)))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    // Masked lines are redrawn on every change; moving the cursor shows nothing new
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        kind != CmdKind::MoveCursor && self.masking.load(Ordering::SeqCst)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(theme::paint(Role::System, hint))
+    }
+}
+
+impl Validator for LineHelper {}
+
+impl Helper for LineHelper {}
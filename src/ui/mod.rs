@@ -1,2 +1,17 @@
+pub mod alert;
 pub mod app_state;
+pub mod chat_log;
 pub mod commands;
+pub mod completion;
+pub mod mute;
+pub mod notify;
+pub mod output;
+pub mod privacy;
+pub mod reactions;
+pub mod session;
+pub mod status_bar;
+pub mod theme;
+pub mod tour;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod wipe;
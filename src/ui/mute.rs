@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// Muted username -> when the mute runs out (None: until /unmute)
+static MUTED: LazyLock<Mutex<HashMap<String, Option<Instant>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Stop showing a peer's chat, for a while or until unmuted
+pub fn mute(username: &str, duration: Option<Duration>) {
+    if let Ok(mut muted) = MUTED.lock() {
+        muted.insert(
+            username.to_string(),
+            duration.map(|duration| Instant::now() + duration),
+        );
+    }
+}
+
+/// Returns false if the peer wasn't muted
+pub fn unmute(username: &str) -> bool {
+    MUTED
+        .lock()
+        .is_ok_and(|mut muted| muted.remove(username).is_some())
+}
+
+/// Whether the peer's chat should be hidden; expired mutes are lifted here
+pub fn is_muted(username: &str) -> bool {
+    let Ok(mut muted) = MUTED.lock() else {
+        return false;
+    };
+    match muted.get(username) {
+        Some(Some(until)) if Instant::now() >= *until => {
+            muted.remove(username);
+            false
+        }
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Muted peers with the time left on their mute, sorted by username
+pub fn list() -> Vec<(String, Option<Duration>)> {
+    let Ok(mut muted) = MUTED.lock() else {
+        return Vec::new();
+    };
+    let now = Instant::now();
+    muted.retain(|_, until| until.is_none_or(|until| until > now));
+    let mut list: Vec<(String, Option<Duration>)> = muted
+        .iter()
+        .map(|(username, until)| (username.clone(), until.map(|until| until - now)))
+        .collect();
+    list.sort();
+    list
+}
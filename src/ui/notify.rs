@@ -0,0 +1,111 @@
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// Without anything typed for this long, the user is taken to be in another window
+pub const IDLE_AFTER: u64 = 30; // seconds
+
+/// Which chat pops up a desktop notification, set with /notify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    // Private messages and mentions
+    On,
+    MentionsOnly,
+}
+
+impl Mode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Mode::Off),
+            "on" => Some(Mode::On),
+            "mentions-only" => Some(Mode::MentionsOnly),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Off => "off",
+            Mode::On => "on",
+            Mode::MentionsOnly => "mentions-only",
+        }
+    }
+}
+
+static MODE: Mutex<Mode> = Mutex::new(Mode::On);
+static LAST_ACTIVITY: LazyLock<Mutex<Instant>> = LazyLock::new(|| Mutex::new(Instant::now()));
+
+/// Whether this build can show desktop notifications at all
+pub fn is_available() -> bool {
+    cfg!(feature = "notifications")
+}
+
+pub fn set_mode(mode: Mode) {
+    if let Ok(mut current) = MODE.lock() {
+        *current = mode;
+    }
+}
+
+pub fn mode() -> Mode {
+    MODE.lock().map_or(Mode::Off, |mode| *mode)
+}
+
+/// Called for every keystroke and line typed; a terminal that's being typed in has the
+/// focus
+pub fn record_activity() {
+    if let Ok(mut last_activity) = LAST_ACTIVITY.lock() {
+        *last_activity = Instant::now();
+    }
+}
+
+fn is_idle() -> bool {
+    LAST_ACTIVITY
+        .lock()
+        .is_ok_and(|last_activity| last_activity.elapsed() >= Duration::from_secs(IDLE_AFTER))
+}
+
+/// Whether chat mentions us as @name; trailing punctuation is ignored, case too
+pub fn mentions(content: &str, own_name: &str) -> bool {
+    content.split_whitespace().any(|word| {
+        word.trim_end_matches(|c: char| c.is_ascii_punctuation() && c != '_' && c != '-')
+            .strip_prefix('@')
+            .is_some_and(|name| name.eq_ignore_ascii_case(own_name))
+    })
+}
+
+/// Pops up a desktop notification for a private message or a mention, if the mode asks
+/// for it and the user seems to be away from the terminal
+pub fn chat(sender: &str, content: &str, is_private: bool, mentioned: bool) {
+    let wanted = match mode() {
+        Mode::Off => false,
+        Mode::On => is_private || mentioned,
+        Mode::MentionsOnly => mentioned,
+    };
+    if !wanted || !is_idle() {
+        return;
+    }
+    let summary = if is_private {
+        format!("Private message from {sender}")
+    } else {
+        format!("{sender} mentioned you")
+    };
+    show(summary, content.to_string());
+}
+
+#[cfg(feature = "notifications")]
+fn show(summary: String, body: String) {
+    // D-Bus calls block, so they're kept off the listener
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = notify_rust::Notification::new()
+            .appname("pung")
+            .summary(&summary)
+            .body(&body)
+            .show()
+        {
+            log::debug!("Could not show a desktop notification: {e}");
+        }
+    });
+}
+
+#[cfg(not(feature = "notifications"))]
+fn show(_summary: String, _body: String) {}
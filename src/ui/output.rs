@@ -0,0 +1,173 @@
+use crate::ui::chat_log;
+use crate::ui::theme::{self, Role};
+use crate::utils;
+use rustyline::ExternalPrinter;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+// A sender's messages this close together are shown as one burst, with the name only once
+const GROUP_WINDOW: i64 = 60; // seconds
+
+/// A chat message from a peer, ready to be shown
+pub struct ChatLine {
+    // Who it's from and how, e.g. "<team> [alice]: "; follow-ups are indented past it
+    pub prefix: String,
+    pub content: String,
+    pub timestamp: i64,
+}
+
+// Prints above the prompt and redraws what's being typed, once the line editor is up
+static PRINTER: Mutex<Option<Box<dyn ExternalPrinter + Send>>> = Mutex::new(None);
+// Whether something is typed at the prompt, as of the last keystroke
+static COMPOSING: AtomicBool = AtomicBool::new(false);
+// Chat that arrived while composing, counted until the line is entered
+static NEW_WHILE_COMPOSING: AtomicUsize = AtomicUsize::new(0);
+// Prefix and time of the last thing printed, if it was chat; anything else ends a burst
+static LAST_CHAT: Mutex<Option<(String, i64)>> = Mutex::new(None);
+// Date of the last chat shown, starting from today so the first day needs no separator
+static CHAT_DATE: LazyLock<Mutex<String>> = LazyLock::new(|| {
+    Mutex::new(utils::display_date_from_timestamp(
+        chrono::Utc::now().timestamp(),
+    ))
+});
+
+/// Print a line in its theme color: system messages start with @@@ and peer events with
+/// ###, anything else is printed as is. Used by say!.
+pub fn line(text: String) {
+    if let Ok(mut last) = LAST_CHAT.lock() {
+        *last = None;
+    }
+    print(text);
+}
+
+fn print(text: String) {
+    let role = if text.starts_with("@@@") {
+        Some(Role::System)
+    } else if text.starts_with("###") {
+        Some(Role::Event)
+    } else {
+        None
+    };
+    match role {
+        Some(role) => {
+            chat_log::note(&text);
+            write(theme::paint(role, &text))
+        }
+        None => write(text),
+    }
+}
+
+/// Print through the line editor from now on, so output lands above the line being typed
+/// instead of cutting through it
+pub fn use_printer(printer: impl ExternalPrinter + Send + 'static) {
+    if let Ok(mut current) = PRINTER.lock() {
+        *current = Some(Box::new(printer));
+    }
+}
+
+fn write(text: String) {
+    if let Ok(mut printer) = PRINTER.lock()
+        && let Some(printer) = printer.as_mut()
+        && printer.print(format!("{text}\n")).is_ok()
+    {
+        return;
+    }
+    println!("{text}");
+}
+
+/// Write escape sequences that draw outside the scrolling text and leave the cursor where
+/// it was, like the status bar. Through the line editor they can't land in the middle of
+/// its redraw; it ends what it prints with a newline, which the trailing cursor-up undoes.
+pub fn overlay(text: &str) {
+    if let Ok(mut printer) = PRINTER.lock()
+        && let Some(printer) = printer.as_mut()
+        && printer.print(format!("{text}\x1b[1A")).is_ok()
+    {
+        return;
+    }
+    print!("{text}");
+    let _ = std::io::stdout().flush();
+}
+
+/// Print a chat line above the prompt; if the user is typing, it's also counted for the
+/// "▼ n new messages" hint until `mark_read` when the line is entered
+pub fn chat(chat: ChatLine) {
+    // Counted first: printing redraws the prompt, and with it the hint
+    if COMPOSING.load(Ordering::SeqCst) {
+        NEW_WHILE_COMPOSING.fetch_add(1, Ordering::SeqCst);
+    }
+    print_chat(chat);
+}
+
+/// Print a chat message we sent, already rendered
+pub fn own_chat(text: String) {
+    separate_date();
+    line(text);
+}
+
+// A "──── 2024-05-12 ────" line before the first chat of each new day. The day is the one
+// chat arrives on: the sender's clock could be off, or a late message from yesterday
+// would have the date go back and forth.
+fn separate_date() {
+    let date = utils::display_date_from_timestamp(chrono::Utc::now().timestamp());
+    let Ok(mut last_date) = CHAT_DATE.lock() else {
+        return;
+    };
+    if *last_date != date {
+        line(theme::paint(Role::Timestamp, &format!("──── {date} ────")));
+        *last_date = date;
+    }
+}
+
+// Follow-ups in a burst from the same sender drop the prefix and line up under the first
+fn print_chat(chat: ChatLine) {
+    separate_date();
+    let Ok(mut last) = LAST_CHAT.lock() else {
+        return;
+    };
+    let follows = last.as_ref().is_some_and(|(prefix, timestamp)| {
+        *prefix == chat.prefix && (chat.timestamp - timestamp).abs() <= GROUP_WINDOW
+    });
+    let base_msg = if follows {
+        format!(
+            "{}{}",
+            " ".repeat(utils::display_width(&chat.prefix)),
+            chat.content
+        )
+    } else {
+        format!("{}{}", chat.prefix, chat.content)
+    };
+    print(utils::format_chat_line(
+        &base_msg,
+        chat.timestamp,
+        utils::chat_width(),
+    ));
+    *last = Some((chat.prefix, chat.timestamp));
+}
+
+pub fn set_composing(composing: bool) {
+    COMPOSING.store(composing, Ordering::SeqCst);
+}
+
+/// How many chat lines arrived while the current line was being typed
+pub fn new_count() -> usize {
+    NEW_WHILE_COMPOSING.load(Ordering::SeqCst)
+}
+
+/// The line was entered, so whatever arrived meanwhile counts as read
+pub fn mark_read() {
+    COMPOSING.store(false, Ordering::SeqCst);
+    NEW_WHILE_COMPOSING.store(0, Ordering::SeqCst);
+}
+
+/// Print a log record above the prompt once the line editor is up, to stderr before that
+pub fn log_line(text: String) {
+    if let Ok(mut printer) = PRINTER.lock()
+        && let Some(printer) = printer.as_mut()
+        && printer.print(format!("{text}\n")).is_ok()
+    {
+        return;
+    }
+    eprintln!("{text}");
+}
@@ -0,0 +1,38 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Mixed into the pseudonyms, so they can't be turned back into addresses by trying every
+// address of the LAN; they only stay the same until we restart
+static SALT: LazyLock<[u8; 16]> = LazyLock::new(|| {
+    let mut salt = [0u8; 16];
+    rand::rng().fill_bytes(&mut salt);
+    salt
+});
+
+/// Privacy mode: addresses are never printed, for screen sharing and streaming
+pub fn set(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// How a peer's address is printed: as is, or in privacy mode as a short hash of its
+/// node ID (of the address itself for peers we don't know the node ID of)
+pub fn addr(addr: impl Display, node_id: Option<&str>) -> String {
+    if !is_enabled() {
+        return addr.to_string();
+    }
+    let name = node_id.map_or_else(|| addr.to_string(), str::to_string);
+    let digest = Sha256::new()
+        .chain_update(*SALT)
+        .chain_update(name.as_bytes())
+        .finalize();
+    format!("node-{}", hex::encode(&digest[..3]))
+}
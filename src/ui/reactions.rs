@@ -0,0 +1,224 @@
+use crate::utils;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+
+/// What /react 1-5 sends, and keys 1-5 in the TUI once a message is selected
+pub const QUICK_REACTIONS: [&str; 5] = ["👍", "❤️", "😂", "😮", "🎉"];
+// Anything longer is a message, not a reaction
+const MAX_REACTION_LEN: usize = 16; // characters
+// Chat messages kept to react to, and to show what incoming reactions are about
+const MAX_RECENT: usize = 200;
+// How much of a message its reactions quote
+const PREVIEW_WIDTH: usize = 30; // columns
+
+/// Who saw a message, and so who sees the reactions to it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Audience {
+    Room,
+    Group(String),
+    // A /msg, to us or from us
+    Private,
+}
+
+/// A chat message shown, by its message ID
+#[derive(Debug, Clone)]
+pub struct Recent {
+    pub message_id: String,
+    pub sender: String,                  // As shown, e.g. "alice#2"
+    pub sender_addr: Option<SocketAddr>, // Where the sender receives, for reacting privately
+    pub content: String,
+    pub audience: Audience,
+    pub own: bool,
+}
+
+// Oldest first
+static RECENT: LazyLock<Mutex<VecDeque<Recent>>> =
+    LazyLock::new(|| Mutex::new(VecDeque::with_capacity(MAX_RECENT)));
+
+/// Remember a chat message that was shown, sent or received
+pub fn record(message: Recent) {
+    if let Ok(mut recent) = RECENT.lock() {
+        push(&mut recent, message);
+    }
+}
+
+fn push(recent: &mut VecDeque<Recent>, message: Recent) {
+    if recent.len() >= MAX_RECENT {
+        recent.pop_front();
+    }
+    recent.push_back(message);
+}
+
+pub fn find(message_id: &str) -> Option<Recent> {
+    let recent = RECENT.lock().ok()?;
+    recent
+        .iter()
+        .find(|message| message.message_id == message_id)
+        .cloned()
+}
+
+/// The `back`th latest message from a peer, counting from 1; our own are skipped
+pub fn from_peers(back: usize) -> Option<Recent> {
+    let recent = RECENT.lock().ok()?;
+    peers_messages(&recent).nth(back.checked_sub(1)?).cloned()
+}
+
+/// Where a message is in the count /react goes by, if it's still kept
+#[cfg(feature = "tui")]
+pub fn position(message_id: &str) -> Option<usize> {
+    let recent = RECENT.lock().ok()?;
+    peers_messages(&recent)
+        .position(|message| message.message_id == message_id)
+        .map(|index| index + 1)
+}
+
+/// The peer's message before (`older`) or after the one with `message_id`, or the
+/// latest one if none is given; None past the latest, or once it isn't kept anymore
+#[cfg(feature = "tui")]
+pub fn step(message_id: Option<&str>, older: bool) -> Option<Recent> {
+    let recent = RECENT.lock().ok()?;
+    step_in(&recent, message_id, older)
+}
+
+#[cfg(any(feature = "tui", test))]
+fn step_in(recent: &VecDeque<Recent>, message_id: Option<&str>, older: bool) -> Option<Recent> {
+    let messages: Vec<&Recent> = peers_messages(recent).collect();
+    let Some(message_id) = message_id else {
+        // Up starts from the latest
+        return messages
+            .first()
+            .filter(|_| older)
+            .map(|message| (*message).clone());
+    };
+    let index = messages
+        .iter()
+        .position(|message| message.message_id == message_id)?;
+    let index = if older {
+        // Stays on the oldest one
+        (index + 1).min(messages.len() - 1)
+    } else {
+        index.checked_sub(1)?
+    };
+    Some(messages[index].clone())
+}
+
+// Newest first
+fn peers_messages(recent: &VecDeque<Recent>) -> impl Iterator<Item = &Recent> {
+    recent.iter().rev().filter(|message| !message.own)
+}
+
+/// What to send for /react's argument: a quick reaction by its number, or a short emoji
+/// or word as is
+pub fn parse(reaction: &str) -> Option<String> {
+    if let Ok(number) = reaction.parse::<usize>() {
+        return QUICK_REACTIONS
+            .get(number.checked_sub(1)?)
+            .map(|reaction| reaction.to_string());
+    }
+    let len = reaction.chars().count();
+    (len > 0 && len <= MAX_REACTION_LEN && !reaction.contains(char::is_whitespace))
+        .then(|| reaction.to_string())
+}
+
+/// E.g. `reacted 👍 to alice: "see you at 3"`, for a reaction to the message with
+/// `message_id`
+pub fn describe(reaction: &str, message_id: &str) -> String {
+    let reaction = utils::truncate_to_width(reaction, MAX_REACTION_LEN);
+    match find(message_id) {
+        Some(message) => {
+            let whose = if message.own { "you" } else { &message.sender };
+            format!(
+                "reacted {reaction} to {whose}: \"{}\"",
+                preview(&message.content)
+            )
+        }
+        // Gone from what we keep, or sent before we joined
+        None => format!("reacted {reaction} to an earlier message"),
+    }
+}
+
+/// The start of a message, on one line
+pub fn preview(content: &str) -> String {
+    let line = content.lines().next().unwrap_or("");
+    let cut = utils::truncate_to_width(line, PREVIEW_WIDTH);
+    if cut.len() < content.len() {
+        format!("{}…", cut.trim_end())
+    } else {
+        cut
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(n: usize, own: bool) -> Recent {
+        Recent {
+            message_id: format!("id{n}"),
+            sender: if own { "me" } else { "alice" }.to_string(),
+            sender_addr: None,
+            content: format!("message {n}"),
+            audience: Audience::Room,
+            own,
+        }
+    }
+
+    #[test]
+    fn selection_steps_through_peers_messages_only() {
+        let mut recent = VecDeque::new();
+        push(&mut recent, message(1, false));
+        push(&mut recent, message(2, true));
+        push(&mut recent, message(3, false));
+
+        let latest = step_in(&recent, None, true).unwrap();
+        assert_eq!(latest.message_id, "id3");
+        // Our own message in between is skipped
+        let older = step_in(&recent, Some("id3"), true).unwrap();
+        assert_eq!(older.message_id, "id1");
+        assert_eq!(
+            step_in(&recent, Some("id1"), true).unwrap().message_id,
+            "id1"
+        );
+        assert_eq!(
+            step_in(&recent, Some("id1"), false).unwrap().message_id,
+            "id3"
+        );
+        // Down from the latest ends the selection
+        assert!(step_in(&recent, Some("id3"), false).is_none());
+        assert!(step_in(&recent, None, false).is_none());
+        assert!(step_in(&recent, Some("gone"), true).is_none());
+    }
+
+    #[test]
+    fn only_the_latest_messages_are_kept() {
+        let mut recent = VecDeque::new();
+        for n in 0..MAX_RECENT + 10 {
+            push(&mut recent, message(n, false));
+        }
+        assert_eq!(recent.len(), MAX_RECENT);
+        assert_eq!(recent[0].message_id, "id10");
+    }
+
+    #[test]
+    fn reactions_are_quick_ones_or_short() {
+        assert_eq!(parse("1").as_deref(), Some("👍"));
+        assert_eq!(parse("5").as_deref(), Some("🎉"));
+        assert!(parse("0").is_none());
+        assert!(parse("6").is_none());
+        assert_eq!(parse("🚀").as_deref(), Some("🚀"));
+        assert_eq!(parse("ok").as_deref(), Some("ok"));
+        assert!(parse("").is_none());
+        assert!(parse("not a reaction").is_none());
+        assert!(parse(&"x".repeat(MAX_REACTION_LEN + 1)).is_none());
+    }
+
+    #[test]
+    fn previews_are_cut_to_one_short_line() {
+        assert_eq!(preview("see you at 3"), "see you at 3");
+        assert_eq!(preview("first line\nsecond line"), "first line…");
+        let long = "word ".repeat(20);
+        assert!(preview(&long).ends_with('…'));
+        assert!(utils::display_width(&preview(&long)) <= PREVIEW_WIDTH + 1);
+    }
+}
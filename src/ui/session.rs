@@ -0,0 +1,120 @@
+use crate::events::{self, Event};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+// Peers remembered for /stats; beyond this, the one seen least recently is forgotten, and
+// counted again if it comes back
+const MAX_PEERS_SEEN: usize = 1024;
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+static MESSAGES_SENT: AtomicU64 = AtomicU64::new(0);
+static MESSAGES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static PEERS_SEEN: LazyLock<Mutex<PeersSeen>> = LazyLock::new(|| Mutex::new(PeersSeen::default()));
+
+// Every peer that showed up, including ones that left since, by node ID (by username
+// for peers without one), so a rename or a second address doesn't count twice
+#[derive(Default)]
+struct PeersSeen {
+    // Peer -> when it last showed up, as the number of sightings before it
+    last_seen: HashMap<String, u64>,
+    sightings: u64,
+    // Forgotten to make room, but still counted
+    forgotten: usize,
+}
+
+impl PeersSeen {
+    fn insert(&mut self, peer: String) {
+        if !self.last_seen.contains_key(&peer)
+            && self.last_seen.len() >= MAX_PEERS_SEEN
+            && let Some(oldest) = self
+                .last_seen
+                .iter()
+                .min_by_key(|(_, seen)| **seen)
+                .map(|(peer, _)| peer.clone())
+        {
+            self.last_seen.remove(&oldest);
+            self.forgotten += 1;
+        }
+        self.last_seen.insert(peer, self.sightings);
+        self.sightings += 1;
+    }
+
+    fn count(&self) -> usize {
+        self.last_seen.len() + self.forgotten
+    }
+}
+
+/// Counts chat and peers for /stats, from the events bus
+pub fn start() {
+    LazyLock::force(&STARTED);
+    let mut receiver = events::subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(Event::ChatSent) => {
+                    MESSAGES_SENT.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(Event::ChatReceived { .. }) => {
+                    MESSAGES_RECEIVED.fetch_add(1, Ordering::SeqCst);
+                }
+                // Placeholders are counted once the peer itself shows up
+                Ok(Event::PeerDiscovered {
+                    username,
+                    node_id,
+                    placeholder: false,
+                }) => {
+                    if let Ok(mut seen) = PEERS_SEEN.lock() {
+                        seen.insert(node_id.unwrap_or(username));
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+pub fn uptime() -> Duration {
+    STARTED.elapsed()
+}
+
+pub fn messages_sent() -> u64 {
+    MESSAGES_SENT.load(Ordering::SeqCst)
+}
+
+pub fn messages_received() -> u64 {
+    MESSAGES_RECEIVED.load(Ordering::SeqCst)
+}
+
+pub fn peers_seen() -> usize {
+    PEERS_SEEN.lock().map(|seen| seen.count()).unwrap_or(0)
+}
+
+/// Start counting chat and peers from zero; uptime keeps going
+pub fn reset() {
+    MESSAGES_SENT.store(0, Ordering::SeqCst);
+    MESSAGES_RECEIVED.store(0, Ordering::SeqCst);
+    if let Ok(mut seen) = PEERS_SEEN.lock() {
+        *seen = PeersSeen::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_seen_stay_capped_but_counted() {
+        let mut seen = PeersSeen::default();
+        for n in 0..MAX_PEERS_SEEN + 10 {
+            seen.insert(format!("node-{n}"));
+        }
+        // Showing up again doesn't count twice
+        seen.insert(format!("node-{}", MAX_PEERS_SEEN + 9));
+        assert_eq!(seen.last_seen.len(), MAX_PEERS_SEEN);
+        assert_eq!(seen.count(), MAX_PEERS_SEEN + 10);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::events::{self, Event};
+use crate::peer::peer_list::Health;
+use crate::peer::{SharedPeerList, discovery, nick};
+use crate::ui::output;
+use crate::utils;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+const REFRESH_INTERVAL: u64 = 1; // seconds
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static TRACKING_UNREAD: AtomicBool = AtomicBool::new(false);
+// Chat received since we last typed something
+static UNREAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Pins a status bar to the bottom line of the terminal: everything else scrolls above it,
+/// in a scroll region one line shorter than the terminal. Does nothing if stdout isn't one.
+pub fn start(peer_list: SharedPeerList, username: String) {
+    if !std::io::stdout().is_terminal() || ACTIVE.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // Scroll everything up a line first, so the cursor isn't left on the bar's line
+    print!("\n\x1b[1A");
+    let _ = std::io::stdout().flush();
+    // A panic would otherwise leave the terminal stuck with the shorter scroll region
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        previous_hook(info);
+    }));
+    track_unread();
+
+    tokio::spawn(async move {
+        let mut height = 0;
+        let mut interval = time::interval(Duration::from_secs(REFRESH_INTERVAL));
+        loop {
+            interval.tick().await;
+            if !ACTIVE.load(Ordering::SeqCst) {
+                return;
+            }
+            let line = status_line(&peer_list, &username).await;
+            height = draw(&line, height);
+        }
+    });
+}
+
+/// Count chat received since we last typed something, for `status_line`
+pub fn track_unread() {
+    if TRACKING_UNREAD.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let mut receiver = events::subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(Event::ChatReceived { .. }) => {
+                    UNREAD.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(Event::ChatSent | Event::CommandRun(_)) => UNREAD.store(0, Ordering::SeqCst),
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+/// Give the whole terminal back, e.g. before exiting
+pub fn stop() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    output::overlay(&restore_sequence());
+}
+
+// Reset the scroll region, then clear the bar
+fn restore_sequence() -> String {
+    let height = terminal_size::terminal_size().map_or(0, |(_, height)| height.0);
+    format!("\x1b7\x1b[r\x1b[{height};1H\x1b[2K\x1b8")
+}
+
+// Straight to the terminal, for when the line editor may be gone, e.g. in a panic
+fn restore_terminal() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    print!("{}", restore_sequence());
+    let _ = std::io::stdout().flush();
+}
+
+/// Our name, room, peer count, unread chat and link health, on one line
+pub async fn status_line(peer_list: &SharedPeerList, username: &str) -> String {
+    let peers = peer_list.lock().await.get_peers();
+    let name = nick::current().unwrap_or_else(|| username.to_string());
+    let room = discovery::room().unwrap_or_else(|| "-".to_string());
+    let count = |health: Health| {
+        peers
+            .iter()
+            .filter(|peer| peer.health == Some(health))
+            .count()
+    };
+    let (degraded, failing) = (count(Health::Degraded), count(Health::Failing));
+    let link = match (degraded, failing) {
+        (0, 0) => "ok".to_string(),
+        (degraded, 0) => format!("{degraded} degraded"),
+        (0, failing) => format!("{failing} failing"),
+        (degraded, failing) => format!("{degraded} degraded, {failing} failing"),
+    };
+    format!(
+        " {name} │ room {room} │ {} peer(s) │ {} unread │ link {link}",
+        peers.len(),
+        UNREAD.load(Ordering::SeqCst)
+    )
+}
+
+// Draw the bar on the last line, leaving the cursor where it was; the scroll region is
+// set again when the terminal's height changes. Returns the height drawn at.
+fn draw(line: &str, last_height: u16) -> u16 {
+    let Some((width, height)) = terminal_size::terminal_size() else {
+        return last_height;
+    };
+    let (width, height) = (width.0 as usize, height.0);
+    let mut out = String::from("\x1b7");
+    if height != last_height {
+        out.push_str(&format!("\x1b[1;{}r", height.saturating_sub(1)));
+    }
+    let text = utils::truncate_to_width(line, width);
+    let padding = width.saturating_sub(utils::display_width(&text));
+    out.push_str(&format!(
+        "\x1b[{height};1H\x1b[2K\x1b[7m{text}{}\x1b[0m\x1b8",
+        " ".repeat(padding)
+    ));
+    output::overlay(&out);
+    height
+}
@@ -0,0 +1,215 @@
+use crate::utils;
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+
+/// What a piece of output is, for picking its color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    // @@@ lines
+    System,
+    // ### lines
+    Event,
+    // Times at the end of chat lines
+    Timestamp,
+    // Our own chat, echoed back
+    Own,
+    // Private messages, both ways
+    Direct,
+}
+
+impl Role {
+    pub const ALL: &[Role] = &[
+        Role::System,
+        Role::Event,
+        Role::Timestamp,
+        Role::Own,
+        Role::Direct,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::Event => "event",
+            Role::Timestamp => "timestamp",
+            Role::Own => "own",
+            Role::Direct => "direct",
+        }
+    }
+}
+
+/// An ANSI SGR code per role (e.g. 36 for cyan); None leaves the text as is. Senders are
+/// each shown in one of the `senders` colors, or as is if there are none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Theme {
+    system: Option<u8>,
+    event: Option<u8>,
+    timestamp: Option<u8>,
+    own: Option<u8>,
+    direct: Option<u8>,
+    senders: &'static [u8],
+}
+
+impl Theme {
+    fn color(&self, role: Role) -> Option<u8> {
+        match role {
+            Role::System => self.system,
+            Role::Event => self.event,
+            Role::Timestamp => self.timestamp,
+            Role::Own => self.own,
+            Role::Direct => self.direct,
+        }
+    }
+
+    fn set_color(&mut self, role: Role, color: Option<u8>) {
+        match role {
+            Role::System => self.system = color,
+            Role::Event => self.event = color,
+            Role::Timestamp => self.timestamp = color,
+            Role::Own => self.own = color,
+            Role::Direct => self.direct = color,
+        }
+    }
+}
+
+// No colors, only our own chat dimmed; what's used until a theme is picked
+const MONO: Theme = Theme {
+    system: None,
+    event: None,
+    timestamp: None,
+    own: Some(2),
+    direct: None,
+    senders: &[],
+};
+// Sender colors leave out red for warnings, and colors too close to the background
+const DARK: Theme = Theme {
+    system: Some(36),
+    event: Some(33),
+    timestamp: Some(90),
+    own: Some(2),
+    direct: Some(35),
+    senders: &[32, 33, 34, 35, 36, 92, 93, 94, 95, 96],
+};
+const LIGHT: Theme = Theme {
+    system: Some(34),
+    event: Some(35),
+    timestamp: Some(90),
+    own: Some(2),
+    direct: Some(32),
+    senders: &[32, 34, 35, 36, 94, 95],
+};
+pub const DEFAULT_THEME: &str = "dark";
+
+// Theme name -> theme; the built-in ones, plus those from the config file
+static THEMES: LazyLock<Mutex<BTreeMap<String, Theme>>> = LazyLock::new(|| {
+    Mutex::new(BTreeMap::from([
+        ("dark".to_string(), DARK),
+        ("light".to_string(), LIGHT),
+        ("mono".to_string(), MONO),
+    ]))
+});
+static CURRENT: Mutex<(Option<String>, Theme)> = Mutex::new((None, MONO));
+
+/// Add the themes from the config file, e.g. `[themes.ocean] system = "blue"`; roles they
+/// leave out aren't colored. Returns warnings about colors that couldn't be read.
+pub fn add_themes(themes: &BTreeMap<String, BTreeMap<String, String>>) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let Ok(mut known) = THEMES.lock() else {
+        return warnings;
+    };
+    for (name, colors) in themes {
+        let mut theme = Theme::default();
+        for (role_name, color) in colors {
+            let Some(role) = Role::ALL.iter().find(|role| role.name() == role_name) else {
+                warnings.push(format!(
+                    "theme {name}: unknown part '{role_name}' (available: system, event, timestamp, own, direct)"
+                ));
+                continue;
+            };
+            match parse_color(color) {
+                Some(color) => theme.set_color(*role, color),
+                None => warnings.push(format!("theme {name}: unknown color '{color}'")),
+            }
+        }
+        known.insert(name.clone(), theme);
+    }
+    warnings
+}
+
+// A color name (red, bright-blue, dim...), "none", or an SGR code
+fn parse_color(color: &str) -> Option<Option<u8>> {
+    const NAMES: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    let color = color.trim().to_ascii_lowercase();
+    if let Some(i) = NAMES.iter().position(|name| *name == color) {
+        return Some(Some(30 + i as u8));
+    }
+    if let Some(i) = color
+        .strip_prefix("bright-")
+        .and_then(|name| NAMES.iter().position(|known| *known == name))
+    {
+        return Some(Some(90 + i as u8));
+    }
+    match color.as_str() {
+        "none" => Some(None),
+        "bold" => Some(Some(1)),
+        "dim" => Some(Some(2)),
+        "gray" | "grey" => Some(Some(90)),
+        code => code.parse::<u8>().ok().map(Some),
+    }
+}
+
+/// Switch to a theme; returns false if there's none by that name
+pub fn select(name: &str) -> bool {
+    let Some(theme) = THEMES
+        .lock()
+        .ok()
+        .and_then(|themes| themes.get(name).copied())
+    else {
+        return false;
+    };
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = (Some(name.to_string()), theme);
+    }
+    true
+}
+
+pub fn names() -> Vec<String> {
+    THEMES
+        .lock()
+        .map(|themes| themes.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+pub fn current_name() -> Option<String> {
+    CURRENT.lock().ok().and_then(|current| current.0.clone())
+}
+
+/// Text in the color of a sender, picked from `key` (its node ID, or else its username),
+/// so the sender has the same color on every line and in every run of a theme
+pub fn paint_sender(text: &str, key: &str) -> String {
+    let senders = CURRENT
+        .lock()
+        .map(|current| current.1.senders)
+        .unwrap_or_default();
+    if senders.is_empty() {
+        return text.to_string();
+    }
+    // FNV-1a; std's hasher may change between Rust releases
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    utils::colorize(text, senders[(hash % senders.len() as u64) as usize])
+}
+
+/// Text in the current theme's color for its role
+pub fn paint(role: Role, text: &str) -> String {
+    match CURRENT
+        .lock()
+        .ok()
+        .and_then(|current| current.1.color(role))
+    {
+        Some(color) => utils::colorize(text, color),
+        None => text.to_string(),
+    }
+}
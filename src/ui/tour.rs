@@ -0,0 +1,135 @@
+use crate::events::{self, Event};
+use crate::peer::SharedPeerList;
+use crate::utils;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use tokio::sync::broadcast::error::RecvError;
+
+struct Step {
+    // Commands (including aliases) that complete this step; empty means "send a chat message"
+    commands: &'static [&'static str],
+    hint: &'static str,
+}
+
+const STEPS: &[Step] = &[
+    Step {
+        commands: &[],
+        hint: "Say hello: type a message and press Enter to send it to everyone",
+    },
+    Step {
+        commands: &["/peers", "/p"],
+        hint: "See who's around: run /peers",
+    },
+    Step {
+        commands: &["/netstat"],
+        hint: "Check how your link is doing: run /netstat",
+    },
+    Step {
+        commands: &["/state", "/s"],
+        hint: "Look at your settings (ports, codec, ...): run /state",
+    },
+    Step {
+        commands: &["/stream"],
+        hint: "Share a command's output with your peers: try /stream uptime",
+    },
+];
+
+// Bumped whenever a tour starts or stops; a running tour exits once it no longer matches
+static TOUR_GENERATION: AtomicU32 = AtomicU32::new(0);
+static TOUR_RUNNING: AtomicBool = AtomicBool::new(false);
+
+impl Step {
+    fn is_completed_by(&self, event: &Event) -> bool {
+        match event {
+            Event::ChatSent => self.commands.is_empty(),
+            Event::CommandRun(command) => self.commands.contains(&command.as_str()),
+            Event::PeerDiscovered { .. }
+            | Event::DiscoveryReply(_)
+            | Event::PeerLeft
+            | Event::ChatReceived { .. } => false,
+        }
+    }
+}
+
+/// Start the tour, restarting it if one is already running
+pub fn start(peer_list: SharedPeerList) {
+    let generation = TOUR_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    TOUR_RUNNING.store(true, Ordering::SeqCst);
+    // Subscribe before returning, so the command that started us can't race the first event
+    let mut receiver = events::subscribe();
+
+    utils::display_message_block(
+        "Tour (/tour)",
+        vec![
+            format!(
+                "Welcome! This tour walks you through pung in {} steps.",
+                STEPS.len()
+            ),
+            "Steps you've already done are skipped. Stop any time with /tour stop.".to_string(),
+        ],
+    );
+
+    tokio::spawn(async move {
+        let mut done = vec![false; STEPS.len()];
+        let mut current = 0;
+        show_hint(current, &peer_list).await;
+
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            if TOUR_GENERATION.load(Ordering::SeqCst) != generation {
+                return;
+            }
+
+            // A peer showing up while we're waiting on /peers is worth pointing out
+            if let Event::PeerDiscovered { username: name, .. } = &event
+                && STEPS[current].commands.contains(&"/peers")
+            {
+                say!("@@@ Tour: {name} just joined, run /peers to see them");
+            }
+
+            for (i, step) in STEPS.iter().enumerate() {
+                if step.is_completed_by(&event) {
+                    done[i] = true;
+                }
+            }
+            if !done[current] {
+                continue;
+            }
+
+            match done.iter().position(|d| !d) {
+                Some(next) => {
+                    say!("@@@ Tour: nice, step {} done!", current + 1);
+                    current = next;
+                    show_hint(current, &peer_list).await;
+                }
+                None => {
+                    say!("@@@ Tour complete! /help lists everything else pung can do.");
+                    TOUR_RUNNING.store(false, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Stop a running tour; returns false if there was none
+pub fn stop() -> bool {
+    TOUR_GENERATION.fetch_add(1, Ordering::SeqCst);
+    TOUR_RUNNING.swap(false, Ordering::SeqCst)
+}
+
+async fn show_hint(step: usize, peer_list: &SharedPeerList) {
+    let hint = STEPS[step].hint;
+    say!("@@@ Tour ({}/{}): {hint}", step + 1, STEPS.len());
+
+    // Adapt to what the user will actually see
+    let has_peers = !peer_list.lock().await.get_peers().is_empty();
+    if !has_peers && (STEPS[step].commands.is_empty() || STEPS[step].commands.contains(&"/peers")) {
+        say!(
+            "@@@ Tour: no peers yet; run /broadcast to look for them, or start pung on another machine"
+        );
+    }
+}
@@ -0,0 +1,701 @@
+use crate::peer::SharedPeerList;
+use crate::peer::peer_list::Health;
+use crate::ui::completion::LineHelper;
+use crate::ui::reactions::{self, QUICK_REACTIONS, Recent};
+use crate::ui::{notify, status_bar};
+use crate::utils;
+use ratatui::crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, ExternalPrinter};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time;
+use unicode_width::UnicodeWidthChar;
+
+// Lines of chat kept for scrolling back; older ones are dropped
+const MAX_SCROLLBACK: usize = 5000;
+const SIDEBAR_WIDTH: u16 = 26;
+const REFRESH_INTERVAL: u64 = 1; // seconds
+// How often the input thread checks whether the TUI is still up
+const INPUT_POLL: u64 = 100; // milliseconds
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+// What's printed goes to the chat pane through this
+static OUTPUT: OnceLock<UnboundedSender<Update>> = OnceLock::new();
+// Lines entered in the input box, for `read_line`
+static SUBMITTED: tokio::sync::Mutex<Option<UnboundedReceiver<Submitted>>> =
+    tokio::sync::Mutex::const_new(None);
+// The question the input box asks, e.g. a password prompt; empty for chat
+static PROMPT: Mutex<(String, bool)> = Mutex::new((String::new(), false));
+
+enum Update {
+    Line(String),
+    Clear,
+    ForgetTyped,
+}
+
+enum Submitted {
+    Line(String),
+    Interrupted,
+    Eof,
+}
+
+/// Prints into the chat pane, for output::use_printer
+pub struct Printer(UnboundedSender<Update>);
+
+impl ExternalPrinter for Printer {
+    fn print(&mut self, msg: String) -> rustyline::Result<()> {
+        for line in msg.strip_suffix('\n').unwrap_or(&msg).split('\n') {
+            self.0
+                .send(Update::Line(line.to_string()))
+                .map_err(|_| ReadlineError::Eof)?;
+        }
+        Ok(())
+    }
+}
+
+/// Take over the terminal with a full-screen layout: scrolling chat, a sidebar with the
+/// peers and their link health, an input box and a status bar. `fit_width` lays chat out
+/// in the width of its pane, instead of the one --width set.
+pub fn start(
+    peer_list: SharedPeerList,
+    username: String,
+    helper: LineHelper,
+    fit_width: bool,
+) -> std::io::Result<Printer> {
+    if ACTIVE.swap(true, Ordering::SeqCst) {
+        return Err(std::io::Error::other("the TUI is already running"));
+    }
+    // Also sets a panic hook, so a panic doesn't leave the terminal in raw mode on the
+    // alternate screen
+    let terminal = ratatui::try_init()?;
+    status_bar::track_unread();
+
+    let (output_sender, output_receiver) = mpsc::unbounded_channel();
+    let _ = OUTPUT.set(output_sender.clone());
+    let (submit_sender, submit_receiver) = mpsc::unbounded_channel();
+    if let Ok(mut submitted) = SUBMITTED.try_lock() {
+        *submitted = Some(submit_receiver);
+    }
+
+    // Reading keys blocks, so it gets a thread of its own
+    let (key_sender, key_receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while ACTIVE.load(Ordering::SeqCst) {
+            match event::poll(Duration::from_millis(INPUT_POLL)) {
+                Ok(true) => match event::read() {
+                    Ok(event) => {
+                        if key_sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => return,
+                },
+                Ok(false) => {}
+                Err(_) => return,
+            }
+        }
+    });
+
+    let app = App {
+        terminal,
+        peer_list,
+        username,
+        helper,
+        history: DefaultHistory::new(),
+        fit_width,
+        submit: submit_sender,
+        lines: VecDeque::new(),
+        scroll: 0,
+        unseen: 0,
+        input: String::new(),
+        cursor: 0,
+        typed: Vec::new(),
+        recalled: None,
+        status: String::new(),
+        peers: Vec::new(),
+        pane_width: 0,
+        selected: None,
+    };
+    tokio::spawn(app.run(output_receiver, key_receiver));
+    Ok(Printer(output_sender))
+}
+
+/// Give the terminal back, e.g. before exiting
+pub fn stop() {
+    if ACTIVE.swap(false, Ordering::SeqCst) {
+        ratatui::restore();
+    }
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Empty the chat pane, like clearing the screen does without the TUI
+pub fn clear() {
+    if let Some(output) = OUTPUT.get() {
+        let _ = output.send(Update::Clear);
+    }
+}
+
+/// The next line entered in the input box, under `prompt` (empty for chat); a secret,
+/// like a password, isn't shown while it's typed
+pub async fn read_line(prompt: &str, secret: bool) -> rustyline::Result<String> {
+    if let Ok(mut current) = PROMPT.lock() {
+        *current = (prompt.to_string(), secret);
+    }
+    let submitted = match SUBMITTED.lock().await.as_mut() {
+        Some(receiver) => receiver.recv().await,
+        None => None,
+    };
+    if let Ok(mut current) = PROMPT.lock() {
+        *current = (String::new(), false);
+    }
+    match submitted {
+        Some(Submitted::Line(line)) => Ok(line),
+        Some(Submitted::Interrupted) => Err(ReadlineError::Interrupted),
+        Some(Submitted::Eof) | None => Err(ReadlineError::Eof),
+    }
+}
+
+/// Forget the lines entered so far, so Up can't bring them back
+pub fn clear_history() {
+    if let Some(output) = OUTPUT.get() {
+        let _ = output.send(Update::ForgetTyped);
+    }
+}
+
+struct App {
+    terminal: DefaultTerminal,
+    peer_list: SharedPeerList,
+    username: String,
+    helper: LineHelper,
+    // Only there for the completer, which wants one
+    history: DefaultHistory,
+    fit_width: bool,
+    submit: UnboundedSender<Submitted>,
+    // Chat pane lines, oldest first
+    lines: VecDeque<Vec<Span<'static>>>,
+    // Rows scrolled back from the bottom
+    scroll: usize,
+    // Lines that arrived while scrolled back
+    unseen: usize,
+    input: String,
+    // In chars, not bytes
+    cursor: usize,
+    // Lines entered, for Up and Down
+    typed: Vec<String>,
+    recalled: Option<usize>,
+    status: String,
+    peers: Vec<Line<'static>>,
+    pane_width: usize,
+    // A peer's message picked with Alt+Up and Alt+Down, for a quick reaction with 1-5
+    selected: Option<Recent>,
+}
+
+impl App {
+    async fn run(
+        mut self,
+        mut output: UnboundedReceiver<Update>,
+        mut keys: UnboundedReceiver<TermEvent>,
+    ) {
+        let mut refresh = time::interval(Duration::from_secs(REFRESH_INTERVAL));
+        loop {
+            tokio::select! {
+                update = output.recv() => match update {
+                    Some(update) => self.update(update),
+                    None => return,
+                },
+                event = keys.recv() => match event {
+                    Some(event) => self.handle(event),
+                    None => return,
+                },
+                _ = refresh.tick() => self.refresh().await,
+            }
+            if !ACTIVE.load(Ordering::SeqCst) {
+                return;
+            }
+            // Draw once for a burst of output, not once per line
+            while let Ok(update) = output.try_recv() {
+                self.update(update);
+            }
+            let mut view = View {
+                lines: &self.lines,
+                scroll: &mut self.scroll,
+                unseen: self.unseen,
+                input: &self.input,
+                cursor: self.cursor,
+                status: &self.status,
+                peers: &self.peers,
+                pane_width: &mut self.pane_width,
+                fit_width: self.fit_width,
+                selected: self.selected.as_ref(),
+            };
+            if let Err(e) = self.terminal.draw(|frame| draw(frame, &mut view)) {
+                log::debug!("Could not draw the TUI: {e}");
+            }
+        }
+    }
+
+    fn update(&mut self, update: Update) {
+        match update {
+            Update::Line(line) => {
+                self.lines.push_back(styled_spans(&line));
+                if self.lines.len() > MAX_SCROLLBACK {
+                    self.lines.pop_front();
+                }
+                if self.scroll > 0 {
+                    self.unseen += 1;
+                }
+            }
+            Update::Clear => {
+                self.lines.clear();
+                self.scroll = 0;
+                self.unseen = 0;
+            }
+            Update::ForgetTyped => {
+                self.typed.clear();
+                self.recalled = None;
+            }
+        }
+    }
+
+    async fn refresh(&mut self) {
+        self.status = status_bar::status_line(&self.peer_list, &self.username).await;
+        let (names, peers) = {
+            let peer_list = self.peer_list.lock().await;
+            (peer_list.display_names(), peer_list.get_peers())
+        };
+        let mut rows: Vec<(String, Option<Health>)> = peers
+            .iter()
+            .map(|peer| {
+                let name = names
+                    .get(&peer.addr)
+                    .cloned()
+                    .unwrap_or_else(|| peer.username.clone());
+                (name, peer.health)
+            })
+            .collect();
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        self.peers = rows
+            .into_iter()
+            .map(|(name, health)| {
+                Line::from(vec![
+                    Span::styled("● ", Style::default().fg(health_color(health))),
+                    Span::raw(name),
+                ])
+            })
+            .collect();
+    }
+
+    fn handle(&mut self, event: TermEvent) {
+        let TermEvent::Key(key) = event else {
+            // Resizes and the like only need the redraw that follows
+            return;
+        };
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        notify::record_activity();
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        match key.code {
+            KeyCode::Up if alt => self.select(true),
+            KeyCode::Down if alt => self.select(false),
+            KeyCode::Esc => self.selected = None,
+            KeyCode::Char(c @ '1'..='5') if self.selected.is_some() && !ctrl && !alt => {
+                self.react(c);
+            }
+            KeyCode::Char('c') if ctrl => {
+                self.input.clear();
+                self.cursor = 0;
+                let _ = self.submit.send(Submitted::Interrupted);
+            }
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => {
+                let _ = self.submit.send(Submitted::Eof);
+            }
+            KeyCode::Char('u') if ctrl => {
+                self.input.clear();
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) => {
+                // Typing goes back to writing a message
+                self.selected = None;
+                let at = self.byte_index();
+                self.input.insert(at, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                let at = self.byte_index();
+                self.input.remove(at);
+            }
+            KeyCode::Delete if self.cursor < self.input.chars().count() => {
+                let at = self.byte_index();
+                self.input.remove(at);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Up => self.recall(true),
+            KeyCode::Down => self.recall(false),
+            KeyCode::PageUp => self.scroll_by(true),
+            KeyCode::PageDown => self.scroll_by(false),
+            KeyCode::Tab => self.complete(),
+            KeyCode::Enter => self.enter(),
+            _ => {}
+        }
+    }
+
+    // Pick the peer's message before (or after) the selected one; past the latest, none
+    fn select(&mut self, older: bool) {
+        let current = self
+            .selected
+            .as_ref()
+            .map(|message| message.message_id.as_str());
+        self.selected = reactions::step(current, older);
+    }
+
+    // Send quick reaction `key` to the selected message, through /react like a typed one
+    fn react(&mut self, key: char) {
+        let Some(message) = self.selected.take() else {
+            return;
+        };
+        // Not while the input box asks something else, like a password
+        if PROMPT.lock().is_ok_and(|prompt| !prompt.0.is_empty()) {
+            return;
+        }
+        match reactions::position(&message.message_id) {
+            Some(back) => {
+                let _ = self
+                    .submit
+                    .send(Submitted::Line(format!("/react {back} {key}")));
+            }
+            None => say!("@@@ That message is too old to react to now"),
+        }
+    }
+
+    fn byte_index(&self) -> usize {
+        self.input
+            .char_indices()
+            .nth(self.cursor)
+            .map_or(self.input.len(), |(i, _)| i)
+    }
+
+    // Enter on an empty line while scrolled back jumps to the latest chat; otherwise the
+    // line is entered
+    fn enter(&mut self) {
+        if self.input.is_empty() && self.scroll > 0 {
+            self.scroll = 0;
+            self.unseen = 0;
+            return;
+        }
+        let line = std::mem::take(&mut self.input);
+        self.cursor = 0;
+        self.recalled = None;
+        self.scroll = 0;
+        self.unseen = 0;
+        let secret = PROMPT.lock().is_ok_and(|prompt| prompt.1);
+        if !secret && !line.trim().is_empty() {
+            self.typed.push(line.clone());
+        }
+        let _ = self.submit.send(Submitted::Line(line));
+    }
+
+    // Bring back an earlier (or later) line entered
+    fn recall(&mut self, earlier: bool) {
+        if self.typed.is_empty() {
+            return;
+        }
+        let index = match (self.recalled, earlier) {
+            (None, true) => Some(self.typed.len() - 1),
+            (None, false) => None,
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < self.typed.len() => Some(i + 1),
+            (Some(_), false) => None,
+        };
+        self.recalled = index;
+        self.input = index.map(|i| self.typed[i].clone()).unwrap_or_default();
+        self.cursor = self.input.chars().count();
+    }
+
+    fn scroll_by(&mut self, back: bool) {
+        let page = self
+            .terminal
+            .size()
+            .map_or(10, |size| size.height as usize / 2);
+        if back {
+            self.scroll += page;
+        } else {
+            self.scroll = self.scroll.saturating_sub(page);
+            if self.scroll == 0 {
+                self.unseen = 0;
+            }
+        }
+    }
+
+    // Complete commands and peer names like the line editor does: the only candidate, or
+    // as much as all of them share
+    fn complete(&mut self) {
+        let at = self.byte_index();
+        let ctx = Context::new(&self.history);
+        let Ok((start, candidates)) = self.helper.complete(&self.input, at, &ctx) else {
+            return;
+        };
+        let replacement = match candidates.as_slice() {
+            [] => return,
+            [only] => only.replacement.clone(),
+            [first, rest @ ..] => {
+                let mut shared = first.display.clone();
+                for candidate in rest {
+                    let common = shared
+                        .chars()
+                        .zip(candidate.display.chars())
+                        .take_while(|(a, b)| a == b)
+                        .map(|(c, _)| c.len_utf8())
+                        .sum();
+                    shared.truncate(common);
+                }
+                shared
+            }
+        };
+        self.input.replace_range(start..at, &replacement);
+        self.cursor = self.input[..start + replacement.len()].chars().count();
+    }
+}
+
+// What drawing needs; the terminal itself is borrowed for the draw
+struct View<'a> {
+    lines: &'a VecDeque<Vec<Span<'static>>>,
+    scroll: &'a mut usize,
+    unseen: usize,
+    input: &'a str,
+    cursor: usize,
+    status: &'a str,
+    peers: &'a [Line<'static>],
+    pane_width: &'a mut usize,
+    fit_width: bool,
+    selected: Option<&'a Recent>,
+}
+
+fn draw(frame: &mut Frame, view: &mut View) {
+    let [main, input_area, status_area] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [chat_area, sidebar_area] =
+        Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)]).areas(main);
+
+    draw_chat(frame, view, chat_area);
+
+    let peers = Paragraph::new(view.peers.to_vec())
+        .block(Block::bordered().title(format!(" peers ({}) ", view.peers.len())));
+    frame.render_widget(peers, sidebar_area);
+
+    let (prompt, secret) = PROMPT
+        .lock()
+        .map(|prompt| prompt.clone())
+        .unwrap_or_default();
+    let title = if prompt.is_empty() {
+        " message ".to_string()
+    } else {
+        format!(" {} ", prompt.trim())
+    };
+    // Only the end of a long line fits; the cursor is kept in view
+    let inner_width = input_area.width.saturating_sub(2) as usize;
+    let shown: String = if secret {
+        String::new()
+    } else {
+        view.input.chars().collect()
+    };
+    let before_cursor: String = shown.chars().take(view.cursor).collect();
+    let cursor_column = utils::display_width(&before_cursor);
+    let skip = cursor_column.saturating_sub(inner_width.saturating_sub(1));
+    let visible = skip_columns(&shown, skip);
+    frame.render_widget(
+        Paragraph::new(visible).block(Block::bordered().title(title)),
+        input_area,
+    );
+    if !secret {
+        frame.set_cursor_position((
+            input_area.x + 1 + (cursor_column - skip) as u16,
+            input_area.y + 1,
+        ));
+    }
+
+    frame.render_widget(
+        Paragraph::new(view.status.to_string())
+            .style(Style::default().add_modifier(Modifier::REVERSED)),
+        status_area,
+    );
+}
+
+// The chat pane, bottom-aligned, scrolled back by `view.scroll` rows
+fn draw_chat(frame: &mut Frame, view: &mut View, area: Rect) {
+    let width = area.width.saturating_sub(2).max(1) as usize;
+    let height = area.height.saturating_sub(2) as usize;
+    if *view.pane_width != width {
+        *view.pane_width = width;
+        // Boxes like /peers are laid out for the pane, and so is chat unless --width says
+        // otherwise
+        utils::set_output_width(width);
+        if view.fit_width {
+            let _ = utils::set_chat_width(width);
+        }
+    }
+
+    // Wrap from the newest line back, only as far as the rows on screen
+    let mut rows: Vec<Line<'static>> = Vec::new();
+    let wanted = *view.scroll + height;
+    for line in view.lines.iter().rev() {
+        let mut wrapped = wrap(line, width);
+        wrapped.reverse();
+        rows.extend(wrapped);
+        if rows.len() >= wanted {
+            break;
+        }
+    }
+    // Can't scroll back past the oldest line
+    *view.scroll = (*view.scroll).min(rows.len().saturating_sub(height));
+    let shown: Vec<Line<'static>> = rows
+        .into_iter()
+        .skip(*view.scroll)
+        .take(height)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    let padding = height.saturating_sub(shown.len());
+    let mut lines = vec![Line::default(); padding];
+    lines.extend(shown);
+
+    let mut block = Block::bordered().title(" pung ");
+    if *view.scroll > 0 {
+        let hint = match view.unseen {
+            0 => " ▲ scrolled back, Enter to jump down ".to_string(),
+            1 => " ▼ 1 new message ".to_string(),
+            count => format!(" ▼ {count} new messages "),
+        };
+        block = block.title_bottom(Line::from(hint).right_aligned());
+    }
+    if let Some(message) = view.selected {
+        let keys: Vec<String> = QUICK_REACTIONS
+            .iter()
+            .enumerate()
+            .map(|(i, reaction)| format!("{} {reaction}", i + 1))
+            .collect();
+        let hint = format!(
+            " ▶ {}: {}  {}  Esc ",
+            message.sender,
+            reactions::preview(&message.content),
+            keys.join(" ")
+        );
+        block = block.title_bottom(
+            Line::from(hint).style(Style::default().add_modifier(Modifier::REVERSED)),
+        );
+    }
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn health_color(health: Option<Health>) -> Color {
+    match health {
+        Some(Health::Good) => Color::Green,
+        Some(Health::Degraded) => Color::Yellow,
+        Some(Health::Failing) => Color::Red,
+        None => Color::DarkGray,
+    }
+}
+
+// Text past its first `columns` columns
+fn skip_columns(text: &str, columns: usize) -> String {
+    let mut used = 0;
+    text.chars()
+        .skip_while(|&c| {
+            used += UnicodeWidthChar::width(c).unwrap_or(0);
+            used <= columns
+        })
+        .collect()
+}
+
+// Output carries the theme's ANSI colors; the chat pane needs them as styles
+fn styled_spans(text: &str) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut style = Style::default();
+    let mut rest = text;
+    while let Some(start) = rest.find('\x1b') {
+        if start > 0 {
+            spans.push(Span::styled(rest[..start].to_string(), style));
+        }
+        let sequence = &rest[start + 1..];
+        let end = sequence
+            .find(|c: char| c.is_ascii_alphabetic())
+            .map_or(sequence.len(), |i| i + 1);
+        // Only SGR sequences like "\x1b[32m" are used; anything else is dropped
+        if let Some(codes) = sequence[..end]
+            .strip_prefix('[')
+            .and_then(|codes| codes.strip_suffix('m'))
+        {
+            style = apply_sgr(style, codes);
+        }
+        rest = &sequence[end..];
+    }
+    if !rest.is_empty() {
+        spans.push(Span::styled(rest.to_string(), style));
+    }
+    spans
+}
+
+fn apply_sgr(style: Style, codes: &str) -> Style {
+    codes
+        .split(';')
+        .fold(style, |style, code| match code.parse::<u8>().unwrap_or(0) {
+            0 => Style::default(),
+            1 => style.add_modifier(Modifier::BOLD),
+            2 => style.add_modifier(Modifier::DIM),
+            7 => style.add_modifier(Modifier::REVERSED),
+            code @ 30..=37 => style.fg(Color::Indexed(code - 30)),
+            code @ 90..=97 => style.fg(Color::Indexed(code - 90 + 8)),
+            _ => style,
+        })
+}
+
+// A line cut into rows of at most `width` columns
+fn wrap(spans: &[Span<'static>], width: usize) -> Vec<Line<'static>> {
+    let mut rows = Vec::new();
+    let mut row: Vec<Span<'static>> = Vec::new();
+    let mut row_width = 0;
+    for span in spans {
+        let mut chunk = String::new();
+        for c in span.content.chars() {
+            let char_width = UnicodeWidthChar::width(c).unwrap_or(0);
+            if row_width + char_width > width && row_width > 0 {
+                if !chunk.is_empty() {
+                    row.push(Span::styled(std::mem::take(&mut chunk), span.style));
+                }
+                rows.push(Line::from(std::mem::take(&mut row)));
+                row_width = 0;
+            }
+            chunk.push(c);
+            row_width += char_width;
+        }
+        if !chunk.is_empty() {
+            row.push(Span::styled(chunk, span.style));
+        }
+    }
+    rows.push(Line::from(row));
+    rows
+}
@@ -0,0 +1,96 @@
+use crate::config;
+use crate::ui::chat_log;
+use std::io::Write;
+use std::path::Path;
+
+// Files in ~/.config/pung each scope wipes; "all" takes the whole directory
+const KEY_FILES: &[&str] = &["identity.key", "known_keys.toml"];
+const STATE_FILES: &[&str] = &[
+    "node_id",
+    "peer_cache.toml",
+    "peers.toml",
+    "groups.toml",
+    "blocklist.toml",
+    "banlist.toml",
+];
+
+/// What /panic deletes, from the panic_wipe setting or /panic's argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    // Identity key and the keys we pinned for peers
+    Keys,
+    // Keys, plus everything that says who we are and who we talked to, the /log file too
+    State,
+    // The whole config directory, settings included
+    All,
+}
+
+impl Scope {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "keys" => Some(Scope::Keys),
+            "state" => Some(Scope::State),
+            "all" => Some(Scope::All),
+            _ => None,
+        }
+    }
+}
+
+/// Clears the screen and the terminal's scrollback
+pub fn clear_terminal() {
+    #[cfg(feature = "tui")]
+    if crate::ui::tui::is_active() {
+        crate::ui::tui::clear();
+        return;
+    }
+    print!("\x1B[3J\x1B[2J\x1B[H");
+    let _ = std::io::stdout().flush();
+}
+
+/// Deletes the files of `scope`, overwriting them first so they can't be recovered as
+/// easily, and stops /log. Returns the files that could not be deleted.
+pub fn wipe(scope: Scope) -> Vec<String> {
+    let chat_log = chat_log::stop().filter(|_| scope != Scope::Keys);
+    let Some(dir) = config::config_dir() else {
+        return Vec::new();
+    };
+    let mut files: Vec<_> = match scope {
+        Scope::Keys => KEY_FILES.iter().map(|name| dir.join(name)).collect(),
+        Scope::State => KEY_FILES
+            .iter()
+            .chain(STATE_FILES)
+            .map(|name| dir.join(name))
+            .collect(),
+        Scope::All => std::fs::read_dir(&dir)
+            .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+            .unwrap_or_default(),
+    };
+    // The log can be anywhere, not only in the config directory
+    files.extend(chat_log);
+
+    let mut failed: Vec<String> = files
+        .iter()
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            shred(path)
+                .err()
+                .map(|e| format!("{}: {e}", path.display()))
+        })
+        .collect();
+    if scope == Scope::All
+        && let Err(e) = std::fs::remove_dir_all(&dir)
+        && dir.exists()
+    {
+        failed.push(format!("{}: {e}", dir.display()));
+    }
+    failed
+}
+
+fn shred(path: &Path) -> std::io::Result<()> {
+    let len = std::fs::metadata(path)?.len();
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0u8; len as usize])?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::remove_file(path)
+}
@@ -1,25 +1,155 @@
-use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use crate::ui::theme::{self, Role};
+use chrono::format::StrftimeItems;
+use chrono::{DateTime, FixedOffset, Local, Offset, TimeZone, Utc};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
 use get_if_addrs::get_if_addrs;
 use rand::Rng;
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// The timezone times are shown in: the system's, unless --tz or /set tz says otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeZoneSetting {
+    Local,
+    Fixed(FixedOffset),
+}
+
+static TIME_ZONE: Mutex<TimeZoneSetting> = Mutex::new(TimeZoneSetting::Local);
+
+/// Parse "local", "utc", or an offset like "+8", "-05:30" or "UTC+5:45"
+pub fn parse_time_zone(value: &str) -> Result<TimeZoneSetting, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("local") {
+        return Ok(TimeZoneSetting::Local);
+    }
+    let offset = value
+        .strip_prefix("UTC")
+        .or_else(|| value.strip_prefix("utc"))
+        .or_else(|| value.strip_prefix("GMT"))
+        .unwrap_or(value);
+    if offset.is_empty() || offset == "Z" {
+        return Ok(TimeZoneSetting::Fixed(Utc.fix()));
+    }
+    let invalid =
+        || format!("Invalid timezone '{value}': use local, utc or an offset like +8 or -05:30");
+    let (sign, offset) = match offset.split_at_checked(1) {
+        Some(("+", rest)) => (1, rest),
+        Some(("-", rest)) => (-1, rest),
+        _ => return Err(invalid()),
+    };
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+    // Digits only: parse() would take another sign, and a huge number would overflow
+    let digits = |part: &str| {
+        (!part.is_empty() && part.len() <= 2 && part.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| part.parse::<u32>().ok())
+            .flatten()
+    };
+    let (Some(hours), Some(minutes)) = (digits(hours), digits(minutes)) else {
+        return Err(invalid());
+    };
+    if minutes >= 60 {
+        return Err(invalid());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60) as i32)
+        .map(TimeZoneSetting::Fixed)
+        .ok_or_else(invalid)
+}
+
+pub fn set_time_zone(time_zone: TimeZoneSetting) {
+    if let Ok(mut current) = TIME_ZONE.lock() {
+        *current = time_zone;
+    }
+}
+
+fn time_zone() -> TimeZoneSetting {
+    TIME_ZONE
+        .lock()
+        .map_or(TimeZoneSetting::Local, |time_zone| *time_zone)
+}
 
 pub fn display_time_from_timestamp(timestamp: i64) -> String {
-    // Default to UTC+8 timezone
-    display_time_from_timestamp_with_tz(timestamp, 8)
+    format_timestamp(timestamp, "%H:%M:%S")
 }
 
-pub fn display_time_from_timestamp_with_tz(timestamp: i64, offset_hours: i32) -> String {
-    // Create a fixed offset for the specified timezone
-    let timezone = FixedOffset::east_opt(offset_hours * 3600).unwrap(); // offset_hours * 3600 seconds
+/// The calendar date of a timestamp, in the timezone times are shown in
+pub fn display_date_from_timestamp(timestamp: i64) -> String {
+    format_timestamp(timestamp, "%Y-%m-%d")
+}
 
-    // First convert to UTC time
-    let utc_time: DateTime<Utc> = Utc.timestamp_opt(timestamp, 0).unwrap();
+/// A timestamp in ISO 8601 with its offset, e.g. 2024-05-12T13:05:09+02:00
+pub fn iso_time_from_timestamp(timestamp: i64) -> String {
+    format_timestamp(timestamp, "%Y-%m-%dT%H:%M:%S%:z")
+}
 
-    // Then convert to the desired timezone
-    let local_time = utc_time.with_timezone(&timezone);
+fn format_timestamp(timestamp: i64, format: &str) -> String {
+    let utc_time: DateTime<Utc> = Utc.timestamp_opt(timestamp, 0).single().unwrap_or_default();
+    match time_zone() {
+        TimeZoneSetting::Local => utc_time.with_timezone(&Local).format(format).to_string(),
+        TimeZoneSetting::Fixed(offset) => {
+            utc_time.with_timezone(&offset).format(format).to_string()
+        }
+    }
+}
 
-    // Format the time in the local timezone
-    local_time.format("%H:%M:%S").to_string()
+/// How chat lines show when they were sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeFormat {
+    Strftime(String),
+    // e.g. "2m ago", as of when the line is printed
+    Relative,
+    Hidden,
+}
+
+static TIME_FORMAT: LazyLock<Mutex<TimeFormat>> =
+    LazyLock::new(|| Mutex::new(TimeFormat::Strftime("%H:%M:%S".to_string())));
+
+/// Parse "full" (13:05:09), "short" (13:05), "12h" (01:05 PM), "relative", "hidden", or
+/// a strftime format like "%H:%M"
+pub fn parse_time_format(value: &str) -> Result<TimeFormat, String> {
+    match value.trim() {
+        "full" => Ok(TimeFormat::Strftime("%H:%M:%S".to_string())),
+        "short" => Ok(TimeFormat::Strftime("%H:%M".to_string())),
+        "12h" => Ok(TimeFormat::Strftime("%I:%M %p".to_string())),
+        "relative" => Ok(TimeFormat::Relative),
+        "hidden" => Ok(TimeFormat::Hidden),
+        format if format.contains('%') && StrftimeItems::new(format).parse().is_ok() => {
+            Ok(TimeFormat::Strftime(format.to_string()))
+        }
+        other => Err(format!(
+            "Invalid time format '{other}': use full, short, 12h, relative, hidden or a strftime format like %H:%M"
+        )),
+    }
+}
+
+pub fn set_time_format(time_format: TimeFormat) {
+    if let Ok(mut current) = TIME_FORMAT.lock() {
+        *current = time_format;
+    }
+}
+
+// The time a chat line ends with, if it shows one
+fn chat_time(timestamp: i64) -> Option<String> {
+    let time_format = TIME_FORMAT.lock().ok()?.clone();
+    match time_format {
+        TimeFormat::Strftime(format) => Some(format_timestamp(timestamp, &format)),
+        TimeFormat::Relative => Some(relative_time(Utc::now().timestamp() - timestamp)),
+        TimeFormat::Hidden => None,
+    }
+}
+
+// How long ago something happened, in its largest unit; clock skew can make it negative
+fn relative_time(seconds_ago: i64) -> String {
+    match seconds_ago {
+        ..=0 => "now".to_string(),
+        1..60 => format!("{seconds_ago}s ago"),
+        60..3600 => format!("{}m ago", seconds_ago / 60),
+        3600..86400 => format!("{}h ago", seconds_ago / 3600),
+        _ => format!("{}d ago", seconds_ago / 86400),
+    }
 }
 
 /// Get the local IP address (non-loopback) for the LAN
@@ -52,41 +182,285 @@ pub fn get_random_port(min: u16, max: u16) -> u16 {
     rng.random_range(min..=max)
 }
 
-/// Check if a new version is available on GitHub
-/// Returns Some(latest_version) if a newer version is available, None otherwise or on error
+/// An inclusive range of ports, written as "min-max"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl PortRange {
+    pub const fn new(min: u16, max: u16) -> Self {
+        PortRange { min, max }
+    }
+
+    /// Parse a "min-max" string, rejecting empty or reversed ranges
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (min, max) = value
+            .split_once('-')
+            .ok_or_else(|| format!("'{value}' is not a range, expected MIN-MAX"))?;
+        let min = min
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| format!("'{min}' is not a valid port"))?;
+        let max = max
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| format!("'{max}' is not a valid port"))?;
+        if min == 0 || min > max {
+            return Err(format!("'{value}' is not a valid port range"));
+        }
+        Ok(PortRange { min, max })
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.min..=self.max).contains(&port)
+    }
+
+    pub fn overlaps(&self, other: &PortRange) -> bool {
+        self.min <= other.max && other.min <= self.max
+    }
+
+    /// Pick a random port within the range
+    pub fn random_port(&self) -> u16 {
+        get_random_port(self.min, self.max)
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
+/// Parse a DSCP class name (e.g. "AF21", "EF", "CS1") or a raw value (0-63)
+pub fn parse_dscp(value: &str) -> Result<u8, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    let dscp = match upper.as_str() {
+        "EF" => 46,
+        "VA" => 44,
+        "BE" | "DF" => 0,
+        class if class.starts_with("CS") => match class[2..].parse::<u8>() {
+            Ok(n) if n <= 7 => n << 3,
+            _ => return Err(format!("'{value}' is not a valid class selector (CS0-CS7)")),
+        },
+        class if class.starts_with("AF") => {
+            let digits: Vec<u8> = class[2..].bytes().map(|b| b.wrapping_sub(b'0')).collect();
+            match digits.as_slice() {
+                [class @ 1..=4, drop @ 1..=3] => (class << 3) | (drop << 1),
+                _ => return Err(format!("'{value}' is not a valid AF class (AF11-AF43)")),
+            }
+        }
+        raw => match raw.parse::<u8>() {
+            Ok(n) if n <= 63 => n,
+            _ => return Err(format!("'{value}' is not a valid DSCP value")),
+        },
+    };
+    Ok(dscp)
+}
+
+// Hex Ed25519 public key the checksums of releases are signed with, set by the release
+// workflow; builds without it can't verify a release, so they never recommend one
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("PUNG_RELEASE_KEY");
+
+/// The latest release, if it's newer than ours and its signed checksums check out
 pub async fn check_for_updates(current_version: &str) -> Option<String> {
     // GitHub API URL for the latest release
     let url = "https://api.github.com/repos/ktlast/pung/releases/latest";
+    let client = reqwest::Client::new();
+
+    let body = download(&client, url, current_version).await?;
+    let json: serde_json::Value = serde_json::from_slice(&body).ok()?;
+    let latest_version = json
+        .get("tag_name")?
+        .as_str()?
+        .trim_start_matches('v')
+        .to_string();
+    // Compare versions (simple string comparison, assumes semver format)
+    if latest_version == current_version {
+        return None;
+    }
 
+    // Asset name -> download URL
+    let assets: HashMap<&str, &str> = json
+        .get("assets")?
+        .as_array()?
+        .iter()
+        .filter_map(|asset| {
+            Some((
+                asset.get("name")?.as_str()?,
+                asset.get("browser_download_url")?.as_str()?,
+            ))
+        })
+        .collect();
+    // Every archive comes with its checksum, and a signature over that checksum
+    let mut verified = 0;
+    for (name, checksums_url) in assets.iter().filter(|(name, _)| name.ends_with(".sha256")) {
+        let Some(signature_url) = assets.get(format!("{name}.sig").as_str()) else {
+            log::warn!("Release {latest_version} has no signature for {name}, not recommending it");
+            return None;
+        };
+        let checksums = download(&client, checksums_url, current_version).await?;
+        let signature = download(&client, signature_url, current_version).await?;
+        if !verify_release_checksums(&checksums, &signature, &latest_version) {
+            log::warn!(
+                "Release {latest_version} failed verification of {name}, not recommending it"
+            );
+            return None;
+        }
+        verified += 1;
+    }
+    (verified > 0).then_some(latest_version)
+}
+
+async fn download(client: &reqwest::Client, url: &str, current_version: &str) -> Option<Vec<u8>> {
     // Send request with proper User-Agent header (required by GitHub API)
-    match reqwest::Client::new()
+    let response = client
         .get(url)
         .header("User-Agent", format!("pung/{current_version}"))
         .send()
         .await
-    {
-        Ok(response) => {
-            if response.status().is_success() {
-                // Parse the JSON response
-                if let Ok(json) = response.json::<serde_json::Value>().await {
-                    // Extract the tag_name (version) from the response
-                    if let Some(tag_name) = json.get("tag_name").and_then(|v| v.as_str()) {
-                        let latest_version = tag_name.trim_start_matches('v');
-
-                        // Compare versions (simple string comparison, assumes semver format)
-                        if latest_version != current_version {
-                            return Some(latest_version.to_string());
-                        }
-                    }
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    response.bytes().await.ok().map(|bytes| bytes.to_vec())
+}
+
+// Whether `checksums` is signed with the release key and covers the archives of `version`,
+// so the checksums of an older release can't be passed off as a newer one's
+fn verify_release_checksums(checksums: &[u8], signature: &[u8], version: &str) -> bool {
+    let Some(key) = RELEASE_PUBLIC_KEY
+        .and_then(|key| hex::decode(key.trim()).ok())
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+    else {
+        log::debug!("This build has no release key to verify updates with");
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    key.verify(checksums, &signature).is_ok()
+        && String::from_utf8_lossy(checksums)
+            .lines()
+            .any(|line| line.contains(&format!("-{version}-")))
+}
+
+// Used when the output isn't a terminal (e.g. piped) and the size can't be queried
+const DEFAULT_TERMINAL_WIDTH: usize = 80;
+// Narrower chat lines can't fit a name and a time; wider ones are surely a typo
+const MIN_CHAT_WIDTH: usize = 20;
+const MAX_CHAT_WIDTH: usize = 1000;
+
+// Columns chat lines are laid out in, from --width or /set terminal_width
+static CHAT_WIDTH: AtomicUsize = AtomicUsize::new(DEFAULT_TERMINAL_WIDTH);
+
+pub fn chat_width() -> usize {
+    CHAT_WIDTH.load(Ordering::SeqCst)
+}
+
+pub fn set_chat_width(width: usize) -> Result<(), String> {
+    if !(MIN_CHAT_WIDTH..=MAX_CHAT_WIDTH).contains(&width) {
+        return Err(format!(
+            "The width must be between {MIN_CHAT_WIDTH} and {MAX_CHAT_WIDTH} columns"
+        ));
+    }
+    CHAT_WIDTH.store(width, Ordering::SeqCst);
+    Ok(())
+}
+
+// Columns output is laid out in when it doesn't get the whole terminal, like the --tui chat
+// pane; 0 when it does
+static OUTPUT_WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "tui")]
+pub fn set_output_width(width: usize) {
+    OUTPUT_WIDTH.store(width, Ordering::SeqCst);
+}
+
+/// Current width of the terminal, queried on every call so resizes are picked up, or of
+/// the part of it output goes to
+pub fn terminal_width() -> usize {
+    match OUTPUT_WIDTH.load(Ordering::SeqCst) {
+        0 => terminal_size::terminal_size()
+            .map(|(width, _)| width.0 as usize)
+            .unwrap_or(DEFAULT_TERMINAL_WIDTH),
+        width => width,
+    }
+}
+
+/// A chat line like `[alice]: hi`, with the time of the message right-aligned to `width` columns
+pub fn format_chat_line(base_msg: &str, timestamp: i64, width: usize) -> String {
+    let Some(time) = chat_time(timestamp) else {
+        return base_msg.to_string();
+    };
+    let time_display = format!(" ({time})");
+    let painted_time = theme::paint(Role::Timestamp, &time_display);
+    let padding = width
+        .saturating_sub(display_width(base_msg))
+        .saturating_sub(display_width(&time_display));
+    format!("{base_msg}{}{painted_time}", " ".repeat(padding))
+}
+
+// Set NO_COLOR to anything to get plain text everywhere (https://no-color.org)
+static USE_COLOR: LazyLock<bool> =
+    LazyLock::new(|| std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()));
+
+/// Wrap text in an ANSI color (e.g. 32 for green); boxes ignore the escape codes when
+/// measuring lines
+pub fn colorize(text: &str, color: u8) -> String {
+    if !*USE_COLOR {
+        return text.to_string();
+    }
+    format!("\x1b[{color}m{text}\x1b[0m")
+}
+
+// Characters with the columns they take up; ANSI escape sequences take none
+fn char_widths(text: &str) -> Vec<(char, usize)> {
+    let mut in_escape = false;
+    text.chars()
+        .map(|c| {
+            if c == '\x1b' {
+                in_escape = true;
+            }
+            if in_escape {
+                // Sequences like "\x1b[32m" end with a letter
+                if c.is_ascii_alphabetic() {
+                    in_escape = false;
                 }
+                return (c, 0);
             }
-            None
-        }
-        Err(_) => None,
-    }
+            (c, UnicodeWidthChar::width(c).unwrap_or(0))
+        })
+        .collect()
+}
+
+/// Columns a line takes up in the terminal
+pub fn display_width(text: &str) -> usize {
+    char_widths(text).iter().map(|(_, width)| width).sum()
+}
+
+/// As much of a line as fits in `width` columns
+pub fn truncate_to_width(text: &str, width: usize) -> String {
+    let mut used = 0;
+    char_widths(text)
+        .into_iter()
+        .take_while(|(_, char_width)| {
+            used += char_width;
+            used <= width
+        })
+        .map(|(c, _)| c)
+        .collect()
 }
 
 pub fn display_message_block(title: &str, messages: Vec<String>) {
+    for line in render_message_block(title, messages, terminal_width()) {
+        say!("{line}");
+    }
+}
+
+/// Render a titled box no wider than `max_width` columns, wrapping lines that don't fit
+pub fn render_message_block(title: &str, messages: Vec<String>, max_width: usize) -> Vec<String> {
     //   ┌───────┐
     //   │ title │
     // ┌─┴───────┴────┐
@@ -94,20 +468,26 @@ pub fn display_message_block(title: &str, messages: Vec<String>) {
     // │ message 2    │
     // └──────────────┘
     if messages.is_empty() {
-        return;
+        return vec![];
     }
 
-    // Find the maximum width needed for the box
-    let title_len = title.chars().count();
+    // The box can't get narrower than the title box sitting on top of it
+    let title_len = UnicodeWidthStr::width(title);
+    let min_content_width = title_len + 3;
+    let max_content_width = max_width.saturating_sub(4).max(min_content_width); // 2 columns of border and padding on each side
+
+    let messages: Vec<String> = messages
+        .iter()
+        .flat_map(|msg| wrap_line(msg, max_content_width))
+        .collect();
+
+    // The content width is the longest (wrapped) message, but at least wide enough for the title
     let max_message_len = messages
         .iter()
-        .map(|msg| msg.chars().count())
+        .map(|msg| display_width(msg))
         .max()
         .unwrap_or(0);
-
-    // The content width is the max of the title length and the longest message
-    // Add some extra padding for better appearance
-    let content_width = std::cmp::max(title_len, max_message_len);
+    let content_width = std::cmp::max(min_content_width, max_message_len);
 
     // Create a box with consistent width
     let box_width = content_width + 4; // 2 spaces on each side
@@ -122,30 +502,246 @@ pub fn display_message_block(title: &str, messages: Vec<String>) {
         " ".repeat(title_right_pad)
     );
 
+    let mut lines = Vec::with_capacity(messages.len() + 4);
+
     // Draw the title box (centered over the main box)
-    println!(
+    lines.push(format!(
         "  ┌{}{}{}┐",
         "─".repeat(title_left_pad),
         "─".repeat(title_len),
         "─".repeat(title_right_pad)
-    );
-    println!("  │{padded_title}│");
+    ));
+    lines.push(format!("  │{padded_title}│"));
 
     // Draw the top of the message box with connections to title box
-    println!(
+    lines.push(format!(
         "┌─┴{}{}{}┴{}┐",
         "─".repeat(title_left_pad),
         "─".repeat(title_len),
         "─".repeat(title_right_pad),
         "─".repeat(box_width - title_len - title_left_pad - title_right_pad - 5)
-    );
+    ));
 
     // Draw each message line with consistent padding
     for message in messages {
-        let padding = content_width - message.chars().count();
-        println!("│ {}{} │", message, " ".repeat(padding));
+        let padding = content_width - display_width(&message);
+        lines.push(format!("│ {}{} │", message, " ".repeat(padding)));
     }
 
     // Draw the bottom of the box
-    println!("└{}┘", "─".repeat(box_width - 2));
+    lines.push(format!("└{}┘", "─".repeat(box_width - 2)));
+    lines
+}
+
+// Wrap a line at word boundaries so no piece is wider than `width` columns;
+// every piece keeps the original indentation when there's room for it
+fn wrap_line(line: &str, width: usize) -> Vec<String> {
+    if display_width(line) <= width {
+        return vec![line.to_string()];
+    }
+
+    let indent_len = line.len() - line.trim_start().len();
+    let indent = if indent_len < width / 2 {
+        &line[..indent_len]
+    } else {
+        ""
+    };
+    let indent_width = UnicodeWidthStr::width(indent);
+
+    let mut wrapped = Vec::new();
+    let mut current = indent.to_string();
+    let mut current_width = indent_width;
+    let mut has_words = false;
+    for word in line.split_whitespace() {
+        let word_width = display_width(word);
+        if has_words && current_width + 1 + word_width > width {
+            wrapped.push(std::mem::replace(&mut current, indent.to_string()));
+            current_width = indent_width;
+            has_words = false;
+        }
+        if has_words {
+            current.push(' ');
+            current_width += 1;
+        }
+
+        // Hard-break words that don't fit on a line of their own
+        for (c, char_width) in char_widths(word) {
+            if has_words && current_width + char_width > width {
+                wrapped.push(std::mem::replace(&mut current, indent.to_string()));
+                current_width = indent_width;
+            }
+            current.push(c);
+            current_width += char_width;
+            has_words = true;
+        }
+    }
+    if has_words {
+        wrapped.push(current);
+    }
+    wrapped
+}
+
+/// Parse a duration like "90s", "10m", "2h" or "1d"; a bare number means minutes
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("'{value}' is not a valid duration"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "" | "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => {
+            return Err(format!(
+                "'{value}' is not a valid duration, use e.g. 30s, 10m or 2h"
+            ));
+        }
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn widths(lines: &[String]) -> Vec<usize> {
+        lines
+            .iter()
+            .map(|line| UnicodeWidthStr::width(line.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn wide_terminal_keeps_lines_intact() {
+        let lines = render_message_block(
+            "Help",
+            vec!["short".to_string(), "a somewhat longer line".to_string()],
+            200,
+        );
+        assert_eq!(lines.len(), 2 + 1 + 2 + 1);
+        assert_eq!(lines[3], "│ short                  │");
+        assert_eq!(lines[4], "│ a somewhat longer line │");
+    }
+
+    #[test]
+    fn narrow_terminal_wraps_inside_the_box() {
+        let long_line =
+            "    /stream <command>     ─ Run a shell command and stream its output to peers";
+        let lines = render_message_block("Help? (/h)", vec![long_line.to_string()], 40);
+
+        // Every box line has the same width and fits the terminal
+        let box_widths = widths(&lines[2..]);
+        assert!(box_widths.iter().all(|w| *w == box_widths[0]));
+        assert!(box_widths[0] <= 40);
+
+        // No words were lost, and continuation lines keep the indentation
+        let content: Vec<&str> = lines[3..lines.len() - 1]
+            .iter()
+            .map(|line| line.trim_start_matches("│ ").trim_end_matches(" │"))
+            .collect();
+        assert!(content.len() > 1);
+        assert!(content.iter().all(|line| line.starts_with("    ")));
+        assert_eq!(
+            content.join(" ").split_whitespace().collect::<Vec<_>>(),
+            long_line.split_whitespace().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn long_words_are_hard_broken() {
+        let lines = render_message_block("Peers", vec!["x".repeat(50)], 20);
+        let box_widths = widths(&lines[2..]);
+        assert!(box_widths.iter().all(|w| *w == 20));
+        assert_eq!(lines.len(), 3 + 4 + 1);
+    }
+
+    #[test]
+    fn wide_characters_are_measured_by_display_width() {
+        let lines =
+            render_message_block("Chat", vec!["你好世界 你好世界 你好世界".to_string()], 16);
+        let box_widths = widths(&lines[2..]);
+        assert!(box_widths.iter().all(|w| *w == box_widths[0]));
+        assert!(box_widths[0] <= 16);
+    }
+
+    #[test]
+    fn box_is_never_narrower_than_its_title() {
+        // Used to underflow when the content was shorter than the title
+        let lines = render_message_block("Traffic (/netstat)", vec!["x".to_string()], 10);
+        let box_widths = widths(&lines[2..]);
+        assert!(box_widths.iter().all(|w| *w == box_widths[0]));
+        assert!(box_widths[0] >= UnicodeWidthStr::width(lines[0].as_str()));
+    }
+
+    #[test]
+    fn empty_block_renders_nothing() {
+        assert!(render_message_block("Empty", vec![], 80).is_empty());
+    }
+
+    #[test]
+    fn colors_dont_count_towards_the_width() {
+        let lines = render_message_block(
+            "Peers",
+            vec![
+                format!("bob {}", colorize("[away]", 33)),
+                "alice [busy]".to_string(),
+            ],
+            80,
+        );
+        let box_widths: Vec<usize> = lines[2..].iter().map(|line| display_width(line)).collect();
+        assert!(box_widths.iter().all(|w| *w == box_widths[0]));
+    }
+
+    #[test]
+    fn chat_time_is_right_aligned() {
+        let line = format_chat_line("[alice]: 你好", 0, 40);
+        assert_eq!(display_width(&line), 40);
+        let time = display_time_from_timestamp(0);
+        assert!(line.ends_with(&format!(" ({time})")));
+        // Too long to align, the time just follows
+        let line = format_chat_line(&"x".repeat(50), 0, 40);
+        assert!(line.ends_with(&format!("x ({time})")));
+    }
+
+    #[test]
+    fn time_zones_are_local_or_an_offset() {
+        assert_eq!(parse_time_zone("local"), Ok(TimeZoneSetting::Local));
+        let offset = |seconds| {
+            Ok(TimeZoneSetting::Fixed(
+                FixedOffset::east_opt(seconds).unwrap(),
+            ))
+        };
+        assert_eq!(parse_time_zone("utc"), offset(0));
+        assert_eq!(parse_time_zone("+8"), offset(8 * 3600));
+        assert_eq!(parse_time_zone("UTC-05:30"), offset(-(5 * 3600 + 30 * 60)));
+        assert!(parse_time_zone("Europe/Berlin").is_err());
+        assert!(parse_time_zone("+8:75").is_err());
+        assert!(parse_time_zone("+-5").is_err());
+        assert!(parse_time_zone("+99999999").is_err());
+    }
+
+    #[test]
+    fn relative_times_use_the_largest_unit() {
+        assert_eq!(relative_time(-5), "now");
+        assert_eq!(relative_time(42), "42s ago");
+        assert_eq!(relative_time(150), "2m ago");
+        assert_eq!(relative_time(7300), "2h ago");
+        assert_eq!(relative_time(3 * 86400), "3d ago");
+        assert!(parse_time_format("%H:%M").is_ok());
+        assert!(parse_time_format("soon").is_err());
+    }
+
+    #[test]
+    fn durations_take_a_unit_or_default_to_minutes() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("m").is_err());
+    }
 }